@@ -0,0 +1,146 @@
+//! Render timing for synthetic candle series.
+//!
+//! Run with `cargo bench --bench render`. Each size is rendered a few times
+//! through `render_chart`; the report lists the median and fastest render
+//! and the peak heap use of a single render.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use corky_charts::{render_chart, ChartData, RenderOptions};
+
+const SIZES: [usize; 3] = [100, 1_000, 10_000];
+const RUNS: usize = 15;
+
+// ─── Allocation Tracking ────────────────────────────────────────────────────────
+
+/// Passes through to the system allocator while tracking live and peak bytes
+struct PeakAlloc;
+
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let live = LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK_BYTES.fetch_max(live, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAlloc = PeakAlloc;
+
+// ─── Synthetic Data ─────────────────────────────────────────────────────────────
+
+/// A 1-minute random walk of `count` candles with per-candle colors, a zone,
+/// a vertical line and a mark every 20 candles
+fn synthetic_chart(count: usize) -> ChartData {
+    const START_MS: i64 = 1_700_000_000_000;
+    const MINUTE_MS: i64 = 60_000;
+
+    let mut rows = Vec::with_capacity(count);
+    let mut colors = Vec::with_capacity(count);
+    let mut price = 30_000.0_f64;
+    // Small LCG so every run draws the same series without pulling in a rand crate
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = || {
+        seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+        (seed >> 33) as f64 / (1u64 << 31) as f64 - 0.5
+    };
+    for i in 0..count {
+        let open = price;
+        let close = (open + next() * 60.0).max(1.0);
+        let high = open.max(close) + next().abs() * 30.0;
+        let low = (open.min(close) - next().abs() * 30.0).max(0.5);
+        let volume = 500.0 + next().abs() * 2_000.0;
+        rows.push(serde_json::json!([START_MS + i as i64 * MINUTE_MS, open, high, low, close, volume]));
+        colors.push(if close >= open { "#26A69A" } else { "#EF5350" });
+        price = close;
+    }
+
+    let end_ms = START_MS + count as i64 * MINUTE_MS;
+    let marks: Vec<_> = (0..count)
+        .step_by(20)
+        .map(|i| {
+            serde_json::json!({
+                "time": START_MS + i as i64 * MINUTE_MS,
+                "position": if i % 40 == 0 { "above" } else { "below" },
+                "color": "#FFAA00",
+                "text": "sig",
+            })
+        })
+        .collect();
+
+    serde_json::from_value(serde_json::json!({
+        "title": format!("BENCH {} candles", count),
+        "ticker": "BENCH",
+        "timeframe": "1m",
+        "cols": ["timestamp", "open", "high", "low", "close", "volume"],
+        "data": rows,
+        "candle_colors": colors,
+        "plots": {
+            "marks": marks,
+            "zones": [{
+                "x1": START_MS,
+                "x2": START_MS + (end_ms - START_MS) / 3,
+                "y1": 29_900.0,
+                "y2": 30_100.0,
+                "color": "#00FF0030",
+            }],
+            "vlines": [{ "time": START_MS + (end_ms - START_MS) / 2, "color": "#0000FF" }],
+        },
+        "desc": "benchmark",
+        "last_price_dot": true,
+        "last_price_tick": true,
+    }))
+    .expect("synthetic chart data should deserialize")
+}
+
+// ─── Main ───────────────────────────────────────────────────────────────────────
+
+fn main() {
+    let opts = RenderOptions::default();
+    println!(
+        "{:>8}  {:>12}  {:>12}  {:>12}  {:>10}",
+        "candles", "median", "fastest", "peak heap", "png bytes"
+    );
+
+    for count in SIZES {
+        let data = synthetic_chart(count);
+
+        // Warm up once so font loading is not charged to the first size
+        let png = render_chart(&data, &opts).expect("benchmark chart should render");
+
+        let mut times: Vec<Duration> = Vec::with_capacity(RUNS);
+        let mut peak = 0;
+        for _ in 0..RUNS {
+            let baseline = LIVE_BYTES.load(Ordering::Relaxed);
+            PEAK_BYTES.store(baseline, Ordering::Relaxed);
+            let started = Instant::now();
+            black_box(render_chart(black_box(&data), &opts).expect("benchmark chart should render"));
+            times.push(started.elapsed());
+            peak = peak.max(PEAK_BYTES.load(Ordering::Relaxed) - baseline);
+        }
+        times.sort_unstable();
+
+        println!(
+            "{:>8}  {:>9.2} ms  {:>9.2} ms  {:>9.1} MB  {:>10}",
+            count,
+            times[RUNS / 2].as_secs_f64() * 1_000.0,
+            times[0].as_secs_f64() * 1_000.0,
+            peak as f64 / (1024.0 * 1024.0),
+            png.len()
+        );
+    }
+}
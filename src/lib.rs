@@ -0,0 +1,6167 @@
+//! Candlestick chart rendering for corky-charts.
+//!
+//! The service binary decodes requests off ZMQ and hands each [`ChartData`] to
+//! [`render_chart`]. Other tools, such as a backtester, can call it directly.
+
+use chrono::{DateTime, FixedOffset, Local, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{error::Error, fmt};
+
+// Add plotters
+use plotters::coord::Shift;
+use plotters::coord::types::RangedCoordf64;
+use plotters::element::DashedPathElement;
+use plotters::prelude::*;
+use plotters::style::text_anchor::{HPos, Pos, VPos};
+use plotters::style::{Color, IntoFont, RGBAColor, RGBColor, TextStyle};
+
+mod theme;
+use theme::Theme;
+
+// ─── Data Structures ────────────────────────────────────────────────────────────
+
+fn parse_hex_color(hex: &str) -> RGBColor {
+    let hex = hex.trim_start_matches('#');
+    if hex.len() == 6
+        && let (Ok(r), Ok(g), Ok(b)) = (
+            u8::from_str_radix(&hex[0..2], 16),
+            u8::from_str_radix(&hex[2..4], 16),
+            u8::from_str_radix(&hex[4..6], 16),
+        )
+    {
+        return RGBColor(r, g, b);
+    }
+    // Default to gray if parsing fails
+    RGBColor(128, 128, 128)
+}
+
+/// Parse a candle body color. Unlike `parse_hex_color`, anything that is not
+/// hex falls back to black and short values are read as a plain number.
+fn parse_candle_color(hex: &str) -> RGBColor {
+    let rgb = u32::from_str_radix(hex.trim_start_matches('#'), 16).unwrap_or(0);
+    RGBColor((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+}
+
+/// Parse hex color with alpha channel support (e.g., "#FF000020")
+fn parse_hex_color_with_alpha(hex: &str) -> RGBAColor {
+    let hex = hex.trim_start_matches('#');
+    if hex.len() == 8 {
+        // #RRGGBBAA format
+        if let (Ok(r), Ok(g), Ok(b), Ok(a)) = (
+            u8::from_str_radix(&hex[0..2], 16),
+            u8::from_str_radix(&hex[2..4], 16),
+            u8::from_str_radix(&hex[4..6], 16),
+            u8::from_str_radix(&hex[6..8], 16),
+        ) {
+            return RGBAColor(r, g, b, a as f64 / 255.0);
+        }
+    } else if hex.len() == 6 {
+        // #RRGGBB format - default 30% opacity
+        if let (Ok(r), Ok(g), Ok(b)) = (
+            u8::from_str_radix(&hex[0..2], 16),
+            u8::from_str_radix(&hex[2..4], 16),
+            u8::from_str_radix(&hex[4..6], 16),
+        ) {
+            return RGBAColor(r, g, b, 0.3);
+        }
+    }
+    // Default to semi-transparent gray if parsing fails
+    RGBAColor(128, 128, 128, 0.3)
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(remote = "Self")]
+pub struct ChartData {
+    /// Heading above the chart; each `\n` starts another line. Only drawn, never used
+    /// to name or look up anything.
+    pub title: String,
+    /// Symbol the image is filed under: the file name, candle cache, retention, image
+    /// metadata and logs all key on it
+    pub ticker: String,
+    /// Candle interval, e.g. `"15m"`; parsed for the time axis and keyed on with `ticker`
+    pub timeframe: String,
+    /// Ticker drawn on the chart (legend, footer) in place of `ticker`
+    #[serde(default)]
+    pub display_ticker: Option<String>,
+    /// Timeframe drawn next to the title and in the footer in place of `timeframe`
+    #[serde(default)]
+    pub display_timeframe: Option<String>,
+    /// Columns describing the data. Typically something like:
+    /// `["timestamp", "open", "high", "low", "close", "volume"]`
+    #[serde(default)]
+    pub cols: Vec<String>,
+    /// Each inner `Vec<f64>` is a row of candle data: [timestamp, open, high, low, close, volume].
+    /// Timestamps are in `timestamp_unit` on the wire and milliseconds once normalized.
+    /// A timestamp may also be sent as an ISO-8601 string, which is read as milliseconds
+    /// whatever `timestamp_unit` says.
+    #[serde(skip)]
+    pub data: Vec<Vec<f64>>,
+    /// `data` as read from the request. Its rows are moved to `data` straight away;
+    /// which of them had a string timestamp is kept for `normalize_timestamps`.
+    #[serde(default, rename = "data", deserialize_with = "deserialize_rows")]
+    sent_rows: SentRows,
+    /// `"full"` (default) when `data` is the whole history, or `"append"` when it only
+    /// holds new and updated candles for the service to merge into the last full one
+    #[serde(default)]
+    pub mode: DataMode,
+    /// Colors for each candle, e.g. `["#FF0000", "#00FF00", ...]`. Candles without
+    /// one take the color scheme's up or down color.
+    #[serde(default)]
+    pub candle_colors: Vec<String>,
+    /// Optional opacity of each candle body from 0 to 1, parallel to `candle_colors`.
+    /// Candles without an entry are fully opaque.
+    #[serde(default)]
+    pub candle_alpha: Vec<f64>,
+    /// What is done with a row whose high and low do not contain its open and close:
+    /// `"clamp"` (default), `"reject"` or `"flag"`
+    #[serde(default)]
+    pub ohlc_policy: OhlcPolicy,
+    /// Also hold a row to `ohlc_policy` when its range is more than this many times the
+    /// median candle range
+    #[serde(default)]
+    pub max_range_multiple: Option<f64>,
+    /// Optional colors for each volume bar, e.g. `["#FF0000", "#00FF00", ...]`
+    #[serde(default)]
+    pub volume_colors: Option<Vec<String>>,
+    /// Optional hex color for every volume bar, replacing the default gray
+    #[serde(default)]
+    pub volume_color: Option<String>,
+    /// Color each volume bar by its candle's direction: green if close >= open, red otherwise
+    #[serde(default)]
+    pub volume_color_by_direction: bool,
+    /// Named set of default colors: `"default"`, `"classic"`, `"tradingview"`,
+    /// `"mono"` or `"colorblind"`
+    #[serde(default)]
+    pub color_scheme: Option<String>,
+    /// Hex color of rising candles without a `candle_colors` entry, replacing the scheme's
+    #[serde(default)]
+    pub up_color: Option<String>,
+    /// Hex color of falling candles without a `candle_colors` entry, replacing the scheme's
+    #[serde(default)]
+    pub down_color: Option<String>,
+    /// Hex color of every wick, replacing the scheme's
+    #[serde(default)]
+    pub wick_color: Option<String>,
+    /// What makes a candle without a `candle_colors` entry rising: `"oc"` (default) for
+    /// a close at or above its open, `"prev_close"` for one at or above the previous close
+    #[serde(default)]
+    pub color_mode: ColorMode,
+    /// How volume maps to bar height: `"linear"` (default) or `"log"`
+    #[serde(default)]
+    pub volume_scale: VolumeScale,
+    /// Draw volume bars along the bottom of the price pane (default true)
+    #[serde(default = "default_show_volume")]
+    pub show_volume: bool,
+    /// Draw a faint line along the top of the volume bars' band, labelled with the
+    /// largest volume
+    #[serde(default)]
+    pub volume_separator: bool,
+    #[serde(default)]
+    pub plots: Plots,
+    pub desc: String,
+    /// Optional chat ID for telegram message
+    #[serde(default)]
+    pub chat_id: Option<i64>,
+    /// Optional subscriber list name for telegram message
+    #[serde(default)]
+    pub subscriber_list: Option<String>,
+    /// Optional output filename template, replacing the service's `--filename-template`
+    /// for this request; `filename` is accepted as well
+    #[serde(default, alias = "filename")]
+    pub image_filename: Option<String>,
+    /// Add the render time in milliseconds to the file name, before its extension, so
+    /// renders of the same name do not replace each other
+    #[serde(default)]
+    pub append_timestamp: bool,
+    /// How the rendered image is returned: `"file"` (default) or `"inline"`
+    #[serde(default)]
+    pub delivery: Delivery,
+    /// Optional close of the candle before the first one, used as the base of the
+    /// price change shown next to the title (defaults to the first candle's open)
+    #[serde(default)]
+    pub prev_close: Option<f64>,
+    /// Optional price for the current-price line and label, such as a mark, index or
+    /// mid price, in place of the last candle's close
+    #[serde(default)]
+    pub current_price: Option<f64>,
+    /// Draw the current price line across the chart (default true)
+    #[serde(default = "default_show_current_price")]
+    pub show_current_price: bool,
+    /// Draw a filled dot at the last candle's close, colored by its direction
+    #[serde(default)]
+    pub last_price_dot: bool,
+    /// Draw a short horizontal tick from the last close to the price axis
+    #[serde(default)]
+    pub last_price_tick: bool,
+    /// Tag the candles with the highest high and the lowest low with their price
+    #[serde(default)]
+    pub high_low_labels: bool,
+    /// Price axis scale: `"log"` (default) or `"linear"`. Series with zero or
+    /// negative prices are always drawn linear.
+    #[serde(default)]
+    pub price_scale: PriceScale,
+    /// What the price axis reads in: `"absolute"` prices (default) or `"percent"`
+    /// change from `percent_base`
+    #[serde(default)]
+    pub y_mode: YMode,
+    /// Price read as 0% with `y_mode: "percent"` (defaults to the first drawn
+    /// candle's open)
+    #[serde(default)]
+    pub percent_base: Option<f64>,
+    /// Add an axis left of the plot labelling the price ticks with their percent change
+    /// from the first drawn candle's open
+    #[serde(default)]
+    pub percent_axis: bool,
+    /// Other instruments drawn over the candles as lines of their percent change,
+    /// which puts the price axis in percent as with `y_mode: "percent"`
+    #[serde(default)]
+    pub compare: Vec<CompareSeries>,
+    /// Margin in pixels around the price chart (default 10); `margin` is accepted as well
+    #[serde(default, alias = "margin")]
+    pub chart_margin: Option<u32>,
+    /// Height in pixels of the time axis labels below the chart; 40 for level labels and
+    /// sized to the longest label when they are turned. `x_label_area` is accepted as well.
+    #[serde(default, alias = "x_label_area")]
+    pub x_label_area_size: Option<u32>,
+    /// Width in pixels of the price axis labels; sized to the widest price when absent.
+    /// `y_label_area` is accepted as well.
+    #[serde(default, alias = "y_label_area")]
+    pub y_label_area_size: Option<u32>,
+    /// Degrees the time axis labels are turned counterclockwise: 0 (default), 45 or 90
+    #[serde(default)]
+    pub x_label_rotation: u32,
+    /// Outline each candle body in a darker shade of its fill
+    #[serde(default)]
+    pub candle_border: bool,
+    /// Hex color for candle body outlines; implies `candle_border`
+    #[serde(default)]
+    pub candle_border_color: Option<String>,
+    /// `"solid"` (default), `"hollow_up"` or `"outline_all"`
+    #[serde(default)]
+    pub candle_fill: CandleFill,
+    /// Wick thickness in pixels
+    #[serde(default = "default_wick_width")]
+    pub wick_width: u32,
+    /// Candle body width as a percentage of the candle's time slot
+    #[serde(default = "default_body_width_pct")]
+    pub body_width_pct: f64,
+    /// Draw only the last N candles. The full history is still validated and cached.
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// `"fit_all"` (default), `"fixed_width"` or `"time"`
+    #[serde(default)]
+    pub candle_layout: CandleLayout,
+    /// Pixels per candle slot with the `"fixed_width"` layout (default 10)
+    #[serde(default)]
+    pub candle_width_px: Option<u32>,
+    /// Merge neighbouring candles when there are more than pixel columns in the plot,
+    /// so each drawn candle is about one pixel wide. Only affects the `"fit_all"` layout.
+    #[serde(default)]
+    pub aggregate: bool,
+    /// Squeeze stretches without candles longer than this many timeframe intervals,
+    /// such as nights and weekends, to one empty candle slot; at least 2
+    #[serde(default)]
+    pub compress_gaps: Option<f64>,
+    /// Move each candle to the nearest multiple of the candle interval before drawing,
+    /// so slightly irregular timestamps do not leave uneven gaps
+    #[serde(default)]
+    pub snap_timestamps: bool,
+    /// Unit of every timestamp in the request: `"s"`, `"ms"` or `"us"`.
+    /// Detected from the magnitude of the candle timestamps when absent.
+    #[serde(default)]
+    pub timestamp_unit: Option<TimestampUnit>,
+    /// Multiplier of every line width, 0.5 to 8. Lines are scaled with a canvas larger
+    /// than the default when absent.
+    #[serde(default)]
+    pub line_width: Option<f64>,
+    /// Resolution multiplier, e.g. 2 for retina displays. The canvas and every font,
+    /// line and margin grow by it, so the layout looks the same at any scale.
+    #[serde(default)]
+    pub scale: Option<f64>,
+    /// Also save a small preview of this size next to the image, e.g.
+    /// `{"width": 320, "height": 180}`
+    #[serde(default)]
+    pub thumbnail: Option<ThumbnailSize>,
+    /// Also save the pixel positions of the plot and of every candle next to the
+    /// image, as `<name>.coords.json`
+    #[serde(default)]
+    pub emit_coords: bool,
+    /// `"full"` (default) draws the whole chart; `"sparkline"` draws only a small
+    /// close-price line
+    #[serde(default)]
+    pub chart_style: ChartStyle,
+    /// Canvas size of a sparkline, [`SPARKLINE_SIZE`] when absent
+    #[serde(default)]
+    pub sparkline_size: Option<ThumbnailSize>,
+    /// Image format: `"png"` (default) or `"webp"`
+    #[serde(default)]
+    pub format: Option<ImageFormat>,
+    /// Quality of lossy formats, 0 to 100 (WebP default 80)
+    #[serde(default)]
+    pub quality: Option<f32>,
+    /// PNG compression level, `"fast"`, `"default"` or `"best"`; the service's
+    /// configured level when absent
+    #[serde(default)]
+    pub png_compression: Option<PngCompression>,
+    /// PNG row filter, `"adaptive"`, `"none"`, `"sub"`, `"up"`, `"avg"` or `"paeth"`;
+    /// the service's configured filter when absent
+    #[serde(default)]
+    pub png_filter: Option<PngFilter>,
+    /// Corner of the price pane holding the legend of its lines, or `"off"`
+    #[serde(default)]
+    pub legend: LegendPosition,
+    /// Side of the canvas the title keeps to: `"left"`, `"center"` (default) or `"right"`
+    #[serde(default)]
+    pub title_align: TitleAlign,
+    /// What a title line too wide for the canvas does: `"wrap"` (default) onto the next
+    /// line, or `"truncate"` with "…"
+    #[serde(default)]
+    pub title_wrap: TitleWrap,
+    /// Cut each title line longer than this many characters, ending it with "…"
+    #[serde(default)]
+    pub title_max_chars: Option<usize>,
+    /// Mini-chart of the newest candles in a corner of the price pane
+    #[serde(default)]
+    pub zoom_inset: Option<ZoomInset>,
+    /// Volume of the drawn candles by price, as bars along the right edge of the price pane
+    #[serde(default)]
+    pub volume_profile: Option<VolumeProfile>,
+    /// Draw a faint vertical line between the last candle of each day and the first of
+    /// the next, in the time axis timezone
+    #[serde(default)]
+    pub day_separators: bool,
+    /// Label each day separator with the date of the day it starts
+    #[serde(default)]
+    pub day_separator_labels: bool,
+    /// Add a strip under the time axis naming the ticker, timeframe, candle count,
+    /// candle dates and when the image was generated
+    #[serde(default)]
+    pub show_footer: bool,
+    /// Free text appended to the footer; implies `show_footer`
+    #[serde(default)]
+    pub footer: Option<String>,
+    /// Charts drawn side by side in one image in place of `data`, each with its own
+    /// candles, title and options
+    #[serde(default)]
+    pub panels: Vec<ChartData>,
+    /// How `panels` are arranged: `"stacked"` (default) or `"grid"`
+    #[serde(default)]
+    pub layout: PanelLayout,
+}
+
+fn default_wick_width() -> u32 {
+    1
+}
+
+fn default_body_width_pct() -> f64 {
+    80.0
+}
+
+fn default_show_volume() -> bool {
+    true
+}
+
+fn default_show_current_price() -> bool {
+    true
+}
+
+/// One value of a candle row as sent: a number, or an ISO-8601 timestamp
+enum RowCell {
+    Number(f64),
+    Text(String),
+}
+
+impl<'de> Deserialize<'de> for RowCell {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct CellVisitor;
+        impl serde::de::Visitor<'_> for CellVisitor {
+            type Value = RowCell;
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a number or an ISO-8601 timestamp string")
+            }
+            fn visit_f64<E>(self, value: f64) -> Result<RowCell, E> {
+                Ok(RowCell::Number(value))
+            }
+            fn visit_i64<E>(self, value: i64) -> Result<RowCell, E> {
+                Ok(RowCell::Number(value as f64))
+            }
+            fn visit_u64<E>(self, value: u64) -> Result<RowCell, E> {
+                Ok(RowCell::Number(value as f64))
+            }
+            fn visit_str<E>(self, value: &str) -> Result<RowCell, E> {
+                Ok(RowCell::Text(value.to_string()))
+            }
+        }
+        deserializer.deserialize_any(CellVisitor)
+    }
+}
+
+/// Milliseconds since the epoch of an RFC 3339 time such as `2024-03-14T09:30:00Z` or
+/// `2024-03-14T09:30:00-04:00`. A time without an offset is read as UTC.
+fn parse_iso_millis(text: &str) -> Result<i64, chrono::ParseError> {
+    DateTime::parse_from_rfc3339(text)
+        .map(|dt| dt.timestamp_millis())
+        .or_else(|e| match NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f") {
+            Ok(naive) => Ok(naive.and_utc().timestamp_millis()),
+            Err(_) => Err(e),
+        })
+}
+
+/// Candle rows as sent, and the positions of those whose timestamp was a string
+#[derive(Debug, Clone, Default)]
+struct SentRows {
+    rows: Vec<Vec<f64>>,
+    /// Rows whose timestamp was an ISO-8601 string, and so is already in milliseconds
+    text_timestamps: Vec<usize>,
+}
+
+impl<'de> Deserialize<'de> for ChartData {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut data = ChartData::deserialize(deserializer)?;
+        data.data = std::mem::take(&mut data.sent_rows.rows);
+        Ok(data)
+    }
+}
+
+/// Candle rows whose timestamps may be numbers or ISO-8601 strings. Strings are
+/// converted to milliseconds; any other value must be a number.
+fn deserialize_rows<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<SentRows, D::Error> {
+    struct RowsVisitor;
+    impl<'de> serde::de::Visitor<'de> for RowsVisitor {
+        type Value = SentRows;
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("an array of candle rows")
+        }
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: serde::de::SeqAccess<'de>,
+        {
+            // The hint of a binary payload is only a claim, so it is not trusted too far
+            let mut rows = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(1 << 16));
+            let mut text_timestamps = Vec::new();
+            while let Some((row, text_timestamp)) = seq.next_element_seed(RowSeed(rows.len()))? {
+                if text_timestamp {
+                    text_timestamps.push(rows.len());
+                }
+                rows.push(row);
+            }
+            Ok(SentRows { rows, text_timestamps })
+        }
+    }
+    deserializer.deserialize_seq(RowsVisitor)
+}
+
+/// Reads data row `.0` straight into numbers, without buffering its cells, and tells
+/// whether its timestamp was a string
+struct RowSeed(usize);
+
+impl<'de> serde::de::DeserializeSeed<'de> for RowSeed {
+    type Value = (Vec<f64>, bool);
+    fn deserialize<D>(self, deserializer: D) -> Result<(Vec<f64>, bool), D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for RowSeed {
+    type Value = (Vec<f64>, bool);
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a candle row")
+    }
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        use serde::de::Error as _;
+        let mut row = Vec::with_capacity(6);
+        let mut text_timestamp = false;
+        while let Some(cell) = seq.next_element::<RowCell>()? {
+            let value = match cell {
+                RowCell::Number(value) => value,
+                RowCell::Text(text) if row.is_empty() => match parse_iso_millis(&text) {
+                    Ok(millis) => {
+                        text_timestamp = true;
+                        millis as f64
+                    }
+                    Err(e) => {
+                        return Err(A::Error::custom(format!(
+                            "data row {}: timestamp \"{}\" is not an ISO-8601 time: {}",
+                            self.0, text, e
+                        )));
+                    }
+                },
+                RowCell::Text(text) => {
+                    return Err(A::Error::custom(format!(
+                        "data row {}: \"{}\" in column {} is not a number; only the \
+                         timestamp may be a string",
+                        self.0,
+                        text,
+                        row.len()
+                    )));
+                }
+            };
+            row.push(value);
+        }
+        Ok((row, text_timestamp))
+    }
+}
+
+impl ChartData {
+    /// The ticker as drawn on the chart
+    pub fn shown_ticker(&self) -> &str {
+        self.display_ticker.as_deref().unwrap_or(&self.ticker)
+    }
+
+    /// Rewrite candle, mark, zone, vline and comparison timestamps in place as
+    /// milliseconds, the unit everything downstream of parsing works in. Candle
+    /// timestamps sent as strings are already in milliseconds and are left alone.
+    pub fn normalize_timestamps(&mut self) {
+        for panel in &mut self.panels {
+            panel.normalize_timestamps();
+        }
+        // Sorted, as rows are numbered in the order they are read
+        let text_timestamps = std::mem::take(&mut self.sent_rows.text_timestamps);
+        let numeric_timestamps: Vec<(usize, f64)> = self
+            .data
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| text_timestamps.binary_search(i).is_err())
+            .filter_map(|(i, row)| Some((i, *row.first()?)))
+            .collect();
+        let unit = self.timestamp_unit.unwrap_or_else(|| {
+            if numeric_timestamps.is_empty() && !text_timestamps.is_empty() {
+                return TimestampUnit::Ms;
+            }
+            let largest = numeric_timestamps
+                .iter()
+                .fold(0.0_f64, |acc, (_, ts)| acc.max(ts.abs()));
+            TimestampUnit::detect(largest)
+        });
+        self.timestamp_unit = Some(TimestampUnit::Ms);
+        if unit == TimestampUnit::Ms {
+            return;
+        }
+        debug!("[DATA] Converting timestamps from {:?} to milliseconds", unit);
+
+        for (i, ts) in numeric_timestamps {
+            self.data[i][0] = unit.f64_to_millis(ts);
+        }
+        for mark in &mut self.plots.marks {
+            match mark {
+                PlotMark::Marker(mark) => mark.time = unit.to_millis(mark.time),
+                PlotMark::Signal(signal) => signal.timestamp = unit.to_millis(signal.timestamp),
+                PlotMark::Zone(zone) => {
+                    zone.from_timestamp = unit.to_millis(zone.from_timestamp);
+                    zone.to_timestamp = unit.to_millis(zone.to_timestamp);
+                }
+                PlotMark::Position(position) => {
+                    position.opened_ts = unit.to_millis(position.opened_ts)
+                }
+                PlotMark::Ema(_)
+                | PlotMark::Vwap(_)
+                | PlotMark::Series(_)
+                | PlotMark::Histogram(_)
+                | PlotMark::Band(_)
+                | PlotMark::Keltner(_) => {}
+            }
+        }
+        for zone in &mut self.plots.zones {
+            zone.x1 = unit.to_millis(zone.x1);
+            zone.x2 = unit.to_millis(zone.x2);
+        }
+        for vline in &mut self.plots.vlines {
+            vline.time = unit.to_millis(vline.time);
+        }
+        for series in &mut self.compare {
+            for (ts, _) in &mut series.data {
+                *ts = unit.f64_to_millis(*ts);
+            }
+        }
+    }
+
+    /// With `snap_timestamps`, move each row's timestamp to the nearest multiple of the
+    /// candle interval: the stated timeframe, or else the rows' median spacing. Multiples
+    /// count from the epoch, or from the first row for intervals longer than a day, whose
+    /// weeks and months need not line up with the epoch. Everything derived from the
+    /// rows, candles and indicators alike, then shares the snapped times. Timestamps
+    /// must already be in milliseconds.
+    pub fn snap_row_timestamps(&mut self) {
+        for panel in &mut self.panels {
+            panel.snap_row_timestamps();
+        }
+        if !self.snap_timestamps {
+            return;
+        }
+        let mut sorted: Vec<i64> = self
+            .data
+            .iter()
+            .filter_map(|row| row.first())
+            .filter(|ts| ts.is_finite())
+            .map(|ts| *ts as i64)
+            .collect();
+        sorted.sort_unstable();
+        let Some(interval) =
+            parse_timeframe_millis(&self.timeframe).or_else(|| median_interval_millis(&sorted))
+        else {
+            return;
+        };
+        let anchor = if interval > DAY_MS { sorted[0] } else { 0 };
+        let snap = |millis: i64| {
+            anchor + (millis - anchor + interval / 2).div_euclid(interval) * interval
+        };
+
+        let mut largest_move = 0;
+        for row in &mut self.data {
+            let Some(ts) = row.first_mut().filter(|ts| ts.is_finite()) else {
+                continue;
+            };
+            let millis = *ts as i64;
+            let snapped = snap(millis);
+            largest_move = largest_move.max((snapped - millis).abs());
+            *ts = snapped as f64;
+        }
+        let mut snapped: Vec<i64> = sorted.iter().map(|&millis| snap(millis)).collect();
+        snapped.dedup();
+        if snapped.len() < sorted.len() {
+            warn!(
+                "Snapping to the {} interval put several candles at the same time",
+                format_interval(interval)
+            );
+        }
+        info!(
+            "Snapped candles to the {} interval; the largest snap moved a candle by {} ms",
+            format_interval(interval),
+            largest_move
+        );
+    }
+
+    /// Whether the rows carry volume. Without `cols` the sixth value is taken to be
+    /// volume; with them, a feed that lists no `volume` column has none.
+    pub fn has_volume_column(&self) -> bool {
+        self.column_index("volume").is_some()
+    }
+
+    /// Position of the column called `name` (ignoring case) in each row. Without
+    /// `cols`, rows are taken to be `timestamp, open, high, low, close, volume`.
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        const DEFAULT_COLUMNS: [&str; 6] = ["timestamp", "open", "high", "low", "close", "volume"];
+        if self.cols.is_empty() {
+            DEFAULT_COLUMNS.iter().position(|col| col.eq_ignore_ascii_case(name))
+        } else {
+            self.cols.iter().position(|col| col.eq_ignore_ascii_case(name))
+        }
+    }
+}
+
+/// Unit of the timestamps in a request
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampUnit {
+    S,
+    Ms,
+    Us,
+}
+
+impl TimestampUnit {
+    /// Guess the unit from the largest timestamp magnitude. 1e11 seconds and 1e14
+    /// milliseconds are both in the year 5138, while 1e11 ms is still in 1973,
+    /// so any real-world date falls clearly on one side of each threshold.
+    fn detect(largest: f64) -> Self {
+        if largest < 1e11 {
+            TimestampUnit::S
+        } else if largest < 1e14 {
+            TimestampUnit::Ms
+        } else {
+            TimestampUnit::Us
+        }
+    }
+
+    fn to_millis(self, ts: i64) -> i64 {
+        match self {
+            TimestampUnit::S => ts.saturating_mul(1_000),
+            TimestampUnit::Ms => ts,
+            TimestampUnit::Us => ts / 1_000,
+        }
+    }
+
+    fn f64_to_millis(self, ts: f64) -> f64 {
+        match self {
+            TimestampUnit::S => ts * 1_000.0,
+            TimestampUnit::Ms => ts,
+            TimestampUnit::Us => (ts / 1_000.0).trunc(),
+        }
+    }
+}
+
+/// What a request's `data` holds
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DataMode {
+    /// The whole candle history
+    #[default]
+    Full,
+    /// Only the newest candles; earlier ones come from the service's last full request
+    /// for the same ticker and timeframe
+    Append,
+}
+
+/// How a rendered chart is handed back to the requester
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Delivery {
+    /// Write the image to the output directory and reply with its path
+    #[default]
+    File,
+    /// Send the encoded image bytes as an extra frame of the reply
+    Inline,
+}
+
+/// Canvas size of a thumbnail, in pixels
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct ThumbnailSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Scaling of volume bars within the volume band
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeScale {
+    /// Bar height proportional to volume
+    #[default]
+    Linear,
+    /// Bar height proportional to `ln(1 + volume)`, so one spike does not flatten the rest
+    Log,
+}
+
+/// Where the legend of the price pane lines goes
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LegendPosition {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    /// No legend
+    Off,
+}
+
+/// Horizontal placement of the title
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TitleAlign {
+    /// Against the left edge of the plot
+    Left,
+    #[default]
+    Center,
+    /// Ending, with its timeframe badge and price change, at the right edge of the plot
+    Right,
+}
+
+/// How a title line wider than the canvas is made to fit
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TitleWrap {
+    /// Break it at spaces onto further lines, and inside a word only when the word
+    /// alone is too wide
+    #[default]
+    Wrap,
+    /// Cut it short, ending in "…"
+    Truncate,
+}
+
+/// A mini-chart of the newest `candles` candles on their own price range, in a
+/// `corner` of the price pane, sized as a fraction of the pane
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct ZoomInset {
+    #[serde(default = "default_inset_candles")]
+    pub candles: usize,
+    #[serde(default = "default_inset_pct")]
+    pub width_pct: f64,
+    #[serde(default = "default_inset_pct")]
+    pub height_pct: f64,
+    #[serde(default)]
+    pub corner: InsetCorner,
+}
+
+fn default_inset_candles() -> usize {
+    60
+}
+
+fn default_inset_pct() -> f64 {
+    0.35
+}
+
+/// Corner of the price pane holding the zoom inset
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InsetCorner {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+/// Volume of the drawn candles bucketed into `bins` equal bands of the price axis,
+/// drawn as bars reaching left from the right edge of the price pane, the longest
+/// `width_pct` of the pane's width
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct VolumeProfile {
+    #[serde(default = "default_profile_bins")]
+    pub bins: usize,
+    #[serde(default = "default_profile_width_pct")]
+    pub width_pct: f64,
+    /// Hex color of the bars, the color scheme's volume color when absent
+    #[serde(default)]
+    pub color: Option<String>,
+    /// Draw the bin with the most volume, the point of control, in full color
+    #[serde(default = "default_highlight_poc")]
+    pub highlight_poc: bool,
+    /// Hex color of the point of control, the bar color when absent
+    #[serde(default)]
+    pub poc_color: Option<String>,
+}
+
+fn default_profile_bins() -> usize {
+    24
+}
+
+fn default_profile_width_pct() -> f64 {
+    0.25
+}
+
+fn default_highlight_poc() -> bool {
+    true
+}
+
+/// Most bins a volume profile may have
+pub const MAX_PROFILE_BINS: usize = 500;
+
+/// Most panels one image holds
+pub const MAX_PANELS: usize = 4;
+
+/// Arrangement of the panels of a composite chart
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PanelLayout {
+    /// One panel above the other, each the full width of the image
+    #[default]
+    Stacked,
+    /// Two panels to a row, filled left to right and top to bottom
+    Grid,
+}
+
+impl PanelLayout {
+    /// Rows and columns of the cells holding `panels` panels
+    fn cells(self, panels: usize) -> (usize, usize) {
+        match self {
+            PanelLayout::Stacked => (panels, 1),
+            PanelLayout::Grid => (panels.div_ceil(2), 2),
+        }
+    }
+}
+
+/// How candles are spread across the plot
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CandleLayout {
+    /// Every candle is drawn, narrowed or widened to fill the plot
+    #[default]
+    FitAll,
+    /// Each candle gets `candle_width_px` pixels; the newest candles that fit are
+    /// drawn against the right edge and older ones are left out
+    FixedWidth,
+    /// Each candle is one timeframe interval wide on the time axis, so missing
+    /// candles leave real gaps
+    Time,
+}
+
+/// What is done with candle rows whose prices do not hang together
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OhlcPolicy {
+    /// Fail the chart, listing the bad rows
+    Reject,
+    /// Widen the high and low to contain the open and close, and log the rows
+    #[default]
+    Clamp,
+    /// Draw the rows as sent with a warning marker above each, and log them
+    Flag,
+}
+
+/// How a candle's direction is judged for its default body color
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorMode {
+    /// Close against the candle's own open
+    #[default]
+    Oc,
+    /// Close against the previous candle's close, i.e. the net change; the first
+    /// candle has none and falls back to its open
+    PrevClose,
+}
+
+/// Which candle bodies are filled
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CandleFill {
+    /// Every body is filled with its color
+    #[default]
+    Solid,
+    /// Rising candles are outlined in their color around an empty body; falling
+    /// candles stay filled
+    HollowUp,
+    /// Every body is outlined in its color around an empty body
+    OutlineAll,
+}
+
+impl CandleFill {
+    fn is_hollow(self, open: f64, close: f64) -> bool {
+        match self {
+            CandleFill::Solid => false,
+            CandleFill::HollowUp => close >= open,
+            CandleFill::OutlineAll => true,
+        }
+    }
+}
+
+/// Scaling of the price axis
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PriceScale {
+    /// Equal percentage moves take equal height
+    #[default]
+    Log,
+    /// Equal price moves take equal height; required once prices reach zero
+    Linear,
+}
+
+impl PriceScale {
+    /// Map a price to the chart's y coordinate. Under log scale, non-positive
+    /// values (e.g. a zone edge at 0) clamp to the bottom instead of becoming NaN.
+    fn price_to_axis(self, price: f64) -> f64 {
+        match self {
+            PriceScale::Log => price.max(1e-12).ln(),
+            PriceScale::Linear => price,
+        }
+    }
+
+    fn axis_to_price(self, y: f64) -> f64 {
+        match self {
+            PriceScale::Log => y.exp(),
+            PriceScale::Linear => y,
+        }
+    }
+}
+
+/// What the price axis reads in
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum YMode {
+    /// Prices, on the request's `price_scale`
+    #[default]
+    Absolute,
+    /// Linear percent change from a base price, for comparing relative moves
+    Percent,
+}
+
+/// Closes of another instrument drawn as a line of percent change from its first
+/// close in the chart, for comparing relative strength
+#[derive(Debug, Deserialize, Clone)]
+pub struct CompareSeries {
+    /// Name of the instrument, used in error messages and the legend
+    pub ticker: String,
+    /// `[timestamp, close]` points. Each goes to the nearest candle; points outside
+    /// the drawn candles are dropped.
+    pub data: Vec<(f64, f64)>,
+    /// Hex color "#RRGGBB" or "#RRGGBBAA"
+    pub color: String,
+}
+
+/// How the price pane maps prices to its y coordinate: the price scale, or a linear
+/// percent change from `percent_base` when the axis reads in percent
+#[derive(Debug, Clone, Copy)]
+struct PriceAxis {
+    scale: PriceScale,
+    percent_base: Option<f64>,
+}
+
+impl PriceAxis {
+    fn price_to_axis(self, price: f64) -> f64 {
+        match self.percent_base {
+            Some(base) => (price - base) / base.abs() * 100.0,
+            None => self.scale.price_to_axis(price),
+        }
+    }
+
+    fn axis_to_price(self, y: f64) -> f64 {
+        match self.percent_base {
+            Some(base) => base + y / 100.0 * base.abs(),
+            None => self.scale.axis_to_price(y),
+        }
+    }
+}
+
+/// Marker to be drawn on the chart (e.g., signal indicators)
+#[derive(Debug, Deserialize, Clone)]
+pub struct Mark {
+    /// Timestamp in milliseconds (candle timestamp)
+    pub time: i64,
+    /// "above" or "below" the candle
+    pub position: String,
+    /// Hex color "#RRGGBB"
+    pub color: String,
+    /// Optional label text (e.g., "4h")
+    #[serde(default)]
+    pub text: Option<String>,
+    /// Relative size (default 1.0)
+    #[serde(default = "default_mark_size")]
+    pub size: f64,
+    /// Drawing layer, see [`PlotMark`] (default 10)
+    #[serde(default)]
+    pub z: Option<i32>,
+}
+
+fn default_mark_size() -> f64 {
+    1.0
+}
+
+/// Translucent box drawn from `plots.marks` with `"type": "zone"`, e.g. a supply
+/// or demand zone
+#[derive(Debug, Deserialize, Clone)]
+pub struct ZoneMark {
+    /// Left edge timestamp (ms)
+    pub from_timestamp: i64,
+    /// Right edge timestamp (ms)
+    pub to_timestamp: i64,
+    /// One price edge of the box
+    pub from_price: f64,
+    /// The other price edge of the box
+    pub to_price: f64,
+    /// Hex color "#RRGGBBAA"; "#RRGGBB" gets 30% opacity
+    pub color: String,
+    /// Drawing layer, see [`PlotMark`] (default -10)
+    #[serde(default)]
+    pub z: Option<i32>,
+}
+
+/// Exponential moving average line drawn from `plots.marks` with `"type": "ema"`
+#[derive(Debug, Deserialize, Clone)]
+pub struct EmaMark {
+    /// Number of values averaged, at least 1
+    pub period: usize,
+    /// Hex color "#RRGGBB" or "#RRGGBBAA"
+    pub color: String,
+    /// Column of `cols` to average (default "close")
+    #[serde(default = "default_ema_source")]
+    pub source: String,
+    /// Line width in pixels (default 2)
+    #[serde(default)]
+    pub width: Option<u32>,
+    /// Drawing layer, see [`PlotMark`] (default 0)
+    #[serde(default)]
+    pub z: Option<i32>,
+}
+
+fn default_ema_source() -> String {
+    "close".to_string()
+}
+
+/// Volume-weighted average price line drawn from `plots.marks` with `"type": "vwap"`
+#[derive(Debug, Deserialize, Clone)]
+pub struct VwapMark {
+    /// Hex color "#RRGGBB" or "#RRGGBBAA"
+    pub color: String,
+    /// Where the average starts over (default `"session"`)
+    #[serde(default)]
+    pub anchor: VwapAnchor,
+    /// Line width in pixels (default 2)
+    #[serde(default)]
+    pub width: Option<u32>,
+    /// Drawing layer, see [`PlotMark`] (default 0)
+    #[serde(default)]
+    pub z: Option<i32>,
+}
+
+/// Values computed by the sender, drawn from `plots.marks` with `"type": "series"`
+#[derive(Debug, Deserialize, Clone)]
+pub struct SeriesMark {
+    /// Name of the series, used in error messages
+    pub name: String,
+    /// One value per row of `data`, `null` where the series has no value
+    pub values: Vec<Option<f64>>,
+    /// Hex color "#RRGGBB" or "#RRGGBBAA"
+    pub color: String,
+    /// How the values are drawn (default `"line"`)
+    #[serde(default)]
+    pub style: SeriesStyle,
+    /// Pane the values are drawn in (default `"price"`)
+    #[serde(default)]
+    pub pane: SeriesPane,
+    /// Line width or dot radius in pixels (default 2)
+    #[serde(default)]
+    pub width: Option<u32>,
+    /// Drawing layer, see [`PlotMark`] (default 0)
+    #[serde(default)]
+    pub z: Option<i32>,
+}
+
+/// Bars above or below zero computed by the sender, such as a MACD histogram or volume
+/// delta, drawn from `plots.marks` with `"type": "histogram"`
+#[derive(Debug, Deserialize, Clone)]
+pub struct HistogramMark {
+    /// Name of the histogram, used in error messages
+    #[serde(default)]
+    pub name: Option<String>,
+    /// One value per row of `data`, `null` where there is no bar
+    pub values: Vec<Option<f64>>,
+    /// Hex color of bars at or above zero
+    pub pos_color: String,
+    /// Hex color of bars below zero
+    pub neg_color: String,
+    /// Pane the bars are drawn in; only `"lower"` is supported
+    #[serde(default = "default_histogram_pane")]
+    pub pane: SeriesPane,
+    /// Drawing layer, see [`PlotMark`] (default -10)
+    #[serde(default)]
+    pub z: Option<i32>,
+}
+
+fn default_histogram_pane() -> SeriesPane {
+    SeriesPane::Lower
+}
+
+/// Area between two series computed by the sender, such as a channel, drawn from
+/// `plots.marks` with `"type": "band"`
+#[derive(Debug, Deserialize, Clone)]
+pub struct BandMark {
+    /// Name of the band, used in error messages and the legend
+    #[serde(default)]
+    pub name: Option<String>,
+    /// One upper edge value per row of `data`, `null` where the band has a gap
+    pub upper: Vec<Option<f64>>,
+    /// One lower edge value per row of `data`, `null` where the band has a gap
+    pub lower: Vec<Option<f64>>,
+    /// Hex color of the fill
+    pub color: String,
+    /// Opacity of the fill from 0 to 1 (default 0.2)
+    #[serde(default = "default_band_opacity")]
+    pub opacity: f64,
+    /// Hex color of a line along the upper edge; no line when absent
+    #[serde(default)]
+    pub upper_color: Option<String>,
+    /// Hex color of a line along the lower edge; no line when absent
+    #[serde(default)]
+    pub lower_color: Option<String>,
+    /// Width in pixels of the edge lines (default 1)
+    #[serde(default)]
+    pub width: Option<u32>,
+    /// Drawing layer, see [`PlotMark`] (default -10)
+    #[serde(default)]
+    pub z: Option<i32>,
+}
+
+fn default_band_opacity() -> f64 {
+    0.2
+}
+
+/// Keltner Channels drawn from `plots.marks` with `"type": "keltner"`: an EMA of the
+/// closes with bands `atr_mult` average true ranges above and below it
+#[derive(Debug, Deserialize, Clone)]
+pub struct KeltnerMark {
+    /// Number of candles in the EMA and the ATR, at least 1
+    pub period: usize,
+    /// Distance of the bands from the midline in ATRs (default 2)
+    #[serde(default = "default_keltner_atr_mult")]
+    pub atr_mult: f64,
+    /// Hex color "#RRGGBB" or "#RRGGBBAA" of the midline and both bands
+    pub color: String,
+    /// Opacity from 0 to 1 of a fill between the bands in `color`; no fill when absent
+    #[serde(default)]
+    pub fill_opacity: Option<f64>,
+    /// Line width in pixels (default 1)
+    #[serde(default)]
+    pub width: Option<u32>,
+    /// Drawing layer, see [`PlotMark`] (default 0)
+    #[serde(default)]
+    pub z: Option<i32>,
+}
+
+fn default_keltner_atr_mult() -> f64 {
+    2.0
+}
+
+/// Open trade drawn from `plots.marks` with `"type": "position"`: its entry, stop and
+/// targets, with the risk and reward shaded from the candle it was opened on
+#[derive(Debug, Deserialize, Clone)]
+pub struct PositionMark {
+    /// Direction of the trade, which decides the side the stop and targets are on
+    pub side: PositionSide,
+    /// Entry price
+    pub entry: f64,
+    /// Stop-loss price
+    pub stop: f64,
+    /// Take-profit prices, nearest first
+    #[serde(default)]
+    pub targets: Vec<f64>,
+    /// Time the position was opened (ms); the zones start at the nearest candle
+    pub opened_ts: i64,
+    /// Text shown before the R-multiples, e.g. "swing #12"
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Drawing layer, see [`PlotMark`] (default 10)
+    #[serde(default)]
+    pub z: Option<i32>,
+}
+
+/// Direction of a position mark
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PositionSide {
+    /// Profits when the price rises; the stop is below the entry
+    Long,
+    /// Profits when the price falls; the stop is above the entry
+    Short,
+}
+
+impl PositionMark {
+    /// Reward-to-risk ratio of each target
+    fn r_multiples(&self) -> impl Iterator<Item = f64> + '_ {
+        let risk = (self.entry - self.stop).abs();
+        self.targets.iter().map(move |target| (target - self.entry).abs() / risk)
+    }
+
+    /// Check the prices are finite and the stop and targets are on the right side
+    fn validate(&self) -> Result<(), ChartError> {
+        let mut prices = [self.entry, self.stop].into_iter().chain(self.targets.iter().copied());
+        if let Some(price) = prices.find(|price| !price.is_finite()) {
+            return Err(ChartError::invalid(format!(
+                "position prices must be finite, got {}",
+                price
+            )));
+        }
+        // Whether `price` lies on the profit side of the entry
+        let in_profit = |price: f64| match self.side {
+            PositionSide::Long => price > self.entry,
+            PositionSide::Short => price < self.entry,
+        };
+        let (side, profit_side, loss_side) = match self.side {
+            PositionSide::Long => ("long", "above", "below"),
+            PositionSide::Short => ("short", "below", "above"),
+        };
+        if in_profit(self.stop) || self.stop == self.entry {
+            return Err(ChartError::invalid(format!(
+                "position stop {} must be {} the entry {} of a {}",
+                self.stop, loss_side, self.entry, side
+            )));
+        }
+        if let Some(target) = self.targets.iter().find(|target| !in_profit(**target)) {
+            return Err(ChartError::invalid(format!(
+                "position target {} must be {} the entry {} of a {}",
+                target, profit_side, self.entry, side
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Strategy entry or exit drawn from `plots.marks` with `"type": "signal"`: a triangle
+/// pointing up under the candle of a buy, or down over the candle of a sell
+#[derive(Debug, Deserialize, Clone)]
+pub struct SignalMark {
+    /// Timestamp of the candle (ms); `time` is accepted as well
+    #[serde(alias = "time")]
+    pub timestamp: i64,
+    pub direction: SignalDirection,
+    /// Hex color, the color scheme's up color for a buy and down color for a sell
+    /// when absent
+    #[serde(default)]
+    pub color: Option<String>,
+    /// Optional label text beyond the triangle
+    #[serde(default)]
+    pub text: Option<String>,
+    /// Relative size (default 1.0)
+    #[serde(default = "default_mark_size")]
+    pub size: f64,
+    /// Drawing layer, see [`PlotMark`] (default 10)
+    #[serde(default)]
+    pub z: Option<i32>,
+}
+
+/// Side of a signal mark
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SignalDirection {
+    Buy,
+    Sell,
+}
+
+/// How a series mark is drawn
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SeriesStyle {
+    /// Straight lines between neighbouring values
+    #[default]
+    Line,
+    /// Each value held until the next one, like a stop level
+    Step,
+    /// A dot per value
+    Dots,
+}
+
+/// Pane a series mark is drawn in
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SeriesPane {
+    /// Over the candles, on the price scale
+    #[default]
+    Price,
+    /// In a pane under the candles with its own linear scale
+    Lower,
+}
+
+/// Where a VWAP line starts accumulating
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VwapAnchor {
+    /// Start over at each midnight in the time axis timezone
+    #[default]
+    Session,
+    /// Accumulate from the first drawn candle
+    Start,
+}
+
+/// An entry of `plots.marks`, picked by its `type` field. Entries without a
+/// `type` (or with `"type": "marker"`) are triangle markers.
+///
+/// Marks are drawn in order of their `z`, lowest first, around two fixed layers:
+///
+/// | z   | Layer                                              |
+/// |-----|----------------------------------------------------|
+/// | -20 | Volume bars, then the volume profile               |
+/// | -10 | Zones, bands and histograms by default             |
+/// | 0   | EMA, VWAP, Keltner and series lines by default     |
+/// | 5   | Candles, the current price line and compare lines  |
+/// | 10  | Markers, signals and positions by default          |
+///
+/// Marks with the same `z` keep their order in `plots.marks`, and a mark on a fixed
+/// layer's `z` is drawn over it. A position's risk and reward shading is drawn 20
+/// below its `z`, with the zones by default.
+#[derive(Debug, Clone)]
+pub enum PlotMark {
+    Marker(Mark),
+    Zone(ZoneMark),
+    Ema(EmaMark),
+    Vwap(VwapMark),
+    Series(SeriesMark),
+    Histogram(HistogramMark),
+    Band(BandMark),
+    Keltner(KeltnerMark),
+    Position(PositionMark),
+    Signal(SignalMark),
+}
+
+/// Layer of the volume bars among the marks
+const VOLUME_LAYER: i32 = -20;
+/// Layer of the candles among the marks
+const CANDLE_LAYER: i32 = 5;
+/// Layer of the warning markers over candles flagged by `ohlc_policy`
+const OHLC_FLAG_LAYER: i32 = 10;
+/// How far below a position mark its shading is drawn
+const POSITION_SHADING_DEPTH: i32 = 20;
+
+impl PlotMark {
+    /// Drawing layer of the mark: its own `z`, or the default for its type
+    pub fn z(&self) -> i32 {
+        let (z, default) = match self {
+            PlotMark::Marker(mark) => (mark.z, 10),
+            PlotMark::Zone(zone) => (zone.z, -10),
+            PlotMark::Ema(ema_mark) => (ema_mark.z, 0),
+            PlotMark::Vwap(vwap_mark) => (vwap_mark.z, 0),
+            PlotMark::Series(series) => (series.z, 0),
+            PlotMark::Histogram(histogram) => (histogram.z, -10),
+            PlotMark::Band(band) => (band.z, -10),
+            PlotMark::Keltner(keltner_mark) => (keltner_mark.z, 0),
+            PlotMark::Position(position) => (position.z, 10),
+            PlotMark::Signal(signal) => (signal.z, 10),
+        };
+        z.unwrap_or(default)
+    }
+}
+
+impl<'de> Deserialize<'de> for PlotMark {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error as _;
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let result = match value.get("type") {
+            None => Mark::deserialize(value).map(PlotMark::Marker),
+            Some(serde_json::Value::String(kind)) => match kind.as_str() {
+                "marker" => Mark::deserialize(value).map(PlotMark::Marker),
+                "zone" => ZoneMark::deserialize(value).map(PlotMark::Zone),
+                "ema" => EmaMark::deserialize(value).map(PlotMark::Ema),
+                "vwap" => VwapMark::deserialize(value).map(PlotMark::Vwap),
+                "series" => SeriesMark::deserialize(value).map(PlotMark::Series),
+                "histogram" => HistogramMark::deserialize(value).map(PlotMark::Histogram),
+                "band" => BandMark::deserialize(value).map(PlotMark::Band),
+                "keltner" => KeltnerMark::deserialize(value).map(PlotMark::Keltner),
+                "position" => PositionMark::deserialize(value).map(PlotMark::Position),
+                "signal" => SignalMark::deserialize(value).map(PlotMark::Signal),
+                other => {
+                    return Err(D::Error::custom(format!(
+                        "unknown mark type \"{}\", expected \"marker\", \"zone\", \"ema\", \
+                         \"vwap\", \"series\", \"histogram\", \"band\", \"keltner\", \
+                         \"position\" or \"signal\"",
+                        other
+                    )));
+                }
+            },
+            Some(_) => return Err(D::Error::custom("mark type must be a string")),
+        };
+        result.map_err(D::Error::custom)
+    }
+}
+
+/// Rectangular zone for resistance/support visualization
+#[derive(Debug, Deserialize, Clone)]
+pub struct Zone {
+    /// Left timestamp (ms) - when signal was confirmed
+    pub x1: i64,
+    /// Right timestamp (ms) - last candle timestamp
+    pub x2: i64,
+    /// Bottom price
+    pub y1: f64,
+    /// Top price
+    pub y2: f64,
+    /// Hex color with alpha "#RRGGBBAA"
+    pub color: String,
+}
+
+/// Vertical line for marking specific timestamps (e.g., alert fire points)
+#[derive(Debug, Deserialize, Clone)]
+pub struct VLine {
+    /// Timestamp in milliseconds
+    pub time: i64,
+    /// Hex color "#RRGGBB" or "#RRGGBBAA"
+    pub color: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Plots {
+    #[serde(default)]
+    pub marks: Vec<PlotMark>,
+    #[serde(default)]
+    pub zones: Vec<Zone>,
+    #[serde(default)]
+    pub vlines: Vec<VLine>,
+}
+
+/// Safely convert milliseconds timestamp to local DateTime.
+/// Returns an error if the timestamp is invalid.
+pub fn safe_timestamp_to_local(millis: i64) -> Result<DateTime<Local>, ChartError> {
+    match Utc.timestamp_millis_opt(millis) {
+        LocalResult::Single(utc_dt) => {
+            Ok(utc_dt.with_timezone(&Local))
+        }
+        _ => Err(ChartError::invalid(format!("Invalid timestamp: {}", millis))),
+    }
+}
+
+/// Convert a milliseconds timestamp to UTC, the zone all chart geometry works in.
+/// Returns an error if the timestamp is invalid.
+fn timestamp_to_utc(millis: i64) -> Result<DateTime<Utc>, ChartError> {
+    match Utc.timestamp_millis_opt(millis) {
+        LocalResult::Single(utc_dt) => Ok(utc_dt),
+        _ => Err(ChartError::invalid(format!("Invalid timestamp: {}", millis))),
+    }
+}
+
+/// Timezone the time axis is labelled in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LabelTimezone {
+    /// The machine's local timezone, including its daylight saving changes
+    #[default]
+    Local,
+    /// A fixed offset from UTC, for output that does not depend on the machine
+    Fixed(FixedOffset),
+}
+
+impl LabelTimezone {
+    fn format(self, dt: DateTime<Utc>, format: &str) -> String {
+        match self {
+            LabelTimezone::Local => dt.with_timezone(&Local).format(format).to_string(),
+            LabelTimezone::Fixed(offset) => dt.with_timezone(&offset).format(format).to_string(),
+        }
+    }
+
+    /// Calendar date of `dt` in this timezone
+    fn date(self, dt: DateTime<Utc>) -> NaiveDate {
+        match self {
+            LabelTimezone::Local => dt.with_timezone(&Local).date_naive(),
+            LabelTimezone::Fixed(offset) => dt.with_timezone(&offset).date_naive(),
+        }
+    }
+}
+
+const MINUTE_MS: i64 = 60_000;
+const HOUR_MS: i64 = 60 * MINUTE_MS;
+const DAY_MS: i64 = 24 * HOUR_MS;
+
+/// Parse a timeframe string such as "15m", "4h", "1d", "1w" or "1M" into milliseconds.
+/// Lowercase `m` is minutes and uppercase `M` is (30-day) months.
+fn parse_timeframe_millis(timeframe: &str) -> Option<i64> {
+    let tf = timeframe.trim();
+    let split = tf.find(|c: char| !c.is_ascii_digit())?;
+    let count: i64 = if split == 0 { 1 } else { tf[..split].parse().ok()? };
+    let unit_ms = match &tf[split..] {
+        "s" | "S" => 1_000,
+        "m" | "min" => MINUTE_MS,
+        "h" | "H" => HOUR_MS,
+        "d" | "D" => DAY_MS,
+        "w" | "W" => 7 * DAY_MS,
+        "M" | "mo" => 30 * DAY_MS,
+        _ => return None,
+    };
+    (count > 0).then(|| count * unit_ms)
+}
+
+/// Median spacing between consecutive (sorted) timestamps, if there are at least two
+fn median_interval_millis(sorted_millis: &[i64]) -> Option<i64> {
+    let mut deltas: Vec<i64> = sorted_millis
+        .windows(2)
+        .map(|w| w[1] - w[0])
+        .filter(|d| *d > 0)
+        .collect();
+    if deltas.is_empty() {
+        return None;
+    }
+    deltas.sort_unstable();
+    Some(deltas[deltas.len() / 2])
+}
+
+/// Format an interval in milliseconds as a compact timeframe label ("15m", "4h", "1d")
+fn format_interval(millis: i64) -> String {
+    if millis >= 30 * DAY_MS && millis % (30 * DAY_MS) == 0 {
+        format!("{}M", millis / (30 * DAY_MS))
+    } else if millis >= 7 * DAY_MS && millis % (7 * DAY_MS) == 0 {
+        format!("{}w", millis / (7 * DAY_MS))
+    } else if millis >= DAY_MS && millis % DAY_MS == 0 {
+        format!("{}d", millis / DAY_MS)
+    } else if millis >= HOUR_MS && millis % HOUR_MS == 0 {
+        format!("{}h", millis / HOUR_MS)
+    } else if millis >= MINUTE_MS {
+        format!("{}m", millis / MINUTE_MS)
+    } else {
+        format!("{}s", (millis / 1_000).max(1))
+    }
+}
+
+/// Choose the x-axis label format and label count for a candle interval and visible span
+fn x_axis_format(interval_ms: i64, span_ms: i64) -> (&'static str, usize) {
+    if interval_ms >= 30 * DAY_MS {
+        ("%Y-%m", 10)
+    } else if interval_ms >= DAY_MS {
+        ("%Y-%m-%d", 10)
+    } else if span_ms <= DAY_MS {
+        ("%H:%M", 16)
+    } else {
+        ("%m-%d %H:%M", 16)
+    }
+}
+
+// ─── Errors ─────────────────────────────────────────────────────────────────────
+
+/// Render time budget for one chart. A thread cannot be interrupted safely,
+/// so drawing checks the deadline between panels and gives up at the next one.
+struct RenderDeadline {
+    started: Instant,
+    limit: Duration,
+}
+
+impl RenderDeadline {
+    fn new(limit: Duration) -> Self {
+        RenderDeadline {
+            started: Instant::now(),
+            limit,
+        }
+    }
+
+    /// Fail if the budget has run out; `stage` names the work just finished
+    fn check(&self, stage: &'static str) -> Result<(), RenderTimeout> {
+        let elapsed = self.started.elapsed();
+        if elapsed > self.limit {
+            return Err(RenderTimeout {
+                stage,
+                elapsed,
+                limit: self.limit,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct RenderTimeout {
+    stage: &'static str,
+    elapsed: Duration,
+    limit: Duration,
+}
+
+impl fmt::Display for RenderTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "render abandoned after {} ({:.1}s elapsed, limit {}s)",
+            self.stage,
+            self.elapsed.as_secs_f64(),
+            self.limit.as_secs()
+        )
+    }
+}
+
+impl Error for RenderTimeout {}
+
+/// Why a chart could not be produced. The worker logs it against the request id
+/// and converts it into the `RequestError` sent back to the requester.
+#[derive(Debug)]
+pub enum ChartError {
+    /// Creating the output directory or writing the image failed
+    Io(std::io::Error),
+    /// The request data cannot be charted; `row` is the offending data row, if known
+    InvalidData { reason: String, row: Option<usize> },
+    /// Plotters or the PNG encoder failed
+    Backend(String),
+    /// The chart geometry is unusable, e.g. a non-finite price range
+    Layout(String),
+    /// Rendering ran past its deadline
+    Timeout(RenderTimeout),
+    /// The render panicked; carries the panic message
+    Panic(String),
+}
+
+impl ChartError {
+    fn invalid(reason: impl Into<String>) -> Self {
+        ChartError::InvalidData {
+            reason: reason.into(),
+            row: None,
+        }
+    }
+
+    /// Attach the data row an `InvalidData` error was found in
+    fn at_row(self, index: usize) -> Self {
+        match self {
+            ChartError::InvalidData { reason, .. } => ChartError::InvalidData {
+                reason,
+                row: Some(index),
+            },
+            other => other,
+        }
+    }
+}
+
+impl fmt::Display for ChartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChartError::Io(e) => write!(f, "I/O error: {}", e),
+            ChartError::InvalidData { reason, row: Some(row) } => {
+                write!(f, "invalid data at row {}: {}", row, reason)
+            }
+            ChartError::InvalidData { reason, row: None } => write!(f, "invalid data: {}", reason),
+            ChartError::Backend(msg) => write!(f, "drawing backend error: {}", msg),
+            ChartError::Layout(msg) => write!(f, "layout error: {}", msg),
+            ChartError::Timeout(timeout) => write!(f, "{}", timeout),
+            ChartError::Panic(msg) => write!(f, "chart thread panicked: {}", msg),
+        }
+    }
+}
+
+impl Error for ChartError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ChartError::Io(e) => Some(e),
+            ChartError::Timeout(timeout) => Some(timeout),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ChartError {
+    fn from(e: std::io::Error) -> Self {
+        ChartError::Io(e)
+    }
+}
+
+impl From<RenderTimeout> for ChartError {
+    fn from(timeout: RenderTimeout) -> Self {
+        ChartError::Timeout(timeout)
+    }
+}
+
+impl<E: Error + Send + Sync> From<DrawingAreaErrorKind<E>> for ChartError {
+    fn from(e: DrawingAreaErrorKind<E>) -> Self {
+        ChartError::Backend(e.to_string())
+    }
+}
+
+impl From<png::EncodingError> for ChartError {
+    fn from(e: png::EncodingError) -> Self {
+        ChartError::Backend(format!("PNG encoding failed: {}", e))
+    }
+}
+
+// ─── Rendering with Plotters ────────────────────────────────────────────────────
+
+/// Canvas dimensions in pixels unless the caller picks others
+const DEFAULT_WIDTH: u32 = 1280;
+const DEFAULT_HEIGHT: u32 = 960;
+
+/// Space around the plot when `chart_margin` is not given
+const DEFAULT_CHART_MARGIN: u32 = 10;
+/// Narrowest price axis label area
+const MIN_Y_LABEL_AREA_SIZE: u32 = 80;
+/// Most steps between neighbouring price axis ticks, each tick labelled and on a grid line
+const PRICE_TICKS: usize = 8;
+/// Empty candle slots kept right of the newest candle
+const RIGHT_PADDING_CANDLES: f64 = 3.0;
+/// Pixels per candle with the fixed-width layout when `candle_width_px` is not given
+const DEFAULT_CANDLE_WIDTH_PX: u32 = 10;
+/// Accepted range of a request's `scale`
+const MIN_SCALE: f64 = 0.5;
+const MAX_SCALE: f64 = 4.0;
+/// Accepted range of a request's `line_width`
+const MIN_LINE_WIDTH: f64 = 0.5;
+const MAX_LINE_WIDTH: f64 = 8.0;
+
+/// WebP quality when neither the request nor the caller picks one
+const DEFAULT_WEBP_QUALITY: f32 = 80.0;
+
+/// Relative open/close difference below which a candle is a doji and gets a
+/// horizontal line, since its zero-height body would not be drawn
+const DOJI_EPSILON: f64 = 1e-9;
+
+/// Colors of a position mark's entry line, stop side and target side
+const POSITION_ENTRY_COLOR: RGBColor = RGBColor(41, 98, 255);
+const POSITION_STOP_COLOR: RGBColor = RGBColor(239, 83, 80);
+const POSITION_TARGET_COLOR: RGBColor = RGBColor(38, 166, 154);
+/// Color of the warning marker over a candle flagged by `ohlc_policy`
+const OHLC_FLAG_COLOR: RGBColor = RGBColor(255, 152, 0);
+/// Opacity of a position mark's risk and reward zones
+const POSITION_ZONE_OPACITY: f64 = 0.15;
+
+/// Height of the footer strip under the time axis
+const FOOTER_HEIGHT: i32 = 22;
+
+/// Legend color of the chart's own ticker next to comparison series when the request
+/// gives no candle colors
+const DEFAULT_COMPARE_OWN_COLOR: RGBColor = RGBColor(80, 80, 80);
+
+/// Encoding of the image returned by [`render_chart`]
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    /// Lossy WebP, much smaller than PNG for charts
+    Webp,
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 2] = [ImageFormat::Png, ImageFormat::Webp];
+
+    /// File extension for images in this format, without the dot
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+        }
+    }
+}
+
+/// Trade-off between PNG encoding time and file size: the deflate level
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PngCompression {
+    /// Single-pass deflate; the quickest encode, roughly twice the bytes of `Default`
+    #[default]
+    Fast,
+    /// zlib's default level 6
+    Default,
+    /// zlib's level 9; the slowest encode for the smallest files
+    Best,
+}
+
+/// How PNG rows are filtered before deflate
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PngFilter {
+    /// The filter that suits each row best
+    #[default]
+    Adaptive,
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+}
+
+/// What a render draws
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChartStyle {
+    /// Title, price table, axes, candles, volume and plots
+    #[default]
+    Full,
+    /// Only a thick close-price line, for previews a few hundred pixels wide.
+    /// Candles and labels would be a blur at that size, so nothing else is drawn.
+    #[serde(skip_deserializing)]
+    Thumbnail,
+    /// A close-price line over a soft fill with the last price at its right end, for
+    /// strips of many tickers. No axes, grid, title or table.
+    Sparkline,
+}
+
+/// Canvas size of a sparkline unless the request gives `sparkline_size`
+pub const SPARKLINE_SIZE: ThumbnailSize = ThumbnailSize {
+    width: 300,
+    height: 80,
+};
+
+/// Rendering choices that are not part of a chart request
+#[derive(Clone)]
+pub struct RenderOptions {
+    /// Canvas width in pixels
+    pub width: u32,
+    /// Canvas height in pixels
+    pub height: u32,
+    pub format: ImageFormat,
+    /// Quality of lossy formats, 0 to 100; `None` uses the format's default
+    pub quality: Option<f32>,
+    /// Compression level of PNG images
+    pub png_compression: PngCompression,
+    /// Row filter of PNG images
+    pub png_filter: PngFilter,
+    pub style: ChartStyle,
+    /// Give up with `ChartError::Timeout` once rendering has run this long.
+    /// The deadline is checked between chart panels.
+    pub timeout: Option<Duration>,
+    /// Reuse candles validated for an earlier request of the same ticker+timeframe
+    pub candle_cache: Option<Arc<CandleCache>>,
+    /// Timezone of the time axis labels. Nothing else in the image depends on it.
+    pub timezone: LabelTimezone,
+    /// Extra key/value pairs embedded in the image after the chart's own metadata,
+    /// such as the request id
+    pub metadata: Vec<(String, String)>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            format: ImageFormat::Png,
+            quality: None,
+            png_compression: PngCompression::Fast,
+            png_filter: PngFilter::Adaptive,
+            style: ChartStyle::Full,
+            timeout: None,
+            candle_cache: None,
+            timezone: LabelTimezone::Local,
+            metadata: Vec::new(),
+        }
+    }
+}
+
+/// Time spent in each stage of a [`render_chart_timed`] call
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RenderTimings {
+    /// Converting timestamps and validating the candle rows
+    pub validate: Duration,
+    /// Drawing the chart into the pixel buffer
+    pub draw: Duration,
+    /// Encoding the pixel buffer into the output format
+    pub encode: Duration,
+}
+
+/// Version of the layout written by [`ChartCoords`]; bumped when fields change meaning
+pub const COORDS_SCHEMA_VERSION: u32 = 1;
+
+/// Where a chart's plot and candles landed on the canvas, for overlaying the image
+/// with tooltips. Pixels count from the top-left corner of the image, at its `scale`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChartCoords {
+    pub schema_version: u32,
+    /// Canvas size in pixels
+    pub width: u32,
+    pub height: u32,
+    /// The plot area of the price pane
+    pub plot: PixelBox,
+    /// The drawn candles, oldest first
+    pub candles: Vec<CandleCoords>,
+    /// The current price and its row, absent without candles
+    pub current_price: Option<PriceRow>,
+}
+
+/// A rectangle of pixels; `right` and `bottom` are exclusive
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PixelBox {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+/// One drawn candle: its values and the pixels of its body and prices
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CandleCoords {
+    /// Position among the drawn candles
+    pub index: usize,
+    /// Open time in milliseconds
+    pub timestamp: i64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+    /// Pixel columns of the body's left and right edges, cut to the plot where the
+    /// first or last candle is only half drawn
+    pub x_left: i32,
+    pub x_right: i32,
+    pub y_open: i32,
+    pub y_high: i32,
+    pub y_low: i32,
+    pub y_close: i32,
+}
+
+/// A price and the pixel row it is drawn at
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PriceRow {
+    pub price: f64,
+    pub y: i32,
+}
+
+/// Validate `data`, draw it and return the encoded image.
+/// Timestamps may be in any `timestamp_unit`; they are converted to milliseconds first.
+pub fn render_chart(data: &ChartData, opts: &RenderOptions) -> Result<Vec<u8>, ChartError> {
+    render_chart_timed(data, opts).map(|(image, _)| image)
+}
+
+/// Same as [`render_chart`], but also reports how long each stage took
+pub fn render_chart_timed(
+    data: &ChartData,
+    opts: &RenderOptions,
+) -> Result<(Vec<u8>, RenderTimings), ChartError> {
+    render_chart_with_coords(data, opts).map(|(image, timings, _)| (image, timings))
+}
+
+/// Same as [`render_chart_timed`], but also returns the chart's [`ChartCoords`] when
+/// `data.emit_coords` is set and a full chart was drawn
+pub fn render_chart_with_coords(
+    data: &ChartData,
+    opts: &RenderOptions,
+) -> Result<(Vec<u8>, RenderTimings, Option<ChartCoords>), ChartError> {
+    let deadline = RenderDeadline::new(opts.timeout.unwrap_or(Duration::MAX));
+    let mut timings = RenderTimings::default();
+    let mut stage_started = Instant::now();
+
+    // Rows are copied only when their timestamps need converting or snapping
+    let ready =
+        |data: &ChartData| data.timestamp_unit == Some(TimestampUnit::Ms) && !data.snap_timestamps;
+    let data = if ready(data) && data.panels.iter().all(ready) {
+        Cow::Borrowed(data)
+    } else {
+        let mut data = data.clone();
+        data.normalize_timestamps();
+        data.snap_row_timestamps();
+        Cow::Owned(data)
+    };
+
+    // A composite's panels are checked one by one like single charts, and a panel that
+    // fails keeps its error to show in its place
+    let panel_count = data.panels.len();
+    if panel_count > MAX_PANELS {
+        return Err(ChartError::invalid(format!(
+            "a chart has at most {} panels, got {}",
+            MAX_PANELS, panel_count
+        )));
+    }
+    if data.panels.iter().any(|panel| !panel.panels.is_empty()) {
+        return Err(ChartError::invalid("a panel cannot have panels of its own"));
+    }
+    if panel_count > 0 && data.emit_coords {
+        return Err(ChartError::invalid("emit_coords is not supported for charts with panels"));
+    }
+    let (rows, columns) = data.layout.cells(panel_count.max(1));
+    let charts: Vec<&ChartData> = if panel_count == 0 {
+        vec![&data]
+    } else {
+        data.panels.iter().collect()
+    };
+    let cache = opts.candle_cache.as_deref();
+    let prepared = if panel_count == 0 {
+        vec![Ok(prepare_chart(&data, opts.width, cache)?)]
+    } else {
+        let panel_width = opts.width / columns as u32;
+        let prepared: Vec<_> =
+            charts.iter().map(|panel| prepare_chart(panel, panel_width, cache)).collect();
+        // Only a composite with no panel left to draw fails as a whole
+        if !prepared.iter().any(Result::is_ok) {
+            return Err(prepared.into_iter().find_map(Result::err).expect("panels is not empty"));
+        }
+        prepared
+    };
+    let sent = prepared
+        .iter()
+        .flatten()
+        .map(|chart| chart.sent)
+        .reduce(SentCandles::merge)
+        .unwrap_or_default();
+    let metadata = image_metadata(&data, sent, &opts.metadata);
+    if let Some(quality) = opts.quality
+        && !(0.0..=100.0).contains(&quality)
+    {
+        return Err(ChartError::invalid(format!(
+            "quality must be between 0 and 100, got {}",
+            quality
+        )));
+    }
+    let pixel_scale = data.scale.unwrap_or(1.0);
+    if !(MIN_SCALE..=MAX_SCALE).contains(&pixel_scale) {
+        return Err(ChartError::invalid(format!(
+            "scale must be between {} and {}, got {}",
+            MIN_SCALE, MAX_SCALE, pixel_scale
+        )));
+    }
+    deadline.check("candle processing")?;
+    timings.validate = stage_started.elapsed();
+    stage_started = Instant::now();
+
+    // The layout is worked out at the requested size; `scale` only adds pixels
+    let width = (opts.width as f64 * pixel_scale).round() as u32;
+    let height = (opts.height as f64 * pixel_scale).round() as u32;
+    if width == 0 || height == 0 {
+        return Err(ChartError::Layout(format!("canvas {}x{} has no pixels", width, height)));
+    }
+
+    // Draw into an in-memory RGB buffer, then encode it
+    let mut buffer = vec![0u8; width as usize * height as usize * 3];
+    let mut coords = None;
+    {
+        let root_area =
+            BitMapBackend::with_buffer(&mut buffer, (width, height)).into_drawing_area();
+        match opts.style {
+            ChartStyle::Full => {
+                let cells = if panel_count == 0 {
+                    vec![root_area.clone()]
+                } else {
+                    root_area.split_evenly((rows, columns))
+                };
+                for (i, ((chart, panel), cell)) in
+                    prepared.into_iter().zip(charts).zip(&cells).enumerate()
+                {
+                    let timezone = opts.timezone;
+                    let drawn = chart.and_then(|chart| {
+                        let (theme, candles) = (chart.theme, chart.candles);
+                        draw_chart(cell, panel, &theme, candles, timezone, pixel_scale, &deadline)
+                    });
+                    match drawn {
+                        Ok(drawn) => coords = drawn,
+                        Err(e) if panel_count > 0 && !matches!(e, ChartError::Timeout(_)) => {
+                            warn!("Panel {} of {} cannot be drawn: {}", i + 1, panel_count, e);
+                            draw_panel_error(cell, panel, &e, pixel_scale)?;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            // A composite's thumbnail or sparkline is that of its first panel that can
+            // be drawn
+            ChartStyle::Thumbnail | ChartStyle::Sparkline => {
+                if let Some((chart, panel)) = prepared
+                    .into_iter()
+                    .zip(charts)
+                    .find_map(|(chart, panel)| chart.ok().map(|chart| (chart, panel)))
+                {
+                    let (theme, candles) = (&chart.theme, &chart.candles);
+                    if opts.style == ChartStyle::Sparkline {
+                        draw_sparkline(&root_area, theme, candles, pixel_scale)?
+                    } else {
+                        draw_thumbnail(&root_area, panel, theme, candles, pixel_scale)?
+                    }
+                }
+            }
+        }
+        root_area.present()?;
+    }
+    deadline.check("drawing")?;
+    timings.draw = stage_started.elapsed();
+    stage_started = Instant::now();
+
+    let image = match opts.format {
+        ImageFormat::Png => {
+            encode_png(&buffer, width, height, &metadata, opts.png_compression, opts.png_filter)?
+        }
+        ImageFormat::Webp => {
+            encode_webp(&buffer, width, height, opts.quality.unwrap_or(DEFAULT_WEBP_QUALITY))?
+        }
+    };
+    timings.encode = stage_started.elapsed();
+    Ok((image, timings, coords))
+}
+
+/// A chart's colors and validated candles, ready to draw
+struct PreparedChart {
+    theme: Theme,
+    candles: ProcessedCandles,
+    sent: SentCandles,
+}
+
+/// Validate one chart for a canvas `width` pixels wide: resolve its colors, check its
+/// candles and merge them when it asks to be aggregated
+fn prepare_chart(
+    data: &ChartData,
+    width: u32,
+    cache: Option<&CandleCache>,
+) -> Result<PreparedChart, ChartError> {
+    // A fixed-width layout draws at most as many candles as fit across the canvas
+    let window = match data.candle_layout {
+        CandleLayout::FitAll | CandleLayout::Time => data.limit,
+        CandleLayout::FixedWidth => {
+            let fit = fixed_width_capacity(data, width)?;
+            Some(data.limit.map_or(fit, |limit| limit.min(fit)))
+        }
+    };
+    let theme = Theme::for_request(data)?;
+    let mut candles = process_candles(data, &theme, cache, window)?;
+    // The metadata describes the candles that were sent, not the merged ones
+    let sent = SentCandles::of(&candles);
+    if data.aggregate && data.candle_layout == CandleLayout::FitAll {
+        aggregate_candles(&mut candles, plot_slots(data, width, 1));
+    }
+    if let Some(price) = data.current_price
+        && !price.is_finite()
+    {
+        return Err(ChartError::invalid(format!("current_price must be finite, got {}", price)));
+    }
+    Ok(PreparedChart {
+        theme,
+        candles,
+        sent,
+    })
+}
+
+/// The candles of a chart as they were sent, before any aggregation
+#[derive(Debug, Clone, Copy, Default)]
+struct SentCandles {
+    first: Option<DateTime<Utc>>,
+    last: Option<DateTime<Utc>>,
+    count: usize,
+}
+
+impl SentCandles {
+    fn of(candles: &ProcessedCandles) -> Self {
+        SentCandles {
+            first: candles.rows.first().map(|candle| candle.0),
+            last: candles.rows.last().map(|candle| candle.0),
+            count: candles.rows.len(),
+        }
+    }
+
+    /// The candles of two panels together: the earlier first, the later last and both counts
+    fn merge(self, other: SentCandles) -> Self {
+        SentCandles {
+            first: self.first.into_iter().chain(other.first).min(),
+            last: self.last.into_iter().chain(other.last).max(),
+            count: self.count + other.count,
+        }
+    }
+}
+
+/// How many candles of the fixed-width layout fit on a canvas `width` pixels wide.
+/// The price labels are taken at their usual width; `draw_chart` drops any candle
+/// that wider labels push off the left edge.
+fn fixed_width_capacity(data: &ChartData, width: u32) -> Result<usize, ChartError> {
+    let candle_px = data.candle_width_px.unwrap_or(DEFAULT_CANDLE_WIDTH_PX);
+    if candle_px == 0 {
+        return Err(ChartError::invalid("candle_width_px must be at least 1"));
+    }
+    Ok(plot_slots(data, width, candle_px))
+}
+
+/// How many candles `candle_px` wide fit on a canvas `width` pixels wide, leaving
+/// room for the right padding. The price labels are taken at their usual width.
+fn plot_slots(data: &ChartData, width: u32, candle_px: u32) -> usize {
+    let plot_px = width
+        .saturating_sub(2 * data.chart_margin.unwrap_or(DEFAULT_CHART_MARGIN))
+        .saturating_sub(data.y_label_area_size.unwrap_or(MIN_Y_LABEL_AREA_SIZE));
+    let slots = (plot_px / candle_px) as usize;
+    slots.saturating_sub(RIGHT_PADDING_CANDLES as usize).max(1)
+}
+
+/// Version of the key set written by [`image_metadata`]; bumped when keys change meaning
+pub const METADATA_SCHEMA_VERSION: u32 = 1;
+
+/// Key/value pairs describing which data produced an image: ticker, timeframe,
+/// the first and last candle handed to the chart, the candle count and the schema
+/// version, followed by the caller's `extra` pairs
+fn image_metadata(
+    data: &ChartData,
+    sent: SentCandles,
+    extra: &[(String, String)],
+) -> Vec<(String, String)> {
+    let candle_time = |time: Option<DateTime<Utc>>| {
+        time.map_or_else(String::new, |time| {
+            time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+        })
+    };
+    let mut metadata = vec![
+        ("Ticker".to_string(), data.ticker.clone()),
+        ("Timeframe".to_string(), data.timeframe.clone()),
+        ("FirstCandle".to_string(), candle_time(sent.first)),
+        ("LastCandle".to_string(), candle_time(sent.last)),
+        ("CandleCount".to_string(), sent.count.to_string()),
+        ("SchemaVersion".to_string(), METADATA_SCHEMA_VERSION.to_string()),
+    ];
+    metadata.extend_from_slice(extra);
+    metadata
+}
+
+/// Encode a raw RGB pixel buffer as a PNG image. Each metadata pair becomes a tEXt
+/// chunk, or an iTXt chunk when the value is not Latin-1.
+fn encode_png(
+    rgb: &[u8],
+    width: u32,
+    height: u32,
+    metadata: &[(String, String)],
+    compression: PngCompression,
+    filter: PngFilter,
+) -> Result<Vec<u8>, ChartError> {
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(match compression {
+        PngCompression::Fast => png::Compression::Fast,
+        PngCompression::Default => png::Compression::Default,
+        PngCompression::Best => png::Compression::Best,
+    });
+    let fixed_filter = match filter {
+        PngFilter::Adaptive => None,
+        PngFilter::None => Some(png::FilterType::NoFilter),
+        PngFilter::Sub => Some(png::FilterType::Sub),
+        PngFilter::Up => Some(png::FilterType::Up),
+        PngFilter::Avg => Some(png::FilterType::Avg),
+        PngFilter::Paeth => Some(png::FilterType::Paeth),
+    };
+    match fixed_filter {
+        Some(filter) => encoder.set_filter(filter),
+        None => encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive),
+    }
+    for (key, value) in metadata {
+        if value.chars().all(|c| u32::from(c) <= 0xFF) {
+            encoder.add_text_chunk(key.clone(), value.clone())?;
+        } else {
+            encoder.add_itxt_chunk(key.clone(), value.clone())?;
+        }
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgb)?;
+    writer.finish()?;
+    Ok(out)
+}
+
+/// Encode a raw RGB pixel buffer as a lossy WebP image. WebP has no text chunks,
+/// so the metadata written into PNGs is left out.
+fn encode_webp(rgb: &[u8], width: u32, height: u32, quality: f32) -> Result<Vec<u8>, ChartError> {
+    // Larger canvases are rejected by libwebp itself, but with an unhelpful error
+    const MAX_WEBP_SIDE: u32 = 16383;
+    if width > MAX_WEBP_SIDE || height > MAX_WEBP_SIDE {
+        return Err(ChartError::Layout(format!(
+            "canvas {}x{} is larger than WebP allows ({} pixels per side)",
+            width, height, MAX_WEBP_SIDE
+        )));
+    }
+    webp::Encoder::from_rgb(rgb, width, height)
+        .encode_simple(false, quality)
+        .map(|image| image.to_vec())
+        .map_err(|e| ChartError::Backend(format!("WebP encoding failed: {:?}", e)))
+}
+
+/// One validated candle: (time, open, high, low, close, volume, body color)
+type Candle = (DateTime<Utc>, f64, f64, f64, f64, f64, RGBAColor);
+
+/// Candle rows validated and converted for drawing, plus the ranges needed for scaling
+struct ProcessedCandles {
+    rows: Vec<Candle>,
+    start_dt: DateTime<Utc>,
+    end_dt: DateTime<Utc>,
+    lowest_price: f64,
+    highest_price: f64,
+    max_volume: f64,
+    /// Position of the first drawn candle in the full validated series. Per-candle
+    /// request fields such as `volume_colors` are indexed from there.
+    window_start: usize,
+    /// Whether each row merges several candles, which per-candle fields cannot address
+    aggregated: bool,
+    /// Times (ms) of the drawn candles flagged by `ohlc_policy: "flag"`
+    flagged: Vec<i64>,
+}
+
+/// Validate the raw rows of a request and convert them into drawable candles.
+/// Any error returned here is a problem with the request data, not with rendering.
+/// With a `cache`, rows unchanged since the last request for the series are not re-validated.
+/// Only the newest `window` candles are kept for drawing.
+fn process_candles(
+    data: &ChartData,
+    theme: &Theme,
+    cache: Option<&CandleCache>,
+    window: Option<usize>,
+) -> Result<ProcessedCandles, ChartError> {
+    // --- 1) Parse Timestamps and OHLCV; find min & max for Y ---
+    let mut min_price = f64::MAX;
+    let mut max_price = f64::MIN;
+    let mut max_volume = 0.0;
+
+    // Convert timestamps to UTC DateTime for the range
+    let candle_count = data.data.len();
+    let (Some(first_row), Some(last_row)) = (data.data.first(), data.data.last()) else {
+        return Err(ChartError::invalid("No candle data"));
+    };
+    if data.limit == Some(0) {
+        return Err(ChartError::invalid("limit must be at least 1"));
+    }
+    // Phase 3B: Guard against empty rows
+    let (Some(&first_ts), Some(&last_ts)) = (first_row.first(), last_row.first()) else {
+        return Err(ChartError::invalid("First or last data row is empty"));
+    };
+    let first_ts = first_ts as i64;
+    let last_ts = last_ts as i64;
+
+    let start_dt: DateTime<Utc> = timestamp_to_utc(first_ts).map_err(|e| e.at_row(0))?;
+    let end_dt: DateTime<Utc> =
+        timestamp_to_utc(last_ts).map_err(|e| e.at_row(candle_count - 1))?;
+
+    // One entry per raw row (None for skipped rows); unchanged leading rows come from the cache
+    let mut row_candles = cache.map(|c| c.reusable_prefix(data, theme)).unwrap_or_default();
+    let reused = row_candles.len();
+    row_candles.reserve(candle_count - reused);
+    let mut prev_close = row_candles.iter().flatten().next_back().map(|candle| candle.4);
+    for (i, row) in data.data.iter().enumerate().skip(reused) {
+        let candle = process_row(data, theme, i, row, prev_close)?;
+        if let Some(candle) = &candle {
+            prev_close = Some(candle.4);
+        }
+        row_candles.push(candle);
+    }
+    if reused > 0 {
+        debug!("Reused {} of {} validated candles from cache", reused, candle_count);
+    }
+
+    // We will store the data in a vector of (DateTime<Utc>, open, high, low, close, volume, body color)
+    let (mut processed_data, mut flagged) = check_ohlc(data, &row_candles)?;
+
+    // Only the newest `limit` candles are drawn. Every row was still validated (and
+    // cached) above, so anything derived from the series sees the full history.
+    let mut window_start = 0;
+    let (start_dt, end_dt) = match window {
+        Some(limit) if processed_data.len() > limit => {
+            processed_data.sort_by_key(|candle| candle.0);
+            window_start = processed_data.len() - limit;
+            debug!("Showing the last {} of {} candles", limit, processed_data.len());
+            processed_data.drain(..window_start);
+            let first_millis = processed_data[0].0.timestamp_millis();
+            flagged.retain(|&millis| millis >= first_millis);
+            (processed_data[0].0, processed_data[limit - 1].0)
+        }
+        _ => (start_dt, end_dt),
+    };
+
+    // Phase 5A: Take min/max in a single pass over the validated candles
+    for (_, o, h, l, c, v, _) in &processed_data {
+        let local_min = o.min(*h).min(*l).min(*c);
+        let local_max = o.max(*h).max(*l).max(*c);
+        if local_min < min_price {
+            min_price = local_min;
+        }
+        if local_max > max_price {
+            max_price = local_max;
+        }
+        if *v > max_volume {
+            max_volume = *v;
+        }
+    }
+
+    // Phase 3E: Guard against empty processed data (all rows skipped)
+    if processed_data.is_empty() {
+        return Err(ChartError::invalid("No valid candle data after processing"));
+    }
+
+    let highest_price = max_price;
+    let lowest_price = min_price;
+
+    if let Some(cache) = cache {
+        cache.store(data, theme, row_candles);
+    }
+
+    Ok(ProcessedCandles {
+        rows: processed_data,
+        start_dt,
+        end_dt,
+        lowest_price,
+        highest_price,
+        max_volume,
+        window_start,
+        aggregated: false,
+        flagged,
+    })
+}
+
+/// Most row indices named in an OHLC error or log line
+const MAX_LISTED_ROWS: usize = 10;
+
+/// Row indices for a message, e.g. `rows 3, 17, 40`, cut off after [`MAX_LISTED_ROWS`]
+fn list_rows(rows: &[usize]) -> String {
+    let listed: Vec<String> = rows.iter().take(MAX_LISTED_ROWS).map(usize::to_string).collect();
+    let noun = if rows.len() == 1 { "row" } else { "rows" };
+    match rows.len().checked_sub(MAX_LISTED_ROWS) {
+        Some(more) if more > 0 => format!("{} {} and {} more", noun, listed.join(", "), more),
+        _ => format!("{} {}", noun, listed.join(", ")),
+    }
+}
+
+/// Hold the candles of every raw row to the request's `ohlc_policy`. A row is bad when
+/// its high and low do not contain its open and close, or, with `max_range_multiple`,
+/// when its range is that many times the median. Returns the candles to draw, clamped
+/// where the policy says so, and the times of flagged candles.
+fn check_ohlc(
+    data: &ChartData,
+    row_candles: &[Option<Candle>],
+) -> Result<(Vec<Candle>, Vec<i64>), ChartError> {
+    let spread =
+        |(_, o, h, l, c, ..): &Candle| o.max(*h).max(*l).max(*c) - o.min(*h).min(*l).min(*c);
+    let max_range = match data.max_range_multiple {
+        Some(multiple) if !(multiple.is_finite() && multiple > 0.0) => {
+            return Err(ChartError::invalid(format!(
+                "max_range_multiple must be a number above 0, got {}",
+                multiple
+            )));
+        }
+        Some(multiple) => {
+            let mut ranges: Vec<f64> = row_candles.iter().flatten().map(spread).collect();
+            ranges.sort_by(f64::total_cmp);
+            // A flat series, or one without a complete row, has no typical range to
+            // compare against
+            ranges
+                .get(ranges.len() / 2)
+                .map(|median| median * multiple)
+                .filter(|&range| range > 0.0)
+        }
+        None => None,
+    };
+
+    let mut inconsistent = Vec::new();
+    let mut wide = Vec::new();
+    let mut flagged = Vec::new();
+    let mut candles = Vec::with_capacity(row_candles.len());
+    for (i, candle) in row_candles.iter().enumerate() {
+        let Some(candle) = candle else { continue };
+        let mut candle = *candle;
+        let (_, o, h, l, c, ..) = candle;
+        let bad = h < o.max(c) || l > o.min(c) || h < l;
+        let too_wide = max_range.is_some_and(|range| spread(&candle) > range);
+        if bad {
+            inconsistent.push(i);
+        }
+        if too_wide {
+            wide.push(i);
+        }
+        match data.ohlc_policy {
+            OhlcPolicy::Clamp if bad => {
+                candle.2 = o.max(h).max(l).max(c);
+                candle.3 = o.min(h).min(l).min(c);
+            }
+            OhlcPolicy::Flag if bad || too_wide => flagged.push(candle.0.timestamp_millis()),
+            _ => {}
+        }
+        candles.push(candle);
+    }
+
+    let mut problems = Vec::new();
+    if !inconsistent.is_empty() {
+        problems.push(format!(
+            "high and low do not contain open and close in {}",
+            list_rows(&inconsistent)
+        ));
+    }
+    if !wide.is_empty() {
+        problems.push(format!(
+            "range is over {} times the median in {}",
+            data.max_range_multiple.unwrap_or_default(),
+            list_rows(&wide)
+        ));
+    }
+    if problems.is_empty() {
+        return Ok((candles, flagged));
+    }
+    let problems = problems.join("; ");
+    match data.ohlc_policy {
+        OhlcPolicy::Reject => {
+            let first = inconsistent.first().into_iter().chain(wide.first()).min();
+            let error = ChartError::invalid(format!("Bad OHLC rows: {}", problems));
+            return Err(match first {
+                Some(&row) => error.at_row(row),
+                None => error,
+            });
+        }
+        OhlcPolicy::Clamp => warn!("Clamped bad OHLC rows: {}", problems),
+        OhlcPolicy::Flag => warn!("Flagged bad OHLC rows: {}", problems),
+    }
+    Ok((candles, flagged))
+}
+
+/// Merge neighbouring candles into at most `max_candles` rows: the open of the first,
+/// the highest high, the lowest low, the close of the last and the summed volume. A
+/// merged candle is placed at its first candle's time and takes its last candle's color.
+/// Buckets are counted back from the newest candle, so only the oldest may be short.
+fn aggregate_candles(candles: &mut ProcessedCandles, max_candles: usize) {
+    let count = candles.rows.len();
+    let bucket = count.div_ceil(max_candles.max(1));
+    if bucket <= 1 {
+        return;
+    }
+    candles.rows.sort_by_key(|candle| candle.0);
+    let mut merged: Vec<Candle> = candles
+        .rows
+        .rchunks(bucket)
+        .map(|chunk| {
+            let (first, last) = (&chunk[0], &chunk[chunk.len() - 1]);
+            let (high, low, volume) = chunk.iter().fold(
+                (f64::MIN, f64::MAX, 0.0),
+                |(high, low, volume), (_, _, h, l, _, v, _)| {
+                    (high.max(*h), low.min(*l), volume + v)
+                },
+            );
+            (first.0, first.1, high, low, last.4, volume, last.6)
+        })
+        .collect();
+    merged.reverse();
+    debug!("Merged {} candles into {} of up to {} each", count, merged.len(), bucket);
+
+    candles.start_dt = merged[0].0;
+    candles.end_dt = merged[merged.len() - 1].0;
+    candles.max_volume = merged.iter().fold(0.0, |acc, candle| candle.5.max(acc));
+    candles.rows = merged;
+    candles.aggregated = true;
+}
+
+/// Validate raw row `i` of a request, whose previous drawn row closed at `prev_close`.
+/// `None` means the row is too short and is skipped.
+fn process_row(
+    data: &ChartData,
+    theme: &Theme,
+    i: usize,
+    row: &[f64],
+    prev_close: Option<f64>,
+) -> Result<Option<Candle>, ChartError> {
+    // Phase 3A: Validate row lengths — skip rows with < 5 elements
+    if row.len() < 5 {
+        warn!("Skipping row {} with only {} elements (need >= 5)", i, row.len());
+        return Ok(None);
+    }
+
+    // row: [ts, open, high, low, close, volume] (assuming exactly that structure)
+    let ts = row[0] as i64;
+    let o = row[1];
+    let h = row[2];
+    let l = row[3];
+    let c = row[4];
+    let v = row.get(5).cloned().unwrap_or(0.0);
+
+    let dt_utc: DateTime<Utc> = timestamp_to_utc(ts).map_err(|e| e.at_row(i))?;
+
+    // Candles past the end of `candle_colors` are colored by direction.
+    // Parsed once here so the drawing loop (and the candle cache) reuse the color.
+    let base = match data.color_mode {
+        ColorMode::Oc => o,
+        ColorMode::PrevClose => prev_close.unwrap_or(o),
+    };
+    let color = data.candle_colors.get(i).map_or(
+        if c >= base { theme.up } else { theme.down },
+        |hex| parse_candle_color(hex),
+    );
+    let alpha = data.candle_alpha.get(i).copied().unwrap_or(1.0);
+    if !(0.0..=1.0).contains(&alpha) {
+        return Err(ChartError::invalid(format!("candle_alpha {} is not between 0 and 1", alpha))
+            .at_row(i));
+    }
+
+    Ok(Some((dt_utc, o, h, l, c, v, color.mix(alpha))))
+}
+
+/// Validated candles of the latest request for each ticker+timeframe. Streaming
+/// senders resend the same history plus a few new (or updated) candles, so only
+/// the rows after the longest unchanged prefix need validating again.
+pub struct CandleCache {
+    capacity: usize,
+    entries: Mutex<HashMap<(String, String), CachedSeries>>,
+}
+
+struct CachedSeries {
+    /// Raw rows, candle colors, candle alphas, and the up and down colors and color
+    /// mode of candles without a color, that the candles were built from
+    raw_rows: Vec<Vec<f64>>,
+    colors: Vec<String>,
+    alphas: Vec<f64>,
+    direction_colors: (RGBColor, RGBColor),
+    color_mode: ColorMode,
+    /// One entry per raw row; `None` for rows that were skipped
+    candles: Vec<Option<Candle>>,
+    last_used: Instant,
+}
+
+impl CandleCache {
+    pub fn new(capacity: usize) -> Self {
+        CandleCache {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn key(data: &ChartData) -> (String, String) {
+        (data.ticker.clone(), data.timeframe.clone())
+    }
+
+    /// Candles for the leading rows of `data` that match the cached series exactly
+    fn reusable_prefix(&self, data: &ChartData, theme: &Theme) -> Vec<Option<Candle>> {
+        if self.capacity == 0 {
+            return Vec::new();
+        }
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(series) = entries
+            .get(&Self::key(data))
+            .filter(|series| {
+                series.direction_colors == (theme.up, theme.down)
+                    && series.color_mode == data.color_mode
+            })
+        else {
+            return Vec::new();
+        };
+        let unchanged = series
+            .raw_rows
+            .iter()
+            .zip(&data.data)
+            .enumerate()
+            .take_while(|(i, (old, new))| {
+                old == new
+                    && series.colors.get(*i) == data.candle_colors.get(*i)
+                    && series.alphas.get(*i) == data.candle_alpha.get(*i)
+            })
+            .count();
+        series.candles[..unchanged].to_vec()
+    }
+
+    /// Remember the candles of a successfully validated request, evicting the
+    /// least recently stored series when full
+    fn store(&self, data: &ChartData, theme: &Theme, candles: Vec<Option<Candle>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = Self::key(data);
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if !entries.contains_key(&key)
+            && entries.len() >= self.capacity
+            && let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, series)| series.last_used)
+                .map(|(k, _)| k.clone())
+        {
+            entries.remove(&oldest);
+        }
+        entries.insert(
+            key,
+            CachedSeries {
+                raw_rows: data.data.clone(),
+                colors: data.candle_colors.clone(),
+                alphas: data.candle_alpha.clone(),
+                direction_colors: (theme.up, theme.down),
+                color_mode: data.color_mode,
+                candles,
+                last_used: Instant::now(),
+            },
+        );
+    }
+}
+
+/// Relative price range below which a series is treated as flat
+const FLAT_RANGE_EPSILON: f64 = 1e-6;
+/// Half-width of the synthetic range given to a flat series (1% either side)
+const FLAT_RANGE_HALF_WIDTH: f64 = 0.01;
+
+/// Widen a (near-)zero price range into a small symmetric range around its midpoint
+/// so the y-axis is never degenerate. Normal ranges are returned unchanged.
+fn flat_range_guard(lowest: f64, highest: f64) -> (f64, f64) {
+    let mid = (lowest + highest) / 2.0;
+    if (highest - lowest) > mid.abs() * FLAT_RANGE_EPSILON {
+        return (lowest, highest);
+    }
+    debug!("Flat price series at {}, using a synthetic ±1% range", mid);
+    // A series flat at zero has no 1% to speak of; give it ±1 instead
+    let half_width = if mid == 0.0 {
+        1.0
+    } else {
+        mid.abs() * FLAT_RANGE_HALF_WIDTH
+    };
+    (mid - half_width, mid + half_width)
+}
+
+// ─── Indicators ─────────────────────────────────────────────────────────────────
+
+/// Exponential moving average with the smoothing factor 2 / (period + 1), seeded
+/// with the simple average of the first `period` values as TA-Lib does. Entries
+/// before the seed are `None`.
+fn ema(values: &[f64], period: usize) -> Vec<Option<f64>> {
+    let mut averages = vec![None; values.len()];
+    if period == 0 || values.len() < period {
+        return averages;
+    }
+    let alpha = 2.0 / (period as f64 + 1.0);
+    let mut average = values[..period].iter().sum::<f64>() / period as f64;
+    averages[period - 1] = Some(average);
+    for (i, value) in values.iter().enumerate().skip(period) {
+        average += alpha * (value - average);
+        averages[i] = Some(average);
+    }
+    averages
+}
+
+/// `(timestamp, value)` of each row's `source` column in time order, skipping rows
+/// without a finite value there. Uses every row, not just the drawn window, so an
+/// average is already warmed up at the first drawn candle.
+fn column_series(data: &ChartData, source: &str) -> Result<Vec<(i64, f64)>, ChartError> {
+    let column = data
+        .column_index(source)
+        .filter(|&column| column > 0)
+        .ok_or_else(|| ChartError::invalid(format!("unknown source column \"{}\"", source)))?;
+    let mut series: Vec<(i64, f64)> = data
+        .data
+        .iter()
+        .filter_map(|row| Some((*row.first()?, *row.get(column)?)))
+        .filter(|(ts, value)| ts.is_finite() && value.is_finite())
+        .map(|(ts, value)| (ts as i64, value))
+        .collect();
+    series.sort_by_key(|(ts, _)| *ts);
+    Ok(series)
+}
+
+/// Volume-weighted average of the typical price (high + low + close) / 3 over rows of
+/// `(timestamp, high, low, close, volume)` in time order. The sums start over whenever
+/// `session_of` changes from one row to the next. Rows without volume add nothing, and
+/// until a session has traded its average is `None`.
+fn vwap<S: PartialEq>(
+    rows: &[(i64, f64, f64, f64, f64)],
+    session_of: impl Fn(i64) -> S,
+) -> Vec<Option<f64>> {
+    let mut session = None;
+    let (mut price_volume, mut volume) = (0.0, 0.0);
+    rows.iter()
+        .map(|&(ts, h, l, c, v)| {
+            let current = session_of(ts);
+            if session.as_ref() != Some(&current) {
+                (price_volume, volume) = (0.0, 0.0);
+                session = Some(current);
+            }
+            if v > 0.0 {
+                price_volume += (h + l + c) / 3.0 * v;
+                volume += v;
+            }
+            (volume > 0.0).then(|| price_volume / volume)
+        })
+        .collect()
+}
+
+/// Average true range with Wilder's smoothing over rows of `(high, low, close)` in time
+/// order. As in TA-Lib the first row has no previous close and gives no true range, so
+/// the average is seeded at row `period` with the simple average of the true ranges
+/// before it; earlier entries are `None`.
+fn atr(rows: &[(f64, f64, f64)], period: usize) -> Vec<Option<f64>> {
+    let mut averages = vec![None; rows.len()];
+    if period == 0 || rows.len() <= period {
+        return averages;
+    }
+    let true_ranges: Vec<f64> = rows
+        .windows(2)
+        .map(|pair| {
+            let (_, _, prev_close) = pair[0];
+            let (high, low, _) = pair[1];
+            (high - low).max((high - prev_close).abs()).max((low - prev_close).abs())
+        })
+        .collect();
+    let mut average = true_ranges[..period].iter().sum::<f64>() / period as f64;
+    averages[period] = Some(average);
+    for (i, true_range) in true_ranges.iter().enumerate().skip(period) {
+        average = (average * (period - 1) as f64 + true_range) / period as f64;
+        averages[i + 1] = Some(average);
+    }
+    averages
+}
+
+/// Keltner Channels over rows of `(timestamp, high, low, close, volume)` in time order:
+/// `(midline, upper, lower)` of each row, the midline the EMA of the closes and the
+/// bands `mult` ATRs either side of it. Rows before both have warmed up are `None`.
+fn keltner(
+    rows: &[(i64, f64, f64, f64, f64)],
+    period: usize,
+    mult: f64,
+) -> Vec<Option<(f64, f64, f64)>> {
+    let closes: Vec<f64> = rows.iter().map(|&(_, _, _, c, _)| c).collect();
+    let ranges: Vec<(f64, f64, f64)> = rows.iter().map(|&(_, h, l, c, _)| (h, l, c)).collect();
+    ema(&closes, period)
+        .into_iter()
+        .zip(atr(&ranges, period))
+        .map(|(mid, range)| {
+            let (mid, range) = mid.zip(range)?;
+            Some((mid, mid + mult * range, mid - mult * range))
+        })
+        .collect()
+}
+
+/// Volume of rows of `(low, high, volume)` summed into `bins` equal bands from `bottom`
+/// to `top`, all on the price axis. Each row's volume is spread evenly over its range,
+/// and a row with no range puts it all in the band holding its price.
+fn volume_profile(rows: &[(f64, f64, f64)], bottom: f64, top: f64, bins: usize) -> Vec<f64> {
+    let mut profile = vec![0.0; bins];
+    let bin_height = (top - bottom) / bins as f64;
+    if bins == 0 || bin_height.is_nan() || bin_height <= 0.0 {
+        return profile;
+    }
+    let bin_of = |y: f64| (((y - bottom) / bin_height).floor().max(0.0) as usize).min(bins - 1);
+    for &(low, high, volume) in rows.iter().filter(|(_, _, volume)| *volume > 0.0) {
+        if high <= low {
+            profile[bin_of(low)] += volume;
+            continue;
+        }
+        for (bin, total) in profile.iter_mut().enumerate().take(bin_of(high) + 1).skip(bin_of(low))
+        {
+            let bin_bottom = bottom + bin as f64 * bin_height;
+            let overlap = high.min(bin_bottom + bin_height) - low.max(bin_bottom);
+            *total += volume * overlap.max(0.0) / (high - low);
+        }
+    }
+    profile
+}
+
+/// `(timestamp, high, low, close, volume)` of every row in time order, skipping rows
+/// that are too short or not finite. A row without a volume column has volume 0.
+fn ohlcv_series(data: &ChartData) -> Vec<(i64, f64, f64, f64, f64)> {
+    let mut series: Vec<_> = data
+        .data
+        .iter()
+        .filter(|row| row.len() >= 5 && row[..5].iter().all(|value| value.is_finite()))
+        .map(|row| {
+            let volume = row.get(5).copied().filter(|v| v.is_finite()).unwrap_or(0.0);
+            (row[0] as i64, row[2], row[3], row[4], volume)
+        })
+        .collect();
+    series.sort_by_key(|(ts, ..)| *ts);
+    series
+}
+
+/// `(timestamp, value)` of a series or histogram mark in time order. `values` line up
+/// with the rows of `data`, so they must be exactly as many; `what` names the mark in
+/// the error.
+fn aligned_series(
+    data: &ChartData,
+    what: &str,
+    values: &[Option<f64>],
+) -> Result<Vec<(i64, Option<f64>)>, ChartError> {
+    if values.len() != data.data.len() {
+        return Err(ChartError::invalid(format!(
+            "{} has {} values for {} candles",
+            what,
+            values.len(),
+            data.data.len()
+        )));
+    }
+    let mut points: Vec<(i64, Option<f64>)> = data
+        .data
+        .iter()
+        .zip(values)
+        .filter_map(|(row, value)| {
+            let ts = row.first().filter(|ts| ts.is_finite())?;
+            Some((*ts as i64, value.filter(|v| v.is_finite())))
+        })
+        .collect();
+    points.sort_by_key(|(ts, _)| *ts);
+    Ok(points)
+}
+
+/// `(timestamp, percent change)` of a comparison series on the candles at
+/// `candle_millis` (sorted), in time order. Each point goes to the candle nearest it
+/// and a candle reached by several points keeps the nearest one. Points more than half
+/// an `interval_ms` slot outside the candles are dropped. The change is from the first
+/// point kept.
+fn compare_percent_points(
+    series: &CompareSeries,
+    candle_millis: &[i64],
+    interval_ms: i64,
+) -> Result<Vec<(i64, f64)>, ChartError> {
+    let (Some(&first), Some(&last)) = (candle_millis.first(), candle_millis.last()) else {
+        return Ok(Vec::new());
+    };
+    let reach = first - interval_ms / 2..=last + interval_ms / 2;
+    // (distance to the candle, close) of the nearest point each candle has received
+    let mut nearest: Vec<Option<(i64, f64)>> = vec![None; candle_millis.len()];
+    for &(ts, close) in &series.data {
+        if !ts.is_finite() || !close.is_finite() || !reach.contains(&(ts as i64)) {
+            continue;
+        }
+        let ts = ts as i64;
+        let pos = candle_millis.partition_point(|&t| t < ts);
+        let index = if pos == candle_millis.len()
+            || (pos > 0 && ts - candle_millis[pos - 1] <= candle_millis[pos] - ts)
+        {
+            pos - 1
+        } else {
+            pos
+        };
+        let distance = (candle_millis[index] - ts).abs();
+        if nearest[index].is_none_or(|(best, _)| distance < best) {
+            nearest[index] = Some((distance, close));
+        }
+    }
+
+    let closes: Vec<(i64, f64)> = candle_millis
+        .iter()
+        .zip(nearest)
+        .filter_map(|(&ts, point)| point.map(|(_, close)| (ts, close)))
+        .collect();
+    let Some(&(_, base)) = closes.first() else {
+        warn!("Compare series \"{}\" has no points on the chart", series.ticker);
+        return Ok(closes);
+    };
+    if base == 0.0 {
+        return Err(ChartError::invalid(format!(
+            "compare series \"{}\" starts at 0, so it has no percent change",
+            series.ticker
+        )));
+    }
+    Ok(closes
+        .into_iter()
+        .map(|(ts, close)| (ts, (close - base) / base.abs() * 100.0))
+        .collect())
+}
+
+/// Parse the color of an indicator line. Unlike a zone, a line without an alpha
+/// channel is opaque.
+fn parse_line_color(hex: &str) -> RGBAColor {
+    match hex.trim_start_matches('#').len() {
+        8 => parse_hex_color_with_alpha(hex),
+        _ => parse_hex_color(hex).to_rgba(),
+    }
+}
+
+/// Label and color of each line in the price pane: with comparison series, the
+/// chart's own ticker and then each of them, followed by the indicator lines in
+/// `plots.marks` in mark order
+fn legend_entries(data: &ChartData) -> Vec<(String, RGBAColor)> {
+    // The chart's own ticker is drawn as candles, so it gets the last candle's color
+    let own_color = data.candle_colors.last().map_or(DEFAULT_COMPARE_OWN_COLOR.to_rgba(), |hex| {
+        parse_hex_color(hex).to_rgba()
+    });
+    let compared = (!data.compare.is_empty())
+        .then(|| (data.shown_ticker().to_string(), own_color))
+        .into_iter()
+        .chain(
+            data.compare
+                .iter()
+                .map(|series| (series.ticker.clone(), parse_line_color(&series.color))),
+        );
+    let indicators = data
+        .plots
+        .marks
+        .iter()
+        .filter_map(|mark| match mark {
+            PlotMark::Ema(ema_mark) if ema_mark.source.eq_ignore_ascii_case("close") => {
+                Some((format!("EMA {}", ema_mark.period), &ema_mark.color))
+            }
+            PlotMark::Ema(ema_mark) => Some((
+                format!("EMA {} ({})", ema_mark.period, ema_mark.source),
+                &ema_mark.color,
+            )),
+            PlotMark::Vwap(vwap_mark) => match vwap_mark.anchor {
+                VwapAnchor::Session => Some(("VWAP".to_string(), &vwap_mark.color)),
+                VwapAnchor::Start => Some(("VWAP (start)".to_string(), &vwap_mark.color)),
+            },
+            PlotMark::Series(series) => Some((series.name.clone(), &series.color)),
+            PlotMark::Band(band) => band.name.clone().map(|name| (name, &band.color)),
+            PlotMark::Keltner(keltner) => {
+                Some((format!("KC {} {}", keltner.period, keltner.atr_mult), &keltner.color))
+            }
+            _ => None,
+        })
+        .map(|(label, color)| (label, parse_line_color(color)));
+    compared.chain(indicators).collect()
+}
+
+// ─── Pane Layout ────────────────────────────────────────────────────────────────
+
+/// One step of drawing the plot: a fixed layer or a mark with the values it was lined
+/// up with. Steps are drawn in order of their z, see [`PlotMark`].
+enum Layer<'a> {
+    Volume,
+    VolumeProfile(&'a VolumeProfile),
+    Candles,
+    Marker(CandleMarker<'a>),
+    Zone(&'a ZoneMark),
+    Ema(&'a EmaMark),
+    Vwap(&'a VwapMark),
+    Series(&'a SeriesMark, Vec<(i64, Option<f64>)>),
+    Histogram(&'a HistogramMark, Vec<(i64, Option<f64>)>),
+    /// Upper and lower edges
+    Band(&'a BandMark, Vec<(i64, Option<f64>)>, Vec<(i64, Option<f64>)>),
+    /// Midline, upper and lower band
+    Keltner(
+        &'a KeltnerMark,
+        Vec<(i64, Option<f64>)>,
+        Vec<(i64, Option<f64>)>,
+        Vec<(i64, Option<f64>)>,
+    ),
+    /// Risk and reward zones of a position, drawn below its levels
+    PositionShading(&'a PositionMark),
+    Position(&'a PositionMark),
+}
+
+/// A triangle at a candle, from a marker or signal mark
+struct CandleMarker<'a> {
+    time: i64,
+    /// Over the candle pointing down, or under it pointing up
+    above: bool,
+    color: RGBColor,
+    size: f64,
+    text: Option<&'a str>,
+}
+
+/// One band of the plot area. Panes are stacked top to bottom and share the time axis.
+#[derive(Debug, Clone, Copy)]
+struct PaneSpec {
+    /// Height relative to the other panes
+    weight: u32,
+    /// Whether the time axis labels are drawn under this pane
+    shows_x_axis: bool,
+}
+
+impl PaneSpec {
+    /// The candles, volume and plots
+    const PRICE: PaneSpec = PaneSpec {
+        weight: 1,
+        shows_x_axis: true,
+    };
+
+    /// The candles, volume and plots with a lower pane stacked under them
+    const PRICE_ABOVE_LOWER: PaneSpec = PaneSpec {
+        weight: 3,
+        shows_x_axis: false,
+    };
+
+    /// Series marks with `"pane": "lower"`, on their own scale
+    const LOWER: PaneSpec = PaneSpec {
+        weight: 1,
+        shows_x_axis: true,
+    };
+}
+
+/// Split `total` pixels between panes in proportion to `weights`. Pixels left over
+/// from rounding down go to the panes with the largest remainders, so the heights
+/// always add up to `total`.
+fn pane_heights(total: u32, weights: &[u32]) -> Vec<u32> {
+    let weight_sum: u64 = weights.iter().map(|&w| u64::from(w)).sum();
+    if weight_sum == 0 {
+        return vec![0; weights.len()];
+    }
+    let exact: Vec<(u32, u64)> = weights
+        .iter()
+        .map(|&w| {
+            let scaled = u64::from(total) * u64::from(w);
+            ((scaled / weight_sum) as u32, scaled % weight_sum)
+        })
+        .collect();
+    let mut heights: Vec<u32> = exact.iter().map(|(height, _)| *height).collect();
+    let mut leftover = total - heights.iter().sum::<u32>();
+    let mut by_remainder: Vec<usize> = (0..weights.len()).collect();
+    by_remainder.sort_by_key(|&i| std::cmp::Reverse(exact[i].1));
+    for i in by_remainder {
+        if leftover == 0 {
+            break;
+        }
+        heights[i] += 1;
+        leftover -= 1;
+    }
+    heights
+}
+
+/// Split `area` into one sub-area per pane, top to bottom
+fn split_panes<DB: DrawingBackend>(
+    area: &DrawingArea<DB, Shift>,
+    panes: &[PaneSpec],
+) -> Vec<DrawingArea<DB, Shift>> {
+    let weights: Vec<u32> = panes.iter().map(|pane| pane.weight).collect();
+    let heights = pane_heights(area.dim_in_pixel().1, &weights);
+    // Each pane ends where the next begins; the last one ends at the bottom edge
+    let breakpoints: Vec<u32> = heights
+        .iter()
+        .scan(0, |top, height| {
+            *top += height;
+            Some(*top)
+        })
+        .take(panes.len().saturating_sub(1))
+        .collect();
+    area.split_by_breakpoints([] as [u32; 0], breakpoints)
+}
+
+/// Stretches without candles that the time axis squeezes, so a night or weekend with no
+/// trading takes the room of one missing candle instead of hours of empty plot
+#[derive(Debug, Clone, Default)]
+struct TimeGaps {
+    /// Milliseconds since the first candle of the candles either side of each gap, in
+    /// time order
+    gaps: Vec<(i64, i64)>,
+    /// Width on the x axis each gap is squeezed to
+    width: i64,
+}
+
+impl TimeGaps {
+    /// Gaps between neighbouring `candle_millis` (sorted) longer than `threshold`
+    /// intervals. Each is squeezed to two intervals, one empty slot between its candles.
+    fn detect(candle_millis: &[i64], start_millis: i64, interval_ms: i64, threshold: f64) -> Self {
+        let gaps = candle_millis
+            .windows(2)
+            .filter(|pair| (pair[1] - pair[0]) as f64 > threshold * interval_ms as f64)
+            .map(|pair| (pair[0] - start_millis, pair[1] - start_millis))
+            .collect();
+        TimeGaps {
+            gaps,
+            width: 2 * interval_ms,
+        }
+    }
+
+    /// x coordinate of `millis` since the first candle. Time inside a gap is spread
+    /// evenly over its squeezed width.
+    fn to_x(&self, millis: i64) -> i64 {
+        let mut removed = 0;
+        for &(from, to) in &self.gaps {
+            if millis >= to {
+                removed += to - from - self.width;
+            } else {
+                if millis > from {
+                    let into = (millis - from) as f64 / (to - from) as f64;
+                    return from - removed + (into * self.width as f64).round() as i64;
+                }
+                break;
+            }
+        }
+        millis - removed
+    }
+
+    /// Milliseconds since the first candle at x coordinate `x`, undoing `to_x`
+    fn to_millis(&self, x: f64) -> f64 {
+        let mut removed = 0.0;
+        for (&(from, to), squeezed) in self.gaps.iter().zip(self.squeezed()) {
+            if x >= squeezed.end {
+                removed += (to - from - self.width) as f64;
+            } else {
+                if x > squeezed.start {
+                    let into = (x - squeezed.start) / self.width as f64;
+                    return from as f64 + into * (to - from) as f64;
+                }
+                break;
+            }
+        }
+        x + removed
+    }
+
+    /// x coordinates each gap is squeezed into, in time order
+    fn squeezed(&self) -> impl Iterator<Item = std::ops::Range<f64>> + '_ {
+        self.gaps.iter().map(|&(from, _)| {
+            let start = self.to_x(from) as f64;
+            start..start + self.width as f64
+        })
+    }
+}
+
+/// Converts between candles, x coordinates and times the same way in every pane.
+/// An x coordinate is milliseconds since the first candle, less the time squeezed out
+/// of any earlier gaps; every pane's chart is built on `x_range`, so a coordinate lands
+/// on the same pixel column in each.
+struct XMapper {
+    start_dt: DateTime<Utc>,
+    gaps: TimeGaps,
+    /// Sorted x coordinates of the drawn candles
+    candle_xs: Vec<i64>,
+    x_range: std::ops::Range<f64>,
+}
+
+impl XMapper {
+    fn new(
+        start_dt: DateTime<Utc>,
+        gaps: TimeGaps,
+        candles: &[Candle],
+        x_range: std::ops::Range<f64>,
+    ) -> Self {
+        let mut candle_xs: Vec<i64> = candles
+            .iter()
+            .map(|(dt, ..)| gaps.to_x(dt.timestamp_millis() - start_dt.timestamp_millis()))
+            .collect();
+        candle_xs.sort_unstable();
+        XMapper {
+            start_dt,
+            gaps,
+            candle_xs,
+            x_range,
+        }
+    }
+
+    fn x_range(&self) -> std::ops::Range<f64> {
+        self.x_range.clone()
+    }
+
+    /// x coordinate of a timestamp in milliseconds
+    fn millis_to_x(&self, millis: i64) -> f64 {
+        self.gaps.to_x(millis - self.start_dt.timestamp_millis()) as f64
+    }
+
+    /// x coordinate of a time
+    fn time_to_x(&self, dt: DateTime<Utc>) -> f64 {
+        self.millis_to_x(dt.timestamp_millis())
+    }
+
+    /// Time at an x coordinate
+    fn x_to_time(&self, x: f64) -> DateTime<Utc> {
+        self.start_dt + chrono::Duration::milliseconds(self.gaps.to_millis(x) as i64)
+    }
+
+    /// Whether `x` falls inside a squeezed gap, where no time reads true
+    fn in_gap(&self, x: f64) -> bool {
+        self.gaps.squeezed().any(|squeezed| x > squeezed.start && x < squeezed.end)
+    }
+
+    /// Position in time order of the candle within `tolerance` of `x`, if any
+    fn candle_index_at(&self, x: i64, tolerance: i64) -> Option<usize> {
+        let pos = self.candle_xs.partition_point(|&t| t < x);
+        // The candle at or after `x` and the one before it are the only candidates
+        if pos < self.candle_xs.len() && (self.candle_xs[pos] - x).abs() < tolerance {
+            Some(pos)
+        } else if pos > 0 && (self.candle_xs[pos - 1] - x).abs() < tolerance {
+            Some(pos - 1)
+        } else {
+            None
+        }
+    }
+
+    /// x coordinate of the candle closest to `x`, if any candle is drawn
+    fn nearest_candle_x(&self, x: i64) -> Option<i64> {
+        let pos = self.candle_xs.partition_point(|&t| t < x);
+        let before = pos.checked_sub(1).map(|p| self.candle_xs[p]);
+        let after = self.candle_xs.get(pos).copied();
+        match (before, after) {
+            (Some(before), Some(after)) if x - before <= after - x => Some(before),
+            (_, Some(after)) => Some(after),
+            (before, None) => before,
+        }
+    }
+}
+
+/// Blank the cell of a composite panel that cannot be drawn and write why in its middle,
+/// so the other panels still reach the caller
+fn draw_panel_error<DB: DrawingBackend>(
+    area: &DrawingArea<DB, Shift>,
+    panel: &ChartData,
+    error: &ChartError,
+    pixel_scale: f64,
+) -> Result<(), ChartError>
+where
+    DB::ErrorType: 'static,
+{
+    let background = Theme::for_request(panel).map_or(WHITE, |theme| theme.background);
+    area.fill(&background)?;
+    let (width, height) = area.dim_in_pixel();
+    let font = TextStyle::from(("sans-serif", 14.0 * pixel_scale))
+        .color(&RGBColor(110, 110, 110))
+        .pos(Pos::new(HPos::Center, VPos::Center));
+    let (x, y) = (width as i32 / 2, height as i32 / 2);
+    let line = (18.0 * pixel_scale).round() as i32;
+    let timeframe = panel.display_timeframe.as_deref().unwrap_or(&panel.timeframe);
+    let heading = format!("{} {}", panel.shown_ticker(), timeframe);
+    area.draw(&Text::new(heading, (x, y - line / 2), &font))?;
+    area.draw(&Text::new(error.to_string(), (x, y + line / 2), &font))?;
+    Ok(())
+}
+
+/// Draw the close-price line of a thumbnail onto `root_area`, green if the series
+/// closed at or above its first open and red otherwise
+fn draw_thumbnail<DB: DrawingBackend>(
+    root_area: &DrawingArea<DB, Shift>,
+    data: &ChartData,
+    theme: &Theme,
+    candles: &ProcessedCandles,
+    pixel_scale: f64,
+) -> Result<(), ChartError>
+where
+    DB::ErrorType: 'static,
+{
+    let (low, high) = flat_range_guard(candles.lowest_price, candles.highest_price);
+    let scale = if data.price_scale == PriceScale::Log && low <= 0.0 {
+        PriceScale::Linear
+    } else {
+        data.price_scale
+    };
+    let (y_low, y_high) = (scale.price_to_axis(low), scale.price_to_axis(high));
+    let start_millis = candles.start_dt.timestamp_millis();
+    // A single candle still needs some width to draw across
+    let end_millis = candles.end_dt.timestamp_millis().max(start_millis + 1);
+    if !(y_low.is_finite() && y_high.is_finite() && y_low < y_high) {
+        return Err(ChartError::Layout(format!(
+            "price range {} - {} cannot be scaled onto the thumbnail",
+            candles.lowest_price, candles.highest_price
+        )));
+    }
+
+    root_area.fill(&theme.background)?;
+    let mut chart_context = ChartBuilder::on(root_area)
+        .margin((4.0 * pixel_scale).round() as u32)
+        .build_cartesian_2d(start_millis..end_millis, y_low..y_high)?;
+
+    let mut rows: Vec<&Candle> = candles.rows.iter().collect();
+    rows.sort_by_key(|candle| candle.0);
+    let rising = match (rows.first(), rows.last()) {
+        (Some(first), Some(last)) => last.4 >= first.1,
+        _ => true,
+    };
+    let color = if rising { theme.price_up } else { theme.price_down };
+    let line_width = (2.0 * pixel_scale).round().max(1.0) as u32;
+    chart_context.draw_series(std::iter::once(PathElement::new(
+        rows.iter()
+            .map(|(dt, _, _, _, c, _, _)| (dt.timestamp_millis(), scale.price_to_axis(*c)))
+            .collect::<Vec<_>>(),
+        color.stroke_width(line_width),
+    )))?;
+    Ok(())
+}
+
+/// Draw a sparkline onto `root_area`: the close prices as a line over a faint fill,
+/// green if the series closed at or above its first open and red otherwise, with the
+/// last close printed at the right. Nothing takes a margin but a couple of pixels.
+fn draw_sparkline<DB: DrawingBackend>(
+    root_area: &DrawingArea<DB, Shift>,
+    theme: &Theme,
+    candles: &ProcessedCandles,
+    pixel_scale: f64,
+) -> Result<(), ChartError>
+where
+    DB::ErrorType: 'static,
+{
+    root_area.fill(&theme.background)?;
+    let mut rows: Vec<&Candle> = candles.rows.iter().collect();
+    rows.sort_by_key(|candle| candle.0);
+    let (Some(first), Some(last)) = (rows.first(), rows.last()) else {
+        return Ok(());
+    };
+    let color = if last.4 >= first.1 { theme.price_up } else { theme.price_down };
+    let pxi = |size: i32| (size as f64 * pixel_scale).round() as i32;
+
+    let label = format_sparkline_price(last.4);
+    let font = TextStyle::from(("sans-serif", 11.0 * pixel_scale))
+        .color(&color)
+        .pos(Pos::new(HPos::Right, VPos::Center));
+    let (label_width, label_height) =
+        root_area.estimate_text_size(&label, &font).map_or((0, 0), |(w, h)| (w as i32, h as i32));
+    let (width, height) = root_area.dim_in_pixel();
+    let line_width = (width as i32 - label_width - pxi(6)).max(1);
+    let (line_area, _) = root_area.split_horizontally(line_width);
+
+    let (low, high) = rows
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), candle| {
+            (lo.min(candle.4), hi.max(candle.4))
+        });
+    let (low, high) = flat_range_guard(low, high);
+    let start_millis = first.0.timestamp_millis();
+    // A single candle still needs some width to draw across
+    let end_millis = last.0.timestamp_millis().max(start_millis + 1);
+    let mut chart_context = ChartBuilder::on(&line_area)
+        .margin(pxi(2).max(1) as u32)
+        .build_cartesian_2d(start_millis..end_millis, low..high)?;
+
+    let stroke = (1.5 * pixel_scale).round().max(1.0) as u32;
+    chart_context.draw_series(
+        AreaSeries::new(
+            rows.iter().map(|candle| (candle.0.timestamp_millis(), candle.4)),
+            low,
+            color.mix(0.15),
+        )
+        .border_style(color.stroke_width(stroke)),
+    )?;
+
+    // The label sits level with the line's end, kept whole inside the canvas
+    let (_, base_y) = root_area.get_base_pixel();
+    let (_, end_y) = chart_context.backend_coord(&(end_millis, last.4));
+    let half = label_height / 2 + 1;
+    let label_y = (end_y - base_y).clamp(half, (height as i32 - half).max(half));
+    root_area.draw(&Text::new(label, (width as i32 - pxi(2), label_y), &font))?;
+    Ok(())
+}
+
+/// A price short enough for a sparkline: whole units with thousands separators from
+/// 1,000 up, cents from 1 up and four significant digits below that
+fn format_sparkline_price(price: f64) -> String {
+    let magnitude = price.abs();
+    if magnitude >= 1000.0 {
+        let digits = format!("{}", magnitude.round() as i64);
+        let grouped: Vec<&str> = digits
+            .as_bytes()
+            .rchunks(3)
+            .rev()
+            .filter_map(|chunk| std::str::from_utf8(chunk).ok())
+            .collect();
+        let sign = if price < 0.0 { "-" } else { "" };
+        format!("{}{}", sign, grouped.join(","))
+    } else if magnitude >= 1.0 || magnitude == 0.0 {
+        format!("{:.2}", price)
+    } else {
+        let decimals = (3 - magnitude.log10().floor() as i32).max(0) as usize;
+        format!("{:.*}", decimals, price)
+    }
+}
+
+/// A volume short enough for an axis label: three significant digits with a K, M, B
+/// or T suffix from 1,000 up
+fn format_volume(volume: f64) -> String {
+    const SUFFIXES: [(f64, &str); 4] = [(1e12, "T"), (1e9, "B"), (1e6, "M"), (1e3, "K")];
+    let Some((unit, suffix)) = SUFFIXES.iter().find(|(unit, _)| volume.abs() >= *unit) else {
+        return format!("{}", (volume * 100.0).round() / 100.0);
+    };
+    let scaled = volume / unit;
+    let decimals = match scaled.abs() {
+        value if value >= 100.0 => 0,
+        value if value >= 10.0 => 1,
+        _ => 2,
+    };
+    format!("{:.*}{}", decimals, scaled, suffix)
+}
+
+/// Draw the points of a series mark in `style`. A `None` point is a gap: lines and
+/// steps stop before it and start again after it.
+fn draw_series_points<DB: DrawingBackend>(
+    chart: &mut ChartContext<'_, DB, Cartesian2d<RangedCoordf64, RangedCoordf64>>,
+    points: &[Option<(f64, f64)>],
+    style: SeriesStyle,
+    color: RGBAColor,
+    width: u32,
+) -> Result<(), ChartError>
+where
+    DB::ErrorType: 'static,
+{
+    if style == SeriesStyle::Dots {
+        chart.draw_series(
+            points
+                .iter()
+                .flatten()
+                .map(|&point| Circle::new(point, width as i32, color.filled())),
+        )?;
+        return Ok(());
+    }
+    for run in points.split(Option::is_none) {
+        let run: Vec<(f64, f64)> = run.iter().flatten().copied().collect();
+        let path = match style {
+            SeriesStyle::Step => {
+                let mut path = Vec::with_capacity(run.len() * 2);
+                for (i, &(x, y)) in run.iter().enumerate() {
+                    if i > 0 {
+                        path.push((x, run[i - 1].1));
+                    }
+                    path.push((x, y));
+                }
+                path
+            }
+            _ => run,
+        };
+        chart.draw_series(std::iter::once(PathElement::new(path, color.stroke_width(width))))?;
+    }
+    Ok(())
+}
+
+/// Decimals of percent labels on an axis spanning `span` percentage points, more
+/// the less it spans
+fn percent_label_decimals(span: f64) -> usize {
+    match span.abs() {
+        span if span < 2.0 => 2,
+        span if span < 20.0 => 1,
+        _ => 0,
+    }
+}
+
+/// Round distance between neighbouring ticks when `span` is cut into at most `target`
+/// steps: the smallest of 1, 2, 2.5 and 5 times a power of ten that is long enough
+fn nice_step(span: f64, target: usize) -> f64 {
+    let shortest = span.abs() / target.max(1) as f64;
+    if !shortest.is_normal() {
+        return 1.0;
+    }
+    let magnitude = 10f64.powf(shortest.log10().floor());
+    [1.0, 2.0, 2.5, 5.0, 10.0]
+        .iter()
+        .map(|mantissa| mantissa * magnitude)
+        // The power of ten is inexact, so a step equal to the shortest may fall just under it
+        .find(|step| *step >= shortest * (1.0 - 1e-9))
+        .unwrap_or(10.0 * magnitude)
+}
+
+/// Multiples of the [`nice_step`] for `target` steps from `low` to `high`, both included
+fn nice_ticks(low: f64, high: f64, target: usize) -> Vec<f64> {
+    let step = nice_step(high - low, target);
+    let first = (low / step - 1e-9).ceil();
+    let last = (high / step + 1e-9).floor();
+    if !(first.is_finite() && last.is_finite()) || last - first > (4 * target) as f64 {
+        return Vec::new();
+    }
+    (first as i64..=last as i64).map(|multiple| multiple as f64 * step).collect()
+}
+
+/// Ticks of a log axis from `low` to `high`, both above 0. Within a decade the round
+/// levels of [`nice_ticks`] read best; across decades it is 1, 2 and 5 times each power
+/// of ten, then powers of ten alone, then every few of them, whichever first gives at
+/// most `target` ticks.
+fn log_ticks(low: f64, high: f64, target: usize) -> Vec<f64> {
+    if high < low * 10.0 {
+        return nice_ticks(low, high, target);
+    }
+    let decades = low.log10().floor() as i32..=high.log10().ceil() as i32;
+    let levels = |mantissas: &[f64]| -> Vec<f64> {
+        decades
+            .clone()
+            .flat_map(|exponent| mantissas.iter().map(move |m| m * 10f64.powi(exponent)))
+            .filter(|price| (low..=high).contains(price))
+            .collect()
+    };
+    let ticks = levels(&[1.0, 2.0, 5.0]);
+    if ticks.len() <= target {
+        return ticks;
+    }
+    let powers = levels(&[1.0]);
+    let every = powers.len().div_ceil(target.max(1));
+    powers.into_iter().step_by(every).collect()
+}
+
+/// Fewest decimals, up to 8, that write every tick without rounding it
+fn tick_decimals(ticks: &[f64]) -> usize {
+    (0..8)
+        .find(|decimals| {
+            ticks.iter().all(|tick| {
+                let scaled = tick * 10f64.powi(*decimals as i32);
+                (scaled - scaled.round()).abs() < 1e-6 * scaled.abs().max(1.0)
+            })
+        })
+        .unwrap_or(8)
+}
+
+/// Labels of `ticks` in order, each with the decimals its value or the step to the next
+/// tick needs, whichever is more; evenly spaced ticks so share theirs
+fn tick_labels(ticks: &[f64], format: impl Fn(f64, usize) -> String) -> Vec<String> {
+    ticks
+        .iter()
+        .enumerate()
+        .map(|(i, tick)| {
+            let step = match (ticks.get(i + 1), i.checked_sub(1)) {
+                (Some(next), _) => next - tick,
+                (None, Some(before)) => tick - ticks[before],
+                (None, None) => 0.0,
+            };
+            format(*tick, tick_decimals(&[*tick, step]))
+        })
+        .collect()
+}
+
+/// A price axis label: `$`, thousands separators and `decimals` decimals
+fn format_axis_price(price: f64, decimals: usize) -> String {
+    let grouped = format_grouped(price, decimals);
+    match grouped.strip_prefix('-') {
+        Some(magnitude) => format!("-${}", magnitude),
+        None => format!("${}", grouped),
+    }
+}
+
+/// Parts of the visible price range that [`format_price`] tells apart, about a pixel
+/// each on the tallest charts
+const PRICE_LABEL_STEPS: f64 = 1000.0;
+/// Most decimals [`format_price`] writes
+const MAX_PRICE_DECIMALS: usize = 12;
+
+/// Decimals for `price` on a chart showing `reference_span` of prices: four significant
+/// digits, and more if it takes them to tell apart prices a pixel apart. A span of zero
+/// or less, or one that is not finite, only asks for the significant digits.
+fn price_decimals(price: f64, reference_span: f64) -> usize {
+    let digits_for = |step: f64| {
+        if step.is_normal() { (-step.log10()).ceil().max(0.0) as usize } else { 0 }
+    };
+    // The step worth a fourth significant digit
+    let significant = digits_for(10f64.powf(price.abs().log10().floor() - 3.0));
+    let resolution = if reference_span > 0.0 {
+        digits_for(reference_span / PRICE_LABEL_STEPS)
+    } else {
+        0
+    };
+    significant.max(resolution).min(MAX_PRICE_DECIMALS)
+}
+
+/// `price` with thousands separators and the decimals [`price_decimals`] picks for a
+/// chart showing `reference_span` of prices, e.g. `60,838` or `0.073123`
+fn format_price(price: f64, reference_span: f64) -> String {
+    format_grouped(price, price_decimals(price, reference_span))
+}
+
+/// `price` with thousands separators and `decimals` decimals, e.g. `-1,234.50`
+fn format_grouped(price: f64, decimals: usize) -> String {
+    let digits = format!("{:.*}", decimals, price.abs());
+    let (whole, fraction) = digits.split_once('.').map_or((digits.as_str(), None), |(w, f)| {
+        (w, Some(f))
+    });
+    let grouped: Vec<&str> = whole
+        .as_bytes()
+        .rchunks(3)
+        .rev()
+        .filter_map(|chunk| std::str::from_utf8(chunk).ok())
+        .collect();
+    // A price that rounds to zero is not negative
+    let sign = if price < 0.0 && digits.bytes().any(|b| b.is_ascii_digit() && b != b'0') {
+        "-"
+    } else {
+        ""
+    };
+    match fraction {
+        Some(fraction) => format!("{}{}.{}", sign, grouped.join(","), fraction),
+        None => format!("{}{}", sign, grouped.join(",")),
+    }
+}
+
+/// Draw `text` in `style` turned `degrees` counterclockwise about the middle of its end,
+/// which goes at `anchor`. Plotters only turns text by right angles, so the text is
+/// drawn level into a scratch buffer and its pixels are turned onto `area`.
+fn draw_rotated_text<DB: DrawingBackend>(
+    area: &DrawingArea<DB, Shift>,
+    text: &str,
+    style: &TextStyle,
+    anchor: (i32, i32),
+    degrees: f64,
+) -> Result<(), ChartError>
+where
+    DB::ErrorType: 'static,
+{
+    let (text_width, text_height) = area.estimate_text_size(text, style)?;
+    if text_width == 0 || text_height == 0 {
+        return Ok(());
+    }
+    // A pixel of white on each side keeps the glyph edges inside the buffer
+    let (width, height) = (text_width as usize + 2, text_height as usize + 2);
+    let mut buffer = vec![255u8; width * height * 3];
+    {
+        let scratch = BitMapBackend::with_buffer(&mut buffer, (width as u32, height as u32))
+            .into_drawing_area();
+        let black = style.color(&BLACK).pos(Pos::new(HPos::Left, VPos::Top));
+        scratch.draw(&Text::new(text, (1, 1), &black))?;
+        scratch.present()?;
+    }
+    // How much of a scratch pixel the glyphs cover, from 0 to 1
+    let coverage = |x: i64, y: i64| -> f64 {
+        if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+            return 0.0;
+        }
+        1.0 - buffer[(y as usize * width + x as usize) * 3] as f64 / 255.0
+    };
+    let (r, g, b) = style.color.rgb;
+    let color = RGBColor(r, g, b);
+
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (pivot_x, pivot_y) = (width as f64 - 1.0, height as f64 / 2.0);
+    // The turned buffer's corners around the anchor bound the pixels to fill
+    let corners = [(0.0, 0.0), (width as f64, 0.0), (0.0, height as f64)]
+        .into_iter()
+        .chain(std::iter::once((width as f64, height as f64)))
+        .map(|(x, y)| {
+            let (u, v) = (x - pivot_x, y - pivot_y);
+            (cos * u + sin * v, -sin * u + cos * v)
+        });
+    let (mut left, mut right, mut top, mut bottom) = (0.0_f64, 0.0_f64, 0.0_f64, 0.0_f64);
+    for (x, y) in corners {
+        (left, right) = (left.min(x), right.max(x));
+        (top, bottom) = (top.min(y), bottom.max(y));
+    }
+    for dy in top.floor() as i32..=bottom.ceil() as i32 {
+        for dx in left.floor() as i32..=right.ceil() as i32 {
+            // Turn back into the scratch buffer and blend its four nearest pixels
+            let (dx_f, dy_f) = (dx as f64, dy as f64);
+            let sx = cos * dx_f - sin * dy_f + pivot_x;
+            let sy = sin * dx_f + cos * dy_f + pivot_y;
+            let (x0, y0) = (sx.floor(), sy.floor());
+            let (fx, fy) = (sx - x0, sy - y0);
+            let (x0, y0) = (x0 as i64, y0 as i64);
+            let alpha = coverage(x0, y0) * (1.0 - fx) * (1.0 - fy)
+                + coverage(x0 + 1, y0) * fx * (1.0 - fy)
+                + coverage(x0, y0 + 1) * (1.0 - fx) * fy
+                + coverage(x0 + 1, y0 + 1) * fx * fy;
+            if alpha > 0.02 {
+                area.draw_pixel((anchor.0 + dx, anchor.1 + dy), &color.mix(alpha))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Most lines a title takes; the last one kept ends in "…" when there would be more
+const MAX_TITLE_LINES: usize = 3;
+
+/// The lines of the title as drawn: split on `\n`, each cut to `title_max_chars`, then
+/// wrapped or truncated per `title_wrap` so it fits `room` pixels as measured by `width`.
+/// The first line leaves `first_reserve` pixels for the badges that follow it.
+fn fit_title(
+    data: &ChartData,
+    room: i32,
+    first_reserve: i32,
+    width: impl Fn(&str) -> i32,
+) -> Vec<String> {
+    let budget = |line: usize| if line == 0 { room - first_reserve } else { room };
+    let mut lines: Vec<String> = Vec::new();
+    for line in data.title.lines() {
+        let line = match data.title_max_chars {
+            Some(max) if line.chars().count() > max => {
+                format!("{}…", line.chars().take(max.saturating_sub(1)).collect::<String>())
+            }
+            _ => line.to_string(),
+        };
+        if data.title_wrap == TitleWrap::Truncate {
+            lines.push(ellipsize(&line, budget(lines.len()), &width));
+            continue;
+        }
+        let mut current = String::new();
+        for word in line.split_whitespace() {
+            let joined = format!("{} {}", current, word);
+            if !current.is_empty() && width(&joined) <= budget(lines.len()) {
+                current = joined;
+                continue;
+            }
+            if !current.is_empty() {
+                lines.push(std::mem::take(&mut current));
+            }
+            current = word.to_string();
+            // A word wider than a whole line is broken where it reaches the edge
+            while current.chars().count() > 1 && width(&current) > budget(lines.len()) {
+                let chars: Vec<char> = current.chars().collect();
+                let fits = (1..chars.len())
+                    .rev()
+                    .find(|&n| width(&chars[..n].iter().collect::<String>()) <= budget(lines.len()))
+                    .unwrap_or(1);
+                lines.push(chars[..fits].iter().collect());
+                current = chars[fits..].iter().collect();
+            }
+        }
+        lines.push(current);
+    }
+    if lines.len() > MAX_TITLE_LINES {
+        lines.truncate(MAX_TITLE_LINES);
+        if let Some(last) = lines.last_mut() {
+            let budget = budget(MAX_TITLE_LINES - 1);
+            *last = ellipsize(&format!("{}…", last.trim_end_matches('…')), budget, &width);
+        }
+    }
+    lines
+}
+
+/// `text` if it is at most `budget` pixels wide, otherwise as much of it as fits
+/// followed by "…"
+fn ellipsize(text: &str, budget: i32, width: impl Fn(&str) -> i32) -> String {
+    if width(text) <= budget {
+        return text.to_string();
+    }
+    let mut chars: Vec<char> = text.trim_end_matches('…').chars().collect();
+    loop {
+        let cut = format!("{}…", chars.iter().collect::<String>().trim_end());
+        if chars.is_empty() || width(&cut) <= budget {
+            return cut;
+        }
+        chars.pop();
+    }
+}
+
+/// Draw a box listing `entries` with a swatch of each color, inset into the `position`
+/// corner of `plot`, the pixel range of the price pane's plotting area within `root_area`
+fn draw_legend<DB: DrawingBackend>(
+    root_area: &DrawingArea<DB, Shift>,
+    plot: (std::ops::Range<i32>, std::ops::Range<i32>),
+    entries: &[(String, RGBAColor)],
+    position: LegendPosition,
+    pixel_scale: f64,
+) -> Result<(), ChartError>
+where
+    DB::ErrorType: 'static,
+{
+    let pxi = |size: i32| (size as f64 * pixel_scale).round() as i32;
+    let font = TextStyle::from(("sans-serif", 12.0 * pixel_scale))
+        .color(&RGBColor(60, 60, 60))
+        .pos(Pos::new(HPos::Left, VPos::Center));
+    let (padding, swatch, gap, row_height, inset) = (pxi(6), pxi(14), pxi(6), pxi(16), pxi(8));
+
+    let text_width = entries
+        .iter()
+        .filter_map(|(label, _)| root_area.estimate_text_size(label, &font).ok())
+        .map(|(w, _)| w as i32)
+        .max()
+        .unwrap_or(0);
+    let width = padding + swatch + gap + text_width + padding;
+    let height = 2 * padding + entries.len() as i32 * row_height;
+    let (x_span, y_span) = plot;
+    let left = match position {
+        LegendPosition::TopRight | LegendPosition::BottomRight => x_span.end - inset - width,
+        _ => x_span.start + inset,
+    };
+    let top = match position {
+        LegendPosition::BottomLeft | LegendPosition::BottomRight => y_span.end - inset - height,
+        _ => y_span.start + inset,
+    };
+
+    let corners = [(left, top), (left + width, top + height)];
+    root_area.draw(&Rectangle::new(corners, WHITE.mix(0.85).filled()))?;
+    root_area.draw(&Rectangle::new(
+        corners,
+        RGBColor(200, 200, 200).stroke_width(pxi(1).max(1) as u32),
+    ))?;
+    for (i, (label, color)) in entries.iter().enumerate() {
+        let y = top + padding + i as i32 * row_height + row_height / 2;
+        root_area.draw(&PathElement::new(
+            vec![(left + padding, y), (left + padding + swatch, y)],
+            color.stroke_width(pxi(3).max(1) as u32),
+        ))?;
+        let text_x = left + padding + swatch + gap;
+        root_area.draw(&Text::new(label.clone(), (text_x, y), font.clone()))?;
+    }
+    Ok(())
+}
+
+/// Draw the footer strip: a rule along its top and `text` in small gray type
+fn draw_footer<DB: DrawingBackend>(
+    area: &DrawingArea<DB, Shift>,
+    text: &str,
+    pixel_scale: f64,
+) -> Result<(), ChartError>
+where
+    DB::ErrorType: 'static,
+{
+    let pxi = |size: i32| (size as f64 * pixel_scale).round() as i32;
+    let (width, height) = area.dim_in_pixel();
+    area.fill(&RGBColor(248, 248, 248))?;
+    area.draw(&PathElement::new(
+        vec![(0, 0), (width as i32, 0)],
+        RGBColor(220, 220, 220).stroke_width(pxi(1).max(1) as u32),
+    ))?;
+    let font = TextStyle::from(("sans-serif", 11.0 * pixel_scale))
+        .color(&RGBColor(130, 130, 130))
+        .pos(Pos::new(HPos::Left, VPos::Center));
+    area.draw(&Text::new(text.to_string(), (pxi(10), height as i32 / 2), font))?;
+    Ok(())
+}
+
+/// Draw the zoom inset into `area`: `rows` side by side on their own price range, with
+/// a thin border and no labels
+fn draw_zoom_inset<DB: DrawingBackend>(
+    area: &DrawingArea<DB, Shift>,
+    data: &ChartData,
+    theme: &Theme,
+    rows: &[Candle],
+    price_scale: PriceScale,
+    pixel_scale: f64,
+) -> Result<(), ChartError>
+where
+    DB::ErrorType: 'static,
+{
+    let pxi = |size: i32| (size as f64 * pixel_scale).round() as i32;
+    let line_width = pxi(1).max(1) as u32;
+    let (low, high) = rows
+        .iter()
+        .fold((f64::MAX, f64::MIN), |(low, high), c| (low.min(c.3), high.max(c.2)));
+    let (low, high) = flat_range_guard(low, high);
+    let scale = if price_scale == PriceScale::Log && low <= 0.0 {
+        PriceScale::Linear
+    } else {
+        price_scale
+    };
+    let (y_low, y_high) = (scale.price_to_axis(low), scale.price_to_axis(high));
+    if !(y_low.is_finite() && y_high.is_finite() && y_low < y_high) {
+        return Err(ChartError::Layout(format!(
+            "price range {} - {} cannot be scaled onto the zoom inset",
+            low, high
+        )));
+    }
+
+    area.fill(&theme.background)?;
+    let mut chart = ChartBuilder::on(area)
+        .margin(pxi(4).max(1) as u32)
+        .build_cartesian_2d(-0.5..rows.len() as f64 - 0.5, y_low..y_high)?;
+    // Bodies thinner than a pixel grow to one, as on the main chart
+    let plot_height = chart.plotting_area().dim_in_pixel().1.max(1);
+    let axis_per_pixel = (y_high - y_low) / plot_height as f64;
+    let half_body = data.body_width_pct.clamp(1.0, 100.0) / 200.0;
+
+    chart.draw_series(rows.iter().enumerate().map(|(i, (_, o, h, l, c, ..))| {
+        let x = i as f64;
+        let wick_color = if c >= o { theme.wick_up } else { theme.wick_down };
+        PathElement::new(
+            vec![(x, scale.price_to_axis(*h)), (x, scale.price_to_axis(*l))],
+            wick_color.stroke_width(line_width),
+        )
+    }))?;
+    chart.draw_series(rows.iter().enumerate().flat_map(|(i, (_, o, _h, _l, c, _v, color))| {
+        let x = i as f64;
+        let (open_y, close_y) = (scale.price_to_axis(*o), scale.price_to_axis(*c));
+        let (mut top, mut bottom) = (open_y.max(close_y), open_y.min(close_y));
+        if top - bottom < axis_per_pixel {
+            let middle = (top + bottom) / 2.0;
+            (top, bottom) = (middle + axis_per_pixel / 2.0, middle - axis_per_pixel / 2.0);
+        }
+        let corners = [(x - half_body, top), (x + half_body, bottom)];
+        if data.candle_fill.is_hollow(*o, *c) {
+            vec![
+                Rectangle::new(corners, theme.background.filled()),
+                Rectangle::new(corners, color.stroke_width(line_width)),
+            ]
+        } else {
+            vec![Rectangle::new(corners, color.filled())]
+        }
+    }))?;
+
+    let (width, height) = area.dim_in_pixel();
+    area.draw(&Rectangle::new(
+        [(0, 0), (width as i32 - 1, height as i32 - 1)],
+        theme.axis.stroke_width(line_width),
+    ))?;
+    Ok(())
+}
+
+/// Draw the full chart (title, table, candles, volume and plots) onto `root_area`.
+/// The caller owns the backend and is responsible for calling `present()`.
+/// `deadline` is checked after each panel so a runaway render stops early.
+/// Returns where the plot and candles landed when `data.emit_coords` is set.
+fn draw_chart<DB: DrawingBackend>(
+    root_area: &DrawingArea<DB, Shift>,
+    data: &ChartData,
+    theme: &Theme,
+    candles: ProcessedCandles,
+    timezone: LabelTimezone,
+    pixel_scale: f64,
+    deadline: &RenderDeadline,
+) -> Result<Option<ChartCoords>, ChartError>
+where
+    DB::ErrorType: 'static,
+{
+    // Every size below is given at scale 1 and converted to canvas pixels here
+    let px = |size: u32| (size as f64 * pixel_scale).round() as u32;
+    let pxi = |size: i32| (size as f64 * pixel_scale).round() as i32;
+    let font_px = |size: f64| size * pixel_scale;
+
+    // Lines grow with `line_width`, or with a canvas larger than the default one, so
+    // they keep their weight however large the chart is drawn
+    let line_scale = match data.line_width {
+        Some(width) if !(MIN_LINE_WIDTH..=MAX_LINE_WIDTH).contains(&width) => {
+            return Err(ChartError::invalid(format!(
+                "line_width must be between {} and {}, got {}",
+                MIN_LINE_WIDTH, MAX_LINE_WIDTH, width
+            )));
+        }
+        Some(width) => width,
+        None => {
+            let (width, height) = root_area.dim_in_pixel();
+            let layout_width = width as f64 / pixel_scale / DEFAULT_WIDTH as f64;
+            let layout_height = height as f64 / pixel_scale / DEFAULT_HEIGHT as f64;
+            layout_width.min(layout_height).max(1.0)
+        }
+    };
+    let line_px = |size: u32| ((size as f64 * pixel_scale * line_scale).round() as u32).max(1);
+
+    let ProcessedCandles {
+        rows: mut processed_data,
+        start_dt,
+        end_dt,
+        lowest_price,
+        highest_price,
+        max_volume,
+        mut window_start,
+        aggregated,
+        flagged,
+    } = candles;
+
+    // Work out the candle interval: trust the stated timeframe, but check it against the data
+    let mut sorted_millis: Vec<i64> = processed_data
+        .iter()
+        .map(|(dt, _, _, _, _, _, _)| dt.timestamp_millis())
+        .collect();
+    sorted_millis.sort_unstable();
+    let stated_interval = parse_timeframe_millis(&data.timeframe);
+    let observed_interval = median_interval_millis(&sorted_millis);
+    if let (Some(stated), Some(observed)) = (stated_interval, observed_interval) {
+        let ratio = stated as f64 / observed as f64;
+        if !(1.0 / 3.0..=3.0).contains(&ratio) {
+            warn!(
+                "Timeframe '{}' disagrees with observed candle spacing of {}",
+                data.timeframe,
+                format_interval(observed)
+            );
+        }
+    }
+    let timeframe_label = match (&data.display_timeframe, stated_interval, observed_interval) {
+        (Some(shown), _, _) => shown.trim().to_string(),
+        (None, Some(_), _) => data.timeframe.trim().to_string(),
+        (None, None, Some(observed)) => format!("~{}", format_interval(observed)),
+        (None, None, None) => data.timeframe.trim().to_string(),
+    };
+    let interval_ms = stated_interval.or(observed_interval).unwrap_or(MINUTE_MS);
+    let gaps = match data.compress_gaps {
+        None => TimeGaps::default(),
+        // Squeezing to two intervals would widen a shorter gap
+        Some(threshold) if !(threshold.is_finite() && threshold >= 2.0) => {
+            return Err(ChartError::invalid(format!(
+                "compress_gaps must be at least 2, got {}",
+                threshold
+            )));
+        }
+        Some(threshold) => {
+            TimeGaps::detect(&sorted_millis, start_dt.timestamp_millis(), interval_ms, threshold)
+        }
+    };
+
+    // Comparison series are lined up with the candles before the price range is known,
+    // since the percent axis has to reach their lines too
+    let compare_lines = data
+        .compare
+        .iter()
+        .map(|series| Ok((series, compare_percent_points(series, &sorted_millis, interval_ms)?)))
+        .collect::<Result<Vec<_>, ChartError>>()?;
+
+    // A current price away from the candles still has to be on the chart
+    let (lowest_price, highest_price) = match data.current_price {
+        Some(price) => (lowest_price.min(price), highest_price.max(price)),
+        None => (lowest_price, highest_price),
+    };
+
+    let first_open = processed_data.iter().min_by_key(|(dt, ..)| *dt).map(|c| c.1);
+    // A percent axis is linear in the change from its base, whatever the price scale.
+    // Comparison lines only make sense against one.
+    let percent_base = if data.y_mode == YMode::Percent || !compare_lines.is_empty() {
+        match data.percent_base.or(first_open) {
+            Some(base) if base != 0.0 && base.is_finite() => Some(base),
+            _ => {
+                return Err(ChartError::invalid(
+                    "a percent price axis needs a finite, non-zero base price",
+                ));
+            }
+        }
+    } else {
+        None
+    };
+    // The percent axis also has to reach the comparison lines
+    let (range_low, range_high) = match percent_base {
+        Some(base) => compare_lines
+            .iter()
+            .flat_map(|(_, points)| points)
+            .map(|(_, percent)| base + percent / 100.0 * base.abs())
+            .fold((lowest_price, highest_price), |(low, high), price| {
+                (low.min(price), high.max(price))
+            }),
+        None => (lowest_price, highest_price),
+    };
+    // A flat series would pin every candle to the bottom edge, so give it a range to sit in
+    let (axis_low_price, axis_high_price) = flat_range_guard(range_low, range_high);
+    // Log scale cannot show zero or negative prices (spreads, PnL), so those go linear
+    let price_scale = if percent_base.is_none()
+        && data.price_scale == PriceScale::Log
+        && axis_low_price <= 0.0
+    {
+        info!(
+            "Series has non-positive prices (lowest {}), using a linear price scale",
+            lowest_price
+        );
+        PriceScale::Linear
+    } else {
+        data.price_scale
+    };
+    let scale = PriceAxis {
+        scale: price_scale,
+        percent_base,
+    };
+    let min_axis_for_chart = scale.price_to_axis(axis_low_price);
+
+    // Add a bit of padding to the max price (0.2%) to ensure highest candle is visible
+    let padding_factor = 1.002;
+    let max_axis_for_chart = match (percent_base, price_scale) {
+        (None, PriceScale::Log) => (axis_high_price * padding_factor).ln(),
+        _ => {
+            let high = scale.price_to_axis(axis_high_price);
+            high + (high - min_axis_for_chart) * (padding_factor - 1.0)
+        }
+    };
+    // Prices near f64::MAX overflow the padded range, which plotters cannot map to pixels
+    if !min_axis_for_chart.is_finite()
+        || !max_axis_for_chart.is_finite()
+        || min_axis_for_chart >= max_axis_for_chart
+    {
+        return Err(ChartError::Layout(format!(
+            "price range {} - {} cannot be scaled onto the chart",
+            lowest_price, highest_price
+        )));
+    }
+
+    let plot_width = root_area.dim_in_pixel().0;
+    root_area.fill(&theme.background)?;
+
+    // The footer takes a strip off the bottom of the image, so everything else,
+    // time axis labels included, is laid out in what is left above it
+    let (chart_root, footer_area) = if data.show_footer || data.footer.is_some() {
+        let root_height = root_area.dim_in_pixel().1 as i32;
+        let (upper, footer_area) = root_area.split_vertically(root_height - pxi(FOOTER_HEIGHT));
+        (upper, Some(footer_area))
+    } else {
+        (root_area.clone(), None)
+    };
+    let root_area = &chart_root;
+    if let Some(footer_area) = footer_area {
+        let date_format = "%Y-%m-%d %H:%M";
+        let first_dt = processed_data.iter().map(|(dt, ..)| *dt).min().unwrap_or(start_dt);
+        let last_dt = processed_data.iter().map(|(dt, ..)| *dt).max().unwrap_or(end_dt);
+        let mut parts = vec![
+            data.shown_ticker().to_string(),
+            timeframe_label.clone(),
+            format!("{} candles", processed_data.len()),
+            format!(
+                "{} → {}",
+                timezone.format(first_dt, date_format),
+                timezone.format(last_dt, date_format)
+            ),
+            timezone.format(Utc::now(), "generated %Y-%m-%d %H:%M:%S UTC%:z"),
+        ];
+        parts.extend(data.footer.iter().filter(|text| !text.trim().is_empty()).cloned());
+        draw_footer(&footer_area, &parts.join("  ·  "), pixel_scale)?;
+    }
+
+    // Prices in text are written to the precision the chart shows them at
+    let price_span = axis_high_price - axis_low_price;
+    debug!(
+        "Price range: ${} - ${}",
+        format_price(lowest_price, price_span),
+        format_price(highest_price, price_span)
+    );
+
+    // --- Title layout: its lines, with the timeframe badge and price change following
+    // the first, fitted to the canvas width before the header is sized ---
+    let title_style = TextStyle::from(("sans-serif", font_px(24.0))).color(&theme.text);
+    let badge_style = TextStyle::from(("sans-serif", font_px(16.0)));
+    let mut title_extras = Vec::new();
+    if !timeframe_label.is_empty() {
+        title_extras.push((timeframe_label.clone(), RGBColor(110, 110, 110)));
+    }
+    // Price change annotation: from the previous close if supplied, otherwise from the
+    // first candle's open, to the current price
+    let change_base = data.prev_close.filter(|p| *p > 0.0).or(first_open);
+    let latest_close = processed_data.iter().max_by_key(|(dt, ..)| *dt).map(|c| c.4);
+    if let (Some(base), Some(price)) = (change_base, data.current_price.or(latest_close)) {
+        let change = price - base;
+        // Relative to the base's magnitude so a negative base keeps the sign meaningful
+        let change_pct = if base == 0.0 { 0.0 } else { change / base.abs() * 100.0 };
+        let (sign, change_color) = if change >= 0.0 {
+            ("+", theme.price_up)
+        } else {
+            ("-", theme.price_down)
+        };
+        let change_label = format!(
+            "{}${} ({}{:.2}%)",
+            sign,
+            format_grouped(change.abs(), price_decimals(price, price_span)),
+            sign,
+            change_pct.abs()
+        );
+        title_extras.push((change_label, change_color));
+    }
+    // Text that cannot be measured is taken as 14px a character
+    let text_width = |text: &str, style: &TextStyle| {
+        root_area
+            .estimate_text_size(text, style)
+            .map_or(text.chars().count() as i32 * pxi(14), |(w, _)| w as i32)
+    };
+    // The extras start 10px after the first line and keep 12px apart
+    let extras_width: i32 = title_extras
+        .iter()
+        .map(|(text, _)| text_width(text, &badge_style))
+        .sum::<i32>()
+        + pxi(10)
+        + pxi(12) * (title_extras.len() as i32 - 1).max(0);
+    let title_margin = px(data.chart_margin.unwrap_or(DEFAULT_CHART_MARGIN)) as i32;
+    let title_lines = fit_title(
+        data,
+        plot_width as i32 - 2 * title_margin,
+        extras_width,
+        |text: &str| text_width(text, &title_style),
+    );
+
+    // Allocate more height for the table area and include title space; every line of
+    // the title after the first adds a row
+    let title_line_height = pxi(30);
+    let title_height = pxi(40) + (title_lines.len().max(1) as i32 - 1) * title_line_height;
+    let table_height = pxi(100); // More height for the table
+    let header_height = title_height + table_height;
+
+    // Split the drawing area into three parts: title, table, and chart
+    let (header_area, chart_area) = root_area.split_vertically(header_height);
+    // Every mark becomes a layer of the plot, with the volume bars and candles at their
+    // fixed layers. Series, histogram and band marks are lined up with the rows, Keltner
+    // Channels computed and position marks checked, before anything is laid out.
+    let mut layers = vec![(VOLUME_LAYER, Layer::Volume)];
+    if let Some(profile) = &data.volume_profile {
+        if !(1..=MAX_PROFILE_BINS).contains(&profile.bins) {
+            return Err(ChartError::invalid(format!(
+                "volume_profile bins must be 1 to {}, got {}",
+                MAX_PROFILE_BINS, profile.bins
+            )));
+        }
+        if !(profile.width_pct > 0.0 && profile.width_pct <= 1.0) {
+            return Err(ChartError::invalid(format!(
+                "volume_profile width_pct must be above 0 and at most 1, got {}",
+                profile.width_pct
+            )));
+        }
+        layers.push((VOLUME_LAYER, Layer::VolumeProfile(profile)));
+    }
+    layers.push((CANDLE_LAYER, Layer::Candles));
+    layers.extend(flagged.iter().map(|&time| {
+        let marker = CandleMarker {
+            time,
+            above: true,
+            color: OHLC_FLAG_COLOR,
+            size: 0.8,
+            text: Some("!"),
+        };
+        (OHLC_FLAG_LAYER, Layer::Marker(marker))
+    }));
+    for mark in &data.plots.marks {
+        let layer = match mark {
+            PlotMark::Marker(marker) => Layer::Marker(CandleMarker {
+                time: marker.time,
+                above: marker.position == "above",
+                color: parse_hex_color(&marker.color),
+                size: marker.size,
+                text: marker.text.as_deref(),
+            }),
+            PlotMark::Signal(signal) => {
+                let (above, default_color) = match signal.direction {
+                    SignalDirection::Buy => (false, theme.up),
+                    SignalDirection::Sell => (true, theme.down),
+                };
+                Layer::Marker(CandleMarker {
+                    time: signal.timestamp,
+                    above,
+                    color: signal.color.as_deref().map_or(default_color, parse_hex_color),
+                    size: signal.size,
+                    text: signal.text.as_deref(),
+                })
+            }
+            PlotMark::Zone(zone) => Layer::Zone(zone),
+            PlotMark::Ema(ema_mark) => Layer::Ema(ema_mark),
+            PlotMark::Vwap(vwap_mark) => Layer::Vwap(vwap_mark),
+            PlotMark::Series(series) => {
+                let what = format!("series \"{}\"", series.name);
+                Layer::Series(series, aligned_series(data, &what, &series.values)?)
+            }
+            PlotMark::Histogram(histogram) => {
+                let what = match &histogram.name {
+                    Some(name) => format!("histogram \"{}\"", name),
+                    None => "histogram".to_string(),
+                };
+                if histogram.pane != SeriesPane::Lower {
+                    return Err(ChartError::invalid(format!(
+                        "{} can only be drawn in the lower pane",
+                        what
+                    )));
+                }
+                Layer::Histogram(histogram, aligned_series(data, &what, &histogram.values)?)
+            }
+            PlotMark::Band(band) => {
+                let what = match &band.name {
+                    Some(name) => format!("band \"{}\"", name),
+                    None => "band".to_string(),
+                };
+                if !(0.0..=1.0).contains(&band.opacity) {
+                    return Err(ChartError::invalid(format!(
+                        "{} opacity {} is not between 0 and 1",
+                        what, band.opacity
+                    )));
+                }
+                let upper = aligned_series(data, &format!("{} upper", what), &band.upper)?;
+                let lower = aligned_series(data, &format!("{} lower", what), &band.lower)?;
+                Layer::Band(band, upper, lower)
+            }
+            PlotMark::Keltner(keltner_mark) => {
+                if keltner_mark.period == 0 {
+                    return Err(ChartError::invalid("keltner period must be at least 1"));
+                }
+                if !(keltner_mark.atr_mult.is_finite() && keltner_mark.atr_mult >= 0.0) {
+                    return Err(ChartError::invalid(format!(
+                        "keltner atr_mult {} is not a number of at least 0",
+                        keltner_mark.atr_mult
+                    )));
+                }
+                if let Some(opacity) = keltner_mark.fill_opacity
+                    && !(0.0..=1.0).contains(&opacity)
+                {
+                    return Err(ChartError::invalid(format!(
+                        "keltner fill_opacity {} is not between 0 and 1",
+                        opacity
+                    )));
+                }
+                let series = ohlcv_series(data);
+                let points = keltner(&series, keltner_mark.period, keltner_mark.atr_mult);
+                let line = |pick: fn((f64, f64, f64)) -> f64| -> Vec<(i64, Option<f64>)> {
+                    let timestamps = series.iter().map(|(ts, ..)| *ts);
+                    timestamps.zip(points.iter().map(|channel| channel.map(pick))).collect()
+                };
+                Layer::Keltner(
+                    keltner_mark,
+                    line(|(mid, _, _)| mid),
+                    line(|(_, upper, _)| upper),
+                    line(|(_, _, lower)| lower),
+                )
+            }
+            PlotMark::Position(position) => {
+                position.validate()?;
+                let shading_z = mark.z().saturating_sub(POSITION_SHADING_DEPTH);
+                layers.push((shading_z, Layer::PositionShading(position)));
+                Layer::Position(position)
+            }
+        };
+        layers.push((mark.z(), layer));
+    }
+    // Lowest first; the sort is stable, so a mark keeps its place among marks on the same
+    // layer and is drawn over a fixed layer it shares a z with
+    layers.sort_by_key(|(z, _)| *z);
+    let has_histograms = layers.iter().any(|(_, layer)| matches!(layer, Layer::Histogram(..)));
+    // The price pane takes the whole plot area unless a lower pane is stacked under it
+    let panes = if has_histograms
+        || layers.iter().any(|(_, layer)| {
+            matches!(layer, Layer::Series(series, _) if series.pane == SeriesPane::Lower)
+        })
+    {
+        vec![PaneSpec::PRICE_ABOVE_LOWER, PaneSpec::LOWER]
+    } else {
+        vec![PaneSpec::PRICE]
+    };
+    let pane_areas = split_panes(&chart_area, &panes);
+    let price_area = &pane_areas[0];
+    let (title_area, table_area) = header_area.split_vertically(title_height);
+
+    // Apply horizontal margin to the table area (inset from left and right)
+    let margin_percent = 0.15; // 15% margin on each side
+    let left_margin = (plot_width as f64 * margin_percent) as u32;
+    let right_margin = plot_width - (plot_width as f64 * margin_percent) as u32;
+    // Split into left margin, table area, and right margin
+    let (_left_area, rest) = table_area.split_horizontally(left_margin);
+    let (table_area, _right_area) = rest.split_horizontally(right_margin - left_margin);
+
+    let effective_chart_area_width = plot_width as f64 * 0.85;
+    let num_candles = processed_data.len();
+
+    let pixel_gap_between_candles = 1.0;
+    let candles_to_fit = num_candles as f64;
+
+    let total_gap_space = pixel_gap_between_candles * (candles_to_fit - 1.0);
+    let _available_width = effective_chart_area_width - total_gap_space;
+
+    // Tighter right edge in time
+    let last_candle_time = processed_data
+        .last()
+        .map(|(dt, _, _, _, _, _, _)| *dt)
+        .unwrap_or(end_dt);
+    let tight_end_dt = last_candle_time + chrono::Duration::seconds(1);
+    // Instead of calculating the exact milliseconds per pixel (which can cause overflow),
+    // we'll use a safer approach with relative positioning
+    let total_time_span_millis = tight_end_dt.timestamp_millis() - start_dt.timestamp_millis();
+
+    // Safety check to avoid potential overflow
+    if total_time_span_millis <= 0 || total_time_span_millis > i64::MAX / 2 {
+        warn!("Time span is too large or invalid, adjusting calculations");
+    }
+
+    let volume_visible_bottom = min_axis_for_chart;
+    let volume_visible_top =
+        min_axis_for_chart + (0.15 * (max_axis_for_chart - min_axis_for_chart));
+
+    let volume_to_axis = |vol: f64| -> f64 {
+        if max_volume <= 0.0 {
+            return volume_visible_bottom;
+        }
+        let normalized_vol = match data.volume_scale {
+            VolumeScale::Linear => vol / max_volume,
+            VolumeScale::Log => vol.max(0.0).ln_1p() / max_volume.ln_1p(),
+        };
+        volume_visible_bottom + (normalized_vol * (volume_visible_top - volume_visible_bottom))
+    };
+
+    // x coordinate of a time, with any squeezed gaps taken out
+    let millis_since_start = |dt: DateTime<Utc>| -> i64 {
+        gaps.to_x(dt.timestamp_millis() - start_dt.timestamp_millis())
+    };
+
+    // Calculate millisecond values for start and end points
+    let start_millis = 0; // 0 milliseconds since start
+    let end_millis = millis_since_start(end_dt);
+
+    // Calculate the duration of one candle in milliseconds. Fitted candles share the
+    // span evenly, so gaps in the data widen them; time-scaled candles keep to the interval.
+    // A lone candle has no span to share, so it takes the interval too.
+    let total_candles = processed_data.len() as f64;
+    let mut candle_duration_ms = match data.candle_layout {
+        CandleLayout::FitAll | CandleLayout::FixedWidth if end_millis > start_millis => {
+            (end_millis - start_millis) as f64 / total_candles
+        }
+        _ => interval_ms as f64,
+    };
+
+    // Add 3 candles worth of space to the end
+    let mut padded_end_millis = end_millis as f64 + (candle_duration_ms * RIGHT_PADDING_CANDLES);
+
+    trace!(
+        "  - Time range converted to milliseconds: {} to {} ms (with padding: {} ms)",
+        start_millis, end_millis, padded_end_millis
+    );
+
+    // Price ticks sit at round levels of what the axis reads, percent or price, and the
+    // labels, grid lines and percent axis all use exactly these
+    let tick_value_to_axis = |value: f64| match percent_base {
+        Some(_) => value,
+        None => scale.price_to_axis(value),
+    };
+    let tick_values = match (percent_base, price_scale) {
+        (Some(_), _) => nice_ticks(min_axis_for_chart, max_axis_for_chart, PRICE_TICKS),
+        (None, PriceScale::Log) => log_ticks(
+            scale.axis_to_price(min_axis_for_chart),
+            scale.axis_to_price(max_axis_for_chart),
+            PRICE_TICKS,
+        ),
+        (None, PriceScale::Linear) => nice_ticks(
+            scale.axis_to_price(min_axis_for_chart),
+            scale.axis_to_price(max_axis_for_chart),
+            PRICE_TICKS,
+        ),
+    };
+    let y_ticks: Vec<f64> = tick_values.iter().map(|value| tick_value_to_axis(*value)).collect();
+    let y_tick_labels = tick_labels(&tick_values, |value, decimals| match percent_base {
+        Some(_) => format!("{:+.*}%", decimals, value),
+        None => format_axis_price(value, decimals),
+    });
+    let y_label_style = TextStyle::from(("sans-serif", font_px(15.0))).color(&theme.text);
+
+    // Size the price axis to its widest label so long prices are not clipped; 80px
+    // stays the minimum
+    let y_label_area_size = data.y_label_area_size.map(px).unwrap_or_else(|| {
+        let widest = y_tick_labels
+            .iter()
+            .filter_map(|label| root_area.estimate_text_size(label, &y_label_style).ok())
+            .map(|(w, _)| w)
+            .max()
+            .unwrap_or(0);
+        (widest + px(25)).max(px(MIN_Y_LABEL_AREA_SIZE))
+    });
+    let chart_margin = px(data.chart_margin.unwrap_or(DEFAULT_CHART_MARGIN));
+
+    // The secondary percent axis re-labels the price ticks as the change from the first
+    // open; on a percent price axis it would only repeat the labels
+    let percent_axis_base = first_open
+        .filter(|base| data.percent_axis && percent_base.is_none() && *base != 0.0);
+    let percent_change =
+        |price: f64| percent_axis_base.map(|base| (price - base) / base.abs() * 100.0);
+    let percent_axis_decimals = percent_label_decimals(
+        percent_change(scale.axis_to_price(max_axis_for_chart)).unwrap_or(0.0)
+            - percent_change(scale.axis_to_price(min_axis_for_chart)).unwrap_or(0.0),
+    );
+    // Each tick gives the change to the rounded price it is labelled with on the right
+    let format_percent_label = |y: f64| {
+        percent_change(scale.axis_to_price(y))
+            .map(|pct| format!("{:+.*}%", percent_axis_decimals, pct))
+    };
+    // It sits in the left margin, which is widened to fit it in every pane
+    let percent_axis_size = [min_axis_for_chart, max_axis_for_chart]
+        .iter()
+        .filter_map(|y| format_percent_label(*y))
+        .filter_map(|label| root_area.estimate_text_size(&label, &y_label_style).ok())
+        .map(|(w, _)| w + px(15))
+        .max()
+        .unwrap_or(0);
+
+    // Fixed-width candles: one slot per candle interval, `candle_width_px` wide, and
+    // the x range spans as many slots as the plot has room for. With few candles the
+    // left of the plot stays empty. A lone candle otherwise sits in the middle, with as
+    // many empty slots left of it as right.
+    let mut x_range_start = if end_millis > start_millis {
+        start_millis as f64
+    } else {
+        start_millis as f64 - candle_duration_ms * RIGHT_PADDING_CANDLES
+    };
+    if data.candle_layout == CandleLayout::FixedWidth {
+        let candle_px = px(data.candle_width_px.unwrap_or(DEFAULT_CANDLE_WIDTH_PX)).max(1);
+        let plot_px = price_area
+            .dim_in_pixel()
+            .0
+            .saturating_sub(2 * chart_margin + y_label_area_size + percent_axis_size)
+            .max(candle_px);
+        candle_duration_ms = interval_ms as f64;
+        padded_end_millis = end_millis as f64 + candle_duration_ms * RIGHT_PADDING_CANDLES;
+        x_range_start =
+            padded_end_millis - (plot_px as f64 / candle_px as f64) * candle_duration_ms;
+
+        // Wider price labels than `fixed_width_capacity` assumed leave less room
+        let first_visible = x_range_start + candle_duration_ms / 2.0;
+        let before = processed_data.len();
+        processed_data.retain(|(dt, ..)| millis_since_start(*dt) as f64 >= first_visible);
+        window_start += before - processed_data.len();
+        if processed_data.is_empty() {
+            return Err(ChartError::Layout(format!(
+                "no candle fits a {}px wide plot at {}px per candle",
+                plot_px, candle_px
+            )));
+        }
+    }
+
+    let x_mapper = XMapper::new(start_dt, gaps, &processed_data, x_range_start..padded_end_millis);
+
+    // The label format suits the time shown, including any squeezed out
+    let time_span_millis = end_dt.timestamp_millis() - start_dt.timestamp_millis();
+    let (x_label_format, x_label_count) = x_axis_format(interval_ms, time_span_millis);
+    let x_label_style = TextStyle::from(("sans-serif", font_px(12.0))).color(&theme.text);
+    if ![0, 45, 90].contains(&data.x_label_rotation) {
+        return Err(ChartError::invalid(format!(
+            "x_label_rotation must be 0, 45 or 90, got {}",
+            data.x_label_rotation
+        )));
+    }
+    // The latest time is as long as any label; turned labels need its turned height
+    let x_label_angle = (data.x_label_rotation as f64).to_radians();
+    let (x_label_width, x_label_height) = root_area
+        .estimate_text_size(&timezone.format(end_dt, x_label_format), &x_label_style)
+        .map_or((0, 0), |(w, h)| (w as i32, h as i32));
+    let x_label_area_size = data.x_label_area_size.map(px).unwrap_or_else(|| {
+        if data.x_label_rotation == 0 {
+            px(40)
+        } else {
+            let extent = x_label_width as f64 * x_label_angle.sin()
+                + x_label_height as f64 * x_label_angle.cos();
+            extent.ceil() as u32 + px(15)
+        }
+    });
+    // Fewer candles than labels would just repeat the same timestamps, and no more
+    // labels are drawn than fit side by side without touching
+    let x_label_footprint = match data.x_label_rotation {
+        0 => x_label_width + pxi(8),
+        _ => ((x_label_height + pxi(4)) as f64 / x_label_angle.sin()).ceil() as i32,
+    };
+    let x_plot_px = price_area.dim_in_pixel().0 as i32
+        - (2 * chart_margin + y_label_area_size + percent_axis_size) as i32;
+    let x_label_count = x_label_count
+        .min(num_candles.max(2))
+        .min((x_plot_px / x_label_footprint.max(1)).max(2) as usize);
+
+    // Build the chart using milliseconds since start instead of DateTime objects or hours
+    let mut chart_context = ChartBuilder::on(price_area)
+        .margin(chart_margin)
+        .margin_bottom(px(data.chart_margin.map_or(20, |m| m * 2)))
+        .margin_left(chart_margin + percent_axis_size)
+        .set_label_area_size(LabelAreaPosition::Left, 0)
+        .set_label_area_size(LabelAreaPosition::Right, y_label_area_size)
+        .set_label_area_size(
+            LabelAreaPosition::Bottom,
+            if panes[0].shows_x_axis {
+                x_label_area_size
+            } else {
+                0
+            },
+        )
+        .build_cartesian_2d(x_mapper.x_range(), min_axis_for_chart..max_axis_for_chart)?;
+
+    // Clear the table area with white before we begin
+    table_area.fill(&theme.background)?;
+
+    // Create a formatter to convert milliseconds back to readable dates
+    let millis_to_datetime =
+        |millis: &f64| -> String { timezone.format(x_mapper.x_to_time(*millis), x_label_format) };
+    // Turned labels are drawn once the panes are built; the mesh then only draws their ticks
+    // A tick inside a squeezed gap is left unlabelled
+    let mesh_x_label = |millis: &f64| -> String {
+        if data.x_label_rotation == 0 && !x_mapper.in_gap(*millis) {
+            millis_to_datetime(millis)
+        } else {
+            String::new()
+        }
+    };
+
+    // Phase 5D: Pre-compute candle/body widths before draw loops
+    let slot_width = candle_duration_ms;
+    let candle_width = slot_width * 0.8;
+    let body_width = slot_width * data.body_width_pct.clamp(1.0, 100.0) / 100.0;
+
+    let y_desc = if percent_base.is_some() { "Change (%)" } else { "Price" };
+    chart_context
+        .configure_mesh()
+        .light_line_style(theme.grid)
+        .axis_style(theme.axis.stroke_width(line_px(1)))
+        .x_labels(x_label_count)
+        .x_label_formatter(&mesh_x_label)
+        // The price ticks are drawn below, at the round levels plotters does not pick
+        .y_labels(0)
+        .disable_mesh()
+        .set_tick_mark_size(LabelAreaPosition::Bottom, pxi(5))
+        .x_label_style(x_label_style.clone())
+        .axis_desc_style(TextStyle::from(("sans-serif", font_px(12.0))).color(&theme.text))
+        .y_desc(y_desc)
+        .draw()?;
+
+    // Pixel row of a point on the price axis within `root_area`, for elements laid out in
+    // whole pixels. The area is offset on the backend when it is one panel of several.
+    let (origin_x, origin_y) = root_area.get_base_pixel();
+    let coord_spec = chart_context.as_coord_spec().clone();
+    let axis_to_pixel_y = |axis: f64| coord_spec.translate(&(x_range_start, axis)).1 - origin_y;
+    // The plot's pixel columns and rows within `root_area`
+    let (plot_x, plot_y) = chart_context.plotting_area().get_pixel_range();
+    let plot_pixels = (
+        plot_x.start - origin_x..plot_x.end - origin_x,
+        plot_y.start - origin_y..plot_y.end - origin_y,
+    );
+    let price_to_pixel_y = |price: f64| axis_to_pixel_y(scale.price_to_axis(price));
+    // The axis is linear in pixels (log prices are mapped through ln first)
+    let axis_per_pixel = (max_axis_for_chart - min_axis_for_chart)
+        / (axis_to_pixel_y(min_axis_for_chart) - axis_to_pixel_y(max_axis_for_chart)).max(1)
+            as f64;
+
+    // Price axis: a tick and label at each price tick, right of the plot
+    {
+        let (plot_x, _) = plot_pixels.clone();
+        let label_style = y_label_style.clone().pos(Pos::new(HPos::Left, VPos::Center));
+        for (y, label) in y_ticks.iter().zip(&y_tick_labels) {
+            let tick_y = axis_to_pixel_y(*y);
+            root_area.draw(&PathElement::new(
+                vec![(plot_x.end, tick_y), (plot_x.end + pxi(5), tick_y)],
+                theme.axis.stroke_width(line_px(1)),
+            ))?;
+            root_area.draw(&Text::new(
+                label.as_str(),
+                (plot_x.end + pxi(10), tick_y),
+                &label_style,
+            ))?;
+        }
+    }
+
+    // Percent axis: the price ticks, re-labelled left of the plot
+    if percent_axis_base.is_some() {
+        let (plot_x, plot_y) = plot_pixels.clone();
+        let axis_color = theme.axis;
+        root_area.draw(&PathElement::new(
+            vec![(plot_x.start, plot_y.start), (plot_x.start, plot_y.end)],
+            axis_color.stroke_width(line_px(1)),
+        ))?;
+        let label_style = y_label_style.clone().pos(Pos::new(HPos::Right, VPos::Center));
+        for &y in &y_ticks {
+            let Some(label) = format_percent_label(y) else {
+                continue;
+            };
+            let tick_y = axis_to_pixel_y(y);
+            root_area.draw(&PathElement::new(
+                vec![(plot_x.start - pxi(5), tick_y), (plot_x.start, tick_y)],
+                axis_color.stroke_width(line_px(1)),
+            ))?;
+            root_area.draw(&Text::new(label, (plot_x.start - pxi(8), tick_y), &label_style))?;
+        }
+    }
+
+    // --- Lower pane: series on their own linear scale, sharing the time axis ---
+    let visible_x = x_mapper.x_range();
+    let mut lower_context = match pane_areas.get(1) {
+        Some(lower_area) => {
+            let (low, high) = layers
+                .iter()
+                .filter_map(|(_, layer)| match layer {
+                    Layer::Series(series, values) if series.pane == SeriesPane::Lower => {
+                        Some(values)
+                    }
+                    Layer::Histogram(_, values) => Some(values),
+                    _ => None,
+                })
+                .flatten()
+                .filter(|(ts, _)| visible_x.contains(&x_mapper.millis_to_x(*ts)))
+                .filter_map(|(_, value)| *value)
+                .fold((f64::MAX, f64::MIN), |(low, high), v| (low.min(v), high.max(v)));
+            let (low, high) = if low > high {
+                (0.0, 1.0)
+            } else if has_histograms {
+                // Centered on zero so bars either side of it compare at a glance
+                let reach = low.abs().max(high.abs());
+                if reach > 0.0 { (-reach, reach) } else { (-1.0, 1.0) }
+            } else {
+                flat_range_guard(low, high)
+            };
+            let padding = (high - low) * 0.05;
+            let mut lower = ChartBuilder::on(lower_area)
+                .margin(chart_margin)
+                .margin_left(chart_margin + percent_axis_size)
+                .set_label_area_size(LabelAreaPosition::Left, 0)
+                .set_label_area_size(LabelAreaPosition::Right, y_label_area_size)
+                .set_label_area_size(LabelAreaPosition::Bottom, x_label_area_size)
+                .build_cartesian_2d(x_mapper.x_range(), (low - padding)..(high + padding))?;
+            let format_value = |v: &f64| -> String {
+                if v.abs() >= 1000.0 {
+                    format!("{:.0}", v)
+                } else {
+                    format!("{:.2}", v)
+                }
+            };
+            lower
+                .configure_mesh()
+                .axis_style(theme.axis.stroke_width(line_px(1)))
+                .x_labels(x_label_count)
+                .x_label_formatter(&mesh_x_label)
+                .y_labels(4)
+                .disable_mesh()
+                .set_tick_mark_size(LabelAreaPosition::Right, pxi(5))
+                .set_tick_mark_size(LabelAreaPosition::Bottom, pxi(5))
+                .x_label_style(TextStyle::from(("sans-serif", font_px(12.0))))
+                .y_label_style(y_label_style)
+                .y_label_formatter(&format_value)
+                .draw()?;
+            Some(lower)
+        }
+        None => None,
+    };
+
+    // Turned time labels, ending at the ticks under whichever pane shows the time axis
+    if data.x_label_rotation != 0 {
+        let axis_context = lower_context.as_ref().unwrap_or(&chart_context);
+        let (_, plot_y) = axis_context.plotting_area().get_pixel_range();
+        let label_top = plot_y.end - origin_y + pxi(8);
+        // How far left of its tick a label reaches; one that would be cut off by the
+        // edge of the canvas is left out
+        let reach = x_label_width as f64 * x_label_angle.cos()
+            + x_label_height as f64 * x_label_angle.sin() / 2.0;
+        for x in axis_context.as_coord_spec().x_spec().key_points(x_label_count) {
+            let tick_x = axis_context.backend_coord(&(x, 0.0)).0 - origin_x;
+            if (tick_x as f64) < reach || x_mapper.in_gap(x) {
+                continue;
+            }
+            draw_rotated_text(
+                root_area,
+                &millis_to_datetime(&x),
+                &x_label_style,
+                (tick_x, label_top),
+                data.x_label_rotation as f64,
+            )?;
+        }
+    }
+
+    // A squeezed gap breaks the time axis under whichever pane shows it: the axis line
+    // is cut and two slanted strokes mark where time was taken out
+    {
+        let axis_context = lower_context.as_ref().unwrap_or(&chart_context);
+        let (_, plot_y) = axis_context.plotting_area().get_pixel_range();
+        let axis_y = plot_y.end - origin_y;
+        let (half, rise) = (pxi(3), pxi(5));
+        for squeezed in x_mapper.gaps.squeezed() {
+            let middle = (squeezed.start + squeezed.end) / 2.0;
+            if !visible_x.contains(&middle) {
+                continue;
+            }
+            let x = axis_context.backend_coord(&(middle, 0.0)).0 - origin_x;
+            root_area.draw(&Rectangle::new(
+                [(x - half, axis_y - pxi(1)), (x + half, axis_y + pxi(1))],
+                theme.background.filled(),
+            ))?;
+            for edge in [x - half, x + half] {
+                root_area.draw(&PathElement::new(
+                    vec![(edge - pxi(2), axis_y + rise), (edge + pxi(2), axis_y - rise)],
+                    theme.axis.stroke_width(line_px(1)),
+                ))?;
+            }
+        }
+    }
+
+    // Grid lines sit on the price ticks, with a lighter line halfway between neighbouring
+    // ones and a step's half beyond the outermost. Halfway is taken in what the ticks
+    // read, so between $60,000 and $62,500 it is at $61,250.
+    let midpoints = tick_values.windows(2).map(|pair| (pair[0] + pair[1]) / 2.0);
+    let outer = match tick_values.as_slice() {
+        [first, second, ..] => {
+            let [.., before_last, last] = tick_values[..] else {
+                unreachable!("at least two ticks")
+            };
+            vec![first - (second - first) / 2.0, last + (last - before_last) / 2.0]
+        }
+        _ => Vec::new(),
+    };
+    let minor_ticks = midpoints
+        .chain(outer)
+        .map(&tick_value_to_axis)
+        .filter(|y| (min_axis_for_chart..=max_axis_for_chart).contains(y));
+    let grid_lines = minor_ticks
+        .map(|y| (y, theme.grid_minor()))
+        .chain(y_ticks.iter().map(|y| (*y, theme.grid)));
+    chart_context.draw_series(grid_lines.map(|(y, color)| {
+        PathElement::new(
+            vec![(x_range_start, y), (end_millis as f64, y)],
+            color.stroke_width(line_px(1)),
+        )
+    }))?;
+    let vertical_grid_style = theme.grid_vertical().stroke_width(line_px(1));
+    let x_ticks = coord_spec.x_spec().key_points(x_label_count);
+    chart_context.draw_series(x_ticks.into_iter().filter(|x| !x_mapper.in_gap(*x)).map(|x| {
+        PathElement::new(
+            vec![(x, min_axis_for_chart), (x, max_axis_for_chart)],
+            vertical_grid_style,
+        )
+    }))?;
+
+    deadline.check("grid")?;
+
+    // --- Draw zones (semi-transparent rectangles behind everything) ---
+    for zone in &data.plots.zones {
+        let x1 = x_mapper.millis_to_x(zone.x1);
+        let x2 = x_mapper.millis_to_x(zone.x2);
+
+        let y1 = scale.price_to_axis(zone.y1);
+        let y2 = scale.price_to_axis(zone.y2);
+
+        let color = parse_hex_color_with_alpha(&zone.color);
+
+        // Phase 4A: Log zone draw failures
+        if let Err(e) = chart_context.draw_series(std::iter::once(Rectangle::new(
+            [(x1, y1), (x2, y2)],
+            color.filled(),
+        ))) {
+            warn!("Failed to draw zone: {}", e);
+        }
+    }
+
+    // --- Draw vertical lines (e.g., alert fire timestamps) ---
+    for vline in &data.plots.vlines {
+        // Plotters pins lines left of the x range to the left edge; with `limit` those are common
+        let x = x_mapper.millis_to_x(vline.time);
+        if x < x_range_start {
+            continue;
+        }
+        let color = parse_hex_color_with_alpha(&vline.color);
+        // Phase 4A: Log vline draw failures
+        if let Err(e) = chart_context.draw_series(std::iter::once(PathElement::new(
+            vec![(x, min_axis_for_chart), (x, max_axis_for_chart)],
+            color.stroke_width(line_px(2)),
+        ))) {
+            warn!("Failed to draw vline: {}", e);
+        }
+    }
+
+    // Day separators sit halfway between the last candle of a day and the first of the
+    // next, with the new day's date at the top when asked for
+    if data.day_separators {
+        let mut times: Vec<DateTime<Utc>> = processed_data.iter().map(|(dt, ..)| *dt).collect();
+        times.sort_unstable();
+        let label_style = TextStyle::from(("sans-serif", font_px(11.0)))
+            .color(&RGBColor(140, 140, 140))
+            .pos(Pos::new(HPos::Left, VPos::Top));
+        for pair in times.windows(2) {
+            let (before, after) = (pair[0], pair[1]);
+            if timezone.date(before) == timezone.date(after) {
+                continue;
+            }
+            let x = (x_mapper.time_to_x(before) + x_mapper.time_to_x(after)) / 2.0;
+            chart_context.draw_series(std::iter::once(PathElement::new(
+                vec![(x, min_axis_for_chart), (x, max_axis_for_chart)],
+                RGBColor(200, 200, 200).stroke_width(line_px(1)),
+            )))?;
+            if data.day_separator_labels {
+                let label = timezone.format(after, "%b %d");
+                chart_context.draw_series(std::iter::once(
+                    EmptyElement::at((x, max_axis_for_chart))
+                        + Text::new(label, (pxi(3), pxi(3)), label_style.clone()),
+                ))?;
+            }
+        }
+    }
+
+    deadline.check("zones and vertical lines")?;
+
+    // --- Volume bars, laid out while the candles are still in row order ---
+    // A feed without volume would get a row of zero-height bars along the bottom edge
+    let has_volume = data.show_volume && data.has_volume_column() && max_volume > 0.0;
+    if !data.show_volume {
+        debug!("[DATA] Volume bars turned off");
+    } else if !has_volume {
+        debug!("[DATA] No volume in the series, skipping volume bars");
+    }
+    // The single override is the same for every bar, so parse it once
+    let volume_override_color = data
+        .volume_color
+        .as_deref()
+        .map(parse_hex_color)
+        .unwrap_or(theme.volume);
+    let mut volume_bars: Vec<_> = processed_data
+        .iter()
+        .filter(|_| has_volume)
+        .enumerate()
+        .map(|(idx, (dt, o, _h, _l, c, v, _color))| {
+            let dt_hours = x_mapper.time_to_x(*dt);
+            // Phase 5D: Use pre-computed candle_width
+            let x0 = dt_hours - (candle_width / 2.0);
+            let x1 = dt_hours + (candle_width / 2.0);
+
+            let y_bottom = volume_visible_bottom;
+            let y_top = volume_to_axis(*v);
+
+            // Per-bar colors win, then direction coloring, then the single override
+            let volume_color = data
+                .volume_colors
+                .as_ref()
+                .filter(|_| !aggregated)
+                .and_then(|colors| colors.get(window_start + idx))
+                .map(|color| parse_hex_color(color))
+                .unwrap_or_else(|| {
+                    if data.volume_color_by_direction {
+                        if c >= o { theme.volume_up } else { theme.volume_down }
+                    } else {
+                        volume_override_color
+                    }
+                });
+
+            Rectangle::new(
+                [(x0, y_bottom), (x1, y_top)],
+                volume_color.mix(0.8).filled(),
+            )
+        })
+        .collect();
+
+    // Sort data by timestamp to ensure correct order for candle drawing
+    processed_data.sort_by_key(|a| a.0);
+
+    // --- Draw the dotted line for current price on the last candle ---
+    let last_candle = processed_data.last().cloned();
+    // Phase 3D: Use 1e-12 instead of 0.0 fallback for current_price to protect ln()
+    let current_price = data.current_price.unwrap_or_else(|| {
+        last_candle
+            .as_ref()
+            .map(|(_, _, _, _, c, _, _)| *c)
+            .unwrap_or(1e-12)
+    });
+    let current_price_y = scale.price_to_axis(current_price);
+
+    // Where the plot and candles landed, for `emit_coords`
+    let coords = data.emit_coords.then(|| {
+        let (width, height) = root_area.dim_in_pixel();
+        let (plot_x, plot_y) = chart_context.plotting_area().get_pixel_range();
+        let pixel =
+            |x: f64, price: f64| chart_context.backend_coord(&(x, scale.price_to_axis(price)));
+        let candles = processed_data
+            .iter()
+            .enumerate()
+            .map(|(index, (dt, o, h, l, c, v, _color))| {
+                let x = x_mapper.time_to_x(*dt);
+                CandleCoords {
+                    index,
+                    timestamp: dt.timestamp_millis(),
+                    open: *o,
+                    high: *h,
+                    low: *l,
+                    close: *c,
+                    volume: *v,
+                    x_left: pixel(x - body_width / 2.0, *o).0.max(plot_x.start),
+                    x_right: pixel(x + body_width / 2.0, *o).0.min(plot_x.end),
+                    y_open: pixel(x, *o).1,
+                    y_high: pixel(x, *h).1,
+                    y_low: pixel(x, *l).1,
+                    y_close: pixel(x, *c).1,
+                }
+            })
+            .collect();
+        ChartCoords {
+            schema_version: COORDS_SCHEMA_VERSION,
+            width,
+            height,
+            plot: PixelBox {
+                left: plot_x.start,
+                top: plot_y.start,
+                right: plot_x.end,
+                bottom: plot_y.end,
+            },
+            candles,
+            current_price: last_candle.is_some().then(|| PriceRow {
+                price: current_price,
+                y: pixel(x_range_start, current_price).1,
+            }),
+        }
+    });
+
+    // The direction is taken from the last candle's open, or the close before it with
+    // `color_mode: "prev_close"`, to the current price
+    let prev_close = match processed_data.len() {
+        len if len >= 2 && data.color_mode == ColorMode::PrevClose => {
+            Some(processed_data[len - 2].4)
+        }
+        _ => None,
+    };
+    let (_is_green, last_candle_color) = if let Some((_, o, ..)) = last_candle {
+        let is_up = current_price >= prev_close.unwrap_or(o);
+        if is_up {
+            (true, theme.price_up)
+        } else {
+            (false, theme.price_down)
+        }
+    } else {
+        (true, theme.price_up)
+    };
+
+    // --- Title, in its own area at the very top of the canvas ---
+    title_area.fill(&theme.background)?;
+    let plot_right = plot_width as i32 - chart_margin as i32 - y_label_area_size as i32;
+    for (i, line) in title_lines.iter().enumerate() {
+        let drawn_width = text_width(line, &title_style);
+        let block_width = if i == 0 { drawn_width + extras_width } else { drawn_width };
+        let x = match data.title_align {
+            TitleAlign::Left => chart_margin as i32,
+            // Centered on the canvas, nudged right by half the price axis
+            TitleAlign::Center => {
+                let approx_width = line.chars().count() as i32 * pxi(15);
+                (plot_width as i32 / 2) - (approx_width / 2) + (y_label_area_size as i32 / 2)
+            }
+            TitleAlign::Right => plot_right - block_width,
+        };
+        // Whatever the alignment, a line that fits the canvas is drawn whole
+        let x = x.clamp(
+            chart_margin as i32,
+            (plot_width as i32 - chart_margin as i32 - block_width).max(chart_margin as i32),
+        );
+        let top = pxi(20) + i as i32 * title_line_height;
+        title_area.draw_text(line, &title_style, (x, top))?;
+        if i > 0 {
+            continue;
+        }
+        let mut extras_x = x + drawn_width + pxi(10);
+        for (text, color) in &title_extras {
+            title_area.draw_text(text, &badge_style.color(color), (extras_x, top + pxi(5)))?;
+            extras_x += text_width(text, &badge_style) + pxi(12);
+        }
+    }
+
+    // Now draw the table in table_area instead of showing the price on the chart
+    // Find the highest price in the visible plot
+    let highest_price = processed_data
+        .iter()
+        .map(|(_, _, h, _, _, _, _)| *h)
+        .fold(f64::NEG_INFINITY, f64::max);
+
+    // Calculate percentage from high
+    let percent_from_high = if highest_price > 0.0 {
+        ((highest_price - current_price) / highest_price) * 100.0
+    } else {
+        0.0
+    };
+
+    // Table setup
+    let rows = ["Current Price", "High (in plot)", "% from High"];
+    // On a percent axis the prices are given as the change they are drawn at
+    let format_table_price = |price: f64| match percent_base {
+        Some(_) => format!("{:+.2}%", scale.price_to_axis(price)),
+        None => format!("${}", format_price(price, price_span)),
+    };
+    // With a percent axis the current price also gives its change from the first open
+    let current_price_cell = match percent_change(current_price) {
+        Some(pct) => format!("{} ({:+.2}%)", format_table_price(current_price), pct),
+        None => format_table_price(current_price),
+    };
+    let table_data = [
+        current_price_cell,
+        format_table_price(highest_price),
+        format!("{:.2}%", percent_from_high),
+    ];
+
+    // Compute cell sizes
+    let cell_h = table_height as f64 / (rows.len() + 1) as f64;
+
+    // First, fill the table area with white for a clean background
+    table_area.fill(&theme.background)?;
+
+    // Setup for cell drawing
+    let cell_padding = pxi(5);
+    let section_width = table_area.get_pixel_range().0.end
+        - table_area.get_pixel_range().0.start;
+    let mid_point = section_width / 2;
+
+    // Calculate optimal row height with spacing
+    let row_spacing = (cell_h * 0.15) as i32;
+    let effective_row_height = cell_h as i32 - row_spacing;
+
+    // Cell background color - medium grey for good visibility
+    let cell_bg_color = RGBColor(220, 220, 220);
+
+    // Additional padding for bottom of cells
+    let bottom_padding = pxi(6);
+
+    // Both columns share one font; only its color changes per row
+    let table_font = ("sans-serif", font_px(14.0)).into_font();
+
+    for (ri, row_label) in rows.iter().enumerate() {
+        // Calculate position with proper spacing between rows
+        let row_pos =
+            (ri as i32 * (effective_row_height + row_spacing + bottom_padding)) + cell_padding;
+        let row_height = effective_row_height;
+        let row_center = row_pos + (row_height / 2);
+
+        // Text color based on data
+        let text_color = if ri == 0 {
+            &last_candle_color
+        } else {
+            &theme.text
+        };
+        let text_style = table_font.color(text_color);
+
+        // Draw left column cell with padding on all sides
+        table_area.draw(&Rectangle::new(
+            [
+                (cell_padding, row_pos),
+                (mid_point - cell_padding, row_pos + row_height),
+            ],
+            cell_bg_color.filled(),
+        ))?;
+
+        // Draw right column cell with padding on all sides
+        table_area.draw(&Rectangle::new(
+            [
+                (mid_point + cell_padding, row_pos),
+                (section_width - cell_padding, row_pos + row_height),
+            ],
+            cell_bg_color.filled(),
+        ))?;
+
+        let text_y_adjustment = pxi(4);
+        let text_y_pos = row_center - text_y_adjustment;
+
+        // Left column text (label) with proper vertical alignment
+        table_area.draw(&Text::new(
+            row_label.to_string(),
+            (cell_padding * 4, text_y_pos),
+            text_style.clone(),
+        ))?;
+
+        // Right column text (value) with proper vertical alignment
+        table_area.draw(&Text::new(
+            table_data[ri].clone(),
+            (mid_point + cell_padding * 4, text_y_pos),
+            text_style,
+        ))?;
+    }
+
+    deadline.check("price table")?;
+
+    // --- Zoom inset: the newest candles it shows ---
+    let zoom = match data.zoom_inset {
+        Some(inset) => {
+            if inset.candles == 0 {
+                return Err(ChartError::invalid("zoom_inset candles must be at least 1"));
+            }
+            for (name, pct) in [("width_pct", inset.width_pct), ("height_pct", inset.height_pct)] {
+                if !(pct > 0.0 && pct <= 1.0) {
+                    return Err(ChartError::invalid(format!(
+                        "zoom_inset {} must be above 0 and at most 1, got {}",
+                        name, pct
+                    )));
+                }
+            }
+            let first = processed_data.len().saturating_sub(inset.candles);
+            if first == 0 {
+                debug!("Zoom inset would show every candle, skipping it");
+            }
+            (first > 0).then_some((inset, first))
+        }
+        None => None,
+    };
+    // Points outside the x range would be pinned to the plot edge, so only visible ones are drawn
+    let to_points = |values: &[(i64, Option<f64>)], to_axis: &dyn Fn(f64) -> f64| {
+        values
+            .iter()
+            .map(|(ts, value)| {
+                let x = x_mapper.millis_to_x(*ts);
+                let y = to_axis((*value)?);
+                (visible_x.contains(&x) && y.is_finite()).then_some((x, y))
+            })
+            .collect::<Vec<_>>()
+    };
+    // Each run of candles with both edges of a band is one polygon, out along the upper
+    // edge and back along the lower one; where the edges cross it still fills the area
+    // between them
+    let band_fill = |upper: &[(i64, Option<f64>)], lower: &[(i64, Option<f64>)], color: RGBAColor| {
+        let edges = upper
+            .iter()
+            .zip(lower)
+            .map(|((ts, up), (_, low))| {
+                let x = x_mapper.millis_to_x(*ts);
+                let up = scale.price_to_axis((*up)?);
+                let low = scale.price_to_axis((*low)?);
+                (visible_x.contains(&x) && up.is_finite() && low.is_finite())
+                    .then_some(((x, up), (x, low)))
+            })
+            .collect::<Vec<_>>();
+        edges
+            .split(Option::is_none)
+            // A single candle has no width to fill
+            .filter(|run| run.len() >= 2)
+            .map(|run| {
+                let outline: Vec<(f64, f64)> = run
+                    .iter()
+                    .flatten()
+                    .map(|(up, _)| *up)
+                    .chain(run.iter().rev().flatten().map(|(_, low)| *low))
+                    .collect();
+                Polygon::new(outline, color.filled())
+            })
+            .collect::<Vec<_>>()
+    };
+    let first_drawn_millis = processed_data
+        .first()
+        .map_or(i64::MIN, |(dt, ..)| dt.timestamp_millis());
+    // Positions are drawn from the candle they were opened on to the right edge
+    let position_x = |position: &PositionMark| {
+        let opened = x_mapper.millis_to_x(position.opened_ts) as i64;
+        x_mapper
+            .nearest_candle_x(opened)
+            .map(|x| (x as f64).max(x_range_start))
+    };
+    let visible_y = min_axis_for_chart..=max_axis_for_chart;
+    let candle_duration_millis = if processed_data.len() > 1
+        || data.candle_layout != CandleLayout::FitAll
+    {
+        candle_duration_ms
+    } else {
+        60000.0 // Default 1 minute if only one candle
+    };
+    let marker_candle_width = candle_duration_millis * 0.8;
+
+    // Histogram bars sit on a zero line, under everything else in the lower pane
+    if let Some(lower) = lower_context.as_mut()
+        && has_histograms
+    {
+        lower.draw_series(std::iter::once(PathElement::new(
+            vec![(visible_x.start, 0.0), (visible_x.end, 0.0)],
+            theme.axis.stroke_width(line_px(1)),
+        )))?;
+    }
+
+    // --- Volume, candles and marks, from the lowest layer up ---
+    for (_, layer) in layers {
+        match layer {
+            Layer::Volume => {
+                chart_context.draw_series(std::mem::take(&mut volume_bars))?;
+                // The band's top edge, labelled just under it with the volume it stands for
+                if data.volume_separator && has_volume {
+                    let label_color = theme.text.mix(0.6);
+                    let label_style = TextStyle::from(("sans-serif", font_px(10.0)))
+                        .color(&label_color)
+                        .pos(Pos::new(HPos::Left, VPos::Top));
+                    chart_context.draw_series(std::iter::once(
+                        EmptyElement::at((visible_x.start, volume_visible_top))
+                            + PathElement::new(
+                                vec![(0, 0), (plot_pixels.0.end - plot_pixels.0.start, 0)],
+                                theme.axis.mix(0.5).stroke_width(line_px(1)),
+                            )
+                            + Text::new(
+                                format!("Vol {}", format_volume(max_volume)),
+                                (pxi(4), pxi(3)),
+                                label_style,
+                            ),
+                    ))?;
+                }
+                deadline.check("volume")?;
+            }
+            Layer::VolumeProfile(profile) => {
+                if !(data.has_volume_column() && max_volume > 0.0) {
+                    debug!("[DATA] No volume in the series, skipping the volume profile");
+                    continue;
+                }
+                let ranges: Vec<_> = processed_data
+                    .iter()
+                    .filter(|(dt, ..)| visible_x.contains(&x_mapper.time_to_x(*dt)))
+                    .map(|(_, _, h, l, _, v, _)| {
+                        (scale.price_to_axis(*l), scale.price_to_axis(*h), *v)
+                    })
+                    .collect();
+                let bins = volume_profile(
+                    &ranges,
+                    min_axis_for_chart,
+                    max_axis_for_chart,
+                    profile.bins,
+                );
+                let most = bins.iter().copied().fold(0.0, f64::max);
+                if most <= 0.0 {
+                    continue;
+                }
+                let color = profile.color.as_deref().map_or(theme.volume, parse_hex_color);
+                let poc_color = profile.poc_color.as_deref().map_or(color, parse_hex_color);
+                let poc = bins.iter().position(|total| *total == most);
+                let bin_height = (max_axis_for_chart - min_axis_for_chart) / bins.len() as f64;
+                // A pixel between neighbouring bars, when they are tall enough to spare it
+                let gap = if bin_height > axis_per_pixel * 3.0 { axis_per_pixel } else { 0.0 };
+                let longest = (visible_x.end - visible_x.start) * profile.width_pct;
+                let bars = bins.iter().enumerate().filter(|(_, total)| **total > 0.0).map(
+                    |(bin, total)| {
+                        let bottom = min_axis_for_chart + bin as f64 * bin_height;
+                        let style = if profile.highlight_poc && Some(bin) == poc {
+                            poc_color.mix(0.8).filled()
+                        } else {
+                            color.mix(0.35).filled()
+                        };
+                        Rectangle::new(
+                            [
+                                (visible_x.end - longest * total / most, bottom + gap),
+                                (visible_x.end, bottom + bin_height),
+                            ],
+                            style,
+                        )
+                    },
+                );
+                chart_context.draw_series(bars)?;
+                deadline.check("volume profile")?;
+            }
+            Layer::Candles => {
+                if data.show_current_price {
+                    // Draw a single horizontal line at the current price level for reference
+                    chart_context.draw_series(std::iter::once(PathElement::new(
+                        vec![
+                            (x_range_start, current_price_y),
+                            (end_millis as f64, current_price_y),
+                        ],
+                        theme.price_line.stroke_width(line_px(1)),
+                    )))?;
+
+                    // Add a horizontal line at the current price level using the same color
+                    // as the last candle
+                    chart_context.draw_series(std::iter::once(PathElement::new(
+                        vec![
+                            (x_range_start, current_price_y),
+                            (end_millis as f64, current_price_y),
+                        ],
+                        last_candle_color.stroke_width(line_px(1)),
+                    )))?;
+                }
+
+                // The span the zoom inset shows is shaded behind the candles
+                if let Some((_, first)) = zoom
+                    && let (Some((first_dt, ..)), Some((last_dt, ..))) =
+                        (processed_data.get(first), processed_data.last())
+                {
+                    chart_context.draw_series(std::iter::once(Rectangle::new(
+                        [
+                            (x_mapper.time_to_x(*first_dt) - slot_width / 2.0, max_axis_for_chart),
+                            (x_mapper.time_to_x(*last_dt) + slot_width / 2.0, min_axis_for_chart),
+                        ],
+                        theme.axis.mix(0.12).filled(),
+                    )))?;
+                }
+
+                // --- Draw the candlestick bodies (no wicks) with consistent spacing ---
+
+                // First draw the wicks so they appear behind the candle bodies.
+                // A stroked line stays centered on the candle at any pixel width.
+                let wick_width = line_px(data.wick_width.max(1));
+                chart_context.draw_series(
+                    processed_data
+                        .iter()
+                        .map(|(dt, o, h, l, c, _v, _color)| {
+                            let dt_millis = x_mapper.time_to_x(*dt);
+                            let high_y = scale.price_to_axis(*h);
+                            let low_y = scale.price_to_axis(*l);
+                            let wick_color = if c >= o { theme.wick_up } else { theme.wick_down };
+                            PathElement::new(
+                                vec![(dt_millis, high_y), (dt_millis, low_y)],
+                                wick_color.stroke_width(wick_width),
+                            )
+                        }),
+                )?;
+
+                // Next draw the candle bodies on top of the wicks, outlined if requested
+                let fixed_border_color =
+                    data.candle_border_color.as_deref().map(parse_hex_color).or(theme.border);
+                let draw_borders = data.candle_border || fixed_border_color.is_some();
+                chart_context.draw_series(
+                    processed_data
+                        .iter()
+                        .filter(|(_dt, o, _h, _l, c, ..)| !data.candle_fill.is_hollow(*o, *c))
+                        .flat_map(|(dt, o, _h, _l, c, _v, candle_color)| {
+                            let open_y = scale.price_to_axis(*o);
+                            let close_y = scale.price_to_axis(*c);
+
+                            let (body_top, body_bottom) = if open_y <= close_y {
+                                (close_y, open_y)
+                            } else {
+                                (open_y, close_y)
+                            };
+                            // A body thinner than a pixel would vanish, so it grows to one pixel
+                            // around its middle
+                            let (body_top, body_bottom) =
+                                if (price_to_pixel_y(*o) - price_to_pixel_y(*c)).abs() < 1 {
+                                    let middle = (body_top + body_bottom) / 2.0;
+                                    (middle + axis_per_pixel / 2.0, middle - axis_per_pixel / 2.0)
+                                } else {
+                                    (body_top, body_bottom)
+                                };
+
+                            // Phase 5D: Use pre-computed body_width
+                            let dt_millis = x_mapper.time_to_x(*dt);
+                            let body_left = dt_millis - (body_width / 2.0);
+                            let body_right = dt_millis + (body_width / 2.0);
+
+                            let corners = [(body_left, body_top), (body_right, body_bottom)];
+                            // A faded body gets an equally faded outline
+                            let border = draw_borders.then(|| {
+                                let RGBAColor(r, g, b, alpha) = *candle_color;
+                                let border_color = fixed_border_color.unwrap_or(RGBColor(
+                                    (r as f64 * 0.6) as u8,
+                                    (g as f64 * 0.6) as u8,
+                                    (b as f64 * 0.6) as u8,
+                                ));
+                                Rectangle::new(
+                                    corners,
+                                    border_color.mix(alpha).stroke_width(line_px(1)),
+                                )
+                            });
+                            std::iter::once(Rectangle::new(corners, candle_color.filled()))
+                                .chain(border)
+                        }),
+                )?;
+
+                // Hollow bodies are laid out in pixels: an outline in the candle's color (or the
+                // fixed outline color) around a background-colored inside that hides the wick. The
+                // outline widens with the body so narrow candles are not all outline.
+                let mut hollow_outlines = Vec::new();
+                let mut hollow_insides = Vec::new();
+                for (dt, o, _h, _l, c, _v, candle_color) in &processed_data {
+                    if !data.candle_fill.is_hollow(*o, *c) {
+                        continue;
+                    }
+                    let x = x_mapper.time_to_x(*dt);
+                    let top_left = (x - body_width / 2.0, scale.price_to_axis(o.max(*c)));
+                    let (left, _) = chart_context.backend_coord(&top_left);
+                    let (right, _) =
+                        chart_context.backend_coord(&(x + body_width / 2.0, top_left.1));
+                    let top = price_to_pixel_y(o.max(*c));
+                    let height = (price_to_pixel_y(o.min(*c)) - top).max(1);
+                    let width = right - left;
+                    let outline = (width / 8).clamp(1, pxi(2).max(1));
+                    let outline_color = fixed_border_color.map_or(*candle_color, |border| {
+                        border.mix(candle_color.3)
+                    });
+                    hollow_outlines.push(
+                        EmptyElement::at(top_left)
+                            + Rectangle::new([(0, 0), (width, height)], outline_color.filled()),
+                    );
+                    if width > 2 * outline && height > 2 * outline {
+                        hollow_insides.push(
+                            EmptyElement::at(top_left)
+                                + Rectangle::new(
+                                    [(outline, outline), (width - outline, height - outline)],
+                                    theme.background.filled(),
+                                ),
+                        );
+                    }
+                }
+                chart_context.draw_series(hollow_outlines)?;
+                chart_context.draw_series(hollow_insides)?;
+
+                // A doji's body is empty, so mark its open/close level with a 1-pixel line. A fixed
+                // outline color is used when there is one, so a white body still shows.
+                chart_context.draw_series(
+                    processed_data
+                        .iter()
+                        .filter(|(_dt, o, _h, _l, c, ..)| {
+                            (o - c).abs() <= DOJI_EPSILON * o.abs().max(1.0)
+                        })
+                        .map(|(dt, _o, _h, _l, c, _v, candle_color)| {
+                            let x = x_mapper.time_to_x(*dt);
+                            let y = scale.price_to_axis(*c);
+                            let line_color = fixed_border_color
+                                .map_or(*candle_color, |border| border.mix(candle_color.3));
+                            PathElement::new(
+                                vec![(x - body_width / 2.0, y), (x + body_width / 2.0, y)],
+                                line_color.stroke_width(line_px(1)),
+                            )
+                        }),
+                )?;
+
+                // Emphasize the latest close so it stands out when scanning many charts
+                if let Some((dt, ..)) = &last_candle {
+                    let last_x = x_mapper.time_to_x(*dt);
+                    if data.last_price_tick {
+                        chart_context.draw_series(std::iter::once(PathElement::new(
+                            vec![
+                                (last_x, current_price_y),
+                                (padded_end_millis, current_price_y),
+                            ],
+                            last_candle_color.stroke_width(line_px(2)),
+                        )))?;
+                    }
+                    if data.last_price_dot {
+                        chart_context.draw_series(std::iter::once(Circle::new(
+                            (last_x, current_price_y),
+                            pxi(5),
+                            last_candle_color.filled(),
+                        )))?;
+                    }
+                }
+
+                // Tag the range extremes at their wick tips. Labels point inward, toward the middle
+                // of the plot and below the high or above the low, so they stay inside the plot.
+                if data.high_low_labels {
+                    let highest = processed_data.iter().max_by(|a, b| a.2.total_cmp(&b.2));
+                    let lowest = processed_data.iter().min_by(|a, b| a.3.total_cmp(&b.3));
+                    let middle_x = (visible_x.start + visible_x.end) / 2.0;
+                    let tick = pxi(6);
+                    let extremes = [
+                        highest.map(|c| (c.0, c.2, VPos::Top)),
+                        lowest.map(|c| (c.0, c.3, VPos::Bottom)),
+                    ];
+                    for (dt, price, vpos) in extremes.into_iter().flatten() {
+                        let x = x_mapper.time_to_x(dt);
+                        let (direction, hpos) =
+                            if x > middle_x { (-1, HPos::Right) } else { (1, HPos::Left) };
+                        let style = TextStyle::from(("sans-serif", font_px(12.0)))
+                            .color(&theme.text)
+                            .pos(Pos::new(hpos, vpos));
+                        chart_context.draw_series(std::iter::once(
+                            EmptyElement::at((x, scale.price_to_axis(price)))
+                                + PathElement::new(
+                                    vec![(0, 0), (direction * tick, 0)],
+                                    theme.text.stroke_width(line_px(1)),
+                                )
+                                + Text::new(
+                                    format_table_price(price),
+                                    (direction * (tick + pxi(2)), 0),
+                                    style,
+                                ),
+                        ))?;
+                    }
+                }
+
+                // Comparison lines are already in percent, what the price axis reads in
+                for (series, points) in &compare_lines {
+                    let values: Vec<(i64, Option<f64>)> =
+                        points.iter().map(|&(ts, percent)| (ts, Some(percent))).collect();
+                    draw_series_points(
+                        &mut chart_context,
+                        &to_points(&values, &|percent| percent),
+                        SeriesStyle::Line,
+                        parse_line_color(&series.color),
+                        line_px(2),
+                    )?;
+                }
+                deadline.check("candles")?;
+            }
+            // Zone marks are clipped to the visible box, so one reaching outside it (say,
+            // before the `limit` window) is not pinned to the plot edge
+            Layer::Zone(zone) => {
+                let from_x = x_mapper.millis_to_x(zone.from_timestamp);
+                let to_x = x_mapper.millis_to_x(zone.to_timestamp);
+                let x1 = from_x.min(to_x).max(x_range_start);
+                let x2 = from_x.max(to_x).min(padded_end_millis);
+
+                let from_y = scale.price_to_axis(zone.from_price);
+                let to_y = scale.price_to_axis(zone.to_price);
+                let y1 = from_y.min(to_y).max(min_axis_for_chart);
+                let y2 = from_y.max(to_y).min(max_axis_for_chart);
+
+                // Also skips a box whose price can't be placed, e.g. a negative price on a
+                // log axis
+                if !(x1 < x2 && y1 < y2) {
+                    continue;
+                }
+                let color = parse_hex_color_with_alpha(&zone.color);
+                if let Err(e) = chart_context.draw_series(std::iter::once(Rectangle::new(
+                    [(x1, y1), (x2, y2)],
+                    color.filled(),
+                ))) {
+                    warn!("Failed to draw zone mark: {}", e);
+                }
+            }
+            Layer::Band(band, upper, lower) => {
+                let color = parse_line_color(&band.color).mix(band.opacity);
+                chart_context.draw_series(band_fill(&upper, &lower, color))?;
+                let edges = [(&band.upper_color, &upper), (&band.lower_color, &lower)];
+                for (color, values) in edges {
+                    let Some(color) = color else {
+                        continue;
+                    };
+                    draw_series_points(
+                        &mut chart_context,
+                        &to_points(values, &|price| scale.price_to_axis(price)),
+                        SeriesStyle::Line,
+                        parse_line_color(color),
+                        line_px(band.width.unwrap_or(1)),
+                    )?;
+                }
+            }
+            Layer::Keltner(keltner_mark, mid, upper, lower) => {
+                let color = parse_line_color(&keltner_mark.color);
+                if let Some(opacity) = keltner_mark.fill_opacity {
+                    chart_context.draw_series(band_fill(&upper, &lower, color.mix(opacity)))?;
+                }
+                for values in [&mid, &upper, &lower] {
+                    draw_series_points(
+                        &mut chart_context,
+                        &to_points(values, &|price| scale.price_to_axis(price)),
+                        SeriesStyle::Line,
+                        color,
+                        line_px(keltner_mark.width.unwrap_or(1)),
+                    )?;
+                }
+            }
+            Layer::Ema(ema_mark) => {
+                if ema_mark.period == 0 {
+                    return Err(ChartError::invalid("ema period must be at least 1"));
+                }
+                let series = column_series(data, &ema_mark.source)?;
+                let values: Vec<f64> = series.iter().map(|(_, value)| *value).collect();
+                let timestamps = series.iter().map(|(ts, _)| *ts);
+                let averages: Vec<_> = timestamps.zip(ema(&values, ema_mark.period)).collect();
+                draw_series_points(
+                    &mut chart_context,
+                    &to_points(&averages, &|price| scale.price_to_axis(price)),
+                    SeriesStyle::Line,
+                    parse_line_color(&ema_mark.color),
+                    line_px(ema_mark.width.unwrap_or(2)),
+                )?;
+            }
+            Layer::Vwap(vwap_mark) => {
+                let mut series = ohlcv_series(data);
+                let averages = match vwap_mark.anchor {
+                    VwapAnchor::Session => vwap(&series, |ts| {
+                        timestamp_to_utc(ts).ok().map(|dt| timezone.date(dt))
+                    }),
+                    VwapAnchor::Start => {
+                        series.retain(|(ts, ..)| *ts >= first_drawn_millis);
+                        vwap(&series, |_| ())
+                    }
+                };
+                let timestamps = series.iter().map(|(ts, ..)| *ts);
+                let averages: Vec<_> = timestamps.zip(averages).collect();
+                draw_series_points(
+                    &mut chart_context,
+                    &to_points(&averages, &|price| scale.price_to_axis(price)),
+                    SeriesStyle::Line,
+                    parse_line_color(&vwap_mark.color),
+                    line_px(vwap_mark.width.unwrap_or(2)),
+                )?;
+            }
+            Layer::Histogram(histogram, values) => {
+                let Some(lower) = lower_context.as_mut() else {
+                    continue;
+                };
+                let pos_color = parse_line_color(&histogram.pos_color);
+                let neg_color = parse_line_color(&histogram.neg_color);
+                lower.draw_series(values.iter().filter_map(|(ts, value)| {
+                    let x = x_mapper.millis_to_x(*ts);
+                    let value = value.filter(|_| visible_x.contains(&x))?;
+                    let color = if value >= 0.0 { pos_color } else { neg_color };
+                    Some(Rectangle::new(
+                        [(x - body_width / 2.0, 0.0), (x + body_width / 2.0, value)],
+                        color.filled(),
+                    ))
+                }))?;
+            }
+            Layer::Series(series, values) => {
+                let (chart, points) = match (series.pane, lower_context.as_mut()) {
+                    (SeriesPane::Lower, Some(lower)) => (lower, to_points(&values, &|value| value)),
+                    _ => (
+                        &mut chart_context,
+                        to_points(&values, &|price| scale.price_to_axis(price)),
+                    ),
+                };
+                draw_series_points(
+                    chart,
+                    &points,
+                    series.style,
+                    parse_line_color(&series.color),
+                    line_px(series.width.unwrap_or(2)),
+                )?;
+            }
+            // The risk zone between the entry and the stop and the reward zone between the
+            // entry and the first target
+            Layer::PositionShading(position) => {
+                let Some(x) = position_x(position) else {
+                    continue;
+                };
+                let entry_y = scale.price_to_axis(position.entry);
+                let zones = std::iter::once((position.stop, POSITION_STOP_COLOR)).chain(
+                    position.targets.first().map(|target| (*target, POSITION_TARGET_COLOR)),
+                );
+                for (price, color) in zones {
+                    let y = scale.price_to_axis(price);
+                    let y1 = entry_y.min(y).max(min_axis_for_chart);
+                    let y2 = entry_y.max(y).min(max_axis_for_chart);
+                    if y1 >= y2 {
+                        continue;
+                    }
+                    chart_context.draw_series(std::iter::once(Rectangle::new(
+                        [(x, y1), (padded_end_millis, y2)],
+                        color.mix(POSITION_ZONE_OPACITY).filled(),
+                    )))?;
+                }
+            }
+            // Position levels: a solid entry line, a dashed stop and dashed targets. Levels
+            // off the price axis are left out.
+            Layer::Position(position) => {
+                let Some(x) = position_x(position) else {
+                    continue;
+                };
+                let levels = std::iter::once((position.stop, POSITION_STOP_COLOR)).chain(
+                    position.targets.iter().map(|target| (*target, POSITION_TARGET_COLOR)),
+                );
+                for (price, color) in levels {
+                    let y = scale.price_to_axis(price);
+                    if !visible_y.contains(&y) {
+                        continue;
+                    }
+                    chart_context.draw_series(std::iter::once(DashedPathElement::new(
+                        vec![(x, y), (padded_end_millis, y)],
+                        px(6),
+                        px(4),
+                        color.stroke_width(line_px(1)),
+                    )))?;
+                }
+
+                let entry_y = scale.price_to_axis(position.entry);
+                if !visible_y.contains(&entry_y) {
+                    continue;
+                }
+                chart_context.draw_series(std::iter::once(PathElement::new(
+                    vec![(x, entry_y), (padded_end_millis, entry_y)],
+                    POSITION_ENTRY_COLOR.stroke_width(line_px(2)),
+                )))?;
+                // The label and each target's reward-to-risk ratio sit just above the entry
+                // line
+                let r_multiples: Vec<String> =
+                    position.r_multiples().map(|r| format!("{:.2}R", r)).collect();
+                let text = position
+                    .label
+                    .iter()
+                    .cloned()
+                    .chain((!r_multiples.is_empty()).then(|| r_multiples.join(" / ")))
+                    .collect::<Vec<_>>()
+                    .join("  ");
+                if text.is_empty() {
+                    continue;
+                }
+                let style = TextStyle::from(("sans-serif", font_px(12.0)))
+                    .color(&POSITION_ENTRY_COLOR)
+                    .pos(Pos::new(HPos::Left, VPos::Bottom));
+                chart_context.draw_series(std::iter::once(
+                    EmptyElement::at((x, entry_y)) + Text::new(text, (pxi(4), -pxi(3)), style),
+                ))?;
+            }
+            Layer::Marker(mark) => {
+                let x = x_mapper.millis_to_x(mark.time);
+
+                // Phase 5C: Binary search for candle lookup O(log N) instead of O(N).
+                // The mapper's candles are in time order, as `processed_data` is by now.
+                let half_candle = (candle_duration_millis as i64) / 2;
+                let Some(idx) = x_mapper.candle_index_at(x as i64, half_candle) else {
+                    continue;
+                };
+                // Outside the body even when a flagged candle's high or low is not
+                let (_, o, h, l, c, _, _) = processed_data[idx];
+                let size = mark.size;
+                let axis_range = max_axis_for_chart - min_axis_for_chart;
+                let offset = axis_range * 0.02 * size;
+
+                let y = if mark.above {
+                    scale.price_to_axis(h.max(o).max(c)) + offset
+                } else {
+                    scale.price_to_axis(l.min(o).min(c)) - offset
+                };
+
+                let color = mark.color;
+                let triangle_half_width = marker_candle_width / 3.0 * size;
+                let triangle_height = offset / 2.0;
+
+                // Draw triangle marker
+                if mark.above {
+                    chart_context.draw_series(std::iter::once(Polygon::new(
+                        vec![
+                            (x, y - triangle_height),
+                            (x - triangle_half_width, y + triangle_height),
+                            (x + triangle_half_width, y + triangle_height),
+                        ],
+                        color.filled(),
+                    )))?;
+                } else {
+                    chart_context.draw_series(std::iter::once(Polygon::new(
+                        vec![
+                            (x, y + triangle_height),
+                            (x - triangle_half_width, y - triangle_height),
+                            (x + triangle_half_width, y - triangle_height),
+                        ],
+                        color.filled(),
+                    )))?;
+                }
+
+                // Draw label text if provided
+                if let Some(text) = mark.text {
+                    let text_y = if mark.above {
+                        y + offset * 1.2
+                    } else {
+                        y - offset * 1.2
+                    };
+                    let font_size = font_px((12.0 * size).max(8.0) as i32 as f64);
+                    chart_context.draw_series(std::iter::once(Text::new(
+                        text.to_string(),
+                        (x, text_y),
+                        TextStyle::from(("sans-serif", font_size)).color(&color),
+                    )))?;
+                }
+            }
+        }
+    }
+
+    // --- Zoom inset, over the price pane but under its legend ---
+    if let Some((inset, first)) = zoom {
+        let (x_span, y_span) = plot_pixels.clone();
+        let margin = pxi(8);
+        let width = ((x_span.end - x_span.start) as f64 * inset.width_pct).round() as i32;
+        let height = ((y_span.end - y_span.start) as f64 * inset.height_pct).round() as i32;
+        let right = matches!(inset.corner, InsetCorner::TopRight | InsetCorner::BottomRight);
+        let mut bottom = matches!(inset.corner, InsetCorner::BottomLeft | InsetCorner::BottomRight);
+        let top_at = |bottom: bool| {
+            if bottom { y_span.end - margin - height } else { y_span.start + margin }
+        };
+        // The current price line meets the last price dot and tick at the right edge, so
+        // a right-hand inset over them moves to the other corner of that side
+        let price_row = price_to_pixel_y(current_price);
+        if right && (top_at(bottom)..=top_at(bottom) + height).contains(&price_row) {
+            bottom = !bottom;
+        }
+        let left = if right { x_span.end - margin - width } else { x_span.start + margin };
+        let inset_area = root_area.clone().shrink(
+            (left, top_at(bottom)),
+            (width.max(1) as u32, height.max(1) as u32),
+        );
+        draw_zoom_inset(
+            &inset_area,
+            data,
+            theme,
+            &processed_data[first..],
+            price_scale,
+            pixel_scale,
+        )?;
+    }
+
+    // --- Legend of the price pane lines, over everything else in the price pane ---
+    let legend = legend_entries(data);
+    if data.legend != LegendPosition::Off && !legend.is_empty() {
+        draw_legend(root_area, plot_pixels, &legend, data.legend, pixel_scale)?;
+    }
+
+    Ok(coords)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pane_heights_add_up_to_the_full_height() {
+        let weight_sets: [&[u32]; 6] =
+            [&[1], &[3, 1], &[1, 1, 1], &[5, 2, 2], &[7, 3, 3, 1], &[1, 0]];
+        for weights in weight_sets {
+            let weight_sum: u32 = weights.iter().sum();
+            for total in [0, 1, 2, 99, 100, 101, 479, 480, 961, 1999] {
+                let heights = pane_heights(total, weights);
+                assert_eq!(heights.len(), weights.len());
+                assert_eq!(heights.iter().sum::<u32>(), total, "{:?} of {}", weights, total);
+                // Rounding moves each pane by less than a pixel
+                for (height, weight) in heights.iter().zip(weights) {
+                    let exact = total as f64 * *weight as f64 / weight_sum as f64;
+                    assert!((*height as f64 - exact).abs() < 1.0, "{:?} of {}", weights, total);
+                }
+            }
+        }
+        assert_eq!(pane_heights(100, &[3, 1]), [75, 25]);
+        assert_eq!(pane_heights(100, &[1, 1, 1]), [34, 33, 33]);
+        assert_eq!(pane_heights(100, &[0, 0]), [0, 0]);
+    }
+
+    #[test]
+    fn ema_matches_ta_lib() {
+        // The 10-period EMA of this series as TA-Lib (and StockCharts' worked example)
+        // gives it, to the cent
+        let closes = [
+            22.27, 22.19, 22.08, 22.17, 22.18, 22.13, 22.23, 22.43, 22.24, 22.29, 22.15, 22.39,
+            22.38, 22.61, 23.36, 24.05, 23.75, 23.83, 23.95, 23.63, 23.82, 23.87, 23.65, 23.19,
+            23.10, 23.33, 22.68, 23.10, 22.40, 22.17,
+        ];
+        let expected = [
+            22.22, 22.21, 22.24, 22.27, 22.33, 22.52, 22.80, 22.97, 23.13, 23.28, 23.34, 23.43,
+            23.51, 23.54, 23.47, 23.40, 23.39, 23.26, 23.23, 23.08, 22.92,
+        ];
+        let averages = ema(&closes, 10);
+        assert_eq!(averages.len(), closes.len());
+        assert!(averages[..9].iter().all(Option::is_none));
+        for (average, expected) in averages[9..].iter().zip(expected) {
+            let average = average.unwrap();
+            assert!((average - expected).abs() <= 0.01, "{} != {}", average, expected);
+        }
+    }
+
+    #[test]
+    fn ema_is_seeded_with_the_simple_average() {
+        let values: Vec<f64> = (1..=6).map(f64::from).collect();
+        assert_eq!(ema(&values, 3), [None, None, Some(2.0), Some(3.0), Some(4.0), Some(5.0)]);
+        assert_eq!(ema(&values, 1), values.iter().copied().map(Some).collect::<Vec<_>>());
+        assert_eq!(ema(&values[..2], 3), [None, None]);
+        assert_eq!(ema(&values, 0), [None; 6]);
+    }
+
+    /// Two trading days of `(timestamp, high, low, close, volume)` rows. In UTC+2 the
+    /// second day starts at 22:00 UTC, with a candle that has no volume.
+    fn two_day_rows() -> Vec<(i64, f64, f64, f64, f64)> {
+        let at = |day: u32, hour: u32| {
+            Utc.with_ymd_and_hms(2024, 6, day, hour, 0, 0).unwrap().timestamp_millis()
+        };
+        let flat = |ts: i64, price: f64, volume: f64| (ts, price, price, price, volume);
+        vec![
+            (at(10, 4), 12.0, 8.0, 10.0, 1.0),
+            flat(at(10, 10), 20.0, 1.0),
+            flat(at(10, 16), 30.0, 2.0),
+            flat(at(10, 21), 40.0, 0.0),
+            flat(at(10, 22), 50.0, 0.0),
+            flat(at(11, 4), 60.0, 3.0),
+            flat(at(11, 10), 30.0, 1.0),
+        ]
+    }
+
+    #[test]
+    fn vwap_session_anchor_restarts_at_local_midnight() {
+        let timezone = LabelTimezone::Fixed(FixedOffset::east_opt(2 * 3600).unwrap());
+        let session_of = |ts| timestamp_to_utc(ts).ok().map(|dt| timezone.date(dt));
+        let averages = vwap(&two_day_rows(), session_of);
+        assert_eq!(
+            averages,
+            [Some(10.0), Some(15.0), Some(22.5), Some(22.5), None, Some(60.0), Some(52.5)]
+        );
+    }
+
+    #[test]
+    fn vwap_start_anchor_accumulates_over_both_days() {
+        let averages = vwap(&two_day_rows(), |_| ());
+        let expected = [10.0, 15.0, 22.5, 22.5, 22.5, 270.0 / 7.0, 37.5];
+        assert_eq!(averages, expected.map(Some));
+    }
+
+    #[test]
+    fn vwap_without_volume_is_none_rather_than_nan() {
+        let rows: Vec<_> =
+            two_day_rows().into_iter().map(|(ts, h, l, c, _)| (ts, h, l, c, 0.0)).collect();
+        assert!(vwap(&rows, |_| ()).iter().all(Option::is_none));
+    }
+
+    fn chart_data(extra: serde_json::Value) -> ChartData {
+        let mut request = serde_json::json!({
+            "title": "BTCUSD 1h",
+            "ticker": "BTCUSD",
+            "timeframe": "1h",
+            "desc": "",
+        });
+        request.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(request).unwrap()
+    }
+
+    #[test]
+    fn aligned_series_follows_rows_sent_out_of_order() {
+        let data = chart_data(serde_json::json!({
+            "data": [
+                [7_200_000, 3, 3, 3, 3],
+                [0, 1, 1, 1, 1],
+                [3_600_000, 2, 2, 2, 2],
+            ],
+        }));
+        let points = aligned_series(&data, "series \"x\"", &[Some(30.0), Some(10.0), None]);
+        assert_eq!(points.unwrap(), [(0, Some(10.0)), (3_600_000, None), (7_200_000, Some(30.0))]);
+    }
+
+    #[test]
+    fn aligned_series_keeps_gaps_and_drops_rows_without_a_time() {
+        let mut data = chart_data(serde_json::json!({
+            "data": [[0, 1, 1, 1, 1], [3_600_000, 2, 2, 2, 2], [7_200_000, 3, 3, 3, 3]],
+        }));
+        let values = [None, Some(f64::NAN), Some(3.0)];
+        let points = aligned_series(&data, "series \"x\"", &values).unwrap();
+        assert_eq!(points, [(0, None), (3_600_000, None), (7_200_000, Some(3.0))]);
+
+        data.data[1][0] = f64::NAN;
+        let points = aligned_series(&data, "series \"x\"", &[Some(1.0), Some(2.0), None]).unwrap();
+        assert_eq!(points, [(0, Some(1.0)), (7_200_000, None)]);
+    }
+
+    #[test]
+    fn series_of_the_wrong_length_names_the_series() {
+        let data = chart_data(serde_json::json!({
+            "data": [[0, 1, 2, 0.5, 1.5], [3_600_000, 1.5, 2, 1, 1.8]],
+            "plots": {
+                "marks": [
+                    {"type": "series", "name": "SuperTrend", "values": [null], "color": "#00E5FF"},
+                ],
+            },
+        }));
+        let err = render_chart(&data, &RenderOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("series \"SuperTrend\" has 1 values for 2 candles"));
+
+        let data = chart_data(serde_json::json!({"data": [[0, 1, 1, 1, 1]]}));
+        let err = aligned_series(&data, "histogram \"delta\"", &[]).unwrap_err();
+        assert!(err.to_string().contains("histogram \"delta\" has 0 values for 1 candles"));
+    }
+
+    #[test]
+    fn nice_step_picks_round_steps_at_every_magnitude() {
+        let cases = [
+            (10_000.0, 5, 2_000.0),
+            (12_000.0, 5, 2_500.0),
+            (30_000.0, 5, 10_000.0),
+            (10_000.0, 4, 2_500.0),
+            (450.0, 6, 100.0),
+            (1.0, 5, 0.2),
+            (0.07, 5, 0.02),
+            (0.01, 5, 0.002),
+            (0.0003, 4, 0.0001),
+            (0.000_004, 8, 0.000_000_5),
+        ];
+        for (span, target, expected) in cases {
+            let step = nice_step(span, target);
+            assert!((step - expected).abs() < expected * 1e-9, "{} / {}: {}", span, target, step);
+        }
+        assert_eq!(nice_step(-10_000.0, 5), 2_000.0);
+        assert_eq!(nice_step(0.0, 5), 1.0);
+        assert_eq!(nice_step(f64::NAN, 5), 1.0);
+    }
+
+    #[test]
+    fn nice_step_is_a_round_number_just_long_enough() {
+        for exponent in -9..=7 {
+            for mantissa in [1.0, 1.3, 2.0, 2.2, 2.5, 3.7, 5.0, 6.1, 9.9] {
+                let span = mantissa * 10f64.powi(exponent);
+                for target in [3, 5, 8] {
+                    let step = nice_step(span, target);
+                    let shortest = span / target as f64;
+                    let leading = step / 10f64.powf(step.log10().floor());
+                    assert!(
+                        [1.0, 2.0, 2.5, 5.0, 10.0].iter().any(|m| (leading - m).abs() < 1e-6),
+                        "{} / {}: {}",
+                        span,
+                        target,
+                        step
+                    );
+                    assert!(step >= shortest * (1.0 - 1e-9) && step <= shortest * 2.0 + 1e-12);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nice_ticks_land_on_round_prices() {
+        let ticks = nice_ticks(60_000.0, 70_000.0, 4);
+        assert_eq!(ticks, [60_000.0, 62_500.0, 65_000.0, 67_500.0, 70_000.0]);
+        assert_eq!(nice_ticks(61_234.0, 66_789.0, 5), [62_000.0, 64_000.0, 66_000.0]);
+        let ticks = nice_ticks(0.0712, 0.0768, 5);
+        assert_eq!(ticks.len(), 3);
+        for (tick, expected) in ticks.iter().zip([0.072, 0.074, 0.076]) {
+            assert!((tick - expected).abs() < 1e-12, "{:?}", ticks);
+        }
+    }
+
+    #[test]
+    fn format_price_fits_the_magnitude_and_the_visible_range() {
+        let cases = [
+            // BTC
+            (60_838.42, 2_000.0, "60,838"),
+            (60_838.42, 500.0, "60,838.4"),
+            (1_234_567.891, 10.0, "1,234,567.89"),
+            // ETH
+            (3_456.789, 100.0, "3,456.8"),
+            (3_456.789, 1_000.0, "3,457"),
+            // Forex
+            (1.08432, 0.02, "1.08432"),
+            (1.08432, 0.5, "1.0843"),
+            (149.873, 2.0, "149.873"),
+            // Micro-caps
+            (0.073123, 0.001, "0.073123"),
+            (0.000_018_34, 0.000_02, "0.00001834"),
+            (0.000_018_34, 0.0, "0.00001834"),
+        ];
+        for (price, span, expected) in cases {
+            assert_eq!(format_price(price, span), expected, "{} over {}", price, span);
+        }
+    }
+
+    #[test]
+    fn format_price_tells_apart_prices_a_pixel_apart() {
+        for (price, span) in [(60_838.0, 150.0), (1.0843, 0.003), (0.073, 0.0004)] {
+            let pixel = span / PRICE_LABEL_STEPS;
+            assert_ne!(format_price(price, span), format_price(price + pixel, span));
+        }
+    }
+
+    #[test]
+    fn format_price_signs_and_groups() {
+        assert_eq!(format_price(-1_234.56, 0.0), "-1,235");
+        assert_eq!(format_price(-0.000_01, 100.0), "-0.00001000");
+        assert_eq!(format_grouped(-0.004, 2), "0.00");
+        assert_eq!(format_price(999_999.6, 10_000.0), "1,000,000");
+        assert_eq!(format_price(f64::NAN, f64::NAN), "NaN");
+    }
+
+    #[test]
+    fn timestamps_round_trip_as_numbers_and_strings() {
+        let millis = Utc.with_ymd_and_hms(2024, 3, 14, 9, 30, 0).unwrap().timestamp_millis() + 250;
+        let utc = timestamp_to_utc(millis).unwrap();
+        let new_york = utc.with_timezone(&FixedOffset::west_opt(4 * 3600).unwrap());
+        let cells = [
+            serde_json::json!(millis),
+            serde_json::json!(utc.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+            serde_json::json!(new_york.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+            serde_json::json!("2024-03-14T09:30:00.250"),
+        ];
+        assert_eq!(cells[1], "2024-03-14T09:30:00.250Z");
+        assert_eq!(cells[2], "2024-03-14T05:30:00.250-04:00");
+        for cell in cells {
+            let data = chart_data(serde_json::json!({"data": [[cell, 1, 2, 0.5, 1.5, 10]]}));
+            assert_eq!(data.data, [[millis as f64, 1.0, 2.0, 0.5, 1.5, 10.0]], "{}", cell);
+        }
+    }
+
+    #[test]
+    fn unreadable_timestamp_names_the_row_and_the_text() {
+        let request = serde_json::json!({
+            "title": "BTCUSD 1h",
+            "ticker": "BTCUSD",
+            "timeframe": "1h",
+            "desc": "",
+            "data": [
+                ["2024-03-14T09:30:00Z", 1, 2, 0.5, 1.5],
+                ["14/03/2024 10:30", 1, 2, 0.5, 1.5],
+            ],
+        });
+        let err = serde_json::from_value::<ChartData>(request).unwrap_err().to_string();
+        assert!(err.contains("data row 1: timestamp \"14/03/2024 10:30\""), "{}", err);
+    }
+}
@@ -1,51 +1,406 @@
-use chrono::{DateTime, Local, LocalResult, TimeZone, Utc};
+use chrono::{DateTime, Local};
+use corky_charts::{
+    render_chart_timed, render_chart_with_coords, safe_timestamp_to_local, CandleCache,
+    ChartCoords, ChartData, ChartError, ChartStyle, DataMode, Delivery, ImageFormat,
+    PngCompression, PngFilter, RenderOptions, ThumbnailSize, SPARKLINE_SIZE,
+};
 use log::{debug, error, info, warn};
-use serde::Deserialize;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use serde_json::from_str;
+use signal_hook::consts::{SIGINT, SIGTERM};
 use std::panic::AssertUnwindSafe;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::{error::Error, fs, str, thread};
-// Add plotters
-use plotters::prelude::*;
-use plotters::style::{Color, IntoFont, RGBAColor, RGBColor, TextStyle};
-
-/// Configuration structure for the charts section of the config file
-#[derive(Debug, Deserialize)]
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use std::{error::Error, fmt, fs, str, thread};
+
+/// Configuration structure for the charts section of the config file.
+/// Keys not listed here are rejected, so a typo is reported instead of ignored.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 struct ChartsConfig {
     directory: Option<String>,
+    /// Socket requests arrive on; `--mode` overrides it
+    mode: Option<IngestMode>,
+    /// Address the request socket connects to (or binds); `--endpoint` overrides it
+    endpoint: Option<String>,
+    /// Topic prefixes subscribed to in sub mode; `--topics` overrides them
+    topics: Option<Vec<String>>,
+    /// Message sent to the broker after every (re)connect; `--hello` overrides it
+    hello: Option<String>,
+    /// Reconnect attempts before giving up; `--max-retries` overrides it
+    max_retries: Option<u32>,
+    /// Log level for every module; `--log-level` overrides it
+    log_level: Option<String>,
+    /// Log one JSON object per line; `--log-json` turns it on
+    log_json: Option<bool>,
+    /// Largest encoded image (in bytes) that may be sent inline in a reply
+    inline_max_bytes: Option<usize>,
+    /// How many recent request ids are remembered for duplicate suppression
+    dedup_capacity: Option<usize>,
+    /// How long (seconds) a repeated request id counts as a retransmission
+    dedup_ttl_secs: Option<u64>,
+    /// How long (seconds) a chart may take to render before it is abandoned
+    render_timeout_secs: Option<u64>,
+    /// Seconds between heartbeats sent to the broker; 0 disables them
+    heartbeat_interval_secs: Option<u64>,
+    /// How many ticker+timeframe series keep their validated candles between requests
+    candle_cache_entries: Option<usize>,
+    /// How many ticker+timeframe series keep their last full data for append requests
+    series_cache_entries: Option<usize>,
+    /// Most candles kept per series for append requests; older ones are dropped
+    series_cache_max_candles: Option<usize>,
+    /// Largest size (in bytes) a compressed payload may decompress to
+    max_decompressed_bytes: Option<usize>,
+    /// How long (seconds) a shutdown waits for in-flight charts before exiting anyway
+    shutdown_grace_secs: Option<u64>,
+    /// What a render does when another one is writing the same file
+    coalesce: Option<CoalesceMode>,
+    /// Address (e.g. "127.0.0.1:9187") serving Prometheus metrics on `/metrics`
+    metrics_listen: Option<String>,
+    /// Output file name with placeholders; `--filename-template` overrides it
+    filename_template: Option<String>,
+    /// Delete images named by `filename_template` once they are this many hours old
+    retention_max_age_hours: Option<f64>,
+    /// Keep only the newest N images of a ticker named by `filename_template`
+    retention_max_files_per_ticker: Option<usize>,
+    /// Save a thumbnail of this size next to every saved chart; needs `thumbnail_height`
+    thumbnail_width: Option<u32>,
+    thumbnail_height: Option<u32>,
+    /// PNG compression level for requests that do not pick one
+    png_compression: Option<PngCompression>,
+    /// PNG row filter for requests that do not pick one
+    png_filter: Option<PngFilter>,
+    /// How many batch items render at once, across every batch
+    batch_threads: Option<usize>,
 }
 
-/// Overall configuration structure
-#[derive(Debug, Deserialize)]
+/// Overall configuration structure. Other sections of a shared corky config are ignored.
+#[derive(Debug, Deserialize, Serialize)]
 struct Config {
     charts: Option<ChartsConfig>,
 }
 
-/// Gets the output directory from the configuration file
-/// Returns an error if the directory is not specified in the config
-fn get_output_directory() -> Result<String, Box<dyn Error>> {
-    // Get the home directory using the dirs crate
-    let home_dir = dirs::home_dir().ok_or("Could not find home directory")?;
-    let config_path = home_dir.join(".corky").join("config.toml");
+/// Default cap on inline image replies (8 MiB)
+const DEFAULT_INLINE_MAX_BYTES: usize = 8 * 1024 * 1024;
+const DEFAULT_DEDUP_CAPACITY: usize = 256;
+const DEFAULT_DEDUP_TTL_SECS: u64 = 30;
+const DEFAULT_RENDER_TIMEOUT_SECS: u64 = 30;
+/// Default cap on decompressed payloads (64 MiB)
+const DEFAULT_MAX_DECOMPRESSED_BYTES: usize = 64 * 1024 * 1024;
+/// Comfortably inside the broker's 60 second liveness window
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 15;
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 60;
+const DEFAULT_SERIES_CACHE_MAX_CANDLES: usize = 5000;
+
+/// Resolved runtime settings shared with every chart thread
+#[derive(Debug, Clone)]
+struct Settings {
+    output_dir: String,
+    inline_max_bytes: usize,
+    dedup_capacity: usize,
+    dedup_ttl: Duration,
+    render_timeout: Duration,
+    /// `None` when heartbeats are disabled
+    heartbeat_interval: Option<Duration>,
+    /// 0 disables the candle cache
+    candle_cache_entries: usize,
+    /// 0 disables append requests
+    series_cache_entries: usize,
+    series_cache_max_candles: usize,
+    max_decompressed_bytes: usize,
+    shutdown_grace: Duration,
+    coalesce: CoalesceMode,
+    /// `None` when the metrics listener is disabled
+    metrics_listen: Option<String>,
+    /// Output file name template, relative to `output_dir`
+    filename_template: String,
+    retention: RetentionPolicy,
+    /// Thumbnail saved with charts whose request does not ask for one
+    thumbnail: Option<ThumbnailSize>,
+    png_compression: PngCompression,
+    png_filter: PngFilter,
+    batch_threads: usize,
+}
+
+/// Limits on the images kept in the output directory, enforced after each saved chart.
+/// Only files named by the service's filename template are ever removed.
+#[derive(Debug, Clone, Copy, Default)]
+struct RetentionPolicy {
+    max_age: Option<Duration>,
+    max_files_per_ticker: Option<usize>,
+}
+
+impl RetentionPolicy {
+    fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_files_per_ticker.is_some()
+    }
+}
+
+/// Reads the `[charts]` section of the config file at `path`, or of
+/// ~/.corky/config.toml when no path is given
+fn load_config(path: Option<&str>) -> Result<ChartsConfig, Box<dyn Error>> {
+    let config_path = match path {
+        Some(path) => PathBuf::from(path),
+        // Get the home directory using the dirs crate
+        None => dirs::home_dir()
+            .ok_or("Could not find home directory")?
+            .join(".corky")
+            .join("config.toml"),
+    };
 
     // Check if the config file exists
     if !config_path.exists() {
-        return Err("Config file ~/.corky/config.toml not found".into());
+        return Err(format!("Config file {} not found", config_path.display()).into());
     }
 
-    // Read and parse the TOML file
-    let config_content = fs::read_to_string(config_path)?;
-    let config: Config = toml::from_str(&config_content)?;
+    // Read and parse the TOML file; unknown keys are reported with their name and line
+    let config_content = fs::read_to_string(&config_path)?;
+    let config: Config = toml::from_str(&config_content)
+        .map_err(|e| format!("Invalid config file {}: {}", config_path.display(), e))?;
+    config
+        .charts
+        .ok_or_else(|| format!("[charts] section not found in {}", config_path.display()).into())
+}
 
-    // Check if the charts section exists and has a directory
-    match config.charts {
-        Some(charts_config) => match charts_config.directory {
-            Some(dir) => Ok(dir),
-            None => Err("Output directory not specified in [charts] section of config.toml".into()),
+/// Resolves the runtime settings from the config file, filling in defaults
+/// Returns an error if the output directory is not specified in the config
+fn load_settings(charts_config: &ChartsConfig, args: &CliArgs) -> Result<Settings, Box<dyn Error>> {
+    let Some(dir) = charts_config.directory.clone() else {
+        return Err("Output directory not specified in [charts] section of config.toml".into());
+    };
+    let max_age = match charts_config.retention_max_age_hours {
+        Some(hours) if !hours.is_finite() || hours <= 0.0 => {
+            return Err(format!("retention_max_age_hours must be positive, got {}", hours).into());
+        }
+        Some(hours) => Some(Duration::from_secs_f64(hours * 3600.0)),
+        None => None,
+    };
+    if charts_config.retention_max_files_per_ticker == Some(0) {
+        return Err("retention_max_files_per_ticker must be at least 1".into());
+    }
+    let thumbnail = match (charts_config.thumbnail_width, charts_config.thumbnail_height) {
+        (None, None) => None,
+        (Some(width), Some(height)) => {
+            let size = ThumbnailSize { width, height };
+            check_thumbnail_size("thumbnail", size)?;
+            Some(size)
+        }
+        _ => return Err("thumbnail_width and thumbnail_height must be set together".into()),
+    };
+    if charts_config.batch_threads == Some(0) {
+        return Err("batch_threads must be at least 1".into());
+    }
+    Ok(Settings {
+        output_dir: dir,
+        inline_max_bytes: charts_config
+            .inline_max_bytes
+            .unwrap_or(DEFAULT_INLINE_MAX_BYTES),
+        dedup_capacity: charts_config
+            .dedup_capacity
+            .unwrap_or(DEFAULT_DEDUP_CAPACITY),
+        dedup_ttl: Duration::from_secs(
+            charts_config.dedup_ttl_secs.unwrap_or(DEFAULT_DEDUP_TTL_SECS),
+        ),
+        render_timeout: Duration::from_secs(
+            charts_config
+                .render_timeout_secs
+                .unwrap_or(DEFAULT_RENDER_TIMEOUT_SECS),
+        ),
+        heartbeat_interval: match charts_config
+            .heartbeat_interval_secs
+            .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_SECS)
+        {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        candle_cache_entries: charts_config.candle_cache_entries.unwrap_or(0),
+        series_cache_entries: charts_config.series_cache_entries.unwrap_or(0),
+        series_cache_max_candles: charts_config
+            .series_cache_max_candles
+            .unwrap_or(DEFAULT_SERIES_CACHE_MAX_CANDLES),
+        max_decompressed_bytes: charts_config
+            .max_decompressed_bytes
+            .unwrap_or(DEFAULT_MAX_DECOMPRESSED_BYTES),
+        shutdown_grace: Duration::from_secs(
+            charts_config
+                .shutdown_grace_secs
+                .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS),
+        ),
+        coalesce: charts_config.coalesce.unwrap_or_default(),
+        metrics_listen: charts_config.metrics_listen.clone(),
+        filename_template: args.filename_template.clone(),
+        retention: RetentionPolicy {
+            max_age,
+            max_files_per_ticker: charts_config.retention_max_files_per_ticker,
         },
-        None => Err("[charts] section not found in config.toml".into()),
+        thumbnail,
+        png_compression: charts_config.png_compression.unwrap_or_default(),
+        png_filter: charts_config.png_filter.unwrap_or_default(),
+        batch_threads: charts_config
+            .batch_threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get())),
+    })
+}
+
+/// Thumbnails and sparklines larger than this are refused; a full chart is the better choice
+const MAX_THUMBNAIL_SIDE: u32 = 1024;
+
+/// `what` names the image in the error, e.g. "thumbnail"
+fn check_thumbnail_size(what: &str, size: ThumbnailSize) -> Result<(), String> {
+    if !(1..=MAX_THUMBNAIL_SIDE).contains(&size.width)
+        || !(1..=MAX_THUMBNAIL_SIDE).contains(&size.height)
+    {
+        return Err(format!(
+            "{} size {}x{} must be between 1 and {} pixels on each side",
+            what, size.width, size.height, MAX_THUMBNAIL_SIDE
+        ));
     }
+    Ok(())
+}
+
+/// The configuration in effect once defaults and command line overrides are applied,
+/// in config file form
+fn resolved_config_toml(settings: &Settings, args: &CliArgs) -> String {
+    let resolved = ChartsConfig {
+        directory: Some(settings.output_dir.clone()),
+        mode: Some(args.mode),
+        endpoint: Some(args.endpoint.clone()),
+        topics: Some(args.topics.clone()),
+        hello: args.hello.clone(),
+        max_retries: Some(args.max_retries),
+        log_level: args.log_level.map(|level| level.as_str().to_lowercase()),
+        log_json: Some(args.log_json),
+        inline_max_bytes: Some(settings.inline_max_bytes),
+        dedup_capacity: Some(settings.dedup_capacity),
+        dedup_ttl_secs: Some(settings.dedup_ttl.as_secs()),
+        render_timeout_secs: Some(settings.render_timeout.as_secs()),
+        heartbeat_interval_secs: Some(settings.heartbeat_interval.map_or(0, |i| i.as_secs())),
+        candle_cache_entries: Some(settings.candle_cache_entries),
+        series_cache_entries: Some(settings.series_cache_entries),
+        series_cache_max_candles: Some(settings.series_cache_max_candles),
+        max_decompressed_bytes: Some(settings.max_decompressed_bytes),
+        shutdown_grace_secs: Some(settings.shutdown_grace.as_secs()),
+        coalesce: Some(settings.coalesce),
+        metrics_listen: settings.metrics_listen.clone(),
+        filename_template: Some(settings.filename_template.clone()),
+        retention_max_age_hours: settings.retention.max_age.map(|age| age.as_secs_f64() / 3600.0),
+        retention_max_files_per_ticker: settings.retention.max_files_per_ticker,
+        thumbnail_width: settings.thumbnail.map(|size| size.width),
+        thumbnail_height: settings.thumbnail.map(|size| size.height),
+        png_compression: Some(settings.png_compression),
+        png_filter: Some(settings.png_filter),
+        batch_threads: Some(settings.batch_threads),
+    };
+    let config = Config {
+        charts: Some(resolved),
+    };
+    toml::to_string(&config).unwrap_or_else(|e| format!("(cannot display: {})", e))
+}
+
+/// Commented starting point for a config file, printed by `--print-default-config`.
+/// Everything but `directory` is commented out at its default value.
+fn default_config_toml() -> String {
+    format!(
+        r#"# corky-charts configuration. Load it with `--config <path>`; without that flag
+# ~/.corky/config.toml is read. Command line flags override values set here.
+
+[charts]
+# Directory charts are saved to (required)
+directory = "/var/lib/corky-charts"
+
+# Socket requests arrive on: "dealer", "sub" or "bind"
+# mode = "dealer"
+# Address the request socket connects to, or binds in bind mode
+# (default {broker}, or {bind} in bind mode)
+# endpoint = "{broker}"
+# Topic prefixes subscribed to in sub mode
+# topics = ["{topic}"]
+# Message sent to the broker after every (re)connect in dealer mode
+# hello = "ready"
+# Consecutive reconnect attempts before exiting; 0 retries forever
+# max_retries = 0
+
+# Log level for every module: "error", "warn", "info", "debug", "trace" or "off".
+# When unset, RUST_LOG applies (default "info").
+# log_level = "info"
+# Write each log record as one JSON object per line
+# log_json = false
+
+# Largest image (bytes) sent inline in a reply; larger ones are saved to a file
+# inline_max_bytes = {inline_max_bytes}
+# Recent request ids remembered for duplicate suppression; 0 disables it
+# dedup_capacity = {dedup_capacity}
+# Seconds a repeated request counts as a retransmission
+# dedup_ttl_secs = {dedup_ttl_secs}
+# Seconds a chart may take to render before it is abandoned
+# render_timeout_secs = {render_timeout_secs}
+# Seconds between heartbeats sent to the broker; 0 disables them
+# heartbeat_interval_secs = {heartbeat_interval_secs}
+# Ticker+timeframe series whose validated candles are kept between requests; 0 disables it
+# candle_cache_entries = 0
+# Ticker+timeframe series whose last full data is kept for "mode": "append" requests;
+# 0 disables append requests
+# series_cache_entries = 0
+# Most candles kept per series for append requests; the oldest are dropped
+# series_cache_max_candles = {series_cache_max_candles}
+# Largest size (bytes) a compressed payload may decompress to
+# max_decompressed_bytes = {max_decompressed_bytes}
+# Seconds a shutdown waits for in-flight charts before exiting anyway
+# shutdown_grace_secs = {shutdown_grace_secs}
+# When two renders target the same file: "wait" for the other, or "supersede" it
+# coalesce = "wait"
+# Serve Prometheus metrics over HTTP on GET /metrics at this address; unset disables it
+# metrics_listen = "127.0.0.1:9187"
+# Output file name inside `directory`. Placeholders: {{ticker}}, {{timeframe}},
+# {{date}}, {{time}}, {{ts}}, {{id}} and {{ext}}; "/" creates subdirectories
+# filename_template = "{filename_template}"
+
+# Retention, checked after each saved chart. Only images named by filename_template
+# are removed, oldest first; both are off by default.
+# Delete images older than this many hours
+# retention_max_age_hours = 168
+# Keep at most this many images per ticker
+# retention_max_files_per_ticker = 100
+
+# Save a small preview as <name>_thumb.<ext> next to every saved chart; a request's
+# "thumbnail" field overrides it
+# thumbnail_width = 320
+# thumbnail_height = 180
+
+# PNG compression level: "fast" (quickest encode), "default" (zlib level 6, about
+# half the bytes for four times the encode time) or "best" (zlib level 9, slower
+# still for little gain); a request's "png_compression" field overrides it
+# png_compression = "fast"
+
+# PNG row filter: "adaptive" (best per row), "none", "sub", "up", "avg" or "paeth";
+# a request's "png_filter" field overrides it
+# png_filter = "adaptive"
+
+# Batch items rendered at once, shared by every batch (default: the number of CPU cores)
+# batch_threads = 4
+"#,
+        broker = BROKER_ENDPOINT,
+        bind = DEFAULT_BIND_ENDPOINT,
+        topic = DEFAULT_TOPIC_PREFIX,
+        inline_max_bytes = DEFAULT_INLINE_MAX_BYTES,
+        dedup_capacity = DEFAULT_DEDUP_CAPACITY,
+        dedup_ttl_secs = DEFAULT_DEDUP_TTL_SECS,
+        render_timeout_secs = DEFAULT_RENDER_TIMEOUT_SECS,
+        heartbeat_interval_secs = DEFAULT_HEARTBEAT_INTERVAL_SECS,
+        max_decompressed_bytes = DEFAULT_MAX_DECOMPRESSED_BYTES,
+        shutdown_grace_secs = DEFAULT_SHUTDOWN_GRACE_SECS,
+        series_cache_max_candles = DEFAULT_SERIES_CACHE_MAX_CANDLES,
+        filename_template = DEFAULT_FILENAME_TEMPLATE,
+    )
 }
 
 // ─── Data Structures ────────────────────────────────────────────────────────────
@@ -57,239 +412,2473 @@ pub struct ChartRequest(
     #[serde(rename = "2")] pub ChartData,
 );
 
-fn parse_hex_color(hex: &str) -> RGBColor {
-    let hex = hex.trim_start_matches('#');
-    if hex.len() == 6
-        && let (Ok(r), Ok(g), Ok(b)) = (
-            u8::from_str_radix(&hex[0..2], 16),
-            u8::from_str_radix(&hex[2..4], 16),
-            u8::from_str_radix(&hex[4..6], 16),
-        )
-    {
-        return RGBColor(r, g, b);
-    }
-    // Default to gray if parsing fails
-    RGBColor(128, 128, 128)
-}
-
-/// Parse hex color with alpha channel support (e.g., "#FF000020")
-fn parse_hex_color_with_alpha(hex: &str) -> RGBAColor {
-    let hex = hex.trim_start_matches('#');
-    if hex.len() == 8 {
-        // #RRGGBBAA format
-        if let (Ok(r), Ok(g), Ok(b), Ok(a)) = (
-            u8::from_str_radix(&hex[0..2], 16),
-            u8::from_str_radix(&hex[2..4], 16),
-            u8::from_str_radix(&hex[4..6], 16),
-            u8::from_str_radix(&hex[6..8], 16),
-        ) {
-            return RGBAColor(r, g, b, a as f64 / 255.0);
-        }
-    } else if hex.len() == 6 {
-        // #RRGGBB format - default 30% opacity
-        if let (Ok(r), Ok(g), Ok(b)) = (
-            u8::from_str_radix(&hex[0..2], 16),
-            u8::from_str_radix(&hex[2..4], 16),
-            u8::from_str_radix(&hex[4..6], 16),
-        ) {
-            return RGBAColor(r, g, b, 0.3);
-        }
-    }
-    // Default to semi-transparent gray if parsing fails
-    RGBAColor(128, 128, 128, 0.3)
-}
-
-#[derive(Debug, Deserialize, Clone)]
-pub struct ChartData {
-    pub title: String,
-    pub ticker: String,
-    pub timeframe: String,
-    /// Columns describing the data. Typically something like:
-    /// `["timestamp", "open", "high", "low", "close", "volume"]`
-    pub cols: Vec<String>,
-    /// Each inner `Vec<f64>` is a row of candle data: [timestamp_millis, open, high, low, close, volume]
-    pub data: Vec<Vec<f64>>,
-    /// Colors for each candle, e.g. `["#FF0000", "#00FF00", ...]`
-    pub candle_colors: Vec<String>,
-    /// Optional colors for each volume bar, e.g. `["#FF0000", "#00FF00", ...]`
-    #[serde(default)]
-    pub volume_colors: Option<Vec<String>>,
-    pub plots: Plots,
-    pub desc: String,
-    /// Optional chat ID for telegram message
-    #[serde(default)]
-    pub chat_id: Option<i64>,
-    /// Optional subscriber list name for telegram message
-    #[serde(default)]
-    pub subscriber_list: Option<String>,
-    /// Optional unique image filename to prevent race condition overwrites
-    #[serde(default)]
-    pub image_filename: Option<String>,
-}
-
-/// Marker to be drawn on the chart (e.g., signal indicators)
-#[derive(Debug, Deserialize, Clone)]
-pub struct Mark {
-    /// Timestamp in milliseconds (candle timestamp)
-    pub time: i64,
-    /// "above" or "below" the candle
-    pub position: String,
-    /// Hex color "#RRGGBB"
-    pub color: String,
-    /// Optional label text (e.g., "4h")
-    #[serde(default)]
-    pub text: Option<String>,
-    /// Relative size (default 1.0)
-    #[serde(default = "default_mark_size")]
-    pub size: f64,
-}
-
-fn default_mark_size() -> f64 {
-    1.0
-}
-
-/// Rectangular zone for resistance/support visualization
-#[derive(Debug, Deserialize, Clone)]
-pub struct Zone {
-    /// Left timestamp (ms) - when signal was confirmed
-    pub x1: i64,
-    /// Right timestamp (ms) - last candle timestamp
-    pub x2: i64,
-    /// Bottom price
-    pub y1: f64,
-    /// Top price
-    pub y2: f64,
-    /// Hex color with alpha "#RRGGBBAA"
-    pub color: String,
-}
-
-/// Vertical line for marking specific timestamps (e.g., alert fire points)
-#[derive(Debug, Deserialize, Clone)]
-pub struct VLine {
-    /// Timestamp in milliseconds
-    pub time: i64,
-    /// Hex color "#RRGGBB" or "#RRGGBBAA"
-    pub color: String,
-}
-
-#[derive(Debug, Deserialize, Clone)]
-pub struct Plots {
-    #[serde(default)]
-    pub marks: Vec<Mark>,
-    #[serde(default)]
-    pub zones: Vec<Zone>,
-    #[serde(default)]
-    pub vlines: Vec<VLine>,
-}
-
-/// Safely convert milliseconds timestamp to local DateTime.
-/// Returns an error if the timestamp is invalid.
-fn safe_timestamp_to_local(millis: i64) -> Result<DateTime<Local>, Box<dyn Error>> {
-    match Utc.timestamp_millis_opt(millis) {
-        LocalResult::Single(utc_dt) => {
-            Ok(utc_dt.with_timezone(&Local))
-        }
-        _ => Err(format!("Invalid timestamp: {}", millis).into()),
-    }
-}
-
-/// Sanitize a path component by stripping dangerous characters
+/// Payload of a plain-text liveness probe, answered without parsing
+const PING_PROBE: &[u8] = b"PING";
+
+/// Elements of a chart request array, in order
+const CHART_REQUEST_ELEMENTS: [&str; 3] = ["id", "command", "chart_data"];
+
+/// Explain why `value` is not a chart request, naming the missing or mistyped element
+/// and, inside `chart_data`, the offending field. Only used once parsing has failed:
+/// serde's own error for a positional tuple does not say which part was wrong.
+fn chart_request_problem(value: &serde_json::Value) -> String {
+    let Some(items) = value.as_array() else {
+        return format!(
+            "expected a chart request array [id, command, chart_data], found {}",
+            value_kind(value)
+        );
+    };
+    for (index, name) in CHART_REQUEST_ELEMENTS.iter().enumerate() {
+        let Some(item) = items.get(index) else {
+            return format!(
+                "element {} ({}) is missing; expected [id, command, chart_data], found {} element(s)",
+                index,
+                name,
+                items.len()
+            );
+        };
+        let (ok, expected) = match index {
+            0 | 1 => (item.is_string(), "a string"),
+            _ => (item.is_object(), "an object"),
+        };
+        if !ok {
+            return format!(
+                "element {} ({}) must be {}, found {}",
+                index,
+                name,
+                expected,
+                value_kind(item)
+            );
+        }
+    }
+    if items.len() > CHART_REQUEST_ELEMENTS.len() {
+        return format!(
+            "expected 3 elements [id, command, chart_data], found {}",
+            items.len()
+        );
+    }
+    match serde_path_to_error::deserialize::<_, ChartData>(&items[2]) {
+        Err(e) if e.path().iter().next().is_none() => format!("chart_data: {}", e.inner()),
+        Err(e) => format!("chart_data.{}: {}", e.path(), e.inner()),
+        Ok(_) => "malformed chart request".to_string(),
+    }
+}
+
+/// JSON type of `value`, for error messages
+fn value_kind(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "a boolean",
+        serde_json::Value::Number(_) => "a number",
+        serde_json::Value::String(_) => "a string",
+        serde_json::Value::Array(_) => "an array",
+        serde_json::Value::Object(_) => "an object",
+    }
+}
+
+/// Service-level messages sent as a JSON object (chart requests are arrays),
+/// e.g. `{"type": "ping"}`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ControlMessage {
+    /// Liveness probe, answered with a pong
+    Ping,
+    /// Report request counters and uptime
+    Stats,
+    /// Stop taking requests, let in-flight charts finish, then exit
+    Shutdown,
+    /// Delete images in the output directory older than the given age
+    Purge { older_than_hours: f64 },
+    /// Several chart requests rendered under one id. Items stay raw values so a
+    /// malformed one is rejected on its own instead of failing the whole batch.
+    Batch {
+        id: String,
+        requests: Vec<serde_json::Value>,
+    },
+}
+
+/// Sanitize a path component so it only holds `[A-Za-z0-9._-]`. Path separators and
+/// control characters are dropped, so `BTC/USDT` stays `BTCUSDT`; any other character
+/// becomes `_`. Leading and trailing dots are dropped and runs of dots cut to one, so
+/// the result never names a directory above it or a hidden file.
 fn sanitize_path_component(s: &str) -> String {
-    s.replace(['/', '\\'], "")
-        .replace("..", "")
+    let mut sanitized = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '/' | '\\' => {}
+            c if c.is_control() => {}
+            '.' if sanitized.is_empty() || sanitized.ends_with('.') => {}
+            c if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') => sanitized.push(c),
+            _ => sanitized.push('_'),
+        }
+    }
+    sanitized.truncate(sanitized.trim_end_matches('.').len());
+    sanitized
+}
+
+/// Output file name used when neither `--filename-template` nor the config sets one
+const DEFAULT_FILENAME_TEMPLATE: &str = "{ticker}_{timeframe}.{ext}";
+
+/// Placeholders a filename template may use
+const FILENAME_PLACEHOLDERS: [&str; 7] = ["ticker", "timeframe", "date", "time", "ts", "id", "ext"];
+
+/// Replace every `{name}` in a filename template with `value(name)`.
+/// Fails on an unknown placeholder or an unbalanced brace.
+fn expand_filename_template(
+    template: &str,
+    mut value: impl FnMut(&str) -> Option<String>,
+) -> Result<String, String> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find(['{', '}']) {
+        expanded.push_str(&rest[..open]);
+        let placeholder = &rest[open..];
+        let close = placeholder
+            .find('}')
+            .filter(|_| placeholder.starts_with('{'))
+            .ok_or_else(|| format!("unbalanced brace in filename template '{}'", template))?;
+        let name = &placeholder[1..close];
+        let substituted = value(name).ok_or_else(|| {
+            format!(
+                "unknown placeholder {{{}}} in filename template '{}' (expected one of {})",
+                name,
+                template,
+                FILENAME_PLACEHOLDERS.map(|p| format!("{{{}}}", p)).join(", ")
+            )
+        })?;
+        expanded.push_str(&substituted);
+        rest = &placeholder[close + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Check a filename template up front, so a bad `--filename-template` stops the
+/// service at startup instead of failing every request
+fn check_filename_template(template: &str) -> Result<(), String> {
+    let expanded = expand_filename_template(template, |name| {
+        FILENAME_PLACEHOLDERS.contains(&name).then(|| "x".to_string())
+    })?;
+    checked_output_path(&expanded).map(|_| ()).map_err(|_| {
+        format!(
+            "filename template '{}' must expand to a relative path inside the output directory",
+            template
+        )
+    })
+}
+
+/// The output path of a request relative to the output directory. Ticker, timeframe
+/// and id are sanitized before substitution; the result must stay inside the directory.
+/// With `append_timestamp` the render time in milliseconds follows the file's stem.
+fn output_file_name(
+    template: &str,
+    request_id: &str,
+    data: &ChartData,
+    format: ImageFormat,
+    rendered_at: DateTime<Local>,
+) -> Result<String, String> {
+    let expanded = expand_filename_template(template, |name| {
+        Some(match name {
+            "ticker" => sanitize_path_component(&data.ticker),
+            "timeframe" => sanitize_path_component(&data.timeframe),
+            "date" => rendered_at.format("%Y-%m-%d").to_string(),
+            "time" => rendered_at.format("%H%M%S").to_string(),
+            "ts" => rendered_at.timestamp_millis().to_string(),
+            "id" => sanitize_path_component(request_id),
+            "ext" => format.extension().to_string(),
+            _ => return None,
+        })
+    })?;
+    let expanded = if data.append_timestamp {
+        let ts = rendered_at.timestamp_millis();
+        // The extension is only looked for in the last path component
+        match expanded.rsplit_once('.') {
+            Some((stem, ext)) if !ext.contains('/') && !stem.ends_with('/') => {
+                format!("{}_{}.{}", stem, ts, ext)
+            }
+            _ => format!("{}_{}", expanded, ts),
+        }
+    } else {
+        expanded
+    };
+    checked_output_path(&expanded).map(str::to_string)
+}
+
+/// Reject a relative output path that is empty, absolute, or has an empty, `.`
+/// or `..` component, any of which could name something outside the output directory
+fn checked_output_path(path: &str) -> Result<&str, String> {
+    let escapes = path.is_empty()
+        || path.split(['/', '\\']).any(|c| c.is_empty() || c == "." || c == "..")
+        || !Path::new(path)
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+    if escapes {
+        return Err(format!(
+            "output filename '{}' must be a relative path inside the output directory",
+            path
+        ));
+    }
+    Ok(path)
 }
 
 /// Maximum concurrent chart threads
 static ACTIVE_THREADS: AtomicUsize = AtomicUsize::new(0);
 const MAX_CONCURRENT_CHARTS: usize = 4;
 
-// ─── Main Logic ─────────────────────────────────────────────────────────────────
+/// Number of most recent successful render times the p95 is taken over
+const RENDER_SAMPLE_WINDOW: usize = 512;
+
+/// Upper bounds (seconds) of the duration histogram buckets
+const DURATION_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Steps of a chart request that are timed separately
+#[derive(Debug, Clone, Copy)]
+enum Stage {
+    /// Decoding the payload into a request
+    Parse,
+    /// Converting timestamps and validating the candle rows
+    Validate,
+    /// Drawing the chart
+    Render,
+    /// Encoding the image
+    Encode,
+    /// Saving the image to the output directory
+    Write,
+}
 
-fn main() -> Result<(), Box<dyn Error>> {
-    // Phase 6: Initialize structured logging
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
-        .init();
+impl Stage {
+    const ALL: [Stage; 5] = [
+        Stage::Parse,
+        Stage::Validate,
+        Stage::Render,
+        Stage::Encode,
+        Stage::Write,
+    ];
 
-    // Get the output directory from config file
-    let output_dir = get_output_directory()?;
+    fn as_str(self) -> &'static str {
+        match self {
+            Stage::Parse => "parse",
+            Stage::Validate => "validate",
+            Stage::Render => "render",
+            Stage::Encode => "encode",
+            Stage::Write => "write",
+        }
+    }
+}
 
-    info!("[INIT] Using output directory: {}", output_dir);
+/// Lock-free duration histogram over `DURATION_BUCKETS`, exported in Prometheus form
+struct Histogram {
+    /// Observations per bucket (not cumulative); the last slot is above every bound
+    buckets: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
 
-    let context = zmq::Context::new();
-    let socket = context.socket(zmq::DEALER)?;
-    socket.set_identity(b"rustcharts")?;
-    let endpoint = "tcp://127.0.0.1:6565";
-    info!("[INIT] Connecting to {} as 'rustcharts'…", endpoint);
-    socket.connect(endpoint)?;
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; DURATION_BUCKETS.len() + 1],
+            sum_micros: AtomicU64::new(0),
+        }
+    }
 
-    info!("[READY] Awaiting incoming chart messages…");
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
 
-    // Phase 4B: Share ZMQ context for notifications
-    let zmq_ctx = Arc::new(context);
+    /// Append the `_bucket`, `_sum` and `_count` series; `labels` is either empty
+    /// or a `name="value",` list that goes before `le`
+    fn write_prometheus(&self, out: &mut String, name: &str, labels: &str) {
+        use std::fmt::Write as _;
+        let mut cumulative = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = DURATION_BUCKETS.get(i).map_or("+Inf".to_string(), |b| b.to_string());
+            let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, labels, le, cumulative);
+        }
+        let labels = labels.trim_end_matches(',');
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, cumulative);
+    }
+}
 
-    loop {
-        let frames = socket.recv_multipart(0)?;
-        let now = Local::now().format("%Y-%m-%d %H:%M:%S");
+/// Service-wide counters, updated by chart threads and reported by `{"type":"stats"}`
+/// and, when enabled, the Prometheus `/metrics` listener
+struct ServiceStats {
+    received: AtomicU64,
+    rendered: AtomicU64,
+    failed: AtomicU64,
+    render_ms_total: AtomicU64,
+    /// Durations of the last `RENDER_SAMPLE_WINDOW` successful renders, oldest first
+    recent_render_ms: Mutex<VecDeque<u64>>,
+    /// Error replies to chart requests, indexed by `ErrorCode`; unlike `failed`
+    /// this includes requests rejected before rendering
+    failed_by_code: [AtomicU64; ErrorCode::ALL.len()],
+    /// Durations of successful chart requests
+    render_seconds: Histogram,
+    /// Durations of each stage, indexed by `Stage`
+    stage_seconds: [Histogram; Stage::ALL.len()],
+    /// Images deleted by the retention policy
+    retention_removed: AtomicU64,
+}
 
-        match frames.get(1).and_then(|f| str::from_utf8(f).ok()) {
-            Some(json_str) => {
-                match from_str::<ChartRequest>(json_str) {
-                    Ok(req) => {
-                        info!(
-                            "╔══════════════════════════════════════════════════════════════════════"
-                        );
+static STATS: ServiceStats = ServiceStats {
+    received: AtomicU64::new(0),
+    rendered: AtomicU64::new(0),
+    failed: AtomicU64::new(0),
+    render_ms_total: AtomicU64::new(0),
+    recent_render_ms: Mutex::new(VecDeque::new()),
+    failed_by_code: [const { AtomicU64::new(0) }; ErrorCode::ALL.len()],
+    render_seconds: Histogram::new(),
+    stage_seconds: [const { Histogram::new() }; Stage::ALL.len()],
+    retention_removed: AtomicU64::new(0),
+};
+
+impl ServiceStats {
+    /// Count a finished render; only successful renders feed the average, p95 and histogram
+    fn record_render(&self, elapsed: Duration, failure: Option<ErrorCode>) {
+        match failure {
+            None => {
+                let elapsed_ms = elapsed.as_millis() as u64;
+                self.rendered.fetch_add(1, Ordering::Relaxed);
+                self.render_ms_total.fetch_add(elapsed_ms, Ordering::Relaxed);
+                self.render_seconds.observe(elapsed);
+                let mut recent = self.recent_render_ms.lock().unwrap_or_else(|e| e.into_inner());
+                if recent.len() == RENDER_SAMPLE_WINDOW {
+                    recent.pop_front();
+                }
+                recent.push_back(elapsed_ms);
+            }
+            Some(code) => {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                self.record_failure(code);
+            }
+        }
+    }
 
-                        info!(
-                            "[{}] ▶ New Chart Request for {} @ {} [{} candles]",
-                            now,
-                            req.2.ticker,
-                            req.2.timeframe,
-                            req.2.data.len()
-                        );
-                        log_data_summary(&req.2);
+    /// Count an error reply to a chart request
+    fn record_failure(&self, code: ErrorCode) {
+        self.failed_by_code[code as usize].fetch_add(1, Ordering::Relaxed);
+    }
 
-                        // Phase 2: Concurrent thread limit
-                        let current = ACTIVE_THREADS.load(Ordering::SeqCst);
-                        if current >= MAX_CONCURRENT_CHARTS {
-                            warn!(
-                                "[CHART] Dropping request for {} — {} threads active (max {})",
-                                req.2.ticker, current, MAX_CONCURRENT_CHARTS
-                            );
-                            continue;
-                        }
+    fn record_stage(&self, stage: Stage, elapsed: Duration) {
+        self.stage_seconds[stage as usize].observe(elapsed);
+    }
 
-                        // Phase 5B: Use Arc instead of clone
-                        let chart_data = Arc::new(req.2);
-                        let output_dir_clone = output_dir.clone();
-                        let zmq_ctx_clone = Arc::clone(&zmq_ctx);
+    /// 95th percentile of the recent successful render times (nearest rank), 0 before any
+    fn p95_render_ms(&self) -> u64 {
+        let mut recent: Vec<u64> = self
+            .recent_render_ms
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .copied()
+            .collect();
+        if recent.is_empty() {
+            return 0;
+        }
+        recent.sort_unstable();
+        let rank = (recent.len() * 95).div_ceil(100);
+        recent[rank - 1]
+    }
 
-                        ACTIVE_THREADS.fetch_add(1, Ordering::SeqCst);
+    /// Request and render counters shared by the stats reply and the PING probe
+    fn metrics(&self, uptime: Duration) -> serde_json::Map<String, serde_json::Value> {
+        let rendered = self.rendered.load(Ordering::Relaxed);
+        let avg_render_ms = match rendered {
+            0 => 0.0,
+            n => self.render_ms_total.load(Ordering::Relaxed) as f64 / n as f64,
+        };
+        let mut fields = serde_json::Map::new();
+        fields.insert("received".to_string(), self.received.load(Ordering::Relaxed).into());
+        fields.insert("rendered".to_string(), rendered.into());
+        fields.insert("failed".to_string(), self.failed.load(Ordering::Relaxed).into());
+        fields.insert("avg_render_ms".to_string(), avg_render_ms.into());
+        fields.insert("p95_render_ms".to_string(), self.p95_render_ms().into());
+        fields.insert("uptime_secs".to_string(), uptime.as_secs().into());
+        fields
+    }
 
-                        thread::spawn(move || {
-                            // Phase 2: Wrap in catch_unwind for panic protection
-                            let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
-                                handle_chart_request(&chart_data, &output_dir_clone, &zmq_ctx_clone)
-                            }));
+    fn snapshot(&self, uptime: Duration) -> serde_json::Value {
+        let mut fields = self.metrics(uptime);
+        fields.insert("type".to_string(), "stats".into());
+        fields.insert("identity".to_string(), SERVICE_IDENTITY.into());
+        fields.insert("inflight".to_string(), ACTIVE_THREADS.load(Ordering::SeqCst).into());
+        fields.insert(
+            "retention_removed".to_string(),
+            self.retention_removed.load(Ordering::Relaxed).into(),
+        );
+        serde_json::Value::Object(fields)
+    }
 
-                            ACTIVE_THREADS.fetch_sub(1, Ordering::SeqCst);
+    /// Every metric in the Prometheus text exposition format
+    fn prometheus(&self, uptime: Duration) -> String {
+        use std::fmt::Write as _;
+        let mut out = String::new();
+        let header = |out: &mut String, name: &str, kind: &str, help: &str| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+        };
 
-                            match result {
-                                Ok(Ok(())) => {}
-                                Ok(Err(e)) => error!("[CHART] Chart generation failed: {}", e),
-                                Err(_) => error!("[CHART] Chart thread panicked"),
-                            }
-                        });
-                    }
-                    Err(e) => {
-                        error!("[{}] Failed to parse ChartRequest: {}", now, e);
-                    }
+        header(
+            &mut out,
+            "charts_received_total",
+            "counter",
+            "Chart requests received, counting each batch item.",
+        );
+        let _ = writeln!(out, "charts_received_total {}", self.received.load(Ordering::Relaxed));
+        header(
+            &mut out,
+            "charts_rendered_total",
+            "counter",
+            "Chart requests rendered successfully.",
+        );
+        let _ = writeln!(out, "charts_rendered_total {}", self.rendered.load(Ordering::Relaxed));
+
+        header(
+            &mut out,
+            "charts_failed_total",
+            "counter",
+            "Chart requests answered with an error.",
+        );
+        for code in ErrorCode::ALL {
+            let failed = self.failed_by_code[code as usize].load(Ordering::Relaxed);
+            let _ = writeln!(out, "charts_failed_total{{code=\"{}\"}} {}", code.as_str(), failed);
+        }
+
+        header(
+            &mut out,
+            "render_duration_seconds",
+            "histogram",
+            "Time to handle a successful chart request.",
+        );
+        self.render_seconds.write_prometheus(&mut out, "render_duration_seconds", "");
+
+        let name = "charts_stage_duration_seconds";
+        header(&mut out, name, "histogram", "Time spent in each stage of a chart request.");
+        for stage in Stage::ALL {
+            let labels = format!("stage=\"{}\",", stage.as_str());
+            self.stage_seconds[stage as usize].write_prometheus(&mut out, name, &labels);
+        }
+
+        header(
+            &mut out,
+            "charts_queue_depth",
+            "gauge",
+            "Chart requests accepted and not yet finished.",
+        );
+        let _ = writeln!(out, "charts_queue_depth {}", ACTIVE_THREADS.load(Ordering::SeqCst));
+        header(
+            &mut out,
+            "charts_retention_removed_total",
+            "counter",
+            "Images deleted by the retention policy.",
+        );
+        let removed = self.retention_removed.load(Ordering::Relaxed);
+        let _ = writeln!(out, "charts_retention_removed_total {}", removed);
+        header(&mut out, "charts_uptime_seconds", "gauge", "Seconds since the service started.");
+        let _ = writeln!(out, "charts_uptime_seconds {}", uptime.as_secs());
+        out
+    }
+}
+
+/// Serve `STATS` in the Prometheus text format on `GET /metrics` at `addr`, on a
+/// thread of its own. Failing to bind only disables the listener; rendering and
+/// the `{"type":"stats"}` reply are unaffected.
+fn spawn_metrics_listener(addr: &str, started: Instant) {
+    let listener = match std::net::TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("[METRICS] Cannot listen on {}, metrics endpoint disabled: {}", addr, e);
+            return;
+        }
+    };
+    info!("[METRICS] Serving Prometheus metrics on http://{}/metrics", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("[METRICS] Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            // A scraper that never sends its request must not stall the listener
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(5)));
+            let mut request = [0u8; 1024];
+            let len = stream.read(&mut request).unwrap_or(0);
+            let request_line = str::from_utf8(&request[..len])
+                .unwrap_or("")
+                .lines()
+                .next()
+                .unwrap_or("");
+            let (status, content_type, body) = match request_line.split_whitespace().nth(1) {
+                Some("/metrics") if request_line.starts_with("GET ") => (
+                    "200 OK",
+                    "text/plain; version=0.0.4",
+                    STATS.prometheus(started.elapsed()),
+                ),
+                _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                status,
+                content_type,
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()) {
+                debug!("[METRICS] Failed to send metrics: {}", e);
+            }
+        }
+    });
+}
+
+/// How long the main loop waits for a message before flushing queued replies
+const POLL_INTERVAL_MS: i64 = 100;
+
+/// Multipart frames queued by chart threads for the main loop to send.
+/// ZMQ sockets are not thread-safe, so only the main loop touches the socket.
+type ReplyFrames = Vec<Vec<u8>>;
+
+/// Wire encoding of a request payload. Replies are sent back in the same encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Json,
+    MessagePack,
+}
+
+impl Encoding {
+    fn encode(self, body: &serde_json::Value) -> Vec<u8> {
+        match self {
+            Encoding::Json => body.to_string().into_bytes(),
+            Encoding::MessagePack => rmp_serde::to_vec_named(body).unwrap_or_else(|e| {
+                error!("[REPLY] Failed to encode MessagePack reply, sending JSON: {}", e);
+                body.to_string().into_bytes()
+            }),
+        }
+    }
+}
+
+/// Where a request's replies go: the shared reply queue, the routing envelope
+/// (see `split_frames`) prepended to every reply, and the encoding of the body.
+/// Replies to batch items also carry the id of their batch.
+#[derive(Clone)]
+struct ReplySink {
+    tx: mpsc::Sender<ReplyFrames>,
+    envelope: ReplyFrames,
+    encoding: Encoding,
+    batch_id: Option<String>,
+}
+
+impl ReplySink {
+    /// Queue `body`, plus an optional raw attachment frame, behind the envelope.
+    /// Returns false if the main loop has gone away.
+    fn send(&self, body: &serde_json::Value, attachment: Option<Vec<u8>>) -> bool {
+        let mut frames = self.envelope.clone();
+        match (&self.batch_id, body) {
+            (Some(batch_id), serde_json::Value::Object(fields)) => {
+                let mut fields = fields.clone();
+                fields.insert("batch_id".to_string(), batch_id.clone().into());
+                frames.push(self.encoding.encode(&serde_json::Value::Object(fields)));
+            }
+            _ => frames.push(self.encoding.encode(body)),
+        }
+        frames.extend(attachment);
+        self.tx.send(frames).is_ok()
+    }
+
+    /// Queue pre-encoded body frames behind the envelope
+    fn send_raw(&self, body: ReplyFrames) -> bool {
+        let mut frames = self.envelope.clone();
+        frames.extend(body);
+        self.tx.send(frames).is_ok()
+    }
+}
+
+/// Machine-readable error codes carried by error replies.
+/// These strings are part of the wire protocol; do not rename them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The payload was not valid JSON or did not match `ChartRequest`
+    ParseError,
+    /// The request parsed but its data cannot be charted
+    ValidationError,
+    /// Plotters failed (or panicked) while drawing the chart
+    RenderError,
+    /// The output directory or image file could not be written
+    IoError,
+    /// Rendering ran past `charts.render_timeout_secs` and was abandoned
+    Timeout,
+    /// An append request has no full request of its series to merge into
+    SnapshotRequired,
+    /// Every chart thread was busy, so the request was turned away unrendered
+    Busy,
+}
+
+impl ErrorCode {
+    const ALL: [ErrorCode; 7] = [
+        ErrorCode::ParseError,
+        ErrorCode::ValidationError,
+        ErrorCode::RenderError,
+        ErrorCode::IoError,
+        ErrorCode::Timeout,
+        ErrorCode::SnapshotRequired,
+        ErrorCode::Busy,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::ParseError => "PARSE_ERROR",
+            ErrorCode::ValidationError => "VALIDATION_ERROR",
+            ErrorCode::RenderError => "RENDER_ERROR",
+            ErrorCode::IoError => "IO_ERROR",
+            ErrorCode::Timeout => "TIMEOUT",
+            ErrorCode::SnapshotRequired => "SNAPSHOT_REQUIRED",
+            ErrorCode::Busy => "BUSY",
+        }
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// A failed request: the code reported to the sender plus a human-readable detail
+#[derive(Debug)]
+struct RequestError {
+    code: ErrorCode,
+    detail: String,
+}
+
+impl RequestError {
+    fn new(code: ErrorCode, detail: impl fmt::Display) -> Self {
+        RequestError {
+            code,
+            detail: detail.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code.as_str(), self.detail)
+    }
+}
+
+impl From<std::io::Error> for RequestError {
+    fn from(e: std::io::Error) -> Self {
+        RequestError::new(ErrorCode::IoError, e)
+    }
+}
+
+/// Map a chart failure onto the error code reported to the requester
+impl From<ChartError> for RequestError {
+    fn from(e: ChartError) -> Self {
+        let code = match &e {
+            ChartError::Io(_) => ErrorCode::IoError,
+            ChartError::InvalidData { .. } => ErrorCode::ValidationError,
+            ChartError::Backend(_) | ChartError::Layout(_) | ChartError::Panic(_) => {
+                ErrorCode::RenderError
+            }
+            ChartError::Timeout(_) => ErrorCode::Timeout,
+        };
+        RequestError::new(code, e)
+    }
+}
+
+/// Text of a panic payload, which is a `&str` or `String` for `panic!` with a message
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+/// Build the body of an error reply:
+/// `{"status":"error","code":...,"detail":...,"request_id":...}`
+fn error_reply(request_id: Option<&str>, err: &RequestError) -> serde_json::Value {
+    serde_json::json!({
+        "status": "error",
+        "code": err.code,
+        "detail": err.detail,
+        "request_id": request_id,
+    })
+}
+
+/// Best-effort request id from a payload that failed to parse as a `ChartRequest`
+fn request_id_from_raw(payload: &[u8], encoding: Encoding) -> Option<String> {
+    let value: serde_json::Value = match encoding {
+        Encoding::Json => serde_json::from_slice(payload).ok()?,
+        Encoding::MessagePack => rmp_serde::from_slice(payload).ok()?,
+    };
+    value.get(0)?.as_str().map(str::to_string)
+}
+
+/// zstd frame magic number 0xFD2FB528, as it appears on the wire (little-endian)
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/// Decompress a zstd or gzip payload, recognised by its magic bytes; anything else is
+/// returned unchanged. Output is capped at `max_bytes`, so a decompression bomb is
+/// rejected after reading one byte past the cap rather than filling memory.
+fn decompress_payload(payload: &[u8], max_bytes: usize) -> Result<Cow<'_, [u8]>, String> {
+    let (kind, decoder): (&str, Box<dyn Read + '_>) = if payload.starts_with(&ZSTD_MAGIC) {
+        let decoder = zstd::stream::read::Decoder::new(payload)
+            .map_err(|e| format!("failed to start zstd decoder: {}", e))?;
+        ("zstd", Box::new(decoder))
+    } else if payload.starts_with(&GZIP_MAGIC) {
+        ("gzip", Box::new(flate2::read::GzDecoder::new(payload)))
+    } else {
+        return Ok(Cow::Borrowed(payload));
+    };
+
+    let mut decompressed = Vec::new();
+    decoder
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| format!("failed to decompress {} payload: {}", kind, e))?;
+    if decompressed.len() > max_bytes {
+        return Err(format!(
+            "{} payload decompresses to more than the {} byte limit",
+            kind, max_bytes
+        ));
+    }
+
+    debug!(
+        "Decompressed {} payload: {} -> {} bytes",
+        kind,
+        payload.len(),
+        decompressed.len()
+    );
+    Ok(Cow::Owned(decompressed))
+}
+
+/// A decoded request payload
+enum Incoming {
+    Control(ControlMessage),
+    Chart(Box<ChartRequest>),
+}
+
+/// A top-level array of chart requests is shorthand for a batch envelope,
+/// named after its first item since it carries no id of its own
+fn bare_batch(value: Option<serde_json::Value>) -> Option<Incoming> {
+    let serde_json::Value::Array(items) = value? else {
+        return None;
+    };
+    if items.is_empty() || !items.iter().all(serde_json::Value::is_array) {
+        return None;
+    }
+    let first_id = items[0].get(0).and_then(|id| id.as_str()).unwrap_or("unnamed");
+    Some(Incoming::Control(ControlMessage::Batch {
+        id: format!("batch-{}", first_id),
+        requests: items,
+    }))
+}
+
+/// Decode a payload as JSON, falling back to MessagePack when it is not valid JSON.
+/// In either encoding a map is a control message and an array is a chart request
+/// (or, when every element is itself an array, a batch of them).
+/// On failure, the error is paired with the encoding the payload most likely used.
+fn decode_payload(payload: &[u8]) -> Result<(Incoming, Encoding), (String, Encoding)> {
+    let mut json_error = None;
+    if let Ok(text) = str::from_utf8(payload) {
+        let decoded = if text.trim_start().starts_with('{') {
+            from_str::<ControlMessage>(text).map(Incoming::Control)
+        } else {
+            from_str::<ChartRequest>(text)
+                .map(|req| Incoming::Chart(Box::new(req)))
+                .or_else(|e| bare_batch(from_str(text).ok()).ok_or(e))
+        };
+        match decoded {
+            Ok(msg) => return Ok((msg, Encoding::Json)),
+            Err(e) => match from_str::<serde_json::Value>(text) {
+                // Well-formed JSON of the wrong shape is not going to be MessagePack
+                Ok(value) => {
+                    let detail = if value.is_object() {
+                        e.to_string()
+                    } else {
+                        chart_request_problem(&value)
+                    };
+                    return Err((detail, Encoding::Json));
+                }
+                Err(_) => json_error = Some(e.to_string()),
+            },
+        }
+    }
+
+    // MessagePack map markers: fixmap, map16, map32
+    let decoded = if matches!(payload.first(), Some(0x80..=0x8f | 0xde | 0xdf)) {
+        rmp_serde::from_slice::<ControlMessage>(payload).map(Incoming::Control)
+    } else {
+        rmp_serde::from_slice::<ChartRequest>(payload)
+            .map(|req| Incoming::Chart(Box::new(req)))
+            .or_else(|e| bare_batch(rmp_serde::from_slice(payload).ok()).ok_or(e))
+    };
+    match (decoded, json_error) {
+        (Ok(msg), _) => Ok((msg, Encoding::MessagePack)),
+        // Text that is neither: the JSON error is the useful one
+        (Err(_), Some(json_error)) => Err((json_error, Encoding::Json)),
+        (Err(e), None) => {
+            let detail = match rmp_serde::from_slice::<serde_json::Value>(payload) {
+                Ok(value) if !value.is_object() => chart_request_problem(&value),
+                _ => e.to_string(),
+            };
+            Err((detail, Encoding::MessagePack))
+        }
+    }
+}
+
+thread_local! {
+    /// Request id of the chart being handled on this thread, prefixed to every log line
+    static CURRENT_REQUEST: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Tags every log line written on this thread with a request id until dropped
+struct RequestLogScope;
+
+impl RequestLogScope {
+    fn enter(request_id: &str) -> Self {
+        CURRENT_REQUEST.with(|current| *current.borrow_mut() = Some(request_id.to_string()));
+        RequestLogScope
+    }
+}
+
+impl Drop for RequestLogScope {
+    fn drop(&mut self) {
+        CURRENT_REQUEST.with(|current| *current.borrow_mut() = None);
+    }
+}
+
+/// env_logger's default layout, plus a `[req <id>]` prefix while a request is in scope
+fn format_log_line(
+    buf: &mut env_logger::fmt::Formatter,
+    record: &log::Record,
+) -> std::io::Result<()> {
+    let level_style = buf.default_level_style(record.level());
+    write!(
+        buf,
+        "[{} {level_style}{:<5}{level_style:#} {}] ",
+        buf.timestamp(),
+        record.level(),
+        record.target()
+    )?;
+    CURRENT_REQUEST.with(|current| match current.borrow().as_deref() {
+        Some(id) => writeln!(buf, "[req {}] {}", id, record.args()),
+        None => writeln!(buf, "{}", record.args()),
+    })
+}
+
+/// `--log-json` log format: one object per line with the request id as its own field,
+/// e.g. `{"ts":"...","level":"INFO","target":"corky_charts","request_id":"req-1","msg":"..."}`
+fn format_log_json(
+    buf: &mut env_logger::fmt::Formatter,
+    record: &log::Record,
+) -> std::io::Result<()> {
+    let request_id = CURRENT_REQUEST.with(|current| current.borrow().clone());
+    let line = serde_json::json!({
+        "ts": buf.timestamp().to_string(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "request_id": request_id,
+        "msg": record.args().to_string(),
+    });
+    writeln!(buf, "{}", line)
+}
+
+/// Recently dispatched requests, so a broker retransmission of the same request
+/// within the TTL is acknowledged without rendering it again. When full, the least
+/// recently dispatched or retransmitted request is forgotten first.
+struct RecentRequests {
+    capacity: usize,
+    ttl: Duration,
+    /// (request id, payload hash, first seen), least recently used first
+    entries: VecDeque<(String, u64, Instant)>,
+}
+
+impl RecentRequests {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        RecentRequests {
+            capacity,
+            ttl,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn payload_hash(payload: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        payload.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Drop entries older than the TTL. A hit moves an entry to the back without
+    /// renewing it, so they are not in age order.
+    fn expire(&mut self) {
+        let ttl = self.ttl;
+        self.entries.retain(|(_, _, seen)| seen.elapsed() <= ttl);
+    }
+
+    /// True if the same id with an identical payload was dispatched within the TTL.
+    /// The TTL still counts from the first dispatch, but a hit makes the entry the
+    /// most recently used.
+    fn is_duplicate(&mut self, request_id: &str, payload: &[u8]) -> bool {
+        self.expire();
+        let hash = Self::payload_hash(payload);
+        let Some(index) = self
+            .entries
+            .iter()
+            .position(|(id, h, _)| id == request_id && *h == hash)
+        else {
+            return false;
+        };
+        if let Some(entry) = self.entries.remove(index) {
+            self.entries.push_back(entry);
+        }
+        true
+    }
+
+    /// Remember a dispatched request, evicting the least recently used entry when full
+    fn remember(&mut self, request_id: &str, payload: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((
+            request_id.to_string(),
+            Self::payload_hash(payload),
+            Instant::now(),
+        ));
+    }
+}
+
+/// The last full data of each ticker+timeframe, so a `"mode": "append"` request only
+/// has to carry the candles that are new or changed since
+struct SeriesStore {
+    capacity: usize,
+    max_candles: usize,
+    entries: HashMap<(String, String), StoredSeries>,
+}
+
+struct StoredSeries {
+    /// Columns the rows were sent with; an append must use the same ones
+    cols: Vec<String>,
+    /// Rows in millisecond timestamps, at most `max_candles` of the newest
+    rows: Vec<Vec<f64>>,
+    last_used: Instant,
+}
+
+impl SeriesStore {
+    fn new(capacity: usize, max_candles: usize) -> Self {
+        SeriesStore {
+            capacity,
+            max_candles,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Remember the data of a full request, or replace the rows of an append request with
+    /// the stored series merged with them. Timestamps must already be in milliseconds.
+    fn apply(&mut self, data: &mut ChartData) -> Result<(), RequestError> {
+        if !data.panels.is_empty() {
+            if data.mode == DataMode::Append {
+                return Err(RequestError::new(
+                    ErrorCode::ValidationError,
+                    "append mode does not apply to panels; send each series on its own",
+                ));
+            }
+            return Ok(());
+        }
+        let key = (data.ticker.clone(), data.timeframe.clone());
+        match data.mode {
+            DataMode::Full => {
+                if self.capacity == 0 {
+                    return Ok(());
+                }
+                let mut rows = data.data.clone();
+                rows.drain(..rows.len().saturating_sub(self.max_candles));
+                self.store(key, data.cols.clone(), rows);
+            }
+            DataMode::Append => {
+                let Some(series) = self.entries.get_mut(&key) else {
+                    let detail = if self.capacity == 0 {
+                        "append requests are disabled (charts.series_cache_entries is 0); \
+                         send a full snapshot"
+                            .to_string()
+                    } else {
+                        format!(
+                            "no full snapshot of {} @ {} to append to; send one with \
+                             \"mode\": \"full\"",
+                            data.ticker, data.timeframe
+                        )
+                    };
+                    return Err(RequestError::new(ErrorCode::SnapshotRequired, detail));
+                };
+                if series.cols != data.cols {
+                    return Err(RequestError::new(
+                        ErrorCode::SnapshotRequired,
+                        format!(
+                            "cols {:?} differ from the snapshot's {:?}; send a full snapshot",
+                            data.cols, series.cols
+                        ),
+                    ));
+                }
+                let update = std::mem::take(&mut data.data);
+                debug!(
+                    "[SERIES] Merging {} candles into {} stored for {} @ {}",
+                    update.len(),
+                    series.rows.len(),
+                    data.ticker,
+                    data.timeframe
+                );
+                merge_rows(&mut series.rows, update, self.max_candles);
+                series.last_used = Instant::now();
+                data.data = series.rows.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep `rows` for `key`, evicting the least recently used series when full
+    fn store(&mut self, key: (String, String), cols: Vec<String>, rows: Vec<Vec<f64>>) {
+        if !self.entries.contains_key(&key)
+            && self.entries.len() >= self.capacity
+            && let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, series)| series.last_used)
+                .map(|(k, _)| k.clone())
+        {
+            self.entries.remove(&oldest);
+        }
+        self.entries.insert(
+            key,
+            StoredSeries {
+                cols,
+                rows,
+                last_used: Instant::now(),
+            },
+        );
+    }
+}
+
+/// Merge `update` into `rows`: a row with the timestamp of an existing one replaces it,
+/// any other is added, and the result is kept in time order with at most `max_rows` of
+/// the newest rows. Rows without a timestamp cannot be placed and are dropped.
+fn merge_rows(rows: &mut Vec<Vec<f64>>, update: Vec<Vec<f64>>, max_rows: usize) {
+    let timestamp = |row: &[f64]| row.first().map(|ts| *ts as i64);
+    let mut index: HashMap<i64, usize> = rows
+        .iter()
+        .enumerate()
+        .filter_map(|(i, row)| Some((timestamp(row)?, i)))
+        .collect();
+    let mut in_order = rows.windows(2).all(|pair| timestamp(&pair[0]) <= timestamp(&pair[1]));
+    for row in update {
+        let Some(ts) = timestamp(&row) else {
+            warn!("[SERIES] Dropping an appended row without a timestamp");
+            continue;
+        };
+        match index.get(&ts) {
+            Some(&i) => rows[i] = row,
+            None => {
+                let last = rows.last().and_then(|last| timestamp(last));
+                in_order &= last.is_none_or(|last| last < ts);
+                index.insert(ts, rows.len());
+                rows.push(row);
+            }
+        }
+    }
+    // Late or unsorted candles are put back in place; the sort is stable
+    if !in_order {
+        rows.sort_by_key(|row| timestamp(row));
+    }
+    rows.drain(..rows.len().saturating_sub(max_rows));
+}
+
+// ─── Command Line ───────────────────────────────────────────────────────────────
+
+/// Identity of the DEALER socket, also reported in heartbeats and pongs
+const SERVICE_IDENTITY: &str = "rustcharts";
+
+/// Broker endpoint for DEALER mode and outgoing telegram notifications
+const BROKER_ENDPOINT: &str = "tcp://127.0.0.1:6565";
+
+/// Topic prefix subscribed to in SUB mode when `--topics` is not given
+const DEFAULT_TOPIC_PREFIX: &str = "charts.";
+
+/// Which socket chart requests arrive on
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum IngestMode {
+    /// DEALER connected to the ROUTER broker; replies are sent back on it
+    Dealer,
+    /// SUB connected to a PUB broadcaster; there is no reply path
+    Sub,
+    /// ROUTER bound locally so clients can talk to the service without a broker
+    Bind,
+}
+
+/// Default address for `--mode bind`, kept off the broker port so telegram
+/// notifications never loop back into the service
+const DEFAULT_BIND_ENDPOINT: &str = "tcp://127.0.0.1:6566";
+
+/// Options as given on the command line. Anything left unset falls back to the
+/// config file and then to the built-in default (see `resolve_args`).
+#[derive(Default)]
+struct CommandLine {
+    config_path: Option<String>,
+    print_default_config: bool,
+    mode: Option<IngestMode>,
+    endpoint: Option<String>,
+    topics: Vec<String>,
+    hello: Option<String>,
+    max_retries: Option<u32>,
+    log_level: Option<log::LevelFilter>,
+    log_json: bool,
+    filename_template: Option<String>,
+    input: Option<String>,
+}
+
+/// Connection and logging options in effect, after the config file and command line
+struct CliArgs {
+    mode: IngestMode,
+    endpoint: String,
+    topics: Vec<String>,
+    /// Payload sent to the broker after every (re)connect in dealer mode
+    hello: Option<String>,
+    /// Consecutive reconnect attempts before giving up; 0 retries forever
+    max_retries: u32,
+    /// Log level for every module, overriding the default level from `RUST_LOG`
+    log_level: Option<log::LevelFilter>,
+    /// Write each log record as one JSON object per line
+    log_json: bool,
+    /// Output file name template, checked for unknown placeholders
+    filename_template: String,
+    /// Render the single request in this file (`-` for stdin) and exit instead of serving
+    input: Option<String>,
+}
+
+const USAGE: &str = "usage: corky-charts [--config <path>] [--print-default-config] [--mode dealer|sub|bind] [--endpoint <addr>] [--topics <prefix>[,<prefix>...]] [--hello <message>] [--max-retries <n>] [--log-level <level>] [--log-json] [--filename-template <template>] [--input <path>|-]";
+
+/// Parse `--flag value` / `--flag=value` arguments (program name already skipped)
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<CommandLine, String> {
+    let mut cmd = CommandLine::default();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} requires a value\n{}", flag, USAGE))
+        };
+        match flag.as_str() {
+            "--config" => cmd.config_path = Some(value()?),
+            "--print-default-config" => cmd.print_default_config = true,
+            "--mode" => {
+                cmd.mode = Some(match value()?.as_str() {
+                    "dealer" => IngestMode::Dealer,
+                    "sub" => IngestMode::Sub,
+                    "bind" => IngestMode::Bind,
+                    other => return Err(format!("unknown mode '{}'\n{}", other, USAGE)),
+                })
+            }
+            "--endpoint" => cmd.endpoint = Some(value()?),
+            "--topics" => cmd.topics.extend(
+                value()?
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .map(str::to_string),
+            ),
+            "--hello" => cmd.hello = Some(value()?),
+            "--max-retries" => {
+                let raw = value()?;
+                cmd.max_retries = Some(
+                    raw.parse()
+                        .map_err(|_| format!("invalid --max-retries '{}'\n{}", raw, USAGE))?,
+                );
+            }
+            "--log-level" => {
+                let raw = value()?;
+                cmd.log_level = Some(
+                    raw.parse()
+                        .map_err(|_| format!("invalid --log-level '{}'\n{}", raw, USAGE))?,
+                );
+            }
+            "--log-json" => cmd.log_json = true,
+            "--filename-template" => cmd.filename_template = Some(value()?),
+            "--input" => cmd.input = Some(value()?),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            other => return Err(format!("unknown argument '{}'\n{}", other, USAGE)),
+        }
+    }
+
+    Ok(cmd)
+}
+
+/// Combine the command line with the config file: a flag wins over the file,
+/// and the file wins over the built-in default
+fn resolve_args(cmd: CommandLine, file: &ChartsConfig) -> Result<CliArgs, String> {
+    let mode = cmd.mode.or(file.mode).unwrap_or(IngestMode::Dealer);
+
+    let endpoint = cmd
+        .endpoint
+        .or_else(|| file.endpoint.clone())
+        .unwrap_or_else(|| match mode {
+            IngestMode::Dealer | IngestMode::Sub => BROKER_ENDPOINT.to_string(),
+            IngestMode::Bind => DEFAULT_BIND_ENDPOINT.to_string(),
+        });
+
+    let mut topics = if cmd.topics.is_empty() {
+        file.topics.clone().unwrap_or_default()
+    } else {
+        cmd.topics
+    };
+    if topics.is_empty() {
+        topics.push(DEFAULT_TOPIC_PREFIX.to_string());
+    }
+
+    let log_level = match (cmd.log_level, file.log_level.as_deref()) {
+        (Some(level), _) => Some(level),
+        (None, Some(raw)) => Some(
+            raw.parse()
+                .map_err(|_| format!("invalid charts.log_level '{}' in config file", raw))?,
+        ),
+        (None, None) => None,
+    };
+
+    let filename_template = cmd
+        .filename_template
+        .or_else(|| file.filename_template.clone())
+        .unwrap_or_else(|| DEFAULT_FILENAME_TEMPLATE.to_string());
+    check_filename_template(&filename_template)?;
+
+    Ok(CliArgs {
+        mode,
+        endpoint,
+        topics,
+        hello: cmd.hello.or_else(|| file.hello.clone()),
+        max_retries: cmd.max_retries.or(file.max_retries).unwrap_or(0),
+        log_level,
+        log_json: cmd.log_json || file.log_json.unwrap_or(false),
+        filename_template,
+        input: cmd.input,
+    })
+}
+
+/// Split a received message into its reply envelope and payload.
+///
+/// Frame contract, request → reply, per mode:
+/// - `dealer`: `["", payload]` → `["", body, (image)]`. The broker's ROUTER adds and
+///   strips our identity, so only the empty delimiter is visible here.
+/// - `sub`: `[topic, payload]` → no reply; a SUB socket cannot send.
+/// - `bind`: `[identity, "", payload]` → `[identity, "", body, (image)]`. The ROUTER
+///   prepends the sender's identity, and replying with it routes back to that client.
+///
+/// The payload and reply body are JSON or MessagePack (see `decode_payload`).
+/// The payload is `None` if the frame is missing or (in bind mode) not preceded by
+/// the empty delimiter. The envelope is still returned so the sender can be told
+/// about the malformed message.
+fn split_frames(mode: IngestMode, frames: &[Vec<u8>]) -> (ReplyFrames, Option<&[u8]>) {
+    let frame = |idx: usize| frames.get(idx).map(Vec::as_slice);
+    match mode {
+        IngestMode::Dealer => (vec![Vec::new()], frame(1)),
+        IngestMode::Sub => (Vec::new(), frame(1)),
+        IngestMode::Bind => {
+            let identity = frames.first().cloned().unwrap_or_default();
+            let payload = match frames.get(1) {
+                Some(delimiter) if delimiter.is_empty() => frame(2),
+                _ => None,
+            };
+            (vec![identity, Vec::new()], payload)
+        }
+    }
+}
+
+// ─── Connection Management ──────────────────────────────────────────────────────
+
+/// Delay before the first reconnect attempt; doubled for each consecutive failure
+const RECONNECT_BASE_DELAY_MS: u64 = 500;
+/// Upper bound on the reconnect delay
+const RECONNECT_MAX_DELAY_MS: u64 = 30_000;
+
+/// The request socket, plus a monitor reporting connection events for modes that dial out
+struct Connection {
+    socket: zmq::Socket,
+    monitor: Option<zmq::Socket>,
+}
+
+/// Create the request socket for `args.mode` and connect (or bind) it.
+/// `generation` keeps each monitor's inproc endpoint unique across reconnects.
+fn open_connection(
+    ctx: &zmq::Context,
+    args: &CliArgs,
+    generation: usize,
+) -> zmq::Result<Connection> {
+    let socket = match args.mode {
+        IngestMode::Dealer => {
+            let socket = ctx.socket(zmq::DEALER)?;
+            socket.set_identity(SERVICE_IDENTITY.as_bytes())?;
+            info!(
+                "[INIT] Connecting to {} as '{}'…",
+                args.endpoint, SERVICE_IDENTITY
+            );
+            socket
+        }
+        IngestMode::Sub => {
+            let socket = ctx.socket(zmq::SUB)?;
+            for topic in &args.topics {
+                socket.set_subscribe(topic.as_bytes())?;
+            }
+            info!(
+                "[INIT] Subscribing to {} on topics {:?}…",
+                args.endpoint, args.topics
+            );
+            socket
+        }
+        IngestMode::Bind => {
+            let socket = ctx.socket(zmq::ROUTER)?;
+            info!("[INIT] Binding ROUTER on {}…", args.endpoint);
+            socket.bind(&args.endpoint)?;
+            return Ok(Connection {
+                socket,
+                monitor: None,
+            });
+        }
+    };
+
+    // Watch the connection so a lost broker can be noticed and greeted on reconnect.
+    // The monitor must be attached before connecting to see the first handshake.
+    let monitor_endpoint = format!("inproc://rustcharts-monitor-{}", generation);
+    socket.monitor(
+        &monitor_endpoint,
+        (zmq::SocketEvent::DISCONNECTED as i32) | (zmq::SocketEvent::HANDSHAKE_SUCCEEDED as i32),
+    )?;
+    let monitor = ctx.socket(zmq::PAIR)?;
+    monitor.connect(&monitor_endpoint)?;
+
+    socket.connect(&args.endpoint)?;
+
+    Ok(Connection {
+        socket,
+        monitor: Some(monitor),
+    })
+}
+
+/// Read one event off a socket monitor. The first frame holds a native-endian
+/// u16 event id followed by a u32 value; the second holds the peer endpoint.
+fn read_monitor_event(monitor: &zmq::Socket) -> zmq::Result<zmq::SocketEvent> {
+    let frames = monitor.recv_multipart(0)?;
+    let raw = frames
+        .first()
+        .filter(|f| f.len() >= 2)
+        .map(|f| u16::from_ne_bytes([f[0], f[1]]))
+        .unwrap_or(0);
+    Ok(zmq::SocketEvent::from_raw(raw))
+}
+
+/// Errors that leave the socket usable, so the loop simply tries again
+fn is_transient(e: zmq::Error) -> bool {
+    matches!(e, zmq::Error::EINTR | zmq::Error::EAGAIN)
+}
+
+/// Backoff before reconnect attempt number `attempt` (1-based)
+fn reconnect_delay(attempt: u32) -> Duration {
+    let factor = 1u64 << attempt.saturating_sub(1).min(16);
+    Duration::from_millis((RECONNECT_BASE_DELAY_MS * factor).min(RECONNECT_MAX_DELAY_MS))
+}
+
+/// Open the request socket, retrying with exponential backoff while it fails.
+/// `failures` counts consecutive failed attempts and is shared with the main loop,
+/// which resets it once a connection is confirmed.
+fn connect_with_backoff(
+    ctx: &zmq::Context,
+    args: &CliArgs,
+    failures: &mut u32,
+    generation: &mut usize,
+) -> Result<Connection, Box<dyn Error>> {
+    loop {
+        if *failures > 0 {
+            if args.max_retries != 0 && *failures > args.max_retries {
+                return Err(format!("giving up after {} reconnect attempts", args.max_retries).into());
+            }
+            let delay = reconnect_delay(*failures);
+            warn!(
+                "[CONN] Reconnecting in {:.1}s (attempt {}{})",
+                delay.as_secs_f64(),
+                failures,
+                if args.max_retries == 0 {
+                    String::new()
+                } else {
+                    format!(" of {}", args.max_retries)
+                }
+            );
+            thread::sleep(delay);
+        }
+
+        *generation += 1;
+        match open_connection(ctx, args, *generation) {
+            Ok(conn) => return Ok(conn),
+            Err(zmq::Error::ETERM) => return Err(zmq::Error::ETERM.into()),
+            Err(e) => {
+                error!("[CONN] Failed to open socket on {}: {}", args.endpoint, e);
+                *failures += 1;
+            }
+        }
+    }
+}
+
+// ─── Main Logic ─────────────────────────────────────────────────────────────────
+
+/// Everything needed to turn a received JSON payload into a chart thread.
+/// Shared by every ingest mode so they parse, dedupe and render identically.
+struct Dispatcher {
+    settings: Arc<Settings>,
+    zmq_ctx: Arc<zmq::Context>,
+    reply_tx: mpsc::Sender<ReplyFrames>,
+    recent_requests: RecentRequests,
+    /// Only the dispatcher touches it, so appends merge in the order they arrive
+    series_store: SeriesStore,
+    candle_cache: Arc<CandleCache>,
+    output_locks: Arc<OutputLocks>,
+    /// Threads batch items render on, shared so batches together stay within
+    /// `batch_threads`
+    batch_pool: Arc<rayon::ThreadPool>,
+    started: Instant,
+    /// Set by a shutdown command; the main loop drains and exits once it sees it
+    shutdown_requested: bool,
+}
+
+impl Dispatcher {
+    /// Answer a control message such as `{"type":"ping"}`
+    fn handle_control(&mut self, reply: ReplySink, msg: ControlMessage, payload: &[u8]) {
+        match msg {
+            ControlMessage::Ping => {
+                debug!("[CONTROL] Ping received, sending pong");
+                let pong = serde_json::json!({
+                    "type": "pong",
+                    "identity": SERVICE_IDENTITY,
+                    "inflight": ACTIVE_THREADS.load(Ordering::SeqCst),
+                });
+                reply.send(&pong, None);
+            }
+            ControlMessage::Stats => {
+                debug!("[CONTROL] Stats requested");
+                reply.send(&STATS.snapshot(self.started.elapsed()), None);
+            }
+            ControlMessage::Shutdown => {
+                let inflight = ACTIVE_THREADS.load(Ordering::SeqCst);
+                info!("[CONTROL] Shutdown requested, draining {} in-flight charts", inflight);
+                self.shutdown_requested = true;
+                let ack = serde_json::json!({
+                    "type": "shutdown",
+                    "status": "ok",
+                    "inflight": inflight,
+                });
+                reply.send(&ack, None);
+            }
+            ControlMessage::Purge { older_than_hours } => {
+                if !older_than_hours.is_finite() || older_than_hours < 0.0 {
+                    let err = RequestError::new(
+                        ErrorCode::ValidationError,
+                        format!(
+                            "older_than_hours must be a non-negative number, got {}",
+                            older_than_hours
+                        ),
+                    );
+                    reply.send(&error_reply(None, &err), None);
+                    return;
+                }
+                let older_than = Duration::from_secs_f64(older_than_hours * 3600.0);
+                match purge_output_dir(&self.settings.output_dir, older_than) {
+                    Ok(removed) => {
+                        info!(
+                            "[CONTROL] Purged {} images older than {}h from {}",
+                            removed, older_than_hours, self.settings.output_dir
+                        );
+                        let ack = serde_json::json!({
+                            "type": "purge",
+                            "status": "ok",
+                            "removed": removed,
+                        });
+                        reply.send(&ack, None);
+                    }
+                    Err(e) => {
+                        error!("[CONTROL] Purge of {} failed: {}", self.settings.output_dir, e);
+                        let err = RequestError::new(ErrorCode::IoError, e.to_string());
+                        reply.send(&error_reply(None, &err), None);
+                    }
+                }
+            }
+            ControlMessage::Batch { id, requests } => {
+                self.dispatch_batch(reply, id, requests, payload)
+            }
+        }
+    }
+
+    /// Decode a request payload and hand it to a chart thread. Replies are sent
+    /// behind `envelope`; a `None` payload means the message had no usable payload frame.
+    fn dispatch(&mut self, envelope: ReplyFrames, payload: Option<&[u8]>) {
+        let now = Local::now().format("%Y-%m-%d %H:%M:%S");
+        let mut reply = ReplySink {
+            tx: self.reply_tx.clone(),
+            envelope,
+            encoding: Encoding::Json,
+            batch_id: None,
+        };
+
+        let Some(payload) = payload else {
+            error!("[{}] Received invalid or missing payload", now);
+            STATS.record_failure(ErrorCode::ParseError);
+            let err = RequestError::new(ErrorCode::ParseError, "invalid or missing payload");
+            reply.send(&error_reply(None, &err), None);
+            return;
+        };
+
+        // A bare PING is a liveness probe for tools that cannot build a request; it
+        // skips the decoders and is answered with PONG plus uptime and render metrics
+        if payload == PING_PROBE {
+            debug!("[CONTROL] PING probe received, sending PONG");
+            let status = serde_json::Value::Object(STATS.metrics(self.started.elapsed()));
+            reply.send_raw(vec![b"PONG".to_vec(), status.to_string().into_bytes()]);
+            return;
+        }
+
+        let payload = match decompress_payload(payload, self.settings.max_decompressed_bytes) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("[{}] Rejected compressed payload: {}", now, e);
+                STATS.record_failure(ErrorCode::ParseError);
+                reply.send(&error_reply(None, &RequestError::new(ErrorCode::ParseError, e)), None);
+                return;
+            }
+        };
+
+        let parse_started = Instant::now();
+        match decode_payload(&payload) {
+            Ok((msg, encoding)) => {
+                reply.encoding = encoding;
+                // Probes and service commands would only dilute the parse timings
+                if let Incoming::Chart(_) | Incoming::Control(ControlMessage::Batch { .. }) = msg {
+                    STATS.record_stage(Stage::Parse, parse_started.elapsed());
+                }
+                match msg {
+                    Incoming::Chart(req) => self.dispatch_chart(reply, *req, &payload),
+                    Incoming::Control(msg) => self.handle_control(reply, msg, &payload),
+                }
+            }
+            Err((e, encoding)) => {
+                error!("[{}] Failed to parse {:?} payload: {}", now, encoding, e);
+                STATS.record_failure(ErrorCode::ParseError);
+                reply.encoding = encoding;
+                let request_id = request_id_from_raw(&payload, encoding);
+                let err = RequestError::new(ErrorCode::ParseError, e);
+                reply.send(&error_reply(request_id.as_deref(), &err), None);
+            }
+        }
+    }
+
+    /// Dedupe a single chart request and hand it to its own chart thread
+    fn dispatch_chart(&mut self, reply: ReplySink, mut req: ChartRequest, payload: &[u8]) {
+        let now = Local::now().format("%Y-%m-%d %H:%M:%S");
+        let _log_scope = RequestLogScope::enter(&req.0);
+        STATS.received.fetch_add(1, Ordering::Relaxed);
+
+        // A broker retry of a request we already dispatched is only acknowledged
+        if self.recent_requests.is_duplicate(&req.0, payload) {
+            info!(
+                "[{}] Duplicate request for {} @ {} suppressed (seen within {}s)",
+                now,
+                req.2.ticker,
+                req.2.timeframe,
+                self.settings.dedup_ttl.as_secs()
+            );
+            let ack = serde_json::json!({
+                "status": "ok",
+                "request_id": req.0,
+                "duplicate": true,
+            });
+            reply.send(&ack, None);
+            return;
+        }
+
+        // Phase 2: Concurrent thread limit, checked before an append is merged so a
+        // turned-away request leaves the series store as it was
+        let current = ACTIVE_THREADS.load(Ordering::SeqCst);
+        if current >= MAX_CONCURRENT_CHARTS {
+            warn!(
+                "[CHART] Dropping request for {} — {} threads active (max {})",
+                req.2.ticker, current, MAX_CONCURRENT_CHARTS
+            );
+            STATS.record_failure(ErrorCode::Busy);
+            reply.send(&error_reply(Some(&req.0), &busy_error(current)), None);
+            return;
+        }
+
+        req.2.normalize_timestamps();
+        if let Err(err) = self.series_store.apply(&mut req.2) {
+            error!("[{}] Cannot chart {} @ {}: {}", now, req.2.ticker, req.2.timeframe, err);
+            STATS.record_failure(err.code);
+            reply.send(&error_reply(Some(&req.0), &err), None);
+            return;
+        }
+
+        info!("╔══════════════════════════════════════════════════════════════════════");
+
+        info!(
+            "[{}] ▶ New Chart Request for {} @ {} [{} candles]",
+            now,
+            req.2.ticker,
+            req.2.timeframe,
+            req.2.data.len()
+        );
+        log_data_summary(&req.2);
+
+        self.recent_requests.remember(&req.0, payload);
+
+        // Phase 5B: Use Arc instead of clone
+        let request_id = req.0;
+        let chart_data = Arc::new(req.2);
+        let settings_clone = Arc::clone(&self.settings);
+        let zmq_ctx_clone = Arc::clone(&self.zmq_ctx);
+        let candle_cache = Arc::clone(&self.candle_cache);
+        let output_locks = Arc::clone(&self.output_locks);
+
+        ACTIVE_THREADS.fetch_add(1, Ordering::SeqCst);
+
+        thread::spawn(move || {
+            let _log_scope = RequestLogScope::enter(&request_id);
+            render_and_reply(
+                &request_id,
+                &chart_data,
+                &settings_clone,
+                Some(zmq_ctx_clone.as_ref()),
+                &reply,
+                &candle_cache,
+                &output_locks,
+            );
+            ACTIVE_THREADS.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    /// Validate each item of a batch on its own, then render the valid ones side by
+    /// side on the batch pool from a single chart thread. Every item gets its own
+    /// reply, tagged with the batch id, and the batch ends with a reply listing how
+    /// each item went; a failing item never stops the rest.
+    fn dispatch_batch(
+        &mut self,
+        mut reply: ReplySink,
+        batch_id: String,
+        items: Vec<serde_json::Value>,
+        payload: &[u8],
+    ) {
+        let now = Local::now().format("%Y-%m-%d %H:%M:%S");
+        let _log_scope = RequestLogScope::enter(&batch_id);
+
+        if self.recent_requests.is_duplicate(&batch_id, payload) {
+            info!(
+                "[{}] Duplicate batch suppressed (seen within {}s)",
+                now,
+                self.settings.dedup_ttl.as_secs()
+            );
+            let ack = serde_json::json!({
+                "status": "ok",
+                "request_id": batch_id,
+                "duplicate": true,
+            });
+            reply.send(&ack, None);
+            return;
+        }
+
+        reply.batch_id = Some(batch_id.clone());
+        let item_count = items.len();
+        STATS.received.fetch_add(item_count as u64, Ordering::Relaxed);
+        let mut requests = Vec::with_capacity(item_count);
+        // (position in the batch, request id, error code of a failed item)
+        let mut results: Vec<(usize, Option<String>, Option<ErrorCode>)> =
+            Vec::with_capacity(item_count);
+
+        // A batch occupies one chart thread, whatever its size. The check comes before
+        // any append is merged, so a turned-away batch leaves the series store alone.
+        let current = ACTIVE_THREADS.load(Ordering::SeqCst);
+        if current >= MAX_CONCURRENT_CHARTS {
+            warn!(
+                "[BATCH] Dropping batch — {} threads active (max {})",
+                current, MAX_CONCURRENT_CHARTS
+            );
+            let err = busy_error(current);
+            for (index, item) in items.iter().enumerate() {
+                let item_id = item.get(0).and_then(|id| id.as_str()).map(str::to_string);
+                STATS.record_failure(ErrorCode::Busy);
+                reply.send(&error_reply(item_id.as_deref(), &err), None);
+                results.push((index, item_id, Some(ErrorCode::Busy)));
+            }
+            reply.send(&batch_summary(&batch_id, results), None);
+            return;
+        }
+
+        for (index, item) in items.into_iter().enumerate() {
+            let item_id = item.get(0).and_then(|id| id.as_str()).map(str::to_string);
+            match ChartRequest::deserialize(&item) {
+                Ok(mut req) => {
+                    // Appends are merged here, in batch order, rather than on the pool
+                    req.2.normalize_timestamps();
+                    match self.series_store.apply(&mut req.2) {
+                        Ok(()) => requests.push((index, req)),
+                        Err(err) => {
+                            error!("[BATCH] Item {} of {}: {}", index + 1, item_count, err);
+                            STATS.record_failure(err.code);
+                            reply.send(&error_reply(Some(&req.0), &err), None);
+                            results.push((index, Some(req.0), Some(err.code)));
+                        }
+                    }
+                }
+                Err(_) => {
+                    let problem = chart_request_problem(&item);
+                    error!(
+                        "[BATCH] Item {} of {} is not a chart request: {}",
+                        index + 1,
+                        item_count,
+                        problem
+                    );
+                    STATS.record_failure(ErrorCode::ParseError);
+                    let err = RequestError::new(ErrorCode::ParseError, problem);
+                    reply.send(&error_reply(item_id.as_deref(), &err), None);
+                    results.push((index, item_id, Some(ErrorCode::ParseError)));
+                }
+            }
+        }
+
+        info!("╔══════════════════════════════════════════════════════════════════════");
+        info!(
+            "[{}] ▶ New Batch with {} of {} items valid: {}",
+            now,
+            requests.len(),
+            item_count,
+            requests.iter().map(|(_, req)| req.0.as_str()).collect::<Vec<_>>().join(", ")
+        );
+        if requests.is_empty() {
+            reply.send(&batch_summary(&batch_id, results), None);
+            return;
+        }
+
+        self.recent_requests.remember(&batch_id, payload);
+
+        let settings = Arc::clone(&self.settings);
+        let zmq_ctx = Arc::clone(&self.zmq_ctx);
+        let candle_cache = Arc::clone(&self.candle_cache);
+        let output_locks = Arc::clone(&self.output_locks);
+        let batch_pool = Arc::clone(&self.batch_pool);
+
+        ACTIVE_THREADS.fetch_add(1, Ordering::SeqCst);
+
+        thread::spawn(move || {
+            // The pool is shared by every batch, so a burst of batches cannot start
+            // more renders than `batch_threads`
+            let rendered: Vec<_> = batch_pool.install(|| {
+                requests
+                    .into_par_iter()
+                    .map(|(index, req)| {
+                        let _log_scope =
+                            RequestLogScope::enter(&format!("{}/{}", batch_id, req.0));
+                        info!(
+                            "[BATCH] ▶ Item {} of {}: {} @ {} [{} candles]",
+                            index + 1,
+                            item_count,
+                            req.2.ticker,
+                            req.2.timeframe,
+                            req.2.data.len()
+                        );
+                        log_data_summary(&req.2);
+                        let failure = render_and_reply(
+                            &req.0,
+                            &req.2,
+                            &settings,
+                            Some(zmq_ctx.as_ref()),
+                            &reply,
+                            &candle_cache,
+                            &output_locks,
+                        );
+                        (index, Some(req.0), failure)
+                    })
+                    .collect()
+            });
+            results.extend(rendered);
+            reply.send(&batch_summary(&batch_id, results), None);
+            ACTIVE_THREADS.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Error for a request turned away because `active` chart threads are already running
+fn busy_error(active: usize) -> RequestError {
+    RequestError::new(
+        ErrorCode::Busy,
+        format!("too many renders in flight ({} of {})", active, MAX_CONCURRENT_CHARTS),
+    )
+}
+
+/// Final reply of a batch: the outcome of every item, in batch order
+fn batch_summary(
+    batch_id: &str,
+    mut results: Vec<(usize, Option<String>, Option<ErrorCode>)>,
+) -> serde_json::Value {
+    results.sort_by_key(|(index, ..)| *index);
+    let items: Vec<serde_json::Value> = results
+        .into_iter()
+        .map(|(_, request_id, failure)| match failure {
+            None => serde_json::json!({"request_id": request_id, "status": "ok"}),
+            Some(code) => serde_json::json!({
+                "request_id": request_id,
+                "status": "error",
+                "code": code.as_str(),
+            }),
+        })
+        .collect();
+    serde_json::json!({
+        "status": "ok",
+        "request_id": batch_id,
+        "results": items,
+    })
+}
+
+/// Render one chart, sending an error reply if it fails. Returns the error code of
+/// a failed render.
+/// Phase 2: Wrap in catch_unwind for panic protection
+fn render_and_reply(
+    request_id: &str,
+    chart_data: &ChartData,
+    settings: &Settings,
+    zmq_ctx: Option<&zmq::Context>,
+    reply: &ReplySink,
+    candle_cache: &Arc<CandleCache>,
+    output_locks: &OutputLocks,
+) -> Option<ErrorCode> {
+    let started = Instant::now();
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        handle_chart_request(
+            request_id,
+            chart_data,
+            settings,
+            zmq_ctx,
+            reply,
+            candle_cache,
+            output_locks,
+        )
+    }));
+    let failure = match result {
+        Ok(Ok(())) => {
+            STATS.record_render(started.elapsed(), None);
+            return None;
+        }
+        Ok(Err(e)) => e,
+        Err(payload) => ChartError::Panic(panic_message(payload.as_ref())),
+    };
+    error!("[CHART] Chart generation failed for request {}: {}", request_id, failure);
+    let failure = RequestError::from(failure);
+    STATS.record_render(started.elapsed(), Some(failure.code));
+    reply.send(&error_reply(Some(request_id), &failure), None);
+    Some(failure.code)
+}
+
+/// Render the one chart request read from `path` (`-` for stdin) and print its
+/// JSON reply to stdout, bypassing the socket loop. The image is always saved to a
+/// file and Telegram is not notified. Returns whether the chart was rendered.
+fn render_input(path: &str, settings: &Settings) -> bool {
+    let print_reply = |body: &serde_json::Value| {
+        println!("{}", body);
+        body.get("status").and_then(|s| s.as_str()) == Some("ok")
+    };
+
+    let read = if path == "-" {
+        let mut buf = Vec::new();
+        std::io::stdin().read_to_end(&mut buf).map(|_| buf)
+    } else {
+        fs::read(path)
+    };
+    let payload = match read {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("[INPUT] Could not read {}: {}", path, e);
+            let err = RequestError::new(ErrorCode::IoError, format!("cannot read {}: {}", path, e));
+            return print_reply(&error_reply(None, &err));
+        }
+    };
+    let payload = match decompress_payload(&payload, settings.max_decompressed_bytes) {
+        Ok(payload) => payload,
+        Err(e) => {
+            return print_reply(&error_reply(None, &RequestError::new(ErrorCode::ParseError, e)));
+        }
+    };
+    let mut req = match decode_payload(&payload) {
+        Ok((Incoming::Chart(req), _)) => *req,
+        Ok((Incoming::Control(_), _)) => {
+            let err =
+                RequestError::new(ErrorCode::ParseError, "--input expects a single chart request");
+            return print_reply(&error_reply(None, &err));
+        }
+        Err((e, encoding)) => {
+            let request_id = request_id_from_raw(&payload, encoding);
+            let err = RequestError::new(ErrorCode::ParseError, e);
+            return print_reply(&error_reply(request_id.as_deref(), &err));
+        }
+    };
+
+    let _log_scope = RequestLogScope::enter(&req.0);
+    req.2.normalize_timestamps();
+    req.2.delivery = Delivery::File;
+    // A single request has nothing stored to append to
+    let mut series_store =
+        SeriesStore::new(settings.series_cache_entries, settings.series_cache_max_candles);
+    if let Err(err) = series_store.apply(&mut req.2) {
+        return print_reply(&error_reply(Some(&req.0), &err));
+    }
+    info!(
+        "[INPUT] ▶ Chart Request for {} @ {} [{} candles]",
+        req.2.ticker,
+        req.2.timeframe,
+        req.2.data.len()
+    );
+    log_data_summary(&req.2);
+
+    let (tx, rx) = mpsc::channel::<ReplyFrames>();
+    let reply = ReplySink {
+        tx,
+        envelope: Vec::new(),
+        encoding: Encoding::Json,
+        batch_id: None,
+    };
+    render_and_reply(
+        &req.0,
+        &req.2,
+        settings,
+        None,
+        &reply,
+        &Arc::new(CandleCache::new(settings.candle_cache_entries)),
+        &OutputLocks::new(settings.coalesce),
+    );
+    drop(reply);
+
+    // A file delivery carries no attachment, so each reply is a single body frame
+    let mut rendered = false;
+    for frames in rx {
+        let body = frames
+            .first()
+            .and_then(|frame| serde_json::from_slice(frame).ok())
+            .unwrap_or_default();
+        rendered = print_reply(&body);
+    }
+    rendered
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cmd = match parse_args(std::env::args().skip(1)) {
+        Ok(cmd) => cmd,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(2);
+        }
+    };
+    if cmd.print_default_config {
+        print!("{}", default_config_toml());
+        return Ok(());
+    }
+
+    // Get the output directory and other settings from config file
+    let charts_config = match load_config(cmd.config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let args = match resolve_args(cmd, &charts_config) {
+        Ok(args) => args,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(2);
+        }
+    };
+    let settings = Arc::new(load_settings(&charts_config, &args)?);
+
+    // Phase 6: Initialize structured logging
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(level) = args.log_level {
+        logger.filter_level(level);
+    }
+    if args.log_json {
+        logger.format(format_log_json);
+    } else {
+        logger.format(format_log_line);
+    }
+    logger.init();
+
+    info!(
+        "[INIT] Resolved configuration:\n{}",
+        resolved_config_toml(&settings, &args).trim_end()
+    );
+    info!("[INIT] Using output directory: {}", settings.output_dir);
+
+    if let Some(path) = &args.input {
+        let rendered = render_input(path, &settings);
+        std::process::exit(if rendered { 0 } else { 1 });
+    }
+
+    if args.hello.is_some() && args.mode != IngestMode::Dealer {
+        warn!("[INIT] --hello is only sent in dealer mode; ignoring it");
+    }
+
+    // Phase 4B: Share ZMQ context for notifications
+    let zmq_ctx = Arc::new(zmq::Context::new());
+
+    let mut failures = 0;
+    let mut generation = 0;
+    let mut conn = connect_with_backoff(&zmq_ctx, &args, &mut failures, &mut generation)?;
+
+    info!("[READY] Awaiting incoming chart messages…");
+
+    // Only a DEALER talks to a broker that tracks our liveness
+    let heartbeat_interval = settings
+        .heartbeat_interval
+        .filter(|_| args.mode == IngestMode::Dealer);
+    let mut last_heartbeat = Instant::now();
+
+    // The first SIGINT/SIGTERM asks for a graceful drain; a second one during the
+    // drain exits at once. The conditional hook must be registered first so it
+    // only fires when the flag was already set.
+    let signalled = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&signalled))?;
+        signal_hook::flag::register(signal, Arc::clone(&signalled))?;
+    }
+
+    let started = Instant::now();
+    if let Some(addr) = &settings.metrics_listen {
+        spawn_metrics_listener(addr, started);
+    }
+
+    let (reply_tx, reply_rx) = mpsc::channel::<ReplyFrames>();
+    let mut dispatcher = Dispatcher {
+        recent_requests: RecentRequests::new(settings.dedup_capacity, settings.dedup_ttl),
+        series_store: SeriesStore::new(
+            settings.series_cache_entries,
+            settings.series_cache_max_candles,
+        ),
+        candle_cache: Arc::new(CandleCache::new(settings.candle_cache_entries)),
+        output_locks: Arc::new(OutputLocks::new(settings.coalesce)),
+        batch_pool: Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(settings.batch_threads)
+                .thread_name(|i| format!("batch-{}", i))
+                .build()?,
+        ),
+        settings,
+        zmq_ctx: Arc::clone(&zmq_ctx),
+        reply_tx,
+        started,
+        shutdown_requested: false,
+    };
+
+    loop {
+        flush_replies(&conn, &args, &reply_rx);
+
+        if signalled.load(Ordering::SeqCst) {
+            info!("[SHUTDOWN] Signal received; send it again to exit immediately");
+            dispatcher.shutdown_requested = true;
+        }
+        if dispatcher.shutdown_requested {
+            drain(&conn, &args, &reply_rx, dispatcher.settings.shutdown_grace);
+            return Ok(());
+        }
+
+        if let Some(interval) = heartbeat_interval
+            && last_heartbeat.elapsed() >= interval
+        {
+            last_heartbeat = Instant::now();
+            if let Err(e) = conn
+                .socket
+                .send_multipart([Vec::new(), heartbeat_message().into_bytes()], zmq::DONTWAIT)
+            {
+                warn!("[HEARTBEAT] Failed to send heartbeat: {}", e);
+            }
+        }
+
+        match serve_once(&conn, &args, &mut dispatcher, &mut failures) {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("[CONN] Lost connection to {}", args.endpoint);
+                failures += 1;
+                close_connection(conn, &args, &mut dispatcher);
+                conn = connect_with_backoff(&zmq_ctx, &args, &mut failures, &mut generation)?;
+            }
+            Err(e) if is_transient(e) => debug!("[CONN] Transient socket error, retrying: {}", e),
+            Err(zmq::Error::ETERM) => return Err(zmq::Error::ETERM.into()),
+            Err(e) => {
+                error!("[CONN] Socket error, re-creating socket: {}", e);
+                failures += 1;
+                close_connection(conn, &args, &mut dispatcher);
+                conn = connect_with_backoff(&zmq_ctx, &args, &mut failures, &mut generation)?;
+            }
+        }
+    }
+}
+
+/// Send replies queued by chart threads; a SUB socket cannot send, so they are dropped
+fn flush_replies(conn: &Connection, args: &CliArgs, reply_rx: &mpsc::Receiver<ReplyFrames>) {
+    while let Ok(reply) = reply_rx.try_recv() {
+        match args.mode {
+            IngestMode::Dealer | IngestMode::Bind => {
+                if let Err(e) = conn.socket.send_multipart(reply, 0) {
+                    error!("[REPLY] Failed to send reply: {}", e);
+                }
+            }
+            IngestMode::Sub => debug!("[REPLY] No reply path in sub mode; reply dropped"),
+        }
+    }
+}
+
+/// Stop reading requests and wait for in-flight charts, sending their replies as
+/// they finish, for up to `grace`
+fn drain(
+    conn: &Connection,
+    args: &CliArgs,
+    reply_rx: &mpsc::Receiver<ReplyFrames>,
+    grace: Duration,
+) {
+    let finished = || STATS.rendered.load(Ordering::Relaxed) + STATS.failed.load(Ordering::Relaxed);
+    let finished_before = finished();
+    info!(
+        "[SHUTDOWN] Waiting up to {}s for {} chart threads",
+        grace.as_secs(),
+        ACTIVE_THREADS.load(Ordering::SeqCst)
+    );
+
+    let started = Instant::now();
+    while ACTIVE_THREADS.load(Ordering::SeqCst) > 0 && started.elapsed() < grace {
+        flush_replies(conn, args, reply_rx);
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS as u64));
+    }
+    flush_replies(conn, args, reply_rx);
+
+    let completed = finished() - finished_before;
+    let abandoned = ACTIVE_THREADS.load(Ordering::SeqCst);
+    if abandoned > 0 {
+        warn!(
+            "[SHUTDOWN] {} renders completed, {} chart threads abandoned; exiting",
+            completed, abandoned
+        );
+    } else {
+        info!("[SHUTDOWN] {} renders completed, none abandoned; exiting", completed);
+    }
+    // Give queued replies a moment to reach the peer, but never hang on a dead one
+    let _ = conn.socket.set_linger(1_000);
+}
+
+/// Delete images in the output directory and its subdirectories last modified more
+/// than `older_than` ago, together with their coordinates, returning how many images
+/// were removed. Entries that cannot be read or removed are logged and skipped; only
+/// an unreadable `dir` fails the purge.
+fn purge_output_dir(dir: &str, older_than: Duration) -> std::io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("[PURGE] Could not read an entry of {}: {}", dir, e);
+                continue;
+            }
+        };
+        let path = entry.path();
+        // Filename templates may nest images in subdirectories; symlinks are not followed
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => {
+                match purge_output_dir(&path.to_string_lossy(), older_than) {
+                    Ok(count) => removed += count,
+                    Err(e) => warn!("[PURGE] Could not read {}: {}", path.display(), e),
+                }
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("[PURGE] Could not read {}: {}", path.display(), e);
+                continue;
+            }
+        }
+        let is_image = path.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| {
+            ImageFormat::ALL.iter().any(|format| ext.eq_ignore_ascii_case(format.extension()))
+        });
+        // Coordinates are removed with their image below, so they may be gone by now
+        if !is_image {
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                warn!("[PURGE] Could not read {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        let age = match metadata.modified() {
+            Ok(modified) => modified.elapsed().unwrap_or(Duration::ZERO),
+            Err(e) => {
+                warn!("[PURGE] Could not read the age of {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if age < older_than {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => removed += 1,
+            Err(e) => warn!("[PURGE] Could not remove {}: {}", path.display(), e),
+        }
+        // An image's coordinates go with it, however old they are. They are not
+        // counted: the caller reports images.
+        if let Some(path) = path.to_str() {
+            let coords = PathBuf::from(coords_file_path(path));
+            if coords.symlink_metadata().is_ok_and(|meta| meta.is_file())
+                && let Err(e) = fs::remove_file(&coords)
+            {
+                warn!("[PURGE] Could not remove {}: {}", coords.display(), e);
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Whether `text` is something `{name}` in a filename template could have expanded to.
+/// Kept narrow so a ticker's pattern does not claim the files of a longer ticker.
+fn placeholder_matches(name: &str, text: &str) -> bool {
+    !text.is_empty()
+        && match name {
+            "date" => text.chars().all(|c| c.is_ascii_digit() || c == '-'),
+            "time" | "ts" => text.chars().all(|c| c.is_ascii_digit()),
+            "timeframe" => text.chars().all(|c| c.is_ascii_alphanumeric()),
+            "ext" => ImageFormat::ALL.iter().any(|format| text == format.extension()),
+            _ => !text.contains(['/', '\\']),
+        }
+}
+
+/// Whether the relative `path` could have been produced by `template`. With a
+/// `ticker`, `{ticker}` must be exactly that (sanitized) ticker.
+fn matches_filename_template(template: &str, ticker: Option<&str>, path: &str) -> bool {
+    let Some(open) = template.find('{') else {
+        return path == template;
+    };
+    let Some(path) = path.strip_prefix(&template[..open]) else {
+        return false;
+    };
+    let placeholder = &template[open..];
+    let Some(close) = placeholder.find('}') else {
+        return false;
+    };
+    let (name, after) = (&placeholder[1..close], &placeholder[close + 1..]);
+    if name == "ticker"
+        && let Some(ticker) = ticker
+    {
+        return path
+            .strip_prefix(ticker)
+            .is_some_and(|rest| matches_filename_template(after, Some(ticker), rest));
+    }
+    (1..=path.len())
+        .filter(|&end| path.is_char_boundary(end))
+        .any(|end| {
+            placeholder_matches(name, &path[..end])
+                && matches_filename_template(after, ticker, &path[end..])
+        })
+}
+
+/// `path` without the `_<ms>` that `append_timestamp` puts before its extension, or
+/// `None` when it has no such suffix
+fn strip_appended_timestamp(path: &str) -> Option<String> {
+    let (stem, ext) = match path.rsplit_once('.') {
+        Some((stem, ext)) if !ext.contains('/') && !stem.ends_with('/') => (stem, Some(ext)),
+        _ => (path, None),
+    };
+    let (base, ts) = stem.rsplit_once('_')?;
+    if ts.is_empty() || !ts.bytes().all(|b| b.is_ascii_digit()) || base.ends_with('/') {
+        return None;
+    }
+    Some(match ext {
+        Some(ext) => format!("{}.{}", base, ext),
+        None => base.to_string(),
+    })
+}
+
+/// Whether the relative `path` could have been produced by `template`, with or
+/// without the suffix of `append_timestamp`
+fn matches_output_name(template: &str, ticker: Option<&str>, path: &str) -> bool {
+    matches_filename_template(template, ticker, path)
+        || strip_appended_timestamp(path)
+            .is_some_and(|path| matches_filename_template(template, ticker, &path))
+}
+
+/// Regular files under `dir` (symlinks are neither followed nor returned), with
+/// their path relative to `base` and their modification time
+fn collect_output_files(
+    dir: &Path,
+    base: &Path,
+    files: &mut Vec<(String, PathBuf, std::time::SystemTime)>,
+) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            collect_output_files(&path, base, files)?;
+        } else if file_type.is_file()
+            && let Ok(relative) = path.strip_prefix(base)
+            && let Some(relative) = relative.to_str()
+        {
+            files.push((relative.replace('\\', "/"), path.clone(), entry.metadata()?.modified()?));
+        }
+    }
+    Ok(())
+}
+
+/// Enforce `settings.retention` on the output directory after a chart of `ticker`
+/// was saved: images past the maximum age go, then the oldest of the ticker's images
+/// beyond the per-ticker limit. Only files named by the filename template, with or
+/// without the suffix of `append_timestamp`, are considered. Returns how many images
+/// were removed.
+fn apply_retention(settings: &Settings, ticker: &str) -> std::io::Result<usize> {
+    let base = Path::new(&settings.output_dir);
+    let mut files = Vec::new();
+    collect_output_files(base, base, &mut files)?;
+    let template = settings.filename_template.as_str();
+    files.retain(|(relative, ..)| matches_output_name(template, None, relative));
+
+    let mut expired = Vec::new();
+    if let Some(max_age) = settings.retention.max_age {
+        files.retain(|(_, path, modified)| {
+            let too_old = modified.elapsed().unwrap_or(Duration::ZERO) >= max_age;
+            if too_old {
+                expired.push(path.clone());
+            }
+            !too_old
+        });
+    }
+    if let Some(max_files) = settings.retention.max_files_per_ticker {
+        let ticker = sanitize_path_component(ticker);
+        let mut own: Vec<_> = files
+            .iter()
+            .filter(|(relative, ..)| matches_output_name(template, Some(&ticker), relative))
+            .collect();
+        own.sort_by_key(|(_, _, modified)| std::cmp::Reverse(*modified));
+        expired.extend(own.into_iter().skip(max_files).map(|(_, path, _)| path.clone()));
+    }
+
+    // A chart's thumbnail and coordinates go with it; the template would not match
+    // them on their own
+    let companions: Vec<PathBuf> = expired
+        .iter()
+        .filter_map(|path| path.to_str())
+        .flat_map(|path| [thumbnail_file_path(path), coords_file_path(path)])
+        .map(PathBuf::from)
+        .filter(|companion| companion.symlink_metadata().is_ok_and(|meta| meta.is_file()))
+        .collect();
+    expired.extend(companions);
+
+    let mut removed = 0;
+    for path in expired {
+        match fs::remove_file(&path) {
+            Ok(()) => removed += 1,
+            // Another chart thread's sweep got there first
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("[RETENTION] Could not remove {}: {}", path.display(), e),
+        }
+    }
+    STATS.retention_removed.fetch_add(removed as u64, Ordering::Relaxed);
+    Ok(removed)
+}
+
+/// Dispatch anything already queued on a lost socket, then close it. It must be
+/// closed before the backoff: left open, it would reconnect on its own and claim
+/// our identity at the broker, and requests routed to it would be dropped with it.
+fn close_connection(conn: Connection, args: &CliArgs, dispatcher: &mut Dispatcher) {
+    while let Ok(frames) = conn.socket.recv_multipart(zmq::DONTWAIT) {
+        let (envelope, payload) = split_frames(args.mode, &frames);
+        dispatcher.dispatch(envelope, payload);
+    }
+    // Replies still queued for a dead peer are not worth keeping
+    let _ = conn.socket.set_linger(0);
+}
+
+/// `{"type":"heartbeat","identity":...,"inflight":N}`, where N is the number of
+/// chart threads currently rendering
+fn heartbeat_message() -> String {
+    serde_json::json!({
+        "type": "heartbeat",
+        "identity": SERVICE_IDENTITY,
+        "inflight": ACTIVE_THREADS.load(Ordering::SeqCst),
+    })
+    .to_string()
+}
+
+/// Wait up to one poll interval for a request or connection event and handle it.
+/// Returns `Ok(false)` when the monitor reports that the connection was lost.
+fn serve_once(
+    conn: &Connection,
+    args: &CliArgs,
+    dispatcher: &mut Dispatcher,
+    failures: &mut u32,
+) -> zmq::Result<bool> {
+    let mut items = vec![conn.socket.as_poll_item(zmq::POLLIN)];
+    if let Some(monitor) = &conn.monitor {
+        items.push(monitor.as_poll_item(zmq::POLLIN));
+    }
+    if zmq::poll(&mut items, POLL_INTERVAL_MS)? == 0 {
+        return Ok(true);
+    }
+
+    if let Some(monitor) = &conn.monitor
+        && items[1].is_readable()
+    {
+        match read_monitor_event(monitor)? {
+            zmq::SocketEvent::HANDSHAKE_SUCCEEDED => {
+                info!("[CONN] Connected to {}", args.endpoint);
+                *failures = 0;
+                if let (IngestMode::Dealer, Some(hello)) = (args.mode, &args.hello) {
+                    conn.socket
+                        .send_multipart([&b""[..], hello.as_bytes()], zmq::DONTWAIT)?;
+                    debug!("[CONN] Sent hello to {}", args.endpoint);
                 }
             }
-            None => {
-                error!("[{}] Received invalid or missing JSON payload", now);
-            }
+            zmq::SocketEvent::DISCONNECTED => return Ok(false),
+            _ => {}
         }
     }
+
+    if items[0].is_readable() {
+        let frames = conn.socket.recv_multipart(0)?;
+        let (envelope, payload) = split_frames(args.mode, &frames);
+        dispatcher.dispatch(envelope, payload);
+    }
+
+    Ok(true)
 }
 
 // ─── Utility: Print Summary ─────────────────────────────────────────────────────
@@ -310,30 +2899,36 @@ fn log_data_summary(data: &ChartData) {
             safe_timestamp_to_local(end_ts),
         ) {
             (Ok(start_dt), Ok(end_dt)) => {
-                info!(
+                debug!(
                     "       {} candles from {} to {}",
                     data.data.len(),
                     start_dt.format("%Y-%m-%d %H:%M:%S"),
                     end_dt.format("%Y-%m-%d %H:%M:%S"),
                 );
-                info!("       Desc: {}", data.desc);
+                debug!("       Desc: {}", data.desc);
             }
             _ => {
                 warn!("       Could not parse timestamps for summary");
             }
         }
     } else {
-        info!("       No candle data available.");
+        debug!("       No candle data available.");
     }
 }
 
 // ─── Actual Chart Handler with Plotters ─────────────────────────────────────────
 
+/// Render, save and reply to one chart request. Telegram is only notified when a
+/// `zmq_ctx` is given.
 fn handle_chart_request(
+    request_id: &str,
     data: &ChartData,
-    output_dir: &str,
-    zmq_ctx: &zmq::Context,
-) -> Result<(), Box<dyn Error>> {
+    settings: &Settings,
+    zmq_ctx: Option<&zmq::Context>,
+    reply: &ReplySink,
+    candle_cache: &Arc<CandleCache>,
+    output_locks: &OutputLocks,
+) -> Result<(), ChartError> {
     let now = Local::now().format("%Y-%m-%d %H:%M:%S");
     info!(
         "[{}] Processing chart: '{}' with {} candles",
@@ -342,719 +2937,906 @@ fn handle_chart_request(
         data.data.len()
     );
 
-    // If there's no data, nothing to do
-    if data.data.is_empty() {
+    // If there's no data, nothing to do; a composite's panels carry their own
+    if data.data.is_empty() && data.panels.is_empty() {
         warn!("No data found for chart: {}", data.title);
-        return Ok(());
+        return Err(ChartError::InvalidData {
+            reason: "No candle data".to_string(),
+            row: None,
+        });
     }
 
-    // Phase 3G: Propagate directory creation errors
-    fs::create_dir_all(output_dir)?;
-
-    // Phase 3F: Sanitize file path components
-    let file_path = match &data.image_filename {
-        Some(filename) => format!("{}/{}", output_dir, sanitize_path_component(filename)),
-        None => format!(
-            "{}/{}_{}.png",
-            output_dir,
-            sanitize_path_component(&data.ticker),
-            sanitize_path_component(&data.timeframe)
-        ),
+    let thumbnail = data.thumbnail.or(settings.thumbnail);
+    if let Some(size) = thumbnail {
+        check_thumbnail_size("thumbnail", size)
+            .map_err(|reason| ChartError::InvalidData { reason, row: None })?;
+    }
+    // A sparkline has its own small canvas; a full chart keeps the default one
+    let canvas = match data.chart_style {
+        ChartStyle::Sparkline => {
+            let size = data.sparkline_size.unwrap_or(SPARKLINE_SIZE);
+            check_thumbnail_size("sparkline", size)
+                .map_err(|reason| ChartError::InvalidData { reason, row: None })?;
+            Some(size)
+        }
+        _ => None,
     };
 
-    // --- 1) Parse Timestamps and OHLCV; find min & max for Y ---
-    let mut min_price = f64::MAX;
-    let mut max_price = f64::MIN;
-    let mut max_volume = 0.0;
-
-    // Convert timestamps to Local DateTime for the range
-    let candle_count = data.data.len();
-    let first_ts = data.data[0][0] as i64;
-    let last_ts = data.data[candle_count - 1][0] as i64;
-
-    let start_dt: DateTime<Local> = safe_timestamp_to_local(first_ts)?;
-    let end_dt: DateTime<Local> = safe_timestamp_to_local(last_ts)?;
-
-    // We will store the data in a vector of (DateTime<Local>, open, high, low, close, volume, color_hex)
-    let mut processed_data = Vec::with_capacity(candle_count);
-
-    for (i, row) in data.data.iter().enumerate() {
-        // Phase 3A: Validate row lengths — skip rows with < 5 elements
-        if row.len() < 5 {
-            warn!("Skipping row {} with only {} elements (need >= 5)", i, row.len());
-            continue;
-        }
+    let mut render_options = RenderOptions {
+        timeout: Some(settings.render_timeout),
+        candle_cache: Some(Arc::clone(candle_cache)),
+        metadata: vec![("RequestId".to_string(), request_id.to_string())],
+        format: data.format.unwrap_or_default(),
+        quality: data.quality,
+        png_compression: data.png_compression.unwrap_or(settings.png_compression),
+        png_filter: data.png_filter.unwrap_or(settings.png_filter),
+        style: data.chart_style,
+        ..RenderOptions::default()
+    };
+    if let Some(size) = canvas {
+        render_options.width = size.width;
+        render_options.height = size.height;
+    }
 
-        // row: [ts, open, high, low, close, volume] (assuming exactly that structure)
-        let ts = row[0] as i64;
-        let o = row[1].max(1e-12);
-        let h = row[2].max(1e-12);
-        let l = row[3].max(1e-12);
-        let c = row[4].max(1e-12);
-        let v = row.get(5).cloned().unwrap_or(0.0);
+    let output_dir = settings.output_dir.as_str();
+
+    // Phase 3F: Sanitize file path components; a request's own template wins
+    let template = data
+        .image_filename
+        .as_deref()
+        .unwrap_or(&settings.filename_template);
+    let file_name = output_file_name(
+        template,
+        request_id,
+        data,
+        render_options.format,
+        Local::now(),
+    )
+    .map_err(|reason| ChartError::InvalidData { reason, row: None })?;
+    let file_path = format!("{}/{}", output_dir, file_name);
+    // Templates may place the image in subdirectories of the output directory
+    let file_dir = Path::new(&file_path).parent().unwrap_or(Path::new(output_dir));
+
+    // Acknowledge a render dropped in favour of a newer one for the same file
+    let superseded = || {
+        info!("[{}] A newer request for {} superseded this one", now, file_path);
+        let ack = serde_json::json!({
+            "status": "ok",
+            "request_id": request_id,
+            "superseded": true,
+        });
+        reply.send(&ack, None);
+        Ok(())
+    };
 
-        let local_min = o.min(h).min(l).min(c);
-        let local_max = o.max(h).max(l).max(c);
-        if local_min < min_price {
-            min_price = local_min;
-        }
-        if local_max > max_price {
-            max_price = local_max;
+    // Render in memory, then either save the image or return it inline
+    let (png_bytes, timings, coords) = render_chart_with_coords(data, &render_options)?;
+    STATS.record_stage(Stage::Validate, timings.validate);
+    STATS.record_stage(Stage::Render, timings.draw);
+    STATS.record_stage(Stage::Encode, timings.encode);
+    let (saved_path, inline_image) = match data.delivery {
+        Delivery::File => {
+            // Phase 3G: Propagate directory creation errors
+            fs::create_dir_all(file_dir)?;
+            let Some(_guard) = output_locks.acquire(&file_path) else {
+                return superseded();
+            };
+            timed_write(&file_path, &png_bytes)?;
+            (Some(file_path), None)
         }
-        if v > max_volume {
-            max_volume = v;
+        Delivery::Inline => {
+            if png_bytes.len() > settings.inline_max_bytes {
+                warn!(
+                    "[{}] Inline image is {} bytes (max {}), falling back to file delivery",
+                    now,
+                    png_bytes.len(),
+                    settings.inline_max_bytes
+                );
+                fs::create_dir_all(file_dir)?;
+                let Some(_guard) = output_locks.acquire(&file_path) else {
+                    return superseded();
+                };
+                timed_write(&file_path, &png_bytes)?;
+                (Some(file_path), None)
+            } else {
+                (None, Some(png_bytes))
+            }
         }
+    };
 
-        let dt_local: DateTime<Local> = safe_timestamp_to_local(ts)?;
+    // Dashboards read previews from disk, so only saved charts get one
+    let thumbnail_path = match (thumbnail, &saved_path) {
+        (Some(size), Some(path)) => save_thumbnail(data, &render_options, size, path, output_locks)
+            .unwrap_or_else(|e| {
+                warn!("[{}] Could not save thumbnail for {}: {}", now, path, e);
+                None
+            }),
+        _ => None,
+    };
 
-        // If for some reason we have fewer colors than candles, fallback to black
-        let color_hex = data
-            .candle_colors
-            .get(i)
-            .cloned()
-            .unwrap_or_else(|| "#000000".to_string());
+    // The coordinates describe a saved image, so they are saved next to it
+    let coords_path = match (coords, &saved_path) {
+        (Some(coords), Some(path)) => save_coords(&coords, path, output_locks).unwrap_or_else(|e| {
+            warn!("[{}] Could not save coordinates for {}: {}", now, path, e);
+            None
+        }),
+        _ => None,
+    };
 
-        processed_data.push((dt_local, o, h, l, c, v, color_hex));
+    match (&saved_path, &thumbnail_path) {
+        (Some(path), Some(thumb)) => info!(
+            "[{}] Chart processing complete. Saved to: {} (thumbnail: {})",
+            now, path, thumb
+        ),
+        (Some(path), None) => info!("[{}] Chart processing complete. Saved to: {}", now, path),
+        (None, _) => info!(
+            "[{}] Chart processing complete. Sending {} bytes inline",
+            now,
+            inline_image.as_ref().map_or(0, Vec::len)
+        ),
     }
 
-    // Phase 3E: Guard against empty processed data (all rows skipped)
-    if processed_data.is_empty() {
-        return Err("No valid candle data after processing".into());
+    // Reply to the requester; the image bytes, if any, travel as a trailing frame
+    let reply_body = serde_json::json!({
+        "status": "ok",
+        "request_id": request_id,
+        "ticker": data.ticker,
+        "timeframe": data.timeframe,
+        "delivery": if inline_image.is_some() { "inline" } else { "file" },
+        "format": render_options.format.extension(),
+        "path": saved_path,
+        "thumbnail_path": thumbnail_path,
+        "coords_path": coords_path,
+    });
+    if !reply.send(&reply_body, inline_image) {
+        warn!("[{}] Reply channel closed, dropping reply", now);
     }
 
-    // Phase 5A: Reuse min/max from first loop instead of redundant second pass
-    let highest_price = max_price;
-    let lowest_price = min_price;
+    // The telegram service reads the image from disk, so it is only notified for saved files
+    let Some(file_path) = saved_path else {
+        return Ok(());
+    };
 
-    // Phase 3D: Guard ln() on zero/negative
-    if highest_price <= 0.0 || lowest_price <= 0.0 {
-        return Err(format!(
-            "Invalid price range: highest={}, lowest={}",
-            highest_price, lowest_price
-        )
-        .into());
-    }
-
-    let min_log_for_chart = lowest_price.ln();
-
-    // Add a bit of padding to the max price (0.2%) to ensure highest candle is visible
-    let padding_factor = 1.002;
-    let padded_max_price = highest_price * padding_factor;
-    let max_log_for_chart = padded_max_price.ln();
-
-    // Increase plot dimensions for a larger chart
-    let plot_width = 1280;
-    let plot_height = 960;
-    let root_area =
-        BitMapBackend::new(&file_path, (plot_width, plot_height)).into_drawing_area();
-    root_area.fill(&WHITE)?;
-
-    // Log price range in a clean format
-    let format_with_commas = |price: f64| -> String {
-        let price_int = price.round() as i64;
-        format!("{}", price_int)
-            .as_bytes()
-            .rchunks(3)
-            .rev()
-            .map(std::str::from_utf8)
-            .collect::<Result<Vec<&str>, _>>()
-            .unwrap_or_else(|_| vec!["?"])
-            .join(",")
+    if settings.retention.is_enabled() {
+        match apply_retention(settings, &data.ticker) {
+            Ok(0) => {}
+            Ok(removed) => info!("[RETENTION] Removed {} old images from {}", removed, output_dir),
+            Err(e) => warn!("[RETENTION] Could not apply retention to {}: {}", output_dir, e),
+        }
+    }
+
+    let Some(zmq_ctx) = zmq_ctx else {
+        return Ok(());
     };
 
-    debug!(
-        "Price range: ${} - ${}",
-        format_with_commas(lowest_price),
-        format_with_commas(highest_price)
-    );
+    // Phase 4B: Send notification using shared ZMQ context
+    if let Err(e) = send_telegram_notification(zmq_ctx, data, &file_path) {
+        error!("[{}] Failed to send telegram notification: {}", now, e);
+    } else {
+        let destination = match (&data.chat_id, &data.subscriber_list) {
+            (Some(chat_id), _) => format!("chat_id: {}", chat_id),
+            (_, Some(list)) => format!("subscriber_list: {}", list),
+            _ => "default destination".to_string(),
+        };
+        info!(
+            "[{}] Telegram notification sent to {}",
+            now, destination
+        );
+    }
+
+    Ok(())
+}
 
-    // Allocate more height for the table area and include title space
-    let title_height = 40; // Dedicated space for the title
-    let table_height = 100; // More height for the table
-    let header_height = title_height + table_height;
-
-    // Split the drawing area into three parts: title, table, and chart
-    let (header_area, chart_area) = root_area.split_vertically(header_height);
-    let (title_area, table_area) = header_area.split_vertically(title_height);
-
-    // Apply horizontal margin to the table area (inset from left and right)
-    let margin_percent = 0.15; // 15% margin on each side
-    let left_margin = (plot_width as f64 * margin_percent) as u32;
-    let right_margin = plot_width - (plot_width as f64 * margin_percent) as u32;
-    // Split into left margin, table area, and right margin
-    let (_left_area, rest) = table_area.split_horizontally(left_margin);
-    let (table_area, _right_area) = rest.split_horizontally(right_margin - left_margin);
-
-    let effective_chart_area_width = plot_width as f64 * 0.85;
-    let num_candles = processed_data.len();
-
-    let pixel_gap_between_candles = 1.0;
-    let candles_to_fit = num_candles as f64;
-
-    let total_gap_space = pixel_gap_between_candles * (candles_to_fit - 1.0);
-    let _available_width = effective_chart_area_width - total_gap_space;
-
-    // Tighter right edge in time
-    let last_candle_time = processed_data
-        .last()
-        .map(|(dt, _, _, _, _, _, _)| *dt)
-        .unwrap_or(end_dt);
-    let tight_end_dt = last_candle_time + chrono::Duration::seconds(1);
-    // Instead of calculating the exact milliseconds per pixel (which can cause overflow),
-    // we'll use a safer approach with relative positioning
-    let total_time_span_millis = tight_end_dt.timestamp_millis() - start_dt.timestamp_millis();
-
-    // Safety check to avoid potential overflow
-    if total_time_span_millis <= 0 || total_time_span_millis > i64::MAX / 2 {
-        warn!("Time span is too large or invalid, adjusting calculations");
-    }
-
-    let volume_visible_bottom = min_log_for_chart;
-    let volume_visible_top =
-        min_log_for_chart + (0.15 * (max_log_for_chart - min_log_for_chart));
-
-    let log_to_price = |log_val: f64| -> f64 { log_val.exp() };
-    let volume_to_log_scale = |vol: f64| -> f64 {
-        if max_volume <= 0.0 {
-            return volume_visible_bottom;
-        }
-        let normalized_vol = vol / max_volume;
-        volume_visible_bottom + (normalized_vol * (volume_visible_top - volume_visible_bottom))
+/// Path of the thumbnail saved next to the image at `path`: `<name>_thumb.<ext>`
+fn thumbnail_file_path(path: &str) -> String {
+    let (stem, ext) = match path.rsplit_once('.') {
+        Some((stem, ext)) if !ext.contains('/') => (stem, Some(ext)),
+        _ => (path, None),
     };
+    match ext {
+        Some(ext) => format!("{}_thumb.{}", stem, ext),
+        None => format!("{}_thumb", stem),
+    }
+}
 
-    let millis_since_start = |dt: DateTime<Local>| -> i64 {
-        dt.timestamp_millis() - start_dt.timestamp_millis()
+/// Re-render `data` in the thumbnail style at `size` and save it next to the image
+/// at `path`. Re-rendering keeps a few hundred pixels legible, where a downscaled
+/// chart would turn its labels and candles into noise. Returns `None` when a newer
+/// render superseded this one.
+fn save_thumbnail(
+    data: &ChartData,
+    render_options: &RenderOptions,
+    size: ThumbnailSize,
+    path: &str,
+    output_locks: &OutputLocks,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let options = RenderOptions {
+        width: size.width,
+        height: size.height,
+        style: ChartStyle::Thumbnail,
+        ..render_options.clone()
+    };
+    let (thumb_bytes, _) = render_chart_timed(data, &options)?;
+    let thumb_path = thumbnail_file_path(path);
+    let Some(_guard) = output_locks.acquire(&thumb_path) else {
+        return Ok(None);
     };
+    timed_write(&thumb_path, &thumb_bytes)?;
+    Ok(Some(thumb_path))
+}
 
-    // Calculate millisecond values for start and end points
-    let start_millis = 0; // 0 milliseconds since start
-    let end_millis = millis_since_start(end_dt);
+/// Path of the coordinates saved next to the image at `path`: `<name>.coords.json`
+fn coords_file_path(path: &str) -> String {
+    match path.rsplit_once('.') {
+        Some((stem, ext)) if !ext.contains('/') => format!("{}.coords.json", stem),
+        _ => format!("{}.coords.json", path),
+    }
+}
 
-    // Calculate the duration of one candle in milliseconds
-    let total_candles = processed_data.len() as f64;
-    let candle_duration_ms = (end_millis - start_millis) as f64 / total_candles;
+/// Save `coords` as JSON next to the image at `path`. Returns `None` when a newer
+/// render superseded this one.
+fn save_coords(
+    coords: &ChartCoords,
+    path: &str,
+    output_locks: &OutputLocks,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let json = serde_json::to_vec_pretty(coords)?;
+    let coords_path = coords_file_path(path);
+    let Some(_guard) = output_locks.acquire(&coords_path) else {
+        return Ok(None);
+    };
+    timed_write(&coords_path, &json)?;
+    Ok(Some(coords_path))
+}
 
-    // Add 3 candles worth of space to the end
-    let padded_end_millis = end_millis as f64 + (candle_duration_ms * 3.0);
+/// `write_atomically`, recording how long it took in the write stage metrics
+fn timed_write(path: &str, bytes: &[u8]) -> std::io::Result<()> {
+    let started = Instant::now();
+    write_atomically(path, bytes)?;
+    STATS.record_stage(Stage::Write, started.elapsed());
+    Ok(())
+}
 
-    debug!(
-        "  - Time range converted to milliseconds: {} to {} ms (with padding: {} ms)",
-        start_millis, end_millis, padded_end_millis
+/// Write `bytes` to a temp file next to `path` and rename it over `path`, so readers
+/// watching the directory only ever see complete images. The temp file is removed
+/// if anything fails.
+fn write_atomically(path: &str, bytes: &[u8]) -> std::io::Result<()> {
+    static NONCE: AtomicU64 = AtomicU64::new(0);
+    let tmp_path = format!(
+        "{}.tmp.{}.{}",
+        path,
+        std::process::id(),
+        NONCE.fetch_add(1, Ordering::Relaxed)
     );
+    let result = fs::File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(bytes)?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
 
-    // Build the chart using milliseconds since start instead of DateTime objects or hours
-    let mut chart_context = ChartBuilder::on(&chart_area)
-        .margin(10)
-        .margin_bottom(20)
-        .set_label_area_size(LabelAreaPosition::Left, 0)
-        .set_label_area_size(LabelAreaPosition::Right, 80)
-        .set_label_area_size(LabelAreaPosition::Bottom, 40)
-        .build_cartesian_2d(
-            (start_millis as f64)..padded_end_millis,
-            min_log_for_chart..max_log_for_chart,
-        )?;
-
-    // Title is centered in its own dedicated area at the very top of the canvas
-    let title_style = TextStyle::from(("sans-serif", 24)).color(&BLACK);
-
-    // Calculate the exact center of the entire canvas width (not just title area)
-    let _title_pos = ((plot_width / 2) as i32, title_height / 2);
-
-    // Draw a white background for the title area
-    title_area.fill(&WHITE)?;
-
-    // Draw the title text
-    let text_width = data.title.len() as i32 * 15;
-    let y_axis_width = 80;
-
-    let centered_x = (plot_width as i32 / 2) - (text_width / 2) + (y_axis_width / 2);
-
-    title_area.draw_text(
-        &data.title,
-        &title_style,
-        (centered_x, title_height / 2),
-    )?;
+/// What a render does when another render is already writing its output file
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum CoalesceMode {
+    /// Wait for the other render, then write
+    #[default]
+    Wait,
+    /// Of the renders waiting for a file, only the newest writes; the rest are dropped
+    Supersede,
+}
+
+/// Output files currently being written, so two renders never write the same file
+/// at once. Each render takes a ticket for its file as it starts waiting.
+struct OutputLocks {
+    mode: CoalesceMode,
+    files: Mutex<HashMap<String, OutputFile>>,
+    released: Condvar,
+}
 
-    // Clear the table area with white before we begin
-    table_area.fill(&WHITE)?;
+#[derive(Default)]
+struct OutputFile {
+    writing: bool,
+    latest_ticket: u64,
+    /// Renders writing or waiting to write this file
+    holders: usize,
+}
 
-    // Create a formatter to convert milliseconds back to readable dates
-    let millis_to_datetime = |millis: &f64| -> String {
-        let dt = start_dt + chrono::Duration::milliseconds(*millis as i64);
-        dt.format("%m-%d %H:%M").to_string()
-    };
+/// Exclusive right to write one output file, released on drop
+struct OutputGuard<'a> {
+    locks: &'a OutputLocks,
+    path: String,
+}
 
-    // Phase 5D: Pre-compute candle/wick widths before draw loops
-    let total_millis_span = (end_millis as f64) - (start_millis as f64);
-    let candle_width = total_millis_span / processed_data.len() as f64 * 0.8;
-    let wick_width = candle_width * 0.15;
-
-    chart_context
-        .configure_mesh()
-        .light_line_style(RGBColor(235, 235, 235))
-        .axis_style(RGBColor(150, 150, 150))
-        .x_labels(16)
-        .x_label_formatter(&millis_to_datetime)
-        .y_labels(8)
-        .disable_mesh()
-        .x_label_style(TextStyle::from(("sans-serif", 12)))
-        .y_label_style(("sans-serif", 15))
-        .y_desc("Price")
-        .y_label_formatter(&|y| {
-            let actual_price = log_to_price(*y);
-            let rounded_price = if actual_price >= 100000.0 {
-                (actual_price / 500.0).round() * 500.0
-            } else if actual_price >= 10000.0 {
-                (actual_price / 100.0).round() * 100.0
-            } else if actual_price >= 1000.0 {
-                (actual_price / 50.0).round() * 50.0
-            } else {
-                (actual_price / 10.0).round() * 10.0
-            };
-            let price_int = rounded_price as i64;
-            let formatted = format!("{}", price_int)
-                .as_bytes()
-                .rchunks(3)
-                .rev()
-                .map(std::str::from_utf8)
-                .collect::<Result<Vec<&str>, _>>()
-                .unwrap_or_else(|_| vec!["?"])
-                .join(",");
-            format!("${}", formatted)
-        })
-        .y_desc("Price")
-        .draw()?;
-
-    // Add some horizontal grid lines
-    let y_step = (max_log_for_chart - min_log_for_chart) / 8.0;
-    for i in 0..17 {
-        let y_pos = min_log_for_chart + (y_step * (i as f64 / 2.0));
-        let line_style = if i % 2 == 0 {
-            RGBColor(235, 235, 235).stroke_width(1)
-        } else {
-            RGBColor(240, 240, 240).stroke_width(1)
-        };
-        chart_context.draw_series(std::iter::once(PathElement::new(
-            vec![
-                (start_millis as f64, y_pos),
-                (end_millis as f64, y_pos),
-            ],
-            line_style,
-        )))?;
-    }
-
-    // Add a few vertical grid lines (using hours-based coordinates)
-    let x_range = (end_millis as f64) - (start_millis as f64);
-    let x_step = x_range / 5.0;
-    for i in 0..6 {
-        let x_pos = (start_millis as f64) + (x_step * i as f64);
-        chart_context.draw_series(std::iter::once(PathElement::new(
-            vec![(x_pos, min_log_for_chart), (x_pos, max_log_for_chart)],
-            RGBColor(245, 245, 245).stroke_width(1),
-        )))?;
-    }
-
-    // --- Draw zones (semi-transparent rectangles behind everything) ---
-    for zone in &data.plots.zones {
-        let x1 = (zone.x1 - start_dt.timestamp_millis()) as f64;
-        let x2 = (zone.x2 - start_dt.timestamp_millis()) as f64;
-
-        let y1_log = zone.y1.max(1e-12).ln();
-        let y2_log = zone.y2.max(1e-12).ln();
-
-        let color = parse_hex_color_with_alpha(&zone.color);
-
-        // Phase 4A: Log zone draw failures
-        if let Err(e) = chart_context.draw_series(std::iter::once(Rectangle::new(
-            [(x1, y1_log), (x2, y2_log)],
-            color.filled(),
-        ))) {
-            warn!("Failed to draw zone: {}", e);
-        }
-    }
-
-    // --- Draw vertical lines (e.g., alert fire timestamps) ---
-    for vline in &data.plots.vlines {
-        let x = (vline.time - start_dt.timestamp_millis()) as f64;
-        let color = parse_hex_color_with_alpha(&vline.color);
-        // Phase 4A: Log vline draw failures
-        if let Err(e) = chart_context.draw_series(std::iter::once(PathElement::new(
-            vec![(x, min_log_for_chart), (x, max_log_for_chart)],
-            color.stroke_width(2),
-        ))) {
-            warn!("Failed to draw vline: {}", e);
-        }
-    }
-
-    // --- Volume bars (draw behind candles) ---
-    chart_context
-        .draw_series(
-            processed_data
-                .iter()
-                .enumerate()
-                .map(|(idx, (dt, _o, _h, _l, _c, v, _color_hex))| {
-                    let dt_hours = millis_since_start(*dt) as f64;
-                    // Phase 5D: Use pre-computed candle_width
-                    let x0 = dt_hours - (candle_width / 2.0);
-                    let x1 = dt_hours + (candle_width / 2.0);
-
-                    let y_bottom = volume_visible_bottom;
-                    let y_top = volume_to_log_scale(*v);
-
-                    let volume_color = data
-                        .volume_colors
-                        .as_ref()
-                        .and_then(|colors| colors.get(idx).cloned())
-                        .map(|color| parse_hex_color(&color))
-                        .unwrap_or_else(|| RGBColor(130, 130, 130));
-
-                    Rectangle::new(
-                        [(x0, y_bottom), (x1, y_top)],
-                        volume_color.mix(0.8).filled(),
-                    )
-                }),
-        )?;
-
-    // Sort data by timestamp to ensure correct order for candle drawing
-    processed_data.sort_by(|a, b| a.0.cmp(&b.0));
-
-    // --- Draw the dotted line for current price on the last candle ---
-    let last_candle = processed_data.last().cloned();
-    // Phase 3D: Use 1e-12 instead of 0.0 fallback for current_price to protect ln()
-    let current_price = last_candle
-        .as_ref()
-        .map(|(_, _, _, _, c, _, _)| *c)
-        .unwrap_or(1e-12);
-    let current_price_log = current_price.ln();
-
-    let (_is_green, last_candle_color) = if let Some((_, o, _, _, c, _, _)) = last_candle {
-        let is_up = c >= o;
-        if is_up {
-            (true, RGBColor(0, 150, 0))
-        } else {
-            (false, RGBColor(180, 0, 0))
+impl OutputLocks {
+    fn new(mode: CoalesceMode) -> Self {
+        OutputLocks {
+            mode,
+            files: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
         }
-    } else {
-        (true, RGBColor(0, 150, 0))
-    };
+    }
+
+    /// Block until no other render is writing `path`. Returns `None` if, in
+    /// supersede mode, a newer render for the same file started waiting meanwhile.
+    fn acquire(&self, path: &str) -> Option<OutputGuard<'_>> {
+        let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        let file = files.entry(path.to_string()).or_default();
+        file.latest_ticket += 1;
+        file.holders += 1;
+        let ticket = file.latest_ticket;
+
+        loop {
+            let file = files.get_mut(path).expect("held files stay in the map");
+            if self.mode == CoalesceMode::Supersede && file.latest_ticket != ticket {
+                file.holders -= 1;
+                if file.holders == 0 {
+                    files.remove(path);
+                }
+                return None;
+            }
+            if !file.writing {
+                file.writing = true;
+                return Some(OutputGuard {
+                    locks: self,
+                    path: path.to_string(),
+                });
+            }
+            files = self
+                .released
+                .wait(files)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
 
-    let formatted_current_price = format_with_commas(current_price);
+impl Drop for OutputGuard<'_> {
+    fn drop(&mut self) {
+        let mut files = self.locks.files.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(file) = files.get_mut(&self.path) {
+            file.writing = false;
+            file.holders -= 1;
+            if file.holders == 0 {
+                files.remove(&self.path);
+            }
+        }
+        self.locks.released.notify_all();
+    }
+}
 
-    // Draw a single horizontal line at the current price level for reference
-    chart_context.draw_series(std::iter::once(PathElement::new(
-        vec![
-            (start_millis as f64, current_price_log),
-            (end_millis as f64, current_price_log),
-        ],
-        RGBColor(100, 100, 100).stroke_width(1),
-    )))?;
 
-    // Now draw the table in table_area instead of showing the price on the chart
-    // Find the highest price in the visible plot
-    let highest_price = processed_data
-        .iter()
-        .map(|(_, _, h, _, _, _, _)| *h)
-        .fold(f64::NEG_INFINITY, f64::max);
+/// Send a notification to the telegram service via ZMQ with the chart details and image path
+/// Phase 4B: Accepts shared zmq::Context instead of creating a new one each call
+fn send_telegram_notification(
+    zmq_ctx: &zmq::Context,
+    data: &ChartData,
+    image_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let socket = zmq_ctx.socket(zmq::DEALER)?;
 
-    // Calculate percentage from high
-    let percent_from_high = if highest_price > 0.0 {
-        ((highest_price - current_price) / highest_price) * 100.0
-    } else {
-        0.0
-    };
+    // Notifications always go to the broker, whichever mode requests arrive on
+    socket.connect(BROKER_ENDPOINT)?;
 
-    // Table setup
-    let rows = ["Current Price", "High (in plot)", "% from High"];
-    let table_data = [
-        format!("${}", formatted_current_price),
-        format!("${}", format_with_commas(highest_price)),
-        format!("{:.2}%", percent_from_high),
-    ];
+    // Create the payload with chat_id and subscriber_list if available
+    let payload = serde_json::json!([
+        "ok",
+        "send_message",
+        {
+            "text": data.desc,
+            "image_path": image_path,
+            "chat_id": data.chat_id,
+            "subscriber_list": data.subscriber_list
+        }
+    ]);
 
-    // Compute cell sizes
-    let cell_h = table_height as f64 / (rows.len() + 1) as f64;
+    // Convert to string
+    let json_message = payload.to_string();
 
-    // First, fill the table area with white for a clean background
-    table_area.fill(&WHITE)?;
+    // Send the multipart message
+    socket.send_multipart([b"telegram", json_message.as_bytes()], 0)?;
 
-    // Setup for cell drawing
-    let cell_padding = 5;
-    let section_width = table_area.get_pixel_range().0.end
-        - table_area.get_pixel_range().0.start;
-    let mid_point = section_width / 2;
+    Ok(())
+}
 
-    // Calculate optimal row height with spacing
-    let row_spacing = (cell_h * 0.15) as i32;
-    let effective_row_height = cell_h as i32 - row_spacing;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use corky_charts::{render_chart, LabelTimezone};
+
+    /// A small chart request, as a broker would send it
+    fn sample_request() -> serde_json::Value {
+        let rows: Vec<serde_json::Value> = (0..40)
+            .map(|i| {
+                let open = 100.0 + (i as f64 / 4.0).sin() * 5.0;
+                let close = 100.0 + ((i + 1) as f64 / 4.0).sin() * 5.0;
+                serde_json::json!([
+                    1_718_000_000_000_i64 + i * 900_000,
+                    open,
+                    open.max(close) + 1.0,
+                    open.min(close) - 1.0,
+                    close,
+                    1000 + i * 10
+                ])
+            })
+            .collect();
+        serde_json::json!([
+            "req-1",
+            "chart",
+            {"title": "BTCUSD 15m", "ticker": "BTCUSD", "timeframe": "15m", "desc": "", "data": rows}
+        ])
+    }
 
-    // Cell background color - medium grey for good visibility
-    let cell_bg_color = RGBColor(220, 220, 220);
+    /// Render options whose output does not depend on the machine
+    fn fixed_options() -> RenderOptions {
+        RenderOptions {
+            width: 640,
+            height: 480,
+            timezone: LabelTimezone::Fixed(chrono::FixedOffset::east_opt(0).unwrap()),
+            ..RenderOptions::default()
+        }
+    }
 
-    // Additional padding for bottom of cells
-    let bottom_padding = 6;
+    /// An empty directory for one test's files
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join("corky-charts-tests")
+            .join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
 
-    for (ri, row_label) in rows.iter().enumerate() {
-        // Calculate position with proper spacing between rows
-        let row_pos =
-            (ri as i32 * (effective_row_height + row_spacing + bottom_padding)) + cell_padding;
-        let row_height = effective_row_height;
-        let row_center = row_pos + (row_height / 2);
+    /// Width and height of the PNG in `bytes`, which must decode completely
+    fn decode_png(bytes: &[u8]) -> (u32, u32) {
+        let mut reader = png::Decoder::new(bytes).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut pixels).unwrap();
+        (frame.width, frame.height)
+    }
 
-        // Text color based on data
-        let text_color = if ri == 0 {
-            &last_candle_color
-        } else {
-            &BLACK
-        };
+    fn decoded_chart(payload: &[u8]) -> (ChartData, Encoding) {
+        match decode_payload(payload) {
+            Ok((Incoming::Chart(request), encoding)) => (request.2, encoding),
+            Ok((Incoming::Control(_), _)) => panic!("decoded as a control message"),
+            Err((detail, _)) => panic!("payload did not decode: {}", detail),
+        }
+    }
 
-        // Draw left column cell with padding on all sides
-        table_area.draw(&Rectangle::new(
-            [
-                (cell_padding, row_pos),
-                (mid_point - cell_padding, row_pos + row_height),
-            ],
-            cell_bg_color.filled(),
-        ))?;
-
-        // Draw right column cell with padding on all sides
-        table_area.draw(&Rectangle::new(
-            [
-                (mid_point + cell_padding, row_pos),
-                (section_width - cell_padding, row_pos + row_height),
-            ],
-            cell_bg_color.filled(),
-        ))?;
-
-        let text_y_adjustment = 4;
-        let text_y_pos = row_center - text_y_adjustment;
-
-        // Left column text (label) with proper vertical alignment
-        table_area.draw(&Text::new(
-            row_label.to_string(),
-            (cell_padding * 4, text_y_pos),
-            ("sans-serif", 14).into_font().color(text_color),
-        ))?;
-
-        // Right column text (value) with proper vertical alignment
-        table_area.draw(&Text::new(
-            table_data[ri].clone(),
-            (mid_point + cell_padding * 4, text_y_pos),
-            ("sans-serif", 14).into_font().color(text_color),
-        ))?;
-    }
-
-    // Add a horizontal line at the current price level using the same color as the last candle
-    chart_context.draw_series(std::iter::once(PathElement::new(
-        vec![
-            (start_millis as f64, current_price_log),
-            (end_millis as f64, current_price_log),
-        ],
-        last_candle_color.stroke_width(1),
-    )))?;
-
-    // --- Draw the candlestick bodies (no wicks) with consistent spacing ---
-
-    // First draw the wicks (thin dark grey rectangles) so they appear behind the candle bodies
-    chart_context.draw_series(
-        processed_data
-            .iter()
-            .map(|(dt, _o, h, l, _c, _v, _color_hex)| {
-                let dt_millis = millis_since_start(*dt) as f64;
+    #[test]
+    fn error_codes_serialize_to_stable_strings() {
+        let expected = [
+            "PARSE_ERROR",
+            "VALIDATION_ERROR",
+            "RENDER_ERROR",
+            "IO_ERROR",
+            "TIMEOUT",
+            "SNAPSHOT_REQUIRED",
+            "BUSY",
+        ];
+        assert_eq!(ErrorCode::ALL.len(), expected.len());
+        for (code, expected) in ErrorCode::ALL.into_iter().zip(expected) {
+            assert_eq!(code.as_str(), expected);
+            assert_eq!(serde_json::to_value(code).unwrap(), expected);
+        }
+    }
 
-                // Phase 5D: Use pre-computed wick_width
-                let wick_left = dt_millis - (wick_width / 2.0);
-                let wick_right = dt_millis + (wick_width / 2.0);
+    fn frames(parts: &[&[u8]]) -> Vec<Vec<u8>> {
+        parts.iter().map(|part| part.to_vec()).collect()
+    }
 
-                let high_log = h.ln();
-                let low_log = l.ln();
+    #[test]
+    fn dealer_frames_reply_with_an_empty_delimiter() {
+        let frames = frames(&[b"", b"{}"]);
+        let (envelope, payload) = split_frames(IngestMode::Dealer, &frames);
+        assert_eq!(envelope, vec![Vec::<u8>::new()]);
+        assert_eq!(payload, Some(&b"{}"[..]));
 
-                Rectangle::new(
-                    [(wick_left, high_log), (wick_right, low_log)],
-                    RGBColor(70, 70, 70).filled(),
-                )
-            }),
-    )?;
+        let (envelope, payload) = split_frames(IngestMode::Dealer, &frames[..1]);
+        assert_eq!(envelope, vec![Vec::<u8>::new()]);
+        assert_eq!(payload, None);
+    }
 
-    // Next draw the candle bodies on top of the wicks
-    chart_context.draw_series(
-        processed_data
-            .iter()
-            .map(|(dt, o, _h, _l, c, _v, color_hex)| {
-                let open_log = o.ln();
-                let close_log = c.ln();
-
-                let txt = color_hex.trim_start_matches('#');
-                let rgb = u32::from_str_radix(txt, 16).unwrap_or(0);
-                let r = ((rgb >> 16) & 0xFF) as u8;
-                let g = ((rgb >> 8) & 0xFF) as u8;
-                let b = (rgb & 0xFF) as u8;
-                let candle_color = RGBColor(r, g, b);
-
-                let (body_top, body_bottom) = if open_log <= close_log {
-                    (close_log, open_log)
-                } else {
-                    (open_log, close_log)
-                };
+    #[test]
+    fn bind_frames_reply_to_the_sender_identity() {
+        let frames = frames(&[b"client-7", b"", b"{}"]);
+        let (envelope, payload) = split_frames(IngestMode::Bind, &frames);
+        assert_eq!(envelope, vec![b"client-7".to_vec(), Vec::new()]);
+        assert_eq!(payload, Some(&b"{}"[..]));
+    }
 
-                // Phase 5D: Use pre-computed candle_width
-                let dt_millis = millis_since_start(*dt) as f64;
-                let body_left = dt_millis - (candle_width / 2.0);
-                let body_right = dt_millis + (candle_width / 2.0);
+    #[test]
+    fn bind_frames_without_a_delimiter_have_no_payload() {
+        let frames = frames(&[b"client-7", b"{}"]);
+        let (envelope, payload) = split_frames(IngestMode::Bind, &frames);
+        assert_eq!(envelope, vec![b"client-7".to_vec(), Vec::new()]);
+        assert_eq!(payload, None);
+    }
 
-                Rectangle::new(
-                    [(body_left, body_top), (body_right, body_bottom)],
-                    candle_color.filled(),
-                )
-            }),
-    )?;
+    #[test]
+    fn messagepack_request_renders_like_json() {
+        let request = sample_request();
+        let (from_json, json_encoding) = decoded_chart(request.to_string().as_bytes());
+        let (from_msgpack, msgpack_encoding) =
+            decoded_chart(&rmp_serde::to_vec(&request).unwrap());
+        assert!(matches!(json_encoding, Encoding::Json));
+        assert!(matches!(msgpack_encoding, Encoding::MessagePack));
+
+        let options = fixed_options();
+        assert_eq!(
+            render_chart(&from_json, &options).unwrap(),
+            render_chart(&from_msgpack, &options).unwrap()
+        );
+    }
 
-    // --- Draw markers from plots.marks ---
-    let candle_duration_millis = if processed_data.len() > 1 {
-        let total_millis = (end_millis as f64) - (start_millis as f64);
-        total_millis / processed_data.len() as f64
-    } else {
-        60000.0 // Default 1 minute if only one candle
-    };
-    let marker_candle_width = candle_duration_millis * 0.8;
+    #[test]
+    fn zstd_and_gzip_payloads_decompress_to_the_request() {
+        let json = sample_request().to_string().into_bytes();
+        let zstd = zstd::encode_all(&json[..], 3).unwrap();
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&json).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert!(zstd.starts_with(&ZSTD_MAGIC));
+        assert!(gzip.starts_with(&GZIP_MAGIC));
+
+        for compressed in [zstd, gzip] {
+            let decompressed = decompress_payload(&compressed, DEFAULT_MAX_DECOMPRESSED_BYTES);
+            assert_eq!(decompressed.unwrap().as_ref(), &json[..]);
+        }
+        // Anything else passes through untouched
+        assert!(matches!(decompress_payload(&json, 16), Ok(Cow::Borrowed(_))));
+    }
 
-    // Phase 5C: Pre-build sorted timestamp vec for binary search
-    let candle_timestamps: Vec<i64> = processed_data
-        .iter()
-        .map(|(dt, _, _, _, _, _, _)| millis_since_start(*dt))
-        .collect();
+    #[test]
+    fn payload_over_the_decompressed_limit_is_rejected() {
+        let zeros = vec![0u8; 64 * 1024];
+        let zstd = zstd::encode_all(&zeros[..], 3).unwrap();
+        let error = decompress_payload(&zstd, 1024).unwrap_err();
+        assert!(error.contains("more than the 1024 byte limit"), "{}", error);
 
-    for mark in &data.plots.marks {
-        let mark_time_millis = mark.time - start_dt.timestamp_millis();
-        let x = mark_time_millis as f64;
-
-        // Phase 5C: Binary search for candle lookup O(log N) instead of O(N)
-        let half_candle = (candle_duration_millis as i64) / 2;
-        let candle_idx = {
-            let pos = candle_timestamps.partition_point(|&t| t < mark_time_millis);
-            // Check the candidate and its neighbor for closest match
-            let mut found = None;
-            if pos < candle_timestamps.len()
-                && (candle_timestamps[pos] - mark_time_millis).abs() < half_candle
-            {
-                found = Some(pos);
-            } else if pos > 0
-                && (candle_timestamps[pos - 1] - mark_time_millis).abs() < half_candle
-            {
-                found = Some(pos - 1);
+        // Exactly at the limit is fine
+        assert_eq!(decompress_payload(&zstd, zeros.len()).unwrap().len(), zeros.len());
+    }
+
+    #[test]
+    fn concurrent_writes_to_one_file_leave_a_whole_image() {
+        let (data, _) = decoded_chart(sample_request().to_string().as_bytes());
+        let images: Vec<Vec<u8>> = [320, 480, 640, 800]
+            .into_iter()
+            .map(|width| {
+                let options = RenderOptions { width, ..fixed_options() };
+                render_chart(&data, &options).unwrap()
+            })
+            .collect();
+
+        for mode in [CoalesceMode::Wait, CoalesceMode::Supersede] {
+            let path = scratch_dir("output-locks").join("BTCUSD_15m.png");
+            let path = path.to_str().unwrap();
+            let locks = OutputLocks::new(mode);
+            let writing = AtomicBool::new(false);
+            std::thread::scope(|scope| {
+                for thread in 0..8 {
+                    let (locks, writing, images) = (&locks, &writing, &images);
+                    scope.spawn(move || {
+                        for round in 0..5 {
+                            let Some(_guard) = locks.acquire(path) else {
+                                continue;
+                            };
+                            assert!(!writing.swap(true, Ordering::SeqCst), "two writers at once");
+                            write_atomically(path, &images[(thread + round) % images.len()])
+                                .unwrap();
+                            writing.store(false, Ordering::SeqCst);
+                        }
+                    });
+                }
+            });
+            let (width, _) = decode_png(&fs::read(path).unwrap());
+            assert!([320, 480, 640, 800].contains(&width));
+        }
+    }
+
+    #[test]
+    fn atomic_writes_leave_no_temp_files() {
+        let dir = scratch_dir("write-atomically");
+        let (data, _) = decoded_chart(sample_request().to_string().as_bytes());
+        let image = render_chart(&data, &fixed_options()).unwrap();
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let (dir, image) = (&dir, &image);
+                scope.spawn(move || {
+                    for chart in 0..5 {
+                        let path = dir.join(format!("chart_{}.png", (thread + chart) % 6));
+                        timed_write(path.to_str().unwrap(), image).unwrap();
+                    }
+                });
             }
-            found
+        });
+        // A write that cannot be renamed into place cleans up after itself
+        let occupied = dir.join("occupied.png");
+        fs::create_dir(&occupied).unwrap();
+        fs::create_dir(occupied.join("not-empty")).unwrap();
+        assert!(write_atomically(occupied.to_str().unwrap(), &image).is_err());
+
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert!(names.iter().all(|name| !name.contains(".tmp")), "{:?}", names);
+        assert_eq!(names.len(), 7, "{:?}", names);
+        for name in names.iter().filter(|name| name.starts_with("chart_")) {
+            decode_png(&fs::read(dir.join(name)).unwrap());
+        }
+    }
+
+    #[test]
+    fn malformed_requests_name_the_problem() {
+        let mut missing_ticker = sample_request();
+        missing_ticker[2].as_object_mut().unwrap().remove("ticker");
+        let mut bad_row = sample_request();
+        bad_row[2]["data"][3][2] = serde_json::json!("high");
+        let mut bad_nested = sample_request();
+        bad_nested[2]["candle_layout"] = serde_json::json!("sideways");
+        let cases = [
+            (
+                serde_json::json!("chart"),
+                "expected a chart request array [id, command, chart_data], found a string",
+            ),
+            (serde_json::json!(["req-1", "chart"]), "element 2 (chart_data) is missing"),
+            (
+                serde_json::json!([7, "chart", {}]),
+                "element 0 (id) must be a string, found a number",
+            ),
+            (
+                serde_json::json!(["req-1", "chart", []]),
+                "element 2 (chart_data) must be an object, found an array",
+            ),
+            (
+                serde_json::json!(["req-1", "chart", {}, 4]),
+                "expected 3 elements [id, command, chart_data], found 4",
+            ),
+            (missing_ticker, "chart_data: missing field `ticker`"),
+            (bad_row, "data row 3: \"high\" in column 2 is not a number"),
+            (bad_nested, "chart_data.candle_layout: unknown variant `sideways`"),
+        ];
+        for (value, expected) in cases {
+            let problem = chart_request_problem(&value);
+            assert!(problem.contains(expected), "{:?} gave {:?}", value, problem);
+            // The same payload through the decoder reports the same problem
+            let Err((detail, _)) = decode_payload(value.to_string().as_bytes()) else {
+                panic!("{:?} decoded", value);
+            };
+            assert_eq!(detail, problem);
+        }
+    }
+
+    #[test]
+    fn filename_templates_expand_their_placeholders() {
+        let value = |name: &str| (name == "ticker").then(|| "BTCUSD".to_string());
+        let expanded = expand_filename_template("{ticker}/chart.png", value).unwrap();
+        assert_eq!(expanded, "BTCUSD/chart.png");
+        assert_eq!(expand_filename_template("plain.png", value).unwrap(), "plain.png");
+
+        let unknown = expand_filename_template("{ticker}_{nope}.png", value).unwrap_err();
+        assert!(unknown.contains("unknown placeholder {nope}"), "{}", unknown);
+        for unbalanced in ["{ticker.png", "ticker}.png", "{ticker}}.png"] {
+            let error = expand_filename_template(unbalanced, value).unwrap_err();
+            assert!(error.contains("unbalanced brace"), "{}: {}", unbalanced, error);
+        }
+    }
+
+    #[test]
+    fn output_file_names_are_sanitized_and_stay_inside_the_directory() {
+        let (mut data, _) = decoded_chart(sample_request().to_string().as_bytes());
+        data.ticker = "../BTC/USDT".to_string();
+        let rendered_at = Local.timestamp_millis_opt(1_718_000_000_123).unwrap();
+        let name = |template: &str, data: &ChartData| {
+            output_file_name(template, "req/1", data, ImageFormat::Png, rendered_at)
         };
 
-        if let Some(idx) = candle_idx {
-            let (_, _, h, l, _, _, _) = &processed_data[idx];
-            let size = mark.size;
-            let log_range = max_log_for_chart - min_log_for_chart;
-            let offset = log_range * 0.02 * size;
+        assert_eq!(name(DEFAULT_FILENAME_TEMPLATE, &data).unwrap(), "BTCUSDT_15m.png");
+        assert_eq!(
+            name("{ticker}/{date}_{time}_{id}.{ext}", &data).unwrap(),
+            format!("BTCUSDT/{}_req1.png", rendered_at.format("%Y-%m-%d_%H%M%S"))
+        );
+        assert_eq!(name("{ts}.{ext}", &data).unwrap(), "1718000000123.png");
 
-            let y = if mark.position == "above" {
-                h.ln() + offset
-            } else {
-                l.ln() - offset
-            };
+        for escaping in ["../{ticker}.png", "/tmp/{ticker}.png", "a//{ticker}.png", "{ticker}/"] {
+            let error = name(escaping, &data).unwrap_err();
+            assert!(error.contains("must be a relative path"), "{}: {}", escaping, error);
+        }
 
-            let color = parse_hex_color(&mark.color);
-            let triangle_half_width = marker_candle_width / 3.0 * size;
-            let triangle_height = offset / 2.0;
-
-            // Draw triangle marker
-            if mark.position == "above" {
-                chart_context.draw_series(std::iter::once(Polygon::new(
-                    vec![
-                        (x, y - triangle_height),
-                        (x - triangle_half_width, y + triangle_height),
-                        (x + triangle_half_width, y + triangle_height),
-                    ],
-                    color.filled(),
-                )))?;
-            } else {
-                chart_context.draw_series(std::iter::once(Polygon::new(
-                    vec![
-                        (x, y + triangle_height),
-                        (x - triangle_half_width, y - triangle_height),
-                        (x + triangle_half_width, y - triangle_height),
-                    ],
-                    color.filled(),
-                )))?;
-            }
+        data.append_timestamp = true;
+        assert_eq!(name("{ticker}.{ext}", &data).unwrap(), "BTCUSDT_1718000000123.png");
+        assert_eq!(name("{ticker}/latest", &data).unwrap(), "BTCUSDT/latest_1718000000123");
+        assert!(name("../{ticker}.png", &data).is_err());
+    }
 
-            // Draw label text if provided
-            if let Some(text) = &mark.text {
-                let text_y = if mark.position == "above" {
-                    y + offset * 1.2
-                } else {
-                    y - offset * 1.2
-                };
-                let font_size = (12.0 * size).max(8.0) as i32;
-                chart_context.draw_series(std::iter::once(Text::new(
-                    text.clone(),
-                    (x, text_y),
-                    TextStyle::from(("sans-serif", font_size)).color(&color),
-                )))?;
-            }
+    #[test]
+    fn output_paths_outside_the_directory_are_rejected() {
+        for path in ["chart.png", "BTCUSD/2024-06-10/chart.png", "a..b.png", ".hidden.png"] {
+            assert_eq!(checked_output_path(path), Ok(path));
+        }
+        for path in ["", "..", "../chart.png", "a/../../chart.png", "/etc/passwd", "./chart.png"] {
+            assert!(checked_output_path(path).is_err(), "{:?} was accepted", path);
+        }
+        for path in ["a\\..\\chart.png", "a/./chart.png", "a//chart.png", "dir/"] {
+            assert!(checked_output_path(path).is_err(), "{:?} was accepted", path);
         }
     }
 
-    // Present and save the result
-    root_area.present()?;
+    #[test]
+    fn malicious_tickers_sanitize_to_one_plain_component() {
+        let cases = [
+            ("BTC/USDT", "BTCUSDT"),
+            ("../../etc/passwd", "etcpasswd"),
+            ("..\\..\\Windows", "Windows"),
+            ("...hidden", "hidden"),
+            (".env", "env"),
+            ("BTC..USD..", "BTC.USD"),
+            ("BTC\u{0}USD\n\r\t", "BTCUSD"),
+            ("BTC USD:PERP*", "BTC_USD_PERP_"),
+            ("ÉTH", "_TH"),
+            ("..", ""),
+        ];
+        for (ticker, expected) in cases {
+            let sanitized = sanitize_path_component(ticker);
+            assert_eq!(sanitized, expected, "{:?}", ticker);
+            assert!(!sanitized.starts_with('.') && !sanitized.contains(".."));
+            assert!(sanitized.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)));
+        }
+    }
 
-    info!(
-        "[{}] Chart processing complete. Saved to: {}",
-        now, file_path
-    );
+    fn row(ts: f64, close: f64) -> Vec<f64> {
+        vec![ts, close, close, close, close, 1.0]
+    }
 
-    // Phase 4B: Send notification using shared ZMQ context
-    if let Err(e) = send_telegram_notification(zmq_ctx, data, &file_path) {
-        error!("[{}] Failed to send telegram notification: {}", now, e);
-    } else {
-        let destination = match (&data.chat_id, &data.subscriber_list) {
-            (Some(chat_id), _) => format!("chat_id: {}", chat_id),
-            (_, Some(list)) => format!("subscriber_list: {}", list),
-            _ => "default destination".to_string(),
+    fn timestamps(rows: &[Vec<f64>]) -> Vec<f64> {
+        rows.iter().map(|row| row[0]).collect()
+    }
+
+    #[test]
+    fn merged_rows_replace_overlapping_candles() {
+        let mut rows = vec![row(1.0, 10.0), row(2.0, 20.0), row(3.0, 30.0)];
+        merge_rows(&mut rows, vec![row(3.0, 31.0), row(4.0, 40.0)], 10);
+        assert_eq!(timestamps(&rows), [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(rows[2][4], 31.0);
+        assert_eq!(rows[3][4], 40.0);
+
+        // An update repeating a timestamp keeps its last row
+        merge_rows(&mut rows, vec![row(5.0, 50.0), row(5.0, 51.0)], 10);
+        assert_eq!(timestamps(&rows), [1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(rows[4][4], 51.0);
+    }
+
+    #[test]
+    fn merged_rows_put_late_candles_back_in_order() {
+        let mut rows = vec![row(10.0, 1.0), row(30.0, 3.0)];
+        merge_rows(&mut rows, vec![row(40.0, 4.0), row(20.0, 2.0), row(5.0, 0.5)], 10);
+        assert_eq!(timestamps(&rows), [5.0, 10.0, 20.0, 30.0, 40.0]);
+        assert_eq!(rows.iter().map(|row| row[4]).collect::<Vec<_>>(), [0.5, 1.0, 2.0, 3.0, 4.0]);
+
+        // Unsorted stored rows are sorted as well
+        let mut rows = vec![row(3.0, 3.0), row(1.0, 1.0)];
+        merge_rows(&mut rows, vec![row(2.0, 2.0)], 10);
+        assert_eq!(timestamps(&rows), [1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn merged_rows_keep_the_newest_and_drop_rows_without_a_timestamp() {
+        let mut rows: Vec<Vec<f64>> = (0..5).map(|i| row(i as f64, i as f64)).collect();
+        merge_rows(&mut rows, vec![Vec::new(), row(7.0, 7.0), row(6.0, 6.0)], 4);
+        assert_eq!(timestamps(&rows), [3.0, 4.0, 6.0, 7.0]);
+    }
+
+
+    /// A dispatcher writing into `dir`, and the receiving end of its replies
+    fn test_dispatcher(dir: &Path) -> (Dispatcher, mpsc::Receiver<ReplyFrames>) {
+        let config = ChartsConfig {
+            directory: Some(dir.to_string_lossy().into_owned()),
+            series_cache_entries: Some(4),
+            ..ChartsConfig::default()
         };
-        info!(
-            "[{}] Telegram notification sent to {}",
-            now, destination
-        );
+        let args = resolve_args(CommandLine::default(), &config).unwrap();
+        let settings = load_settings(&config, &args).unwrap();
+        let (reply_tx, reply_rx) = mpsc::channel();
+        let dispatcher = Dispatcher {
+            recent_requests: RecentRequests::new(settings.dedup_capacity, settings.dedup_ttl),
+            series_store: SeriesStore::new(
+                settings.series_cache_entries,
+                settings.series_cache_max_candles,
+            ),
+            candle_cache: Arc::new(CandleCache::new(settings.candle_cache_entries)),
+            output_locks: Arc::new(OutputLocks::new(settings.coalesce)),
+            batch_pool: Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap()),
+            settings: Arc::new(settings),
+            zmq_ctx: Arc::new(zmq::Context::new()),
+            reply_tx,
+            started: Instant::now(),
+            shutdown_requested: false,
+        };
+        (dispatcher, reply_rx)
     }
 
-    Ok(())
-}
+    /// Every reply queued so far, as JSON
+    fn replies(rx: &mpsc::Receiver<ReplyFrames>) -> Vec<serde_json::Value> {
+        rx.try_iter().map(|frames| serde_json::from_slice(&frames[0]).unwrap()).collect()
+    }
 
-/// Send a notification to the telegram service via ZMQ with the chart details and image path
-/// Phase 4B: Accepts shared zmq::Context instead of creating a new one each call
-fn send_telegram_notification(
-    zmq_ctx: &zmq::Context,
-    data: &ChartData,
-    image_path: &str,
-) -> Result<(), Box<dyn Error>> {
-    let socket = zmq_ctx.socket(zmq::DEALER)?;
+    #[test]
+    fn requests_are_answered_busy_and_left_unmerged_when_every_chart_thread_is_taken() {
+        let dir = scratch_dir("busy");
+        let (mut dispatcher, rx) = test_dispatcher(&dir);
+        ACTIVE_THREADS.fetch_add(MAX_CONCURRENT_CHARTS, Ordering::SeqCst);
+
+        let chart = sample_request();
+        dispatcher.dispatch(Vec::new(), Some(chart.to_string().as_bytes()));
+        let mut second = sample_request();
+        second[0] = "req-2".into();
+        let batch = serde_json::json!([chart, second]);
+        dispatcher.dispatch(Vec::new(), Some(batch.to_string().as_bytes()));
+
+        ACTIVE_THREADS.fetch_sub(MAX_CONCURRENT_CHARTS, Ordering::SeqCst);
+        // The turned-away full requests left no snapshot for an append to merge into
+        let mut append = sample_request();
+        append[0] = "req-3".into();
+        append[2]["mode"] = "append".into();
+        dispatcher.dispatch(Vec::new(), Some(append.to_string().as_bytes()));
+
+        let replies = replies(&rx);
+        assert_eq!(replies.len(), 5, "{:?}", replies);
+        assert_eq!(replies[0]["status"], "error");
+        assert_eq!(replies[0]["code"], "BUSY");
+        assert_eq!(replies[0]["request_id"], "req-1");
+        for (reply, id) in replies[1..3].iter().zip(["req-1", "req-2"]) {
+            assert_eq!(reply["code"], "BUSY");
+            assert_eq!(reply["request_id"], id);
+            assert_eq!(reply["batch_id"], "batch-req-1");
+        }
+        let summary = &replies[3]["results"];
+        assert_eq!(summary.as_array().unwrap().len(), 2);
+        for item in summary.as_array().unwrap() {
+            assert_eq!(item["status"], "error");
+            assert_eq!(item["code"], "BUSY");
+        }
+        assert_eq!(replies[4]["code"], "SNAPSHOT_REQUIRED", "{}", replies[4]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0, "nothing is rendered");
+    }
 
-    // Connect to the same endpoint as the main application
-    let endpoint = "tcp://127.0.0.1:6565";
-    socket.connect(endpoint)?;
 
-    // Create the payload with chat_id and subscriber_list if available
-    let payload = serde_json::json!([
-        "ok",
-        "send_message",
-        {
-            "text": data.desc,
-            "image_path": image_path,
-            "chat_id": data.chat_id,
-            "subscriber_list": data.subscriber_list
+    #[test]
+    fn purge_counts_images_but_not_their_coordinates() {
+        let dir = scratch_dir("purge");
+        fs::create_dir(dir.join("BTCUSD")).unwrap();
+        for name in ["a.png", "a.coords.json", "BTCUSD/b.webp", "BTCUSD/b.coords.json", "c.txt"] {
+            fs::write(dir.join(name), b"x").unwrap();
         }
-    ]);
 
-    // Convert to string
-    let json_message = payload.to_string();
+        let removed = purge_output_dir(&dir.to_string_lossy(), Duration::ZERO).unwrap();
+        assert_eq!(removed, 2);
+        assert!(dir.join("c.txt").exists());
+        assert!(!dir.join("a.coords.json").exists());
+        assert!(!dir.join("BTCUSD/b.coords.json").exists());
+    }
 
-    // Send the multipart message
-    socket.send_multipart([b"telegram", json_message.as_bytes()], 0)?;
 
-    Ok(())
+    #[test]
+    fn recent_requests_evict_the_least_recently_used() {
+        let mut recent = RecentRequests::new(2, Duration::from_secs(60));
+        recent.remember("a", b"payload a");
+        recent.remember("b", b"payload b");
+        // The retransmission of `a` makes `b` the least recently used
+        assert!(recent.is_duplicate("a", b"payload a"));
+        recent.remember("c", b"payload c");
+
+        assert!(recent.is_duplicate("a", b"payload a"));
+        assert!(!recent.is_duplicate("b", b"payload b"));
+        assert!(recent.is_duplicate("c", b"payload c"));
+        assert!(!recent.is_duplicate("a", b"another payload"));
+    }
 }
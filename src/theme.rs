@@ -0,0 +1,233 @@
+//! Color schemes a request picks by name with `color_scheme`.
+//!
+//! Each scheme is one row of [`PRESETS`]; adding a scheme is adding a row.
+
+use plotters::style::RGBColor;
+
+use crate::{ChartData, ChartError, parse_hex_color};
+
+/// Every color of a chart that the request does not give itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Theme {
+    /// Canvas, and the inside of hollow candles
+    pub background: RGBColor,
+    /// Bodies of rising and falling candles without an entry in `candle_colors`
+    pub up: RGBColor,
+    pub down: RGBColor,
+    /// Wicks of rising and falling candles
+    pub wick_up: RGBColor,
+    pub wick_down: RGBColor,
+    /// Outline of every candle body; `None` outlines them only when the request asks
+    pub border: Option<RGBColor>,
+    /// Volume bars, and bars colored by their candle's direction
+    pub volume: RGBColor,
+    pub volume_up: RGBColor,
+    pub volume_down: RGBColor,
+    /// Major grid lines; minor and vertical lines are lighter shades of it
+    pub grid: RGBColor,
+    pub axis: RGBColor,
+    /// Title, axis labels and table text
+    pub text: RGBColor,
+    /// Current price in the table, last price dot and tick, price change and the
+    /// thumbnail line, by whether the price is up or down
+    pub price_up: RGBColor,
+    pub price_down: RGBColor,
+    /// Reference line at the current price
+    pub price_line: RGBColor,
+}
+
+/// Scheme used when a request names none
+const DEFAULT_SCHEME: &str = "default";
+
+/// Every scheme, by the name a request gives in `color_scheme`
+const PRESETS: [(&str, Theme); 5] = [
+    (
+        DEFAULT_SCHEME,
+        Theme {
+            background: RGBColor(255, 255, 255),
+            up: RGBColor(0, 150, 0),
+            down: RGBColor(180, 0, 0),
+            wick_up: RGBColor(70, 70, 70),
+            wick_down: RGBColor(70, 70, 70),
+            border: None,
+            volume: RGBColor(130, 130, 130),
+            volume_up: RGBColor(0, 150, 0),
+            volume_down: RGBColor(180, 0, 0),
+            grid: RGBColor(235, 235, 235),
+            axis: RGBColor(150, 150, 150),
+            text: RGBColor(0, 0, 0),
+            price_up: RGBColor(0, 150, 0),
+            price_down: RGBColor(180, 0, 0),
+            price_line: RGBColor(100, 100, 100),
+        },
+    ),
+    (
+        "classic",
+        Theme {
+            background: RGBColor(255, 255, 255),
+            up: RGBColor(0, 170, 0),
+            down: RGBColor(220, 0, 0),
+            wick_up: RGBColor(0, 170, 0),
+            wick_down: RGBColor(220, 0, 0),
+            border: None,
+            volume: RGBColor(130, 130, 130),
+            volume_up: RGBColor(0, 170, 0),
+            volume_down: RGBColor(220, 0, 0),
+            grid: RGBColor(230, 230, 230),
+            axis: RGBColor(120, 120, 120),
+            text: RGBColor(0, 0, 0),
+            price_up: RGBColor(0, 150, 0),
+            price_down: RGBColor(200, 0, 0),
+            price_line: RGBColor(80, 80, 80),
+        },
+    ),
+    (
+        "tradingview",
+        Theme {
+            background: RGBColor(255, 255, 255),
+            up: RGBColor(38, 166, 154),
+            down: RGBColor(239, 83, 80),
+            wick_up: RGBColor(38, 166, 154),
+            wick_down: RGBColor(239, 83, 80),
+            border: None,
+            volume: RGBColor(120, 123, 134),
+            volume_up: RGBColor(146, 210, 204),
+            volume_down: RGBColor(247, 169, 167),
+            grid: RGBColor(240, 243, 250),
+            axis: RGBColor(178, 181, 190),
+            text: RGBColor(19, 23, 34),
+            price_up: RGBColor(38, 166, 154),
+            price_down: RGBColor(239, 83, 80),
+            price_line: RGBColor(120, 123, 134),
+        },
+    ),
+    (
+        "mono",
+        Theme {
+            background: RGBColor(255, 255, 255),
+            up: RGBColor(255, 255, 255),
+            down: RGBColor(0, 0, 0),
+            wick_up: RGBColor(0, 0, 0),
+            wick_down: RGBColor(0, 0, 0),
+            border: Some(RGBColor(0, 0, 0)),
+            volume: RGBColor(160, 160, 160),
+            volume_up: RGBColor(200, 200, 200),
+            volume_down: RGBColor(90, 90, 90),
+            grid: RGBColor(235, 235, 235),
+            axis: RGBColor(0, 0, 0),
+            text: RGBColor(0, 0, 0),
+            price_up: RGBColor(0, 0, 0),
+            price_down: RGBColor(0, 0, 0),
+            price_line: RGBColor(0, 0, 0),
+        },
+    ),
+    (
+        "colorblind",
+        Theme {
+            background: RGBColor(255, 255, 255),
+            up: RGBColor(0, 114, 178),
+            down: RGBColor(230, 159, 0),
+            wick_up: RGBColor(0, 114, 178),
+            wick_down: RGBColor(230, 159, 0),
+            border: None,
+            volume: RGBColor(150, 150, 150),
+            volume_up: RGBColor(0, 114, 178),
+            volume_down: RGBColor(230, 159, 0),
+            grid: RGBColor(235, 235, 235),
+            axis: RGBColor(150, 150, 150),
+            text: RGBColor(0, 0, 0),
+            price_up: RGBColor(0, 114, 178),
+            price_down: RGBColor(213, 94, 0),
+            price_line: RGBColor(100, 100, 100),
+        },
+    ),
+];
+
+impl Theme {
+    /// The request's `color_scheme` with its `up_color`, `down_color` and `wick_color`
+    /// laid over it. An unknown scheme name is a validation error.
+    pub(crate) fn for_request(data: &ChartData) -> Result<Theme, ChartError> {
+        let name = data.color_scheme.as_deref().unwrap_or(DEFAULT_SCHEME);
+        let Some(mut theme) = PRESETS
+            .iter()
+            .find(|(preset, _)| preset.eq_ignore_ascii_case(name.trim()))
+            .map(|(_, theme)| *theme)
+        else {
+            let names: Vec<&str> = PRESETS.iter().map(|(preset, _)| *preset).collect();
+            return Err(ChartError::invalid(format!(
+                "unknown color_scheme \"{}\", expected one of: {}",
+                name,
+                names.join(", ")
+            )));
+        };
+        if let Some(hex) = &data.up_color {
+            theme.up = parse_hex_color(hex);
+        }
+        if let Some(hex) = &data.down_color {
+            theme.down = parse_hex_color(hex);
+        }
+        if let Some(hex) = &data.wick_color {
+            theme.wick_up = parse_hex_color(hex);
+            theme.wick_down = parse_hex_color(hex);
+        }
+        Ok(theme)
+    }
+
+    /// Minor grid lines, a quarter of the way from the major grid color to the background
+    pub(crate) fn grid_minor(&self) -> RGBColor {
+        blend(self.grid, self.background, 0.25)
+    }
+
+    /// Vertical grid lines, halfway from the major grid color to the background
+    pub(crate) fn grid_vertical(&self) -> RGBColor {
+        blend(self.grid, self.background, 0.5)
+    }
+}
+
+/// `from` moved `amount` (0 to 1) of the way to `to`
+fn blend(from: RGBColor, to: RGBColor, amount: f64) -> RGBColor {
+    let channel = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * amount).round() as u8;
+    RGBColor(channel(from.0, to.0), channel(from.1, to.1), channel(from.2, to.2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LabelTimezone, RenderOptions, render_chart};
+
+    fn fixture() -> ChartData {
+        serde_json::from_str(include_str!("../tests/fixtures/small.json")).unwrap()
+    }
+
+    #[test]
+    fn every_preset_renders_the_fixture() {
+        let opts = RenderOptions {
+            width: 640,
+            height: 480,
+            timezone: LabelTimezone::Fixed(chrono::FixedOffset::east_opt(0).unwrap()),
+            ..RenderOptions::default()
+        };
+        for (name, theme) in PRESETS {
+            let mut data = fixture();
+            data.color_scheme = Some(name.to_uppercase());
+            let bytes = render_chart(&data, &opts).unwrap();
+
+            let mut reader = png::Decoder::new(bytes.as_slice()).read_info().unwrap();
+            let mut pixels = vec![0; reader.output_buffer_size()];
+            reader.next_frame(&mut pixels).unwrap();
+            let RGBColor(r, g, b) = theme.down;
+            let drawn = pixels.chunks(3).any(|pixel| pixel == [r, g, b]);
+            assert!(drawn, "{} drew no down candles", name);
+            let RGBColor(r, g, b) = theme.background;
+            assert_eq!(pixels[..3], [r, g, b], "{}", name);
+        }
+    }
+
+    #[test]
+    fn unknown_scheme_lists_the_presets() {
+        let mut data = fixture();
+        data.color_scheme = Some("neon".to_string());
+        let err = Theme::for_request(&data).unwrap_err().to_string();
+        assert!(err.contains("default, classic, tradingview, mono, colorblind"), "{}", err);
+    }
+}
@@ -0,0 +1,151 @@
+//! Golden-image tests: each fixture in `tests/fixtures` is rendered at a fixed size
+//! with labels in UTC and compared with its reference PNG in `tests/golden`.
+//!
+//! Run with `UPDATE_GOLDEN=1 cargo test --test golden` to write the references from
+//! the current renderer. A mismatch writes the rendered image and a diff image, with
+//! the differing pixels in red, to `target/tmp/golden-diff`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::FixedOffset;
+use corky_charts::{render_chart, ChartData, LabelTimezone, RenderOptions};
+
+const WIDTH: u32 = 800;
+const HEIGHT: u32 = 600;
+/// Largest difference in any channel that still counts as the same pixel, enough to
+/// absorb antialiasing that differs between builds of the font rasterizer
+const CHANNEL_TOLERANCE: u8 = 16;
+/// Share of pixels that may differ before an image no longer matches its reference
+const MAX_DIFFERING_SHARE: f64 = 0.001;
+
+/// Width, height and RGB pixels of a decoded PNG
+struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    fn decode(bytes: &[u8]) -> Image {
+        let mut reader = png::Decoder::new(bytes).read_info().expect("image is a PNG");
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).expect("PNG decodes");
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        pixels.truncate(info.buffer_size());
+        Image { width: info.width, height: info.height, pixels }
+    }
+
+    fn write(&self, path: &Path) {
+        let file = fs::File::create(path).expect("diff image is writable");
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&self.pixels).unwrap();
+    }
+}
+
+/// `expected` faded, with the pixels of `actual` that differ from it in red, and how
+/// many those are
+fn diff(expected: &Image, actual: &Image) -> (usize, Image) {
+    let mut differing = 0;
+    let pixels = expected
+        .pixels
+        .chunks(3)
+        .zip(actual.pixels.chunks(3))
+        .flat_map(|(want, got)| {
+            if want.iter().zip(got).any(|(a, b)| a.abs_diff(*b) > CHANNEL_TOLERANCE) {
+                differing += 1;
+                [255, 0, 0]
+            } else {
+                want.iter().map(|&c| 191 + c / 4).collect::<Vec<_>>().try_into().unwrap()
+            }
+        })
+        .collect();
+    (differing, Image { width: expected.width, height: expected.height, pixels })
+}
+
+/// Render `tests/fixtures/{name}.json` and compare it with `tests/golden/{name}.png`,
+/// or replace the reference when `UPDATE_GOLDEN` is set
+fn check(name: &str) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let fixture = root.join("tests/fixtures").join(name).with_extension("json");
+    let data: ChartData = serde_json::from_str(&fs::read_to_string(fixture).unwrap()).unwrap();
+    let opts = RenderOptions {
+        width: WIDTH,
+        height: HEIGHT,
+        timezone: LabelTimezone::Fixed(FixedOffset::east_opt(0).unwrap()),
+        ..RenderOptions::default()
+    };
+    let rendered = render_chart(&data, &opts).unwrap();
+
+    let reference = root.join("tests/golden").join(name).with_extension("png");
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(reference.parent().unwrap()).unwrap();
+        fs::write(&reference, &rendered).unwrap();
+        return;
+    }
+    let expected = fs::read(&reference).unwrap_or_else(|e| {
+        panic!("{}: {}; run with UPDATE_GOLDEN=1 to create it", reference.display(), e)
+    });
+    let (expected, actual) = (Image::decode(&expected), Image::decode(&rendered));
+
+    let out_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("golden-diff");
+    let keep_failure = |diff_image: Option<&Image>| {
+        fs::create_dir_all(&out_dir).unwrap();
+        fs::write(out_dir.join(format!("{}.actual.png", name)), &rendered).unwrap();
+        if let Some(diff_image) = diff_image {
+            diff_image.write(&out_dir.join(format!("{}.diff.png", name)));
+        }
+    };
+    if (actual.width, actual.height) != (expected.width, expected.height) {
+        keep_failure(None);
+        panic!(
+            "{} is {}x{}, its reference {}x{}; the render is in {}",
+            name,
+            actual.width,
+            actual.height,
+            expected.width,
+            expected.height,
+            out_dir.display()
+        );
+    }
+    let (differing, diff_image) = diff(&expected, &actual);
+    let share = differing as f64 / (expected.width * expected.height) as f64;
+    if share > MAX_DIFFERING_SHARE {
+        keep_failure(Some(&diff_image));
+        panic!(
+            "{} differs from its reference in {} pixels ({:.3}%); see {}",
+            name,
+            differing,
+            share * 100.0,
+            out_dir.display()
+        );
+    }
+}
+
+#[test]
+fn small() {
+    check("small");
+}
+
+#[test]
+fn medium() {
+    check("medium");
+}
+
+#[test]
+fn gappy() {
+    check("gappy");
+}
+
+#[test]
+fn single_candle() {
+    check("single");
+}
+
+#[test]
+fn flat_range() {
+    check("flat");
+}
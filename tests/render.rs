@@ -0,0 +1,445 @@
+//! Renders fixture charts through the library API, without the service.
+//!
+//! Fixtures live in `tests/fixtures`; each test renders at a fixed size with
+//! labels in UTC so the output does not depend on the machine running it.
+
+use std::fs;
+use std::path::Path;
+
+use chrono::FixedOffset;
+use corky_charts::{
+    render_chart, render_chart_with_coords, ChartCoords, ChartData, ChartStyle, ImageFormat,
+    LabelTimezone, Plots, PriceScale, RenderOptions, TimestampUnit, METADATA_SCHEMA_VERSION,
+};
+
+const WIDTH: u32 = 800;
+const HEIGHT: u32 = 600;
+
+// ─── Helpers ────────────────────────────────────────────────────────────────────
+
+/// The chart in `tests/fixtures/{name}.json`
+fn fixture(name: &str) -> ChartData {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+    let text = fs::read_to_string(path.with_extension("json")).expect("fixture is readable");
+    serde_json::from_str(&text).expect("fixture is a chart")
+}
+
+/// PNG at the test size with labels in UTC
+fn options() -> RenderOptions {
+    RenderOptions {
+        width: WIDTH,
+        height: HEIGHT,
+        timezone: LabelTimezone::Fixed(FixedOffset::east_opt(0).unwrap()),
+        ..RenderOptions::default()
+    }
+}
+
+/// The small fixture without plots, its prices replaced by the `[open, high, low, close]`
+/// that `prices` gives for each candle
+fn with_prices(prices: impl Fn(usize) -> [f64; 4]) -> ChartData {
+    let mut data = fixture("small");
+    data.plots = Plots::default();
+    for (i, row) in data.data.iter_mut().enumerate() {
+        row[1..5].copy_from_slice(&prices(i));
+    }
+    data
+}
+
+/// Width, height and RGB pixels of a PNG
+fn decode_png(bytes: &[u8]) -> (u32, u32, Vec<u8>) {
+    let mut reader = png::Decoder::new(bytes).read_info().expect("output is a PNG");
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).expect("PNG decodes");
+    assert_eq!(info.color_type, png::ColorType::Rgb);
+    pixels.truncate(info.buffer_size());
+    (info.width, info.height, pixels)
+}
+
+/// RGB pixels of `data` rendered at the test size, and where its candles landed
+fn render_with_coords(mut data: ChartData) -> (Vec<u8>, ChartCoords) {
+    data.emit_coords = true;
+    let (bytes, _, coords) = render_chart_with_coords(&data, &options()).unwrap();
+    (decode_png(&bytes).2, coords.expect("coords were asked for"))
+}
+
+/// The RGB pixel at `(x, y)` of an image at the test size
+fn pixel(pixels: &[u8], x: i32, y: i32) -> [u8; 3] {
+    let at = (y as usize * WIDTH as usize + x as usize) * 3;
+    [pixels[at], pixels[at + 1], pixels[at + 2]]
+}
+
+/// Whether any pixel of row `y` between `x_left` and `x_right` is not white
+fn drawn_between(pixels: &[u8], x_left: i32, x_right: i32, y: i32) -> bool {
+    (x_left..=x_right).any(|x| pixel(pixels, x, y) != [255, 255, 255])
+}
+
+// ─── Smoke Tests ────────────────────────────────────────────────────────────────
+
+#[test]
+fn renders_the_fixture_as_png() {
+    let bytes = render_chart(&fixture("small"), &options()).unwrap();
+    let (width, height, pixels) = decode_png(&bytes);
+    assert_eq!((width, height), (WIDTH, HEIGHT));
+    // Something besides the background was drawn
+    let first = &pixels[..3];
+    assert!(pixels.chunks(3).any(|pixel| pixel != first));
+}
+
+#[test]
+fn renders_the_fixture_as_webp() {
+    let opts = RenderOptions { format: ImageFormat::Webp, ..options() };
+    let bytes = render_chart(&fixture("small"), &opts).unwrap();
+    assert_eq!(&bytes[..4], b"RIFF");
+    assert_eq!(&bytes[8..12], b"WEBP");
+}
+
+#[test]
+fn renders_every_style() {
+    for style in [ChartStyle::Full, ChartStyle::Thumbnail, ChartStyle::Sparkline] {
+        let opts = RenderOptions { style, ..options() };
+        let bytes = render_chart(&fixture("small"), &opts).unwrap();
+        assert_eq!(decode_png(&bytes).0, WIDTH, "{:?}", style);
+    }
+}
+
+#[test]
+fn rendering_twice_gives_the_same_bytes() {
+    let data = fixture("small");
+    assert_eq!(render_chart(&data, &options()).unwrap(), render_chart(&data, &options()).unwrap());
+}
+
+#[test]
+fn chart_without_candles_is_an_error() {
+    let mut data = fixture("small");
+    data.data.clear();
+    assert!(render_chart(&data, &options()).is_err());
+}
+
+// ─── Price Ranges ───────────────────────────────────────────────────────────────
+
+#[test]
+fn flat_prices_sit_in_the_middle_of_a_synthetic_range() {
+    // A flat series gets ±1% of its price, or ±1 at zero, so it is drawn where it would
+    // be among candles spanning that range
+    for (price, half_width) in [(63_000.0, 630.0), (0.5, 0.005), (0.0, 1.0)] {
+        let (pixels, flat) = render_with_coords(with_prices(|_| [price; 4]));
+        let spanning = [price, price + half_width, price - half_width, price];
+        let (_, reference) = render_with_coords(with_prices(|_| spanning));
+        for (candle, expected) in flat.candles.iter().zip(&reference.candles) {
+            assert_eq!([candle.y_high, candle.y_low, candle.y_close], [expected.y_close; 3]);
+            assert!(expected.y_high < candle.y_close && candle.y_close < expected.y_low);
+            assert!(drawn_between(&pixels, candle.x_left, candle.x_right, candle.y_close));
+        }
+    }
+}
+
+#[test]
+fn zero_crossing_prices_switch_to_a_linear_scale() {
+    // A spread swinging between about -5 and 5
+    let spread = |i: usize| {
+        let (open, close) = (5.0 * (i as f64 / 3.0).sin(), 5.0 * ((i + 1) as f64 / 3.0).sin());
+        [open, open.max(close) + 0.5, open.min(close) - 0.5, close]
+    };
+    let (pixels, coords) = render_with_coords(with_prices(spread));
+
+    // Prices map to pixels in a straight line, negative ones included
+    let candles = &coords.candles;
+    let (lowest, highest) = (candles.iter().map(|c| c.low), candles.iter().map(|c| c.high));
+    let (lowest, highest) = (lowest.fold(f64::MAX, f64::min), highest.fold(f64::MIN, f64::max));
+    let bottom = candles.iter().map(|c| c.y_low).max().unwrap() as f64;
+    let top = candles.iter().map(|c| c.y_high).min().unwrap() as f64;
+    for candle in candles {
+        for (price, y) in [(candle.low, candle.y_low), (candle.close, candle.y_close)] {
+            let expected = bottom - (price - lowest) / (highest - lowest) * (bottom - top);
+            assert!((y as f64 - expected).abs() <= 1.5, "{} at {} not {}", price, y, expected);
+        }
+        assert!(drawn_between(&pixels, candle.x_left, candle.x_right, candle.y_close));
+    }
+    assert!(lowest < -5.0 && bottom as i32 >= coords.plot.bottom - 1);
+
+    let mut linear = with_prices(spread);
+    linear.price_scale = PriceScale::Linear;
+    let log = with_prices(spread);
+    assert_eq!(log.price_scale, PriceScale::Log);
+    assert_eq!(render_chart(&log, &options()).unwrap(), render_chart(&linear, &options()).unwrap());
+}
+
+#[test]
+fn volume_less_dataset_draws_no_volume_bars() {
+    let mut without_volume = fixture("small");
+    without_volume.cols.retain(|column| column != "volume");
+    for row in &mut without_volume.data {
+        row.truncate(5);
+    }
+    let (pixels, coords) = render_with_coords(without_volume);
+    assert!(coords.candles.iter().all(|candle| candle.volume == 0.0));
+
+    // Nothing but the candles reaches into the band volume bars would take
+    let mut hidden = fixture("small");
+    hidden.show_volume = false;
+    let (hidden_pixels, _) = render_with_coords(hidden);
+    let (volume_pixels, _) = render_with_coords(fixture("small"));
+    let band = coords.plot.bottom - (coords.plot.bottom - coords.plot.top) * 15 / 100;
+    let band_rows = |pixels: &[u8]| -> Vec<u8> {
+        let row_bytes = WIDTH as usize * 3;
+        pixels[band as usize * row_bytes..coords.plot.bottom as usize * row_bytes].to_vec()
+    };
+    assert_eq!(band_rows(&pixels), band_rows(&hidden_pixels));
+    assert_ne!(band_rows(&pixels), band_rows(&volume_pixels));
+}
+
+// ─── Candles ────────────────────────────────────────────────────────────────────
+
+#[test]
+fn doji_is_drawn_as_a_line_across_its_body() {
+    let mut data = fixture("small");
+    let doji = 12;
+    let row = &mut data.data[doji];
+    row[4] = row[1];
+    let (pixels, coords) = render_with_coords(data);
+    let candle = &coords.candles[doji];
+    assert_eq!(candle.y_open, candle.y_close);
+    assert!(candle.y_high < candle.y_open && candle.y_open < candle.y_low);
+    for x in candle.x_left..=candle.x_right {
+        assert_ne!(pixel(&pixels, x, candle.y_open), [255, 255, 255], "gap at x {}", x);
+    }
+}
+
+#[test]
+fn chart_of_only_dojis_shows_every_candle() {
+    // Bodies far thinner than a pixel, some rising and some falling, under long wicks
+    let closes: Vec<f64> = fixture("small").data.iter().map(|row| row[4]).collect();
+    let data = with_prices(|i| {
+        let open = closes[i];
+        let close = open * if i % 2 == 0 { 1.000_001 } else { 0.999_999 };
+        [open, open + 60.0, open - 60.0, close]
+    });
+    let (pixels, coords) = render_with_coords(data);
+    for candle in &coords.candles {
+        assert!(candle.y_open.abs_diff(candle.y_close) <= 1, "{:?}", candle);
+        // The body is widened about its middle, so it may round onto a neighbouring row
+        let rows = candle.y_open.min(candle.y_close) - 1..=candle.y_open.max(candle.y_close) + 1;
+        let body_row = rows.into_iter().find(|&y| {
+            (candle.x_left..candle.x_right).all(|x| pixel(&pixels, x, y) != [255, 255, 255])
+        });
+        assert!(body_row.is_some(), "candle {} has no body", candle.index);
+    }
+}
+
+// ─── Layers ─────────────────────────────────────────────────────────────────────
+
+/// The small fixture with `marks` as its only plots
+fn with_marks(marks: serde_json::Value) -> ChartData {
+    let mut data = fixture("small");
+    data.plots = serde_json::from_value(serde_json::json!({ "marks": marks })).unwrap();
+    data
+}
+
+#[test]
+fn marker_is_drawn_over_an_opaque_zone() {
+    const MAGENTA: [u8; 3] = [255, 0, 255];
+    const BLUE: [u8; 3] = [0, 0, 255];
+    let time = fixture("small").data[15][0] as i64;
+    let marker = serde_json::json!({"time": time, "position": "below", "color": "#FF00FF"});
+    // Listed after the marker, so drawing in list order would cover it
+    let zone = serde_json::json!({
+        "type": "zone",
+        "from_timestamp": fixture("small").data[0][0] as i64,
+        "to_timestamp": fixture("small").data[29][0] as i64,
+        "from_price": 61_000.0,
+        "to_price": 64_000.0,
+        "color": "#0000FFFF",
+    });
+
+    let (alone, _) = render_with_coords(with_marks(serde_json::json!([marker])));
+    let marker_pixels: Vec<usize> = (0..alone.len() / 3)
+        .filter(|i| alone[i * 3..i * 3 + 3] == MAGENTA)
+        .collect();
+    assert!(marker_pixels.len() > 20, "marker has {} pixels", marker_pixels.len());
+
+    let (layered, _) = render_with_coords(with_marks(serde_json::json!([marker, zone])));
+    assert!(layered.chunks(3).filter(|pixel| *pixel == BLUE).count() > 10_000);
+    for i in &marker_pixels {
+        assert_eq!(layered[i * 3..i * 3 + 3], MAGENTA, "zone covers the marker at pixel {}", i);
+    }
+
+    // A marker moved below the zone's layer is hidden by it
+    let mut under = marker.clone();
+    under["z"] = serde_json::json!(-20);
+    let (hidden, _) = render_with_coords(with_marks(serde_json::json!([under, zone])));
+    assert!(marker_pixels.iter().all(|i| hidden[i * 3..i * 3 + 3] != MAGENTA));
+}
+
+// ─── Coordinates ────────────────────────────────────────────────────────────────
+
+#[test]
+fn candle_x_ranges_increase_and_stay_inside_the_plot() {
+    // The fixture, and the fixture repeated until the candles are a few pixels wide
+    let mut long = fixture("small");
+    let hour = long.data[1][0] - long.data[0][0];
+    let rows = long.data.clone();
+    long.data = (0..10 * rows.len())
+        .map(|i| [&[rows[0][0] + i as f64 * hour][..], &rows[i % rows.len()][1..]].concat())
+        .collect();
+    long.plots = Plots::default();
+
+    for data in [fixture("small"), long] {
+        let count = data.data.len();
+        let (_, coords) = render_with_coords(data);
+        let plot = &coords.plot;
+        assert_eq!(coords.candles.len(), count);
+        assert!(plot.left < plot.right && plot.top < plot.bottom);
+        for candle in &coords.candles {
+            // The first candle is cut in half by the plot edge, which may leave no columns
+            assert!(plot.left <= candle.x_left && candle.x_left <= candle.x_right);
+            assert!(candle.x_right <= plot.right, "{:?} leaves {:?}", candle, plot);
+            for y in [candle.y_open, candle.y_high, candle.y_low, candle.y_close] {
+                assert!((plot.top..=plot.bottom).contains(&y), "{:?} leaves {:?}", candle, plot);
+            }
+        }
+        assert!(coords.candles[1..].iter().all(|candle| candle.x_left < candle.x_right));
+        for pair in coords.candles.windows(2) {
+            assert_eq!(pair[1].index, pair[0].index + 1);
+            assert!(pair[0].timestamp < pair[1].timestamp);
+            assert!(pair[0].x_right <= pair[1].x_left, "{:?} overlaps {:?}", pair[0], pair[1]);
+        }
+    }
+}
+
+// ─── Timestamps ─────────────────────────────────────────────────────────────────
+
+/// The small fixture with every timestamp, its plots' included, passed through `convert`
+fn in_unit(convert: fn(i64) -> i64) -> ChartData {
+    serde_json::from_value(request_in_unit(convert)).unwrap()
+}
+
+/// The request behind `in_unit`
+fn request_in_unit(convert: fn(i64) -> i64) -> serde_json::Value {
+    let text = fs::read_to_string(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/small.json"),
+    )
+    .unwrap();
+    let mut request: serde_json::Value = serde_json::from_str(&text).unwrap();
+    let rescale = |value: &mut serde_json::Value| {
+        *value = serde_json::json!(convert(value.as_i64().unwrap()));
+    };
+    for row in request["data"].as_array_mut().unwrap() {
+        rescale(&mut row[0]);
+    }
+    rescale(&mut request["plots"]["marks"][0]["time"]);
+    rescale(&mut request["plots"]["zones"][0]["x1"]);
+    rescale(&mut request["plots"]["zones"][0]["x2"]);
+    rescale(&mut request["plots"]["vlines"][0]["time"]);
+    request
+}
+
+/// The small fixture in seconds, with the candle timestamps of the rows `as_text`
+/// picks sent as ISO-8601 strings instead
+fn seconds_with_text_rows(as_text: fn(usize) -> bool) -> serde_json::Value {
+    let mut request = request_in_unit(|millis| millis / 1_000);
+    let rows = request["data"].as_array_mut().unwrap();
+    for (i, row) in rows.iter_mut().enumerate() {
+        if as_text(i) {
+            let time = chrono::DateTime::from_timestamp(row[0].as_i64().unwrap(), 0).unwrap();
+            row[0] = time.to_rfc3339().into();
+        }
+    }
+    request
+}
+
+#[test]
+fn seconds_are_detected_and_drawn_like_milliseconds() {
+    let expected = render_chart(&fixture("small"), &options()).unwrap();
+
+    let mut seconds = in_unit(|millis| millis / 1_000);
+    assert!(seconds.data[0][0] < 2e9);
+    assert_eq!(render_chart(&seconds, &options()).unwrap(), expected);
+
+    seconds.normalize_timestamps();
+    assert_eq!(seconds.timestamp_unit, Some(TimestampUnit::Ms));
+    assert_eq!(seconds.data, fixture("small").data);
+    assert_eq!(seconds.plots.vlines[0].time, fixture("small").plots.vlines[0].time);
+}
+
+#[test]
+fn declared_timestamp_units_are_honoured() {
+    let expected = render_chart(&fixture("small"), &options()).unwrap();
+    let mut seconds = in_unit(|millis| millis / 1_000);
+    seconds.timestamp_unit = Some(TimestampUnit::S);
+    assert_eq!(render_chart(&seconds, &options()).unwrap(), expected);
+
+    let mut micros = in_unit(|millis| millis * 1_000);
+    micros.timestamp_unit = Some(TimestampUnit::Us);
+    assert_eq!(render_chart(&micros, &options()).unwrap(), expected);
+}
+
+#[test]
+fn text_timestamps_are_not_rescaled_by_the_declared_unit() {
+    let expected = render_chart(&fixture("small"), &options()).unwrap();
+    let mut request = seconds_with_text_rows(|_| true);
+    request["timestamp_unit"] = "s".into();
+    let mut data: ChartData = serde_json::from_value(request).unwrap();
+    assert_eq!(render_chart(&data, &options()).unwrap(), expected);
+
+    data.normalize_timestamps();
+    assert_eq!(data.data, fixture("small").data);
+    assert_eq!(data.plots.vlines[0].time, fixture("small").plots.vlines[0].time);
+}
+
+#[test]
+fn text_timestamps_do_not_sway_unit_detection() {
+    let expected = render_chart(&fixture("small"), &options()).unwrap();
+    let request = seconds_with_text_rows(|i| i % 2 == 0);
+    let mut data: ChartData = serde_json::from_value(request).unwrap();
+    assert!(data.data[0][0] > 1e12 && data.data[1][0] < 2e9);
+    assert_eq!(render_chart(&data, &options()).unwrap(), expected);
+
+    data.normalize_timestamps();
+    assert_eq!(data.timestamp_unit, Some(TimestampUnit::Ms));
+    assert_eq!(data.data, fixture("small").data);
+}
+
+// ─── Metadata ───────────────────────────────────────────────────────────────────
+
+#[test]
+fn png_text_chunks_describe_the_data() {
+    let opts = RenderOptions {
+        metadata: vec![
+            ("RequestId".to_string(), "req-42".to_string()),
+            ("Desk".to_string(), "東京".to_string()),
+        ],
+        ..options()
+    };
+    let bytes = render_chart(&fixture("small"), &opts).unwrap();
+    let file_name = format!("corky-charts-metadata-{}.png", std::process::id());
+    let path = std::env::temp_dir().join(file_name);
+    fs::write(&path, bytes).unwrap();
+
+    let reader = png::Decoder::new(fs::File::open(&path).unwrap()).read_info().unwrap();
+    let info = reader.info();
+    let text: Vec<(&str, &str)> = info
+        .uncompressed_latin1_text
+        .iter()
+        .map(|chunk| (chunk.keyword.as_str(), chunk.text.as_str()))
+        .collect();
+    let schema_version = METADATA_SCHEMA_VERSION.to_string();
+    assert_eq!(
+        text,
+        [
+            ("Ticker", "BTCUSD"),
+            ("Timeframe", "1h"),
+            ("FirstCandle", "2024-06-10T06:13:20.000Z"),
+            ("LastCandle", "2024-06-11T11:13:20.000Z"),
+            ("CandleCount", "30"),
+            ("SchemaVersion", schema_version.as_str()),
+            ("RequestId", "req-42"),
+        ]
+    );
+    // A value that is not Latin-1 goes in an iTXt chunk instead
+    let [international] = info.utf8_text.as_slice() else {
+        panic!("expected one iTXt chunk, found {}", info.utf8_text.len());
+    };
+    assert_eq!(international.keyword, "Desk");
+    assert_eq!(international.get_text().unwrap(), "東京");
+    fs::remove_file(path).unwrap();
+}